@@ -0,0 +1,64 @@
+use deelevate::{PrivilegeLevel, Token};
+use std::ffi::OsString;
+use structopt::*;
+
+#[path = "normal.rs"]
+mod normal;
+
+/// DeElevate - run a command with reduced privileges
+///
+/// DeElevate is intended to be run from an elevated or otherwise
+/// administrative context, such as an elevated shell or an ssh
+/// session, and will run the requested program with a Normal user
+/// token with Medium integrity level, dropping/denying the local
+/// administrator group from the token.  The program will be run in a
+/// PTY that is bridged to the current terminal session.
+///
+/// Unlike NormDo, DeElevate always derives the reduced token from
+/// the current token, and considers it to be an error to run it from
+/// a context that has no privileges to drop.
+///
+/// Example:
+///    `deelevate cargo build`
+#[derive(StructOpt)]
+#[structopt(
+    about = "DeElevate - run a command with reduced privileges",
+    author = "Wez Furlong",
+    setting(clap::AppSettings::TrailingVarArg),
+    setting(clap::AppSettings::ArgRequiredElseHelp),
+    version = env!("VERGEN_SEMVER_LIGHTWEIGHT")
+)]
+#[derive(Debug)]
+struct Opt {
+    #[structopt(flatten)]
+    bridge: normal::BridgeOpt,
+
+    #[structopt(value_name("PROGRAM"), parse(from_os_str))]
+    args: Vec<OsString>,
+}
+
+fn main() -> std::io::Result<()> {
+    let mut opt = Opt::from_args();
+
+    let token = Token::with_current_process()?;
+    let level = token.privilege_level()?;
+
+    if level == PrivilegeLevel::NotPrivileged {
+        eprintln!(
+            "deelevate: the current context is not privileged, so there \
+             are no privileges to drop.  Run the program directly instead."
+        );
+        std::process::exit(1);
+    }
+
+    opt.bridge.resolve_program(&mut opt.args);
+
+    let target_token = token.as_medium_integrity_safer_token()?;
+    let exit_code = opt
+        .bridge
+        .run_bridged(&mut opt.args, &target_token, |bridge_cmd| {
+            bridge_cmd.spawn_as_user(&target_token)
+        })?;
+
+    std::process::exit(exit_code as _);
+}
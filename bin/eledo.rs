@@ -1,7 +1,12 @@
-use deelevate::{BridgeServer, Command, PrivilegeLevel, Token};
-use pathsearch::find_executable_in_path;
+use deelevate::{
+    is_elevation_declined, is_startup_timeout, resolve_program, BridgeServer, Command,
+    PrivilegeLevel, Token, SHUTDOWN_EXIT_CODE,
+};
 use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::*;
+use winapi::shared::minwindef::DWORD;
 
 /// EleDo - "Do" a command with Elevated privileges
 ///
@@ -27,49 +32,471 @@ use structopt::*;
 )]
 #[derive(Debug)]
 struct Opt {
+    /// Pass the exit status of the program through unchanged, rather
+    /// than mapping an interrupted program to 130 and a terminated
+    /// one to 143.
+    #[structopt(long)]
+    raw_exit_code: bool,
+
+    /// Selects how the token for the program is derived once it has
+    /// been elevated; one of low, medium or high.  The default is
+    /// medium, which is the usual behavior.  Low runs the program at
+    /// low integrity, derived from the elevated token, which is useful
+    /// for sandboxing it.  High runs the program with the current
+    /// token unchanged, and so requires that the current context is
+    /// already privileged.
+    #[structopt(long, default_value = "medium", possible_values(&["low", "medium", "high"]))]
+    integrity: IntegrityLevel,
+
+    /// The ShellExecute verb used to launch the elevated bridge when
+    /// elevation is required; one of runas, runasuser or open.
+    /// runasuser prompts for the credentials of a different user,
+    /// while open launches without elevating, which is useful for
+    /// testing the bridge.
+    #[structopt(long, default_value = "runas", possible_values(ELEVATION_VERBS))]
+    verb: String,
+
+    /// When elevating, give up if the program hasn't started within
+    /// SECS seconds, including the time spent at the UAC prompt.
+    /// The default is to wait indefinitely.
+    #[structopt(long, value_name("SECS"))]
+    startup_timeout: Option<u64>,
+
+    /// Report the detected privilege level, the resolved program and
+    /// how it would be launched, without launching it.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Pass the program through verbatim, rather than locating it in
+    /// the path of eledo and running it from the resulting location.
+    /// The program is then located when it is launched, according to
+    /// the usual Windows rules.
+    #[structopt(long)]
+    no_path_search: bool,
+
+    /// Only change the console modes that are required for bridging
+    /// the elevated program, rather than replacing them, and only
+    /// restore the bits that were changed when it exits.
+    #[structopt(long)]
+    keep_console_mode: bool,
+
+    /// When elevating, bridge stdin, stdout and stderr over pipes
+    /// rather than running the program in a pty, leaving the console
+    /// modes untouched and performing no VT processing.  The program
+    /// sees pipes rather than a console.
+    #[structopt(long)]
+    no_pty: bool,
+
+    /// Run COMMAND LINE through cmd, as `cmd /s /c "COMMAND LINE"`,
+    /// rather than running a program, so that pipelines and builtins
+    /// such as `dir` can be used.  It is passed to cmd exactly as
+    /// given, and so has cmd's parsing semantics.
+    #[structopt(
+        long,
+        value_name("COMMAND LINE"),
+        parse(from_os_str),
+        conflicts_with("args")
+    )]
+    shell: Option<OsString>,
+
+    /// Once the program has exited, write the exit status of eledo
+    /// to PATH, as a decimal number followed by a newline.  The file
+    /// is replaced atomically, and is not written if the program
+    /// never ran.
+    #[structopt(long, value_name("PATH"), parse(from_os_str))]
+    exit_code_file: Option<PathBuf>,
+
     #[structopt(value_name("PROGRAM"), parse(from_os_str))]
     args: Vec<OsString>,
 }
 
+/// The verbs that make sense for launching the bridge
+const ELEVATION_VERBS: &[&str] = &["runas", "runasuser", "open"];
+
+/// Selects how the token for the target program is derived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntegrityLevel {
+    Low,
+    Medium,
+    High,
+}
+
+impl FromStr for IntegrityLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(format!("invalid integrity level {:?}", s)),
+        }
+    }
+}
+
+impl IntegrityLevel {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+}
+
+/// How the token for the target program is derived from our own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TargetToken {
+    LowIntegritySafer,
+    MediumIntegritySafer,
+    ShellProcess,
+    Current,
+}
+
+impl TargetToken {
+    fn name(self) -> &'static str {
+        match self {
+            Self::LowIntegritySafer => "low-integrity-safer",
+            Self::MediumIntegritySafer => "medium-integrity-safer",
+            Self::ShellProcess => "shell-process",
+            Self::Current => "current",
+        }
+    }
+}
+
+/// How the target program is launched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Launch {
+    /// Spawned directly with our token, using the environment of the target token
+    Direct,
+    /// Spawned directly with the target token
+    DirectAsUser,
+    /// Spawned via the "runas" verb (or that passed to `--verb`)
+    /// and bridged to our terminal
+    RunasBridge,
+}
+
+impl Launch {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Direct => "direct",
+            Self::DirectAsUser => "direct-as-user",
+            Self::RunasBridge => "runas-bridge",
+        }
+    }
+}
+
+/// Why `plan` refused to run the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlanError {
+    /// `--integrity high` needs an already privileged context
+    NotPrivileged,
+    /// Nothing but low integrity can be reached from a low integrity context
+    LowIntegrityContext,
+}
+
+/// Decide how to run the program.  The privilege level decides how it
+/// is launched, as usual, while the integrity level decides how the
+/// token that it runs with is derived once elevated.
+fn plan(
+    level: PrivilegeLevel,
+    integrity: IntegrityLevel,
+    low_integrity_context: bool,
+) -> Result<(TargetToken, Launch), PlanError> {
+    if low_integrity_context {
+        // Raising a low integrity context requires going via UAC
+        // and the bridge cannot be reached from a low integrity
+        // process, so the only thing that can be honored is running
+        // at the low integrity that we already have.
+        return match integrity {
+            IntegrityLevel::Low => Ok((TargetToken::LowIntegritySafer, Launch::DirectAsUser)),
+            _ => Err(PlanError::LowIntegrityContext),
+        };
+    }
+    match (integrity, level) {
+        // The elevated bridge derives the low integrity token
+        // itself, from its elevated token; the token here only
+        // supplies the environment, as it does for medium.
+        (IntegrityLevel::Low, PrivilegeLevel::NotPrivileged) => {
+            Ok((TargetToken::LowIntegritySafer, Launch::RunasBridge))
+        }
+        // We already have privs, so derive the token from our own
+        (IntegrityLevel::Low, _) => Ok((TargetToken::LowIntegritySafer, Launch::DirectAsUser)),
+        // We already have privs, so just run it directly
+        (IntegrityLevel::Medium, PrivilegeLevel::HighIntegrityAdmin) => {
+            Ok((TargetToken::MediumIntegritySafer, Launch::Direct))
+        }
+        (IntegrityLevel::Medium, PrivilegeLevel::Elevated) => {
+            Ok((TargetToken::ShellProcess, Launch::Direct))
+        }
+        (IntegrityLevel::Medium, PrivilegeLevel::NotPrivileged) => {
+            Ok((TargetToken::MediumIntegritySafer, Launch::RunasBridge))
+        }
+        // The elevated bridge runs the program with a token that it
+        // derives at medium integrity, so there is no way to honor
+        // this without already having the privileges
+        (IntegrityLevel::High, PrivilegeLevel::NotPrivileged) => Err(PlanError::NotPrivileged),
+        (IntegrityLevel::High, _) => Ok((TargetToken::Current, Launch::Direct)),
+    }
+}
+
+/// The exit status when the user declines the UAC prompt; this is the
+/// value of ERROR_CANCELLED, which is what ShellExecuteEx reports.
+/// It is documented in the README so that scripts can rely on it.
+const ELEVATION_CANCELLED_EXIT_CODE: i32 = 1223;
+
+/// The exit status when the program, or the bridge that runs it when
+/// elevating, could not be launched.  This and `NOT_FOUND_EXIT_CODE`
+/// follow the POSIX shell conventions, and are documented in the README
+/// so that scripts can tell them apart from the other failures of eledo.
+/// The program can of course exit with these values itself.
+const CANNOT_EXECUTE_EXIT_CODE: i32 = 126;
+
+/// The exit status when the program could not be found
+const NOT_FOUND_EXIT_CODE: i32 = 127;
+
+/// The exit status of a process that was terminated by CTRL-C or
+/// CTRL-BREAK, which is what the default console control handler exits
+/// with for both, including the CTRL-BREAK sent by `Process::shutdown`
+const STATUS_CONTROL_C_EXIT: DWORD = 0xC000013A;
+
+/// Maps the windows specific interruption and termination statuses to
+/// the conventional values that shell users expect:
+/// * `STATUS_CONTROL_C_EXIT` becomes 130 (128+SIGINT)
+/// * `SHUTDOWN_EXIT_CODE`, given to a program that `Process::shutdown`
+///   had to terminate, becomes 143 (128+SIGTERM)
+///
+/// All other exit codes are passed through untouched.
+fn translate_exit_code(exit_code: DWORD) -> i32 {
+    match exit_code {
+        STATUS_CONTROL_C_EXIT => 130,
+        SHUTDOWN_EXIT_CODE => 143,
+        _ => exit_code as i32,
+    }
+}
+
+/// The status that eledo exits with for a program that exited with
+/// `exit_code`; see `--raw-exit-code`
+fn exit_status(exit_code: DWORD, raw_exit_code: bool) -> i32 {
+    if raw_exit_code {
+        exit_code as i32
+    } else {
+        translate_exit_code(exit_code)
+    }
+}
+
 fn main() -> std::io::Result<()> {
     let mut opt = Opt::from_args();
 
+    if opt.shell.is_none() && opt.args.is_empty() {
+        eprintln!("eledo: either a PROGRAM or --shell is required; see eledo --help");
+        std::process::exit(1);
+    }
+
     let token = Token::with_current_process()?;
     let level = token.privilege_level()?;
 
-    opt.args[0] = match find_executable_in_path(&opt.args[0]) {
-        Some(path) => path.into(),
-        None => {
-            eprintln!("Unable to find {:?} in path", opt.args[0]);
+    if opt.shell.is_none() && !opt.no_path_search {
+        opt.args[0] = match resolve_program(&opt.args[0]) {
+            Ok(path) => path.into(),
+            Err(err) => {
+                eprintln!("Unable to find {:?}: {}", opt.args[0], err);
+                std::process::exit(NOT_FOUND_EXIT_CODE);
+            }
+        };
+    }
+
+    let (target, launch) = match plan(level, opt.integrity, token.is_low_integrity()?) {
+        Ok(plan) => plan,
+        Err(PlanError::LowIntegrityContext) => {
+            eprintln!(
+                "eledo: cannot run at {} integrity from a low integrity context",
+                opt.integrity.name()
+            );
+            std::process::exit(1);
+        }
+        Err(PlanError::NotPrivileged) => {
+            if token.linked_token()?.is_some() {
+                eprintln!(
+                    "eledo: --integrity high requires an elevated context; \
+                     run eledo from an elevated prompt, or omit --integrity \
+                     to elevate via UAC"
+                );
+            } else {
+                eprintln!("eledo: --integrity high requires administrator privileges");
+            }
             std::process::exit(1);
         }
     };
 
-    let target_token = match level {
-        PrivilegeLevel::NotPrivileged | PrivilegeLevel::HighIntegrityAdmin => {
-            token.as_medium_integrity_safer_token()?
-        }
-        PrivilegeLevel::Elevated => Token::with_shell_process()?,
+    if opt.dry_run {
+        println!("privilege_level: {:?}", level);
+        println!("integrity: {}", opt.integrity.name());
+        println!("target_token: {}", target.name());
+        let executable = match &opt.shell {
+            Some(_) => std::env::var_os("COMSPEC").unwrap_or_else(|| "cmd.exe".into()),
+            None => opt.args[0].clone(),
+        };
+        println!("executable: {}", executable.to_string_lossy());
+        println!("launch: {}", launch.name());
+        return Ok(());
+    }
+
+    let target_token = match target {
+        TargetToken::LowIntegritySafer => token.as_low_integrity_safer_token()?,
+        TargetToken::MediumIntegritySafer => token.as_medium_integrity_safer_token()?,
+        TargetToken::ShellProcess => Token::with_shell_process()?,
+        TargetToken::Current => token.duplicate()?,
     };
 
     let mut command = Command::with_environment_for_token(&target_token)?;
+    command.set_path_search(!opt.no_path_search);
 
-    let exit_code = match level {
-        PrivilegeLevel::Elevated | PrivilegeLevel::HighIntegrityAdmin => {
-            // We already have privs, so just run it directly
-            command.set_argv(opt.args);
-            let proc = command.spawn()?;
+    let exit_code = match launch {
+        Launch::Direct => {
+            set_command(&mut command, opt.shell, opt.args);
+            let proc = command.spawn().unwrap_or_else(|err| launch_failed(err));
+            let _ = proc.wait_for(None);
+            proc.exit_code()?
+        }
+        Launch::DirectAsUser => {
+            set_command(&mut command, opt.shell, opt.args);
+            let proc = command
+                .spawn_as_user(&target_token)
+                .unwrap_or_else(|err| launch_failed(err));
             let _ = proc.wait_for(None);
             proc.exit_code()?
         }
-        PrivilegeLevel::NotPrivileged => {
-            let mut server = BridgeServer::new();
+        Launch::RunasBridge => match run_bridged(&mut opt, &target_token)? {
+            Ok(exit_code) => exit_code,
+            Err(status) => std::process::exit(status),
+        },
+    };
+    exit(exit_code, opt.raw_exit_code, opt.exit_code_file.as_deref());
+}
+
+/// Run the program elevated, bridged to our terminal, and return its
+/// exit code.  When eledo itself has to give up, the failure has been
+/// reported and the status to exit with is returned as the error.
+/// Starting the server changes the console modes, so exiting here
+/// would leave the terminal unusable; by the time this returns, the
+/// server has been dropped and has restored them.
+fn run_bridged(opt: &mut Opt, target_token: &Token) -> std::io::Result<Result<DWORD, i32>> {
+    let mut server = BridgeServer::new();
+    server.set_path_search(!opt.no_path_search);
+    server.set_low_integrity(opt.integrity == IntegrityLevel::Low);
+    server.set_preserve_console_modes(opt.keep_console_mode);
+    if opt.no_pty {
+        server.disable_pty();
+    }
+    if let Some(secs) = opt.startup_timeout {
+        server.set_startup_timeout(std::time::Duration::from_secs(secs));
+    }
 
-            let mut bridge_cmd = server.start_for_command(&mut opt.args, &target_token)?;
+    let bridge_cmd = match &opt.shell {
+        Some(line) => server.start_for_shell_command(line, target_token),
+        None => server.start_for_command(&mut opt.args, target_token),
+    };
+    let mut bridge_cmd = match bridge_cmd {
+        Ok(bridge_cmd) => bridge_cmd,
+        Err(err) => return Ok(Err(bridge_failed(err))),
+    };
 
-            let proc = bridge_cmd.shell_execute("runas")?;
-            server.serve(proc)?
+    let verb = opt.verb.clone();
+    let proc = match server.launch_bridge(move || {
+        bridge_cmd.shell_execute_with_retry(&verb, 3, std::time::Duration::from_millis(250))
+    }) {
+        Ok(proc) => proc,
+        Err(err) if is_elevation_declined(&err) => {
+            eprintln!("eledo: elevation was cancelled by the user");
+            return Ok(Err(ELEVATION_CANCELLED_EXIT_CODE));
         }
+        Err(err) => return Ok(Err(bridge_failed(err))),
     };
-    std::process::exit(exit_code as _);
+    let outcome = match server.serve_with_outcome(proc) {
+        Ok(outcome) => outcome,
+        Err(err) if is_startup_timeout(&err) => return Ok(Err(bridge_failed(err))),
+        Err(err) => return Err(err),
+    };
+    if !outcome.client_connected {
+        // The exit code is from the launcher rather than the
+        // program, so it doesn't mean anything to our caller
+        eprintln!("eledo: the elevated program never started");
+        return Ok(Err(CANNOT_EXECUTE_EXIT_CODE));
+    }
+    Ok(Ok(outcome.exit_code))
+}
+
+/// Set up `command` to run either the `--shell` command line or `args`
+fn set_command(command: &mut Command, shell: Option<OsString>, args: Vec<OsString>) {
+    match shell {
+        Some(line) => command.set_shell_command(line),
+        None => command.set_argv(args),
+    }
+}
+
+/// Report that the program could not be launched directly, and exit
+/// with the reserved status that describes why
+fn launch_failed(err: std::io::Error) -> ! {
+    eprintln!("eledo: unable to launch the program: {}", err);
+    std::process::exit(match err.kind() {
+        // With --no-path-search, this is where a missing program is found out
+        std::io::ErrorKind::NotFound => NOT_FOUND_EXIT_CODE,
+        _ => CANNOT_EXECUTE_EXIT_CODE,
+    });
+}
+
+/// Report that the bridge for the elevated program could not be set
+/// up or launched, returning the status to exit with.  The program
+/// itself is only located by the bridge, so even a missing file here
+/// is a problem with the bridge.
+fn bridge_failed(err: std::io::Error) -> i32 {
+    eprintln!("eledo: unable to launch the elevated bridge: {}", err);
+    CANNOT_EXECUTE_EXIT_CODE
+}
+
+fn exit(exit_code: DWORD, raw_exit_code: bool, exit_code_file: Option<&Path>) -> ! {
+    let status = exit_status(exit_code, raw_exit_code);
+    if let Some(path) = exit_code_file {
+        if let Err(err) = write_exit_code_file(path, status) {
+            eprintln!(
+                "eledo: unable to write the exit code to {}: {}",
+                path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    }
+    std::process::exit(status);
+}
+
+/// Write `status` to `path` for `--exit-code-file`.  It is written to
+/// a temporary file alongside `path` that is then renamed over it, so
+/// that a reader never sees a partially written value.
+fn write_exit_code_file(path: &Path, status: i32) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let temp = PathBuf::from(temp);
+    std::fs::write(&temp, format!("{}\n", status))?;
+    std::fs::rename(&temp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_exit_codes() {
+        assert_eq!(exit_status(STATUS_CONTROL_C_EXIT, false), 130);
+        assert_eq!(exit_status(SHUTDOWN_EXIT_CODE, false), 143);
+        for &code in &[0, 1, 2, 126, 127, 255, 0xC0000005] {
+            assert_eq!(exit_status(code, false), code as i32);
+        }
+
+        // --raw-exit-code passes everything through
+        for &code in &[0, 1, STATUS_CONTROL_C_EXIT, SHUTDOWN_EXIT_CODE] {
+            assert_eq!(exit_status(code, true), code as i32);
+        }
+    }
 }
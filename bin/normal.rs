@@ -0,0 +1,67 @@
+//! The part of `normdo` and `deelevate` that runs a program with a
+//! reduced privilege token, bridged to the current terminal session.
+//! Both of them include it via `#[path]`.
+
+use deelevate::{resolve_program, BridgeServer, Command, Process, Token};
+use std::ffi::OsString;
+use std::io::Result as IoResult;
+use structopt::*;
+use winapi::shared::minwindef::DWORD;
+
+/// The options that control how the program is located and bridged
+#[derive(StructOpt, Debug)]
+pub struct BridgeOpt {
+    /// Bridge stdin, stdout and stderr over pipes rather than running
+    /// the program in a pty, leaving the console modes untouched and
+    /// performing no VT processing.  The program sees pipes rather
+    /// than a console.
+    #[structopt(long)]
+    pub no_pty: bool,
+
+    /// Pass the program through verbatim, rather than locating it in
+    /// the path and running it from the resulting location.
+    /// The program is then located when it is launched, according to
+    /// the usual Windows rules.
+    #[structopt(long)]
+    pub no_path_search: bool,
+}
+
+impl BridgeOpt {
+    /// Locate the program in `args[0]` via `resolve_program`, unless
+    /// `--no-path-search` was given, exiting if it can't be found.
+    pub fn resolve_program(&self, args: &mut [OsString]) {
+        if self.no_path_search {
+            return;
+        }
+        args[0] = match resolve_program(&args[0]) {
+            Ok(path) => path.into(),
+            Err(err) => {
+                eprintln!("Unable to find {:?}: {}", args[0], err);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    /// Run `args` with `target_token` via the bridge, returning its exit
+    /// code.  `spawn` launches the bridge command with the token, as
+    /// the appropriate way to do that depends on how it was derived.
+    pub fn run_bridged<F>(
+        &self,
+        args: &mut Vec<OsString>,
+        target_token: &Token,
+        spawn: F,
+    ) -> IoResult<DWORD>
+    where
+        F: FnOnce(&mut Command) -> IoResult<Process>,
+    {
+        let mut server = BridgeServer::new();
+        server.set_path_search(!self.no_path_search);
+        if self.no_pty {
+            server.disable_pty();
+        }
+
+        let mut bridge_cmd = server.start_for_command(args, target_token)?;
+        let proc = spawn(&mut bridge_cmd)?;
+        server.serve(proc)
+    }
+}
@@ -1,8 +1,10 @@
-use deelevate::{BridgeServer, Command, PrivilegeLevel, Token};
-use pathsearch::find_executable_in_path;
+use deelevate::{Command, PrivilegeLevel, Token};
 use std::ffi::OsString;
 use structopt::*;
 
+#[path = "normal.rs"]
+mod normal;
+
 /// NormDo - "Do" a command with Normal privileges
 ///
 /// NormDo will check to see if the current context has admin privileges.
@@ -26,6 +28,9 @@ use structopt::*;
 )]
 #[derive(Debug)]
 struct Opt {
+    #[structopt(flatten)]
+    bridge: normal::BridgeOpt,
+
     #[structopt(value_name("PROGRAM"), parse(from_os_str))]
     args: Vec<OsString>,
 }
@@ -36,13 +41,7 @@ fn main() -> std::io::Result<()> {
     let token = Token::with_current_process()?;
     let level = token.privilege_level()?;
 
-    opt.args[0] = match find_executable_in_path(&opt.args[0]) {
-        Some(path) => path.into(),
-        None => {
-            eprintln!("Unable to find {:?} in path", opt.args[0]);
-            std::process::exit(1);
-        }
-    };
+    opt.bridge.resolve_program(&mut opt.args);
 
     let target_token = match level {
         PrivilegeLevel::NotPrivileged => token,
@@ -51,6 +50,7 @@ fn main() -> std::io::Result<()> {
     };
 
     let mut command = Command::with_environment_for_token(&target_token)?;
+    command.set_path_search(!opt.bridge.no_path_search);
 
     let exit_code = match level {
         PrivilegeLevel::NotPrivileged => {
@@ -61,18 +61,13 @@ fn main() -> std::io::Result<()> {
             proc.exit_code()?
         }
         PrivilegeLevel::HighIntegrityAdmin | PrivilegeLevel::Elevated => {
-            let mut server = BridgeServer::new();
-
-            let mut bridge_cmd = server.start_for_command(&mut opt.args, &target_token)?;
-
-            let proc = match level {
-                PrivilegeLevel::Elevated => bridge_cmd.spawn_with_token(&target_token)?,
-                PrivilegeLevel::NotPrivileged | PrivilegeLevel::HighIntegrityAdmin => {
-                    bridge_cmd.spawn_as_user(&target_token)?
-                }
-            };
-
-            server.serve(proc)?
+            opt.bridge
+                .run_bridged(&mut opt.args, &target_token, |bridge_cmd| match level {
+                    PrivilegeLevel::Elevated => bridge_cmd.spawn_with_token(&target_token),
+                    PrivilegeLevel::NotPrivileged | PrivilegeLevel::HighIntegrityAdmin => {
+                        bridge_cmd.spawn_as_user(&target_token)
+                    }
+                })?
         }
     };
 
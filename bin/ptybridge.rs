@@ -1,46 +1,20 @@
-use deelevate::{BridgePtyClient, Command, PipeHandle, Token};
-use std::convert::TryInto;
+use deelevate::{run_bridge_client, BridgeArgs, Command, Token};
 use std::ffi::OsString;
-use std::path::PathBuf;
-use structopt::*;
-use winapi::um::wincon::{SetConsoleCP, SetConsoleCursorPosition, SetConsoleOutputCP, COORD};
+use winapi::um::wincon::{SetConsoleCP, SetConsoleOutputCP};
 use winapi::um::winnls::CP_UTF8;
 
 /// A helper program for `eledo` and `normdo` that is used to
 /// bridge pty and pipes between the different privilege levels.
-/// This utility is not intended to be run by humans.
-#[derive(StructOpt)]
-#[structopt(
-    version = env!("VERGEN_SEMVER_LIGHTWEIGHT")
-)]
-struct Opt {
-    #[structopt(long, parse(from_os_str))]
-    stdin: Option<PathBuf>,
-    #[structopt(long, parse(from_os_str))]
-    stdout: Option<PathBuf>,
-    #[structopt(long, parse(from_os_str))]
-    stderr: Option<PathBuf>,
-    #[structopt(long, parse(from_os_str))]
-    conin: Option<PathBuf>,
-    #[structopt(long, parse(from_os_str))]
-    conout: Option<PathBuf>,
-
-    #[structopt(long)]
-    width: Option<usize>,
-    #[structopt(long)]
-    height: Option<usize>,
-
-    #[structopt(long)]
-    cursor_x: Option<usize>,
-    #[structopt(long)]
-    cursor_y: Option<usize>,
-
-    #[structopt(parse(from_os_str))]
-    args: Vec<OsString>,
-}
-
+/// This utility is not intended to be run by humans; its arguments
+/// are described by `BridgeArgs`, and the work is done by
+/// `run_bridge_client`.
 fn main() -> std::io::Result<()> {
-    let mut opt = Opt::from_args();
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if args.len() == 1 && (args[0] == "--version" || args[0] == "-V") {
+        println!("eledo-pty-bridge {}", env!("VERGEN_SEMVER_LIGHTWEIGHT"));
+        return Ok(());
+    }
+    let opt = BridgeArgs::from_args(args)?;
 
     unsafe {
         SetConsoleCP(CP_UTF8);
@@ -48,76 +22,19 @@ fn main() -> std::io::Result<()> {
     }
 
     let token = Token::with_current_process()?;
-
-    if let Some(conin) = opt.conin {
-        let pty_client = BridgePtyClient::with_params(
-            &conin,
-            &opt.conout.unwrap(),
-            opt.width.unwrap(),
-            opt.height.unwrap(),
-        )?;
-
-        let mut args: Vec<OsString> = vec![std::env::current_exe()?.into()];
-
-        if let Some(stdin) = opt.stdin {
-            args.push("--stdin".into());
-            args.push(stdin.into());
-        }
-        if let Some(stdout) = opt.stdout {
-            args.push("--stdout".into());
-            args.push(stdout.into());
-        }
-        if let Some(stderr) = opt.stderr {
-            args.push("--stderr".into());
-            args.push(stderr.into());
-        }
-        if let Some(cursor_x) = opt.cursor_x {
-            args.push("--cursor-x".into());
-            args.push(cursor_x.to_string().into());
-        }
-        if let Some(cursor_y) = opt.cursor_x {
-            args.push("--cursor-y".into());
-            args.push(cursor_y.to_string().into());
-        }
-
-        args.push("--".into());
-        args.append(&mut opt.args);
-
-        let mut cmd = Command::with_environment_for_token(&token)?;
-        cmd.set_argv(args);
-
-        let exit_code = pty_client.run(cmd)?;
-        std::process::exit(exit_code as _);
+    let mut cmd = Command::with_environment_for_token(&token)?;
+    if opt.conin.is_some() {
+        // Run ourselves again inside the pty to take care of the
+        // remaining streams
+        let mut argv: Vec<OsString> = vec![std::env::current_exe()?.into()];
+        argv.append(&mut opt.pty_stage().to_args());
+        cmd.set_argv(argv);
+    } else if opt.shell {
+        cmd.set_shell_command(opt.command[0].clone());
     } else {
-        let mut cmd = Command::with_environment_for_token(&token)?;
-        cmd.set_argv(opt.args);
-
-        if let Some(stdin) = opt.stdin {
-            cmd.set_stdin(PipeHandle::open_pipe(stdin)?)?;
-        }
-        if let Some(stdout) = opt.stdout {
-            cmd.set_stdout(PipeHandle::open_pipe(stdout)?)?;
-        }
-        if let Some(stderr) = opt.stderr {
-            cmd.set_stderr(PipeHandle::open_pipe(stderr)?)?;
-        }
-
-        if let Some(cursor_x) = opt.cursor_x {
-            let conout = PipeHandle::open_pipe("CONOUT$")?;
-            unsafe {
-                SetConsoleCursorPosition(
-                    conout.as_handle(),
-                    COORD {
-                        X: cursor_x.try_into().unwrap(),
-                        Y: opt.cursor_y.unwrap().try_into().unwrap(),
-                    },
-                );
-            }
-        }
-
-        let proc = cmd.spawn()?;
-        let _ = proc.wait_for(None)?;
-        let exit_code = proc.exit_code()?;
-        std::process::exit(exit_code as _);
+        cmd.set_argv(opt.command.clone());
     }
+
+    let exit_code = run_bridge_client(opt, cmd)?;
+    std::process::exit(exit_code as _);
 }
@@ -1,48 +1,286 @@
-use crate::command::Command;
+use crate::command::{Command, NoCommand};
 use crate::pipe::*;
 use crate::process::Process;
-use crate::psuedocon::PsuedoCon;
+use crate::psuedocon::{conpty_available, PsuedoCon};
 use crate::win32_error_with_context;
 use crate::Token;
+pub use args::BridgeArgs;
+use ctrl::CtrlForwarder;
+use legacy::{ConsoleOutput, LegacyConsole};
+use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
-use std::io::{Error as IoError, Read, Result as IoResult, Write};
+use std::io::{BufRead, BufReader, Error as IoError, Read, Result as IoResult, Write};
 use std::os::windows::prelude::*;
 use std::path::{Path, PathBuf};
-use winapi::shared::minwindef::DWORD;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+pub use transport::BridgeTransport;
+use winapi::shared::minwindef::{DWORD, HMODULE, WORD};
+use winapi::shared::winerror::ERROR_TIMEOUT;
 use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
 use winapi::um::consoleapi::{ReadConsoleW, WriteConsoleW};
 use winapi::um::fileapi::GetFileType;
-use winapi::um::winbase::FILE_TYPE_CHAR;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::libloaderapi::{
+    GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+    GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+};
+use winapi::um::synchapi::{CreateEventW, SetEvent};
+use winapi::um::winbase::{FILE_TYPE_CHAR, INFINITE, WAIT_OBJECT_0};
 use winapi::um::wincon::{
-    GetConsoleScreenBufferInfo, CONSOLE_SCREEN_BUFFER_INFO, DISABLE_NEWLINE_AUTO_RETURN,
-    ENABLE_PROCESSED_OUTPUT, ENABLE_VIRTUAL_TERMINAL_INPUT, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
-    ENABLE_WRAP_AT_EOL_OUTPUT,
+    GetConsoleScreenBufferInfo, GetLargestConsoleWindowSize, SetConsoleCP,
+    SetConsoleCursorPosition, SetConsoleOutputCP, SetConsoleScreenBufferSize, SetConsoleWindowInfo,
+    CONSOLE_SCREEN_BUFFER_INFO, DISABLE_NEWLINE_AUTO_RETURN, ENABLE_ECHO_INPUT,
+    ENABLE_EXTENDED_FLAGS, ENABLE_LINE_INPUT, ENABLE_MOUSE_INPUT, ENABLE_PROCESSED_INPUT,
+    ENABLE_PROCESSED_OUTPUT, ENABLE_QUICK_EDIT_MODE, ENABLE_VIRTUAL_TERMINAL_INPUT,
+    ENABLE_VIRTUAL_TERMINAL_PROCESSING, ENABLE_WRAP_AT_EOL_OUTPUT,
+};
+use winapi::um::wincontypes::{COORD, SMALL_RECT};
+use winapi::um::winnls::IsValidCodePage;
+use winapi::um::winnt::{
+    FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE,
+    IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64, IMAGE_FILE_MACHINE_ARMNT,
+    IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_IA64,
 };
-use winapi::um::wincontypes::COORD;
 
+mod args;
+#[cfg(feature = "tokio")]
+mod asyncserve;
+mod ctrl;
+mod legacy;
+#[cfg(test)]
+mod loopback;
+mod transport;
+mod trust;
+
+/// Serializes the tests that change the state of the console that the
+/// test process shares with the others, such as its modes and code page
+#[cfg(test)]
+fn lock_console() -> std::sync::MutexGuard<'static, ()> {
+    lazy_static::lazy_static! {
+        static ref CONSOLE: Mutex<()> = Mutex::new(());
+    }
+    CONSOLE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// How long to wait for the bridge client to connect before
+/// checking whether the bridge process is still running
+const CLIENT_CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a client that has connected has to send the nonce of the
+/// connect handshake before it is disconnected
+const CLIENT_NONCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the remaining output to be drained after the
+/// bridge process has been terminated by the idle timeout.  A process
+/// that it spawned may still be holding the pipes open, so the output
+/// might otherwise never end; see `BridgeServer::set_output_drain_timeout`.
+/// This also bounds the wait when the bridge client went away before
+/// connecting all of the streams.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the bridge client waits for the pipes of the server to
+/// become available
+const BRIDGE_PIPE_OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The default for `BridgeServer::set_copy_chunk_size`
+const DEFAULT_COPY_CHUNK_SIZE: usize = 4096;
+
+/// The bridge client owns a pseudo console that is connected to the
+/// conin and conout pipes of the bridge server.
+/// This requires conpty; when it is not available, `BridgeServer`
+/// bridges only pipes and doesn't ask for a pty client.
+///
+/// `run` is the simple case of running a single command.  To run
+/// several commands over the same pseudo console, use `spawn` followed
+/// by `run_keep_alive` for each of them.  The pseudo console, and with
+/// it the connection to the bridge server, is torn down when the client
+/// is dropped.
 pub struct BridgePtyClient {
     con: PsuedoCon,
 }
 
 impl BridgePtyClient {
-    pub fn with_params(conin: &Path, conout: &Path, width: usize, height: usize) -> IoResult<Self> {
-        let client_to_server = PipeHandle::open_pipe(conout)?;
-        let server_to_client = PipeHandle::open_pipe(conin)?;
-
-        let con = PsuedoCon::new(
-            COORD {
-                X: width as i16,
-                Y: height as i16,
-            },
-            server_to_client,
-            client_to_server,
-        )?;
+    /// Connect to the conin and conout pipes of the bridge server and
+    /// create a pty with a viewport of `width` x `height`.
+    /// `buffer_height` specifies a larger screen buffer for the pty;
+    /// when it is `None` the buffer is the same size as the viewport.
+    /// `nonce` is the value passed by the server for the connect
+    /// handshake; see `BridgeServer::set_connect_handshake`.
+    /// The dimensions must not be zero, and are clamped to the range
+    /// that the console supports.
+    pub fn with_params(
+        conin: &Path,
+        conout: &Path,
+        width: usize,
+        height: usize,
+        buffer_height: Option<usize>,
+        nonce: Option<&str>,
+    ) -> IoResult<Self> {
+        Self::connect(conin, conout, width, height, buffer_height, nonce, false)
+    }
+
+    /// Connect as for `with_params`, and create the pty via
+    /// `PsuedoCon::with_input_injection` so that `write_input` can be
+    /// used to feed it scripted input alongside that of the server.
+    pub fn with_input_injection(
+        conin: &Path,
+        conout: &Path,
+        width: usize,
+        height: usize,
+        buffer_height: Option<usize>,
+        nonce: Option<&str>,
+    ) -> IoResult<Self> {
+        Self::connect(conin, conout, width, height, buffer_height, nonce, true)
+    }
+
+    fn connect(
+        conin: &Path,
+        conout: &Path,
+        width: usize,
+        height: usize,
+        buffer_height: Option<usize>,
+        nonce: Option<&str>,
+        inject_input: bool,
+    ) -> IoResult<Self> {
+        let size = COORD {
+            X: pty_dimension("width", width)?,
+            Y: pty_dimension("height", height)?,
+        };
+        let buffer_height = buffer_height
+            .map(|h| pty_dimension("buffer height", h))
+            .transpose()?;
+
+        let mut client_to_server = open_bridge_pipe("conout", conout)?;
+        let mut server_to_client = open_bridge_pipe("conin", conin)?;
+        if let Some(nonce) = nonce {
+            // The server completes the handshakes in this order
+            server_to_client.send_nonce(nonce)?;
+            client_to_server.send_nonce(nonce)?;
+        }
+
+        let con = if inject_input {
+            PsuedoCon::with_input_injection(
+                size,
+                buffer_height,
+                server_to_client,
+                client_to_server,
+            )?
+        } else {
+            PsuedoCon::new(size, buffer_height, server_to_client, client_to_server)?
+        };
 
         Ok(Self { con })
     }
 
-    pub fn run(&self, mut command: Command) -> IoResult<DWORD> {
-        let proc = command.spawn_with_pty(&self.con)?;
+    /// Report the size of the viewport of the console that we are
+    /// attached to over the control pipe at `control`, whenever it
+    /// changes, so that the bridge server can follow it; see
+    /// `BridgeServer::set_follow_child_resize`.
+    /// This is intended to be called from the process that runs inside
+    /// the pty; the reports are sent from a background thread.
+    pub fn report_viewport_changes(control: &Path, nonce: Option<&str>) -> IoResult<()> {
+        let mut control = PipeHandle::open_pipe(control)?;
+        if let Some(nonce) = nonce {
+            control.send_nonce(nonce)?;
+        }
+        let conout = PipeHandle::open_pipe_with_access(
+            "CONOUT$",
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+        )?;
+        std::thread::spawn(move || {
+            send_viewport_changes(&mut control, || console_viewport(&conout))
+        });
+        Ok(())
+    }
+
+    /// Resize the pty whenever the bridge server reports that its
+    /// console has changed size over the pipe at `resize`; see
+    /// `BridgeServer::set_follow_console_resize`.
+    /// The reports are received on a background thread, which holds
+    /// a reference to the client until the server closes the pipe.
+    pub fn follow_console_resize(
+        self: &Arc<Self>,
+        resize: &Path,
+        nonce: Option<&str>,
+    ) -> IoResult<()> {
+        let mut pipe = open_bridge_pipe("resize", resize)?;
+        if let Some(nonce) = nonce {
+            pipe.send_nonce(nonce)?;
+        }
+        let client = Arc::clone(self);
+        std::thread::spawn(move || {
+            read_viewport_reports(pipe, |width, height| {
+                if let Err(err) = client.resize(width, height) {
+                    log::warn!("unable to resize pty to {}x{}: {}", width, height, err);
+                }
+            });
+        });
+        Ok(())
+    }
+
+    /// Write VT encoded input to the pty, as though it had been typed.
+    /// See `PsuedoCon::write_input` for details of the encoding.
+    /// This requires a client created via `with_input_injection`.
+    pub fn write_input(&self, bytes: &[u8]) -> IoResult<()> {
+        self.con.write_input(bytes)
+    }
+
+    /// Returns the pseudo console that the bridge client created, for
+    /// interoperating with other pseudo console aware code
+    pub fn psuedo_console(&self) -> &PsuedoCon {
+        &self.con
+    }
+
+    /// Returns the screen buffer height that was requested for the pty.
+    /// The process spawned into the pty is responsible for applying it.
+    pub fn buffer_height(&self) -> Option<usize> {
+        self.con.buffer_height().map(|h| h as usize)
+    }
+
+    /// Spawn `command` into the pseudo console
+    pub fn spawn(&self, mut command: Command) -> IoResult<Process> {
+        command.spawn_with_pty(&self.con)
+    }
+
+    /// Resize the viewport of the pty to `width` x `height`, as when
+    /// the window of the host terminal has changed size.  The program
+    /// running in it sees the new size straight away, along with a
+    /// `WINDOW_BUFFER_SIZE_EVENT` if it is reading console input.
+    /// The client can be shared with another thread, for example via
+    /// `Arc`, so that it can be resized while `run` or `run_keep_alive`
+    /// is waiting for the program.
+    pub fn resize(&self, width: usize, height: usize) -> IoResult<()> {
+        self.con.resize(COORD {
+            X: pty_dimension("width", width)?,
+            Y: pty_dimension("height", height)?,
+        })
+    }
+
+    /// Run `command` in the pseudo console and return its exit code
+    pub fn run(&self, command: Command) -> IoResult<DWORD> {
+        let proc = self.spawn(command)?;
+        self.wait_for_output(&proc)
+    }
+
+    /// Wait for `proc`, which was started via `spawn`, to complete
+    /// and return its exit code.  The pseudo console is left intact
+    /// and is reset so that another command can be spawned into it,
+    /// without the screen state left behind by this one; see
+    /// `PsuedoCon::reset`.  The reset is best effort: if it fails, a
+    /// warning is logged and the exit code is still returned.
+    pub fn run_keep_alive(&self, proc: Process) -> IoResult<DWORD> {
+        let exit_code = self.wait_for_output(&proc)?;
+        if let Err(err) = self.con.reset() {
+            log::warn!("unable to reset the pty for the next command: {}", err);
+        }
+        Ok(exit_code)
+    }
+
+    fn wait_for_output(&self, proc: &Process) -> IoResult<DWORD> {
         proc.wait_for(None)?;
         // Well, this is a bit awkward.
         // If we kill the pty immediately when the child process exits,
@@ -57,15 +295,383 @@ impl BridgePtyClient {
     }
 }
 
-#[allow(unused)]
-fn join_with_timeout(join_handle: std::thread::JoinHandle<()>, timeout: std::time::Duration) {
-    use std::sync::mpsc::channel;
+/// Connect `command` to the stdio pipes of the bridge server, as named
+/// by the `--stdin`, `--stdout` and `--stderr` arguments produced by
+/// `BridgeServer::start`, so that spawning it completes the piped side
+/// of the bridge.  This is the counterpart of `BridgePtyClient` for
+/// streams that are not attached to a pty.
+/// `nonce` is the value passed by the server for the connect
+/// handshake; see `BridgeServer::set_connect_handshake`.
+pub fn connect_stdio_pipes(
+    command: &mut Command,
+    stdin: Option<&Path>,
+    stdout: Option<&Path>,
+    stderr: Option<&Path>,
+    nonce: Option<&str>,
+) -> IoResult<()> {
+    let open = |path: &Path| -> IoResult<PipeHandle> {
+        let mut pipe = PipeHandle::open_pipe(path)?;
+        if let Some(nonce) = nonce {
+            pipe.send_nonce(nonce)?;
+        }
+        Ok(pipe)
+    };
+    // The server completes the handshakes in this order
+    if let Some(stdin) = stdin {
+        command.set_stdin(open(stdin)?)?;
+    }
+    if let Some(stdout) = stdout {
+        let pipe = open(stdout)?;
+        if stderr == Some(stdout) {
+            // The server merged stderr into stdout; see
+            // BridgeServer::set_merge_stderr_into_stdout
+            command.set_stderr(pipe.duplicate()?)?;
+        }
+        command.set_stdout(pipe)?;
+    }
+    if let Some(stderr) = stderr.filter(|&stderr| Some(stderr) != stdout) {
+        command.set_stderr(open(stderr)?)?;
+    }
+    Ok(())
+}
+
+/// Run the bridge client for the arguments produced by
+/// `BridgeServer::start`, as `eledo-pty-bridge.exe` does, and return
+/// the exit code of `command`.  This allows an application to act as
+/// its own bridge client, rather than shipping the separate executable.
+///
+/// The client runs in two stages when the console is bridged.  In the
+/// first, `args.conin` is set, and this creates the pty and runs
+/// `command` inside it.  The remaining streams have to be connected,
+/// and the console of the pty prepared, from within the pty, so
+/// `command` must run a process that calls this function again with
+/// the arguments from `BridgeArgs::pty_stage`; the executable does
+/// that by running itself.
+/// In the second stage, or when only pipes are bridged, the stdio
+/// streams of `command` are connected to the pipes of the server, the
+/// console is prepared (which includes setting its code page when
+/// `args.code_page` is set) and `command` is run to completion.
+///
+/// `command` is run as given, other than in those respects and the
+/// path search and integrity of the second stage, which are taken
+/// from `args`;
+/// `args.command` is unused, as the caller prepares `command`.
+pub fn run_bridge_client(args: BridgeArgs, mut command: Command) -> IoResult<DWORD> {
+    if let (Some(conin), Some(conout)) = (&args.conin, &args.conout) {
+        let (width, height) = match (args.width, args.height) {
+            (Some(width), Some(height)) => (width, height),
+            _ => {
+                return Err(IoError::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--width and --height are required when bridging the console",
+                ))
+            }
+        };
+        let pty_client = BridgePtyClient::with_params(
+            conin,
+            conout,
+            width,
+            height,
+            args.buffer_height,
+            args.nonce.as_deref(),
+        )?;
+        let pty_client = Arc::new(pty_client);
+        if let Some(resize) = &args.resize {
+            pty_client.follow_console_resize(resize, args.nonce.as_deref())?;
+        }
+        return pty_client.run(command);
+    }
+
+    command.set_path_search(!args.no_path_search);
+    let nonce = args.nonce.as_deref();
+    connect_stdio_pipes(
+        &mut command,
+        args.stdin.as_deref(),
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        nonce,
+    )?;
+
+    if let Some(buffer_height) = args.buffer_height {
+        // We're running inside the pty; grow its buffer to the
+        // requested height.  This is best effort, as it isn't
+        // fatal for the buffer to match the viewport.
+        if let Ok(conout) = PipeHandle::open_pipe("CONOUT$") {
+            unsafe {
+                let mut console_info: CONSOLE_SCREEN_BUFFER_INFO = std::mem::zeroed();
+                if GetConsoleScreenBufferInfo(conout.as_handle(), &mut console_info) != 0 {
+                    SetConsoleScreenBufferSize(
+                        conout.as_handle(),
+                        COORD {
+                            X: console_info.dwSize.X,
+                            Y: coord_dimension("buffer height", buffer_height),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    if let (Some(cursor_x), Some(cursor_y)) = (args.cursor_x, args.cursor_y) {
+        let conout = PipeHandle::open_pipe("CONOUT$")?;
+        unsafe {
+            SetConsoleCursorPosition(
+                conout.as_handle(),
+                COORD {
+                    X: coord_dimension("cursor column", cursor_x),
+                    Y: coord_dimension("cursor row", cursor_y),
+                },
+            );
+        }
+    }
+
+    if let Some(control) = &args.control {
+        // We're running inside the pty; let the server know if
+        // the program changes the size of the console
+        BridgePtyClient::report_viewport_changes(control, nonce)?;
+    }
+
+    if let Some(ctrl) = &args.ctrl {
+        // Raise the signals that the server catches in the console
+        // that the program shares with us
+        ctrl::raise_ctrl_events(ctrl, nonce)?;
+    }
+
+    if let Some(code_page) = args.code_page {
+        // The code page belongs to the console rather than to a
+        // process, so the program picks it up from the console that
+        // it shares with us, provided that we set it first
+        set_console_code_page(code_page)?;
+    }
+
+    let proc = if args.low_integrity {
+        let token = Token::with_current_process()?.as_low_integrity_safer_token()?;
+        command.spawn_as_user(&token)?
+    } else {
+        command.spawn()?
+    };
+    let _ = proc.wait_for(None)?;
+    proc.exit_code()
+}
+
+/// Check that `code_page` is installed, so that an invalid one is
+/// reported as such rather than with a generic SetConsoleCP error
+fn validate_code_page(code_page: u32) -> IoResult<()> {
+    if unsafe { IsValidCodePage(code_page) } == 0 {
+        return Err(IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("code page {} is not installed", code_page),
+        ));
+    }
+    Ok(())
+}
+
+/// Set both the input and output code page of the console that we
+/// are attached to, and thus of the programs that share it
+fn set_console_code_page(code_page: u32) -> IoResult<()> {
+    validate_code_page(code_page)?;
+    if unsafe { SetConsoleCP(code_page) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleCP",
+            IoError::last_os_error(),
+        ));
+    }
+    if unsafe { SetConsoleOutputCP(code_page) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleOutputCP",
+            IoError::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Convert a dimension to the `i16` used by `COORD`.  Values that are
+/// too large are clamped, rather than wrapping around to a negative
+/// and thus nonsensical size.
+fn coord_dimension(name: &str, value: usize) -> i16 {
+    if value > i16::MAX as usize {
+        log::warn!(
+            "console {} {} is too large; clamping it to {}",
+            name,
+            value,
+            i16::MAX
+        );
+        i16::MAX
+    } else {
+        value as i16
+    }
+}
+
+/// Like `coord_dimension`, but a pty can't be created with a zero
+/// sized dimension, so reject that up front rather than leaving it
+/// to CreatePseudoConsole's bare E_INVALIDARG.
+fn pty_dimension(name: &str, value: usize) -> IoResult<i16> {
+    if value == 0 {
+        return Err(IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("the pty {} must not be zero", name),
+        ));
+    }
+    Ok(coord_dimension(name, value))
+}
+
+/// Open one of the pipes that the bridge server created for the pty,
+/// saying which one in the error, since the OS error alone doesn't.
+fn open_bridge_pipe(name: &str, path: &Path) -> IoResult<PipeHandle> {
+    PipeHandle::open_pipe_waiting(path, BRIDGE_PIPE_OPEN_TIMEOUT)
+        .map_err(|err| IoError::new(err.kind(), format!("failed to open {} pipe: {}", name, err)))
+}
+
+/// Wait up to `timeout` for the thread to complete, or for as long
+/// as it takes if there is none.
+/// Returns false if it was still running when the time elapsed.
+fn join_with_timeout<T: Send + 'static>(
+    join_handle: std::thread::JoinHandle<T>,
+    timeout: Option<std::time::Duration>,
+) -> bool {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            let _ = join_handle.join();
+            return true;
+        }
+    };
     let (tx, rx) = channel();
     std::thread::spawn(move || {
         let _ = join_handle.join();
         let _ = tx.send(());
     });
-    let _ = rx.recv_timeout(timeout);
+    rx.recv_timeout(timeout).is_ok()
+}
+
+/// The threads that copy the output of the program, which the bridge
+/// server waits for once the bridge process has exited
+#[derive(Default)]
+struct OutputThreads {
+    conout: Option<std::thread::JoinHandle<IoResult<()>>>,
+    stdout: Option<std::thread::JoinHandle<()>>,
+    stderr: Option<std::thread::JoinHandle<()>>,
+    /// Set once we stop waiting, so that the conout thread stops
+    /// writing to the console, as its mode may be restored after that
+    abandoned: Arc<AtomicBool>,
+}
+
+impl OutputThreads {
+    /// Wait for the threads to finish, for at most `timeout` if set,
+    /// and abandon any that are still running.
+    /// Returns false if any had to be abandoned.
+    fn join(&mut self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let remaining = || deadline.map(|at| at.saturating_duration_since(Instant::now()));
+        let mut drained = true;
+        if let Some(t) = self.stdout.take() {
+            drained &= join_with_timeout(t, remaining());
+        }
+        if let Some(t) = self.stderr.take() {
+            drained &= join_with_timeout(t, remaining());
+        }
+        if let Some(t) = self.conout.take() {
+            drained &= join_with_timeout(t, remaining());
+        }
+        if !drained {
+            self.abandoned.store(true, Ordering::SeqCst);
+        }
+        drained
+    }
+}
+
+impl Drop for OutputThreads {
+    /// Anything still running when we bail out early is abandoned
+    fn drop(&mut self) {
+        self.abandoned.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Describes the dimensions of the console and the position of the
+/// cursor within it, as passed to the bridge client so that its pty
+/// can match the console that the bridge server is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleGeometry {
+    /// The width of the viewport
+    pub width: usize,
+    /// The height of the viewport
+    pub height: usize,
+    /// The column of the cursor
+    pub cursor_x: usize,
+    /// The row of the cursor, relative to the top of the viewport
+    pub cursor_y: usize,
+    /// The height of the screen buffer, if it is taller than the viewport
+    pub buffer_height: Option<usize>,
+}
+
+impl ConsoleGeometry {
+    /// Compute the geometry from the information reported by
+    /// GetConsoleScreenBufferInfo
+    pub fn with_console_info(console_info: &CONSOLE_SCREEN_BUFFER_INFO) -> Self {
+        let (width, height) = viewport_dimensions(console_info);
+
+        // A real console usually has a buffer that is taller than
+        // the window; pass that along so that the pty can match it.
+        // The console reports these as i16; don't let a negative value
+        // wrap around to an enormous usize
+        let buffer_height = console_info.dwSize.Y.max(0) as usize;
+        let buffer_height = if buffer_height > height {
+            Some(buffer_height)
+        } else {
+            None
+        };
+
+        let cursor_x = console_info.dwCursorPosition.X.max(0) as usize;
+        let cursor_y = console_info
+            .dwCursorPosition
+            .Y
+            .saturating_sub(console_info.srWindow.Top)
+            .max(0) as usize;
+
+        Self {
+            width,
+            height,
+            cursor_x,
+            cursor_y,
+            buffer_height,
+        }
+    }
+
+    /// Append the arguments that convey this geometry to the bridge client
+    pub fn append_args(&self, args: &mut Vec<OsString>) {
+        let mut bridge_args = BridgeArgs::default();
+        bridge_args.set_geometry(self);
+        args.append(&mut bridge_args.to_args());
+    }
+}
+
+/// The pipes passed to `BridgeServer::with_pipes`, which are used
+/// by `start` in place of creating new ones
+#[derive(Default)]
+struct ProvidedPipes {
+    stdin: Option<NamedPipeServer>,
+    stdout: Option<NamedPipeServer>,
+    stderr: Option<NamedPipeServer>,
+    conin: Option<NamedPipeServer>,
+    conout: Option<NamedPipeServer>,
+}
+
+/// Returns the pipe that was provided for a stream, or creates a new one
+fn provided_or_new(
+    provided: &mut Option<NamedPipeServer>,
+    token: &Token,
+) -> IoResult<NamedPipeServer> {
+    match provided.take() {
+        Some(pipe) => Ok(pipe),
+        None => NamedPipeServer::for_token_overlapped(token),
+    }
+}
+
+/// Input to send to the pty in place of, or alongside, the console
+/// input; see `BridgeServer::set_scripted_input`.
+struct ScriptedInput {
+    script: Box<dyn Read + Send>,
+    replace_console: bool,
 }
 
 /// The bridge server is the originator of the spawned command.
@@ -75,6 +681,7 @@ pub struct BridgeServer {
     stdin_is_pty: bool,
     stdout_is_pty: bool,
     stderr_is_pty: bool,
+    bridge_stdin: bool,
 
     stdin: Option<PipeHandle>,
     stdout: Option<PipeHandle>,
@@ -85,23 +692,84 @@ pub struct BridgeServer {
     conout: Option<PipeHandle>,
     conout_pipe: Option<PipeHandle>,
 
-    input_mode: Option<DWORD>,
-    output_mode: Option<DWORD>,
+    input_mode: Option<SavedMode>,
+    output_mode: Option<SavedMode>,
+    preserve_console_modes: bool,
+
+    geometry: Option<ConsoleGeometry>,
+    soft_reset_on_drop: bool,
+    restore_main_screen_on_drop: bool,
+    forward_focus_events: bool,
+    bracketed_paste: bool,
+    legacy_console_translation: Option<bool>,
+    /// The text attributes of the console before `start`, when the VT
+    /// output of the program is being translated for it
+    legacy_attributes: Option<WORD>,
+    mouse_input: bool,
+    alt_screen: Arc<AtomicBool>,
+
+    follow_child_resize: bool,
+    control_pipe: Option<PipeHandle>,
+    follow_console_resize: bool,
+    resize_pipe: Option<PipeHandle>,
+    forward_ctrl_events: bool,
+    ctrl_pipe: Option<PipeHandle>,
+    /// Whether `start` bridges the console that we are attached to, if
+    /// any.  This is turned off when conpty is unavailable, and by
+    /// tests that drive the bridge from within the test process.
+    bridge_console: bool,
+    path_search: bool,
+    low_integrity: bool,
+    code_page: Option<u32>,
+    merge_stderr: bool,
+    ctrl_z_eof: bool,
+    strip_ansi: bool,
+    idle_timeout: Option<Duration>,
+    idle_timed_out: bool,
+    output_drain_timeout: Option<Duration>,
+    startup_timeout: Option<Duration>,
+    startup_deadline: Option<StartupDeadline>,
+    counts: StreamCounts,
+
+    provided: ProvidedPipes,
+    newline_mode: NewlineMode,
+    scripted_input: Option<ScriptedInput>,
+    nonce: Option<String>,
+    copy_chunk_size: usize,
+    output_coalesce: Option<Duration>,
+    output_tail: Option<Arc<OutputTail>>,
+    stdout_sink: Option<Box<dyn Write + Send>>,
+    stderr_sink: Option<Box<dyn Write + Send>>,
+    captured: Option<(CaptureBuffer, CaptureBuffer)>,
+    on_osc: Option<OscCallback>,
+    connected_event: Option<ConnectedEvent>,
+    require_trusted_bridge: bool,
+
+    #[cfg(feature = "tokio")]
+    kill_on_drop: bool,
 }
 
 impl Drop for BridgeServer {
     fn drop(&mut self) {
-        if let Some(mode) = self.output_mode {
+        if let Some(saved) = self.output_mode {
             if let Ok(mut conout) = PipeHandle::open_pipe("CONOUT$") {
-                // Emit a soft reset
-                let _ = write!(&mut conout, "\x1b[!p");
+                match self.legacy_attributes {
+                    // There is no VT to reset a legacy console with,
+                    // but the colors that the program left can be undone
+                    Some(attributes) => {
+                        let _ = conout.set_text_attribute(attributes);
+                    }
+                    None => {
+                        let _ = write!(&mut conout, "{}", self.teardown_sequence());
+                    }
+                }
                 // Restore mode
-                let _ = set_console_mode(&conout, mode);
+                let _ = self.restore_console_mode(&conout, saved);
             }
         }
-        if let Some(mode) = self.input_mode {
+        if let Some(saved) = self.input_mode {
             if let Ok(conin) = PipeHandle::open_pipe("CONIN$") {
-                let _ = set_console_mode(&conin, mode);
+                let _ = self.restore_console_mode(&conin, saved);
             }
         }
     }
@@ -120,6 +788,57 @@ fn get_console_mode(pipe: &PipeHandle) -> IoResult<DWORD> {
     }
 }
 
+/// The mode bits that must be clear on the console input for it to be
+/// bridged; otherwise the console would handle line editing, echo and
+/// CTRL-C itself rather than passing the keys along to the pty.
+const CONIN_CLEARED_MODE: DWORD = ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT;
+
+/// Apply `mode` to the console input via `apply`.  A legacy console
+/// rejects `ENABLE_VIRTUAL_TERMINAL_INPUT`, so when `legacy` is set the
+/// mode is retried without it.  Typed text is still bridged then, but
+/// keys that only have a VT encoding, such as the arrows, are not.
+fn apply_input_mode_with<F>(mode: DWORD, legacy: bool, mut apply: F) -> IoResult<SavedMode>
+where
+    F: FnMut(DWORD) -> IoResult<SavedMode>,
+{
+    match apply(mode) {
+        Err(err) if legacy && mode & ENABLE_VIRTUAL_TERMINAL_INPUT != 0 => {
+            log::warn!(
+                "the console doesn't support VT input ({}); bridging it without",
+                err
+            );
+            apply(mode & !ENABLE_VIRTUAL_TERMINAL_INPUT)
+        }
+        result => result,
+    }
+}
+
+/// The mode of a console handle before `BridgeServer::start` changed
+/// it, along with the mode that it was changed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SavedMode {
+    original: DWORD,
+    applied: DWORD,
+}
+
+impl SavedMode {
+    /// Returns the mode to restore when the console is now in mode
+    /// `now`: the bits that were changed by `start` are returned to
+    /// their original state, while any others that have been changed
+    /// since, for example by another process sharing the console, are
+    /// left alone.
+    fn undo_changes(&self, now: DWORD) -> DWORD {
+        let changed = self.original ^ self.applied;
+        (now & !changed) | (self.original & changed)
+    }
+}
+
+/// Returns `current` with the `required` bits set and the `cleared`
+/// bits clear, leaving all of the other bits as they are
+fn merge_console_mode(current: DWORD, required: DWORD, cleared: DWORD) -> DWORD {
+    (current | required) & !cleared
+}
+
 fn set_console_mode(pipe: &PipeHandle, mode: DWORD) -> IoResult<()> {
     let res = unsafe { SetConsoleMode(pipe.as_handle(), mode) };
     if res == 0 {
@@ -138,343 +857,3769 @@ fn set_console_mode(pipe: &PipeHandle, mode: DWORD) -> IoResult<()> {
 // we end up with problems with mismatching codepages.
 fn write_console(out: &mut PipeHandle, s: &str) -> IoResult<()> {
     let c: Vec<u16> = OsStr::new(s).encode_wide().collect();
-    let mut wrote = 0;
-    let res = unsafe {
-        WriteConsoleW(
-            out.as_handle(),
-            c.as_ptr() as *const _,
-            c.len() as _,
-            &mut wrote,
-            std::ptr::null_mut(),
-        )
-    };
-    if res == 0 {
-        Err(IoError::last_os_error())
-    } else {
-        Ok(())
+    let mut remain = &c[..];
+    // WriteConsoleW may accept fewer units than we offer it,
+    // so keep going until it has all been written
+    while !remain.is_empty() {
+        let mut wrote = 0;
+        let res = unsafe {
+            WriteConsoleW(
+                out.as_handle(),
+                remain.as_ptr() as *const _,
+                remain.len() as _,
+                &mut wrote,
+                std::ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            return Err(IoError::last_os_error());
+        }
+        remain = &remain[wrote as usize..];
     }
+    Ok(())
 }
 
-fn is_pty_stream<F: AsRawHandle>(f: &F) -> bool {
+/// Returns true if `f` is a console (a character device) rather than
+/// a pipe or a file.  Streams that are not consoles have to be bridged
+/// by `BridgeServer` using pipes.
+pub fn stream_is_pty<F: AsRawHandle>(f: &F) -> bool {
     let handle = f.as_raw_handle();
     unsafe { GetFileType(handle as _) == FILE_TYPE_CHAR }
 }
 
-impl BridgeServer {
-    pub fn new() -> Self {
-        let stdin_is_pty = is_pty_stream(&std::io::stdin());
-        let stdout_is_pty = is_pty_stream(&std::io::stdout());
-        let stderr_is_pty = is_pty_stream(&std::io::stderr());
-
-        Self {
-            stdin_is_pty,
-            stdout_is_pty,
-            stderr_is_pty,
-            conin: None,
-            conout: None,
-            conin_pipe: None,
-            conout_pipe: None,
-            input_mode: None,
-            output_mode: None,
-            stderr: None,
-            stdout: None,
-            stdin: None,
+/// Read a chunk of input from the console and return it as UTF-8.
+/// `buf` is used as scratch space for the UTF-16 console data; up to
+/// one less than its length is read at a time, leaving room for the
+/// unit held in `pending`, so it must have room for at least two.
+/// A surrogate pair may be split between reads, so a high surrogate at
+/// the end of a read is held back in `pending` until the next one.
+fn read_console(
+    conin: &PipeHandle,
+    buf: &mut [u16],
+    pending: &mut Option<u16>,
+) -> IoResult<String> {
+    let start = match pending.take() {
+        Some(high) => {
+            buf[0] = high;
+            1
         }
-    }
+        None => 0,
+    };
+    let mut num_read = 0;
+    let res = unsafe {
+        ReadConsoleW(
+            conin.as_handle(),
+            buf[start..].as_mut_ptr() as *mut _,
+            (buf.len() - 1) as _,
+            &mut num_read,
+            std::ptr::null_mut(),
+        )
+    };
 
-    pub fn start_for_command(
-        &mut self,
-        argv: &mut Vec<OsString>,
-        target_token: &Token,
-    ) -> IoResult<Command> {
-        let bridge_path = locate_pty_bridge()?;
-        let mut bridge_args = self.start(target_token)?;
+    if res == 0 {
+        return Err(IoError::last_os_error());
+    }
 
-        bridge_args.insert(0, bridge_path.into_os_string());
-        bridge_args.push("--".into());
-        bridge_args.append(argv);
+    Ok(decode_console_input(
+        &buf[0..start + num_read as usize],
+        pending,
+    ))
+}
 
-        let mut bridge_cmd = Command::with_environment_for_token(&target_token)?;
-        bridge_cmd.set_argv(bridge_args);
-        bridge_cmd.hide_window();
+/// Decode UTF-16 console input, holding back a trailing high surrogate
+/// in `pending` rather than decoding it on its own; see `read_console`
+fn decode_console_input(units: &[u16], pending: &mut Option<u16>) -> String {
+    let units = match units.split_last() {
+        Some((&last, rest)) if (0xD800..0xDC00).contains(&last) => {
+            pending.replace(last);
+            rest
+        }
+        _ => units,
+    };
+    OsString::from_wide(units).to_string_lossy().into_owned()
+}
 
-        Ok(bridge_cmd)
-    }
+/// The event that is signalled once the bridge client has connected;
+/// see `BridgeServer::connected_event`
+struct ConnectedEvent(HANDLE);
 
-    /// Creates the server pipe and returns the name of the pipe
-    /// so that it can be passed to the client process
-    pub fn start(&mut self, token: &Token) -> IoResult<Vec<OsString>> {
-        let mut args = vec![];
+/// The event is only signalled and closed, which is fine from any thread
+unsafe impl Send for ConnectedEvent {}
 
-        if !self.stdin_is_pty {
-            let pipe = NamedPipeServer::for_token(token)?;
-            self.stdin.replace(pipe.pipe);
-            args.push("--stdin".into());
-            args.push(pipe.path.into());
+impl Drop for ConnectedEvent {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
         }
+    }
+}
 
-        if !self.stdout_is_pty {
-            let pipe = NamedPipeServer::for_token(token)?;
-            self.stdout.replace(pipe.pipe);
-            args.push("--stdout".into());
-            args.push(pipe.path.into());
+impl ConnectedEvent {
+    fn new() -> IoResult<Self> {
+        let manual_reset = true;
+        let initial_state = false;
+        let event = unsafe {
+            CreateEventW(
+                std::ptr::null_mut(),
+                manual_reset as _,
+                initial_state as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if event.is_null() {
+            return Err(win32_error_with_context(
+                "CreateEventW",
+                IoError::last_os_error(),
+            ));
         }
+        Ok(Self(event))
+    }
 
-        if !self.stderr_is_pty {
-            let pipe = NamedPipeServer::for_token(token)?;
-            self.stderr.replace(pipe.pipe);
-            args.push("--stderr".into());
-            args.push(pipe.path.into());
+    fn signal(&self) -> IoResult<()> {
+        if unsafe { SetEvent(self.0) } == 0 {
+            Err(win32_error_with_context(
+                "SetEvent",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(())
         }
+    }
+}
 
-        if let Ok(conin) = PipeHandle::open_pipe("CONIN$") {
-            self.input_mode.replace(get_console_mode(&conin)?);
-            let pipe = NamedPipeServer::for_token(token)?;
-            self.conin_pipe.replace(pipe.pipe);
-
-            args.push("--conin".into());
-            args.push(pipe.path.into());
-
-            set_console_mode(
-                &conin,
-                // ENABLE_PROCESSED_OUTPUT |  FIXME: CTRl-C handling?
-                ENABLE_VIRTUAL_TERMINAL_INPUT,
-            )?;
-            self.conin.replace(conin);
-        }
+/// Processes the output from the pty before it is sent to CONOUT$.
+/// This is ostensibly just a matter of taking the output
+/// from the pty created by the bridge executable and piping it
+/// into our own CONOUT$ stream, but it is made a little bit
+/// more complicated because the Windows console APIs emit
+/// some slightly hostile initialization sequences when creating
+/// a fresh PTY and launching a process inside it: it will emit
+/// sequences that move the cursor, clear the screen and change
+/// the window title sequence.
+/// For our embedding use case those are distinctly unwanted.
+/// In order to deal with this, we need to parse the terminal
+/// output so that we can filter them out.
+/// The approach is simple: until we spot that initial title
+/// change, we'll filter out CSI and OSC sequences.
+/// Just in case the behavior changes in the future, we'll
+/// also disable suppression if we see any other kind of
+/// output from the pty stream.
+/// The filter also keeps track of whether the output has switched
+/// to the alternate screen, so that the bridge server can switch
+/// back to the main screen when it is done, and passes the OSC
+/// sequences that it forwards to the callback, if any, that was set
+/// with `BridgeServer::on_osc`.
+struct ConoutFilter {
+    parser: termwiz::escape::parser::Parser,
+    suppress_control: bool,
+    alt_screen: Arc<AtomicBool>,
+    on_osc: Option<OscCallback>,
+}
 
-        if let Ok(conout) = PipeHandle::open_pipe("CONOUT$") {
-            self.output_mode.replace(get_console_mode(&conout)?);
-            let pipe = NamedPipeServer::for_token(token)?;
-            self.conout_pipe.replace(pipe.pipe);
-
-            args.push("--conout".into());
-            args.push(pipe.path.into());
-
-            let mut console_info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
-            let res = unsafe { GetConsoleScreenBufferInfo(conout.as_handle(), &mut console_info) };
-
-            if res == 0 {
-                return Err(win32_error_with_context(
-                    "GetConsoleScreenBufferInfo",
-                    IoError::last_os_error(),
-                ));
-            }
-
-            // The console info describes the buffer dimensions.
-            // We need to do a little bit of math to obtain the viewport dimensions!
-            let width = console_info
-                .srWindow
-                .Right
-                .saturating_sub(console_info.srWindow.Left) as usize
-                + 1;
-
-            args.push("--width".into());
-            args.push(width.to_string().into());
-
-            let height = console_info
-                .srWindow
-                .Bottom
-                .saturating_sub(console_info.srWindow.Top) as usize
-                + 1;
-
-            args.push("--height".into());
-            args.push(height.to_string().into());
-
-            let cursor_x = console_info.dwCursorPosition.X as usize;
-            let cursor_y = console_info
-                .dwCursorPosition
-                .Y
-                .saturating_sub(console_info.srWindow.Top) as usize;
-
-            args.push("--cursor-x".into());
-            args.push(cursor_x.to_string().into());
-
-            args.push("--cursor-y".into());
-            args.push(cursor_y.to_string().into());
-
-            set_console_mode(
-                &conout,
-                ENABLE_PROCESSED_OUTPUT
-                    | ENABLE_WRAP_AT_EOL_OUTPUT
-                    | ENABLE_VIRTUAL_TERMINAL_PROCESSING
-                    | DISABLE_NEWLINE_AUTO_RETURN,
-            )?;
+/// The callback passed to `BridgeServer::on_osc`
+pub type OscCallback = Box<dyn Fn(&[u8]) + Send>;
 
-            self.conout.replace(conout);
+impl ConoutFilter {
+    fn new(alt_screen: Arc<AtomicBool>) -> Self {
+        Self {
+            parser: termwiz::escape::parser::Parser::new(),
+            suppress_control: true,
+            alt_screen,
+            on_osc: None,
         }
-
-        Ok(args)
     }
 
-    pub fn serve(mut self, proc: Process) -> IoResult<DWORD> {
-        if let Some(conin) = self.conin.take() {
-            let mut conin_dest = self.conin_pipe.take().unwrap();
-            conin_dest.wait_for_pipe_client()?;
-            std::thread::spawn(move || -> IoResult<()> {
-                let mut buf = [0u16; 8192];
-                let mut num_read = 0;
-                loop {
-                    let res = unsafe {
-                        ReadConsoleW(
-                            conin.as_handle(),
-                            buf.as_mut_ptr() as *mut _,
-                            buf.len() as _,
-                            &mut num_read,
-                            std::ptr::null_mut(),
-                        )
-                    };
+    /// Parse `data` and pass the interesting parts of it to `output`
+    fn write_with<F: FnMut(&str) -> IoResult<()>>(
+        &mut self,
+        data: &[u8],
+        mut output: F,
+    ) -> IoResult<()> {
+        use termwiz::escape::osc::OperatingSystemCommand;
+        use termwiz::escape::Action;
 
-                    if res == 0 {
-                        return Err(IoError::last_os_error());
+        let suppress_control = &mut self.suppress_control;
+        let alt_screen = &self.alt_screen;
+        let on_osc = &self.on_osc;
+        let mut error = None;
+        let mut callback = |action: Action| -> IoResult<()> {
+            match action {
+                Action::OperatingSystemCommand(osc) => {
+                    if *suppress_control {
+                        if let OperatingSystemCommand::SetIconNameAndWindowTitle(_) = *osc {
+                            // We're now sync'd up with the new pty instance.
+                            // We ignore this first title change request because
+                            // it is going to be the uninteresting bridge exe
+                            *suppress_control = false;
+                            return Ok(());
+                        }
+                    }
+                    let sequence = format!("{}", osc);
+                    if let Some(on_osc) = on_osc {
+                        on_osc(osc_payload(&sequence));
+                    }
+                    output(&sequence)
+                }
+                Action::CSI(c) => {
+                    if !*suppress_control {
+                        if let Some(enabled) = alt_screen_change(&c) {
+                            alt_screen.store(enabled, Ordering::SeqCst);
+                        }
+                        output(&format!("{}", c))
+                    } else {
+                        Ok(())
                     }
+                }
+                _ => {
+                    *suppress_control = false;
+                    output(&format!("{}", action))
+                }
+            }
+        };
 
-                    let s = OsString::from_wide(&buf[0..num_read as usize]);
-                    let utf8 = s.to_string_lossy();
+        self.parser.parse(data, |action| {
+            if let Err(e) = callback(action) {
+                error.replace(e);
+            }
+        });
 
-                    conin_dest.write_all(utf8.as_bytes())?;
-                }
-            });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
+    }
+}
 
-        // Start up the console output processing thread.
-        // This is ostensibly just a matter of taking the output
-        // from the pty created by the bridge executable and piping it
-        // into our own CONOUT$ stream, but it is made a little bit
-        // more complicated because the Windows console APIs emit
-        // some slightly hostile initialization sequences when creating
-        // a fresh PTY and launching a process inside it: it will emit
-        // sequences that move the cursor, clear the screen and change
-        // the window title sequence.
-        // For our embedding use case those are distinctly unwanted.
-        // In order to deal with this, we need to parse the terminal
-        // output so that we can filter them out.
-        // The approach is simple: until we spot that initial title
-        // change, we'll filter out CSI and OSC sequences.
-        // Just in case the behavior changes in the future, we'll
-        // also disable suppression if we see any other kind of
-        // output from the pty stream.
-        let conout_thread = self.conout.take().map(|mut conout| {
-            let mut conout_src = self.conout_pipe.take().unwrap();
-            let _ = conout_src.wait_for_pipe_client();
-            std::thread::spawn(move || -> IoResult<()> {
-                use termwiz::escape::osc::OperatingSystemCommand;
-                use termwiz::escape::parser::Parser;
-                use termwiz::escape::Action;
+/// The payload of an OSC sequence formatted by termwiz, which is
+/// everything between the introducing `ESC ]` and the terminating BEL
+fn osc_payload(sequence: &str) -> &[u8] {
+    let sequence = sequence.strip_prefix("\x1b]").unwrap_or(sequence);
+    let sequence = sequence.strip_suffix('\x07').unwrap_or(sequence);
+    sequence.as_bytes()
+}
 
-                let mut parser = Parser::new();
+/// Write the console output received from `rx` via `write`, gathering
+/// it for up to `interval` after the first piece arrives, so that a
+/// burst of small pieces reaches the console in a single write.
+/// The pieces are whole actions produced by ConoutFilter, so escape
+/// sequences are never split between writes.
+fn coalesce_output<F: FnMut(&str) -> IoResult<()>>(
+    rx: Receiver<String>,
+    interval: Duration,
+    mut write: F,
+) -> IoResult<()> {
+    let mut pending = String::new();
+    while let Ok(first) = rx.recv() {
+        pending.push_str(&first);
+        let deadline = Instant::now() + interval;
+        let mut disconnected = false;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            match rx.recv_timeout(remaining) {
+                Ok(more) => pending.push_str(&more),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+        write(&pending)?;
+        pending.clear();
+        if disconnected {
+            break;
+        }
+    }
+    Ok(())
+}
 
-                let mut buf = [0u8; 4096];
-                let mut suppress_control = true;
+/// Spawn a thread that writes console output via `write`, coalescing
+/// it as described by `coalesce_output`.  The returned sender feeds
+/// it; once that is dropped, the thread writes whatever remains and
+/// exits.
+fn spawn_output_coalescer<F: FnMut(&str) -> IoResult<()> + Send + 'static>(
+    interval: Duration,
+    write: F,
+) -> (Sender<String>, std::thread::JoinHandle<IoResult<()>>) {
+    let (tx, rx) = channel();
+    let thread = std::thread::spawn(move || coalesce_output(rx, interval, write));
+    (tx, thread)
+}
 
-                loop {
-                    let len = conout_src.read(&mut buf)?;
-                    if len == 0 {
-                        return Ok(());
-                    }
+/// Wait for the thread started by spawn_output_coalescer to finish
+fn join_output_coalescer(thread: std::thread::JoinHandle<IoResult<()>>) -> IoResult<()> {
+    thread.join().unwrap_or_else(|_| {
+        Err(IoError::new(
+            std::io::ErrorKind::Other,
+            "console output thread panicked",
+        ))
+    })
+}
+
+/// Returns true if `err` indicates that the other end of a pipe has
+/// been closed, which is how the end of the data is reported once the
+/// bridge client has exited.  Errors from `PipeHandle` report that as
+/// `BrokenPipe`; those from elsewhere, such as the tokio pipes used
+/// by `serve_async`, may carry any of the codes for it.
+fn is_end_of_pipe(err: &IoError) -> bool {
+    err.kind() == std::io::ErrorKind::BrokenPipe
+        || err.raw_os_error().is_some_and(is_pipe_closed_error)
+}
+
+/// If `csi` switches to or from the alternate screen, returns true
+/// or false respectively
+fn alt_screen_change(csi: &termwiz::escape::CSI) -> Option<bool> {
+    use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Mode};
+    use termwiz::escape::CSI;
+
+    let (mode, enabled) = match csi {
+        CSI::Mode(Mode::SetDecPrivateMode(mode)) => (mode, true),
+        CSI::Mode(Mode::ResetDecPrivateMode(mode)) => (mode, false),
+        _ => return None,
+    };
+    match mode {
+        DecPrivateMode::Code(DecPrivateModeCode::ClearAndEnableAlternateScreen)
+        | DecPrivateMode::Code(DecPrivateModeCode::EnableAlternateScreen)
+        | DecPrivateMode::Unspecified(1047) => Some(enabled),
+        _ => None,
+    }
+}
+
+/// Copy the output of the pty from `src` to `output` until the bridge
+/// client closes its end of the pipe, so that nothing that it wrote
+/// before it exited is lost.
+fn drain_conout<R: Read, F: FnMut(&str) -> IoResult<()>>(
+    src: &mut R,
+    mut filter: ConoutFilter,
+    chunk_size: usize,
+    mut output: F,
+) -> IoResult<()> {
+    let mut buf = vec![0u8; chunk_size];
+
+    loop {
+        let len = match src.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) if is_end_of_pipe(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        filter.write_with(&buf[0..len], &mut output)?;
+    }
+}
+
+/// How often the viewport of the pty is checked for changes
+const VIEWPORT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Format a message that reports the viewport size, either from the
+/// bridge client to the bridge server over the control pipe, or from
+/// the server to the client over the resize pipe
+fn format_viewport_report(width: usize, height: usize) -> String {
+    format!("viewport {} {}\n", width, height)
+}
+
+/// Parse a line produced by `format_viewport_report`
+fn parse_viewport_report(line: &str) -> Option<(usize, usize)> {
+    let mut fields = line.trim_end().split(' ');
+    if fields.next()? != "viewport" {
+        return None;
+    }
+    let width = fields.next()?.parse().ok()?;
+    let height = fields.next()?.parse().ok()?;
+    if fields.next().is_some() || width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+/// Clamp the requested viewport size to the largest window that
+/// will fit on the screen
+fn clamp_viewport(width: usize, height: usize, largest: COORD) -> (i16, i16) {
+    let clamp = |value: usize, max: i16| value.min(max.max(1) as usize).max(1) as i16;
+    (clamp(width, largest.X), clamp(height, largest.Y))
+}
+
+/// Resize the console window, and its screen buffer if necessary,
+/// so that the viewport is `width` x `height`
+fn resize_console(conout: &PipeHandle, width: usize, height: usize) -> IoResult<()> {
+    let handle = conout.as_handle();
+    let largest = unsafe { GetLargestConsoleWindowSize(handle) };
+    let (width, height) = clamp_viewport(width, height, largest);
+
+    let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+    if unsafe { GetConsoleScreenBufferInfo(handle, &mut info) } == 0 {
+        return Err(win32_error_with_context(
+            "GetConsoleScreenBufferInfo",
+            IoError::last_os_error(),
+        ));
+    }
+
+    // The window cannot be larger than the buffer, so grow the
+    // buffer first, then size the window, and then trim the width
+    // of the buffer to match the window
+    let buffer = COORD {
+        X: info.dwSize.X.max(width),
+        Y: info.dwSize.Y.max(height),
+    };
+    if unsafe { SetConsoleScreenBufferSize(handle, buffer) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleScreenBufferSize",
+            IoError::last_os_error(),
+        ));
+    }
+
+    let top = info.srWindow.Top.min(buffer.Y - height);
+    let window = SMALL_RECT {
+        Left: 0,
+        Top: top,
+        Right: width - 1,
+        Bottom: top + height - 1,
+    };
+    let absolute = true;
+    if unsafe { SetConsoleWindowInfo(handle, absolute as _, &window) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleWindowInfo",
+            IoError::last_os_error(),
+        ));
+    }
+
+    let buffer = COORD {
+        X: width,
+        Y: buffer.Y,
+    };
+    if unsafe { SetConsoleScreenBufferSize(handle, buffer) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleScreenBufferSize",
+            IoError::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Spawn a thread that waits for the bridge client to connect to the
+/// control pipe and then resizes the console to match the viewport
+/// reports that it sends.  This is best effort; failures are logged.
+fn follow_viewport_reports(mut control: PipeHandle, proc: Process, nonce: Option<String>) {
+    std::thread::spawn(move || {
+        match wait_for_client(&mut control, &proc, nonce.as_deref(), None) {
+            Ok(true) => {}
+            _ => return,
+        }
+        let conout = match PipeHandle::open_pipe_with_access(
+            "CONOUT$",
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+        ) {
+            Ok(conout) => conout,
+            Err(err) => {
+                log::warn!("unable to follow child resize: {}", err);
+                return;
+            }
+        };
+        read_viewport_reports(control, |width, height| {
+            if let Err(err) = resize_console(&conout, width, height) {
+                log::warn!("unable to resize console to {}x{}: {}", width, height, err);
+            }
+        });
+    });
+}
+
+/// The viewport dimensions of the console that `conout` refers to,
+/// or `None` if they can't be determined right now
+fn console_viewport(conout: &PipeHandle) -> Option<(usize, usize)> {
+    let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+    if unsafe { GetConsoleScreenBufferInfo(conout.as_handle(), &mut info) } == 0 {
+        return None;
+    }
+    Some(viewport_dimensions(&info))
+}
+
+/// Poll `viewport` and send a report over `control` each time that
+/// it changes, until the other end goes away.  The initial size is
+/// not reported, as each side already learned it at startup.
+fn send_viewport_changes<T: BridgeTransport, F: FnMut() -> Option<(usize, usize)>>(
+    control: &mut T,
+    mut viewport: F,
+) -> IoResult<()> {
+    let mut last = None;
+    loop {
+        if let Some(current) = viewport() {
+            if last.is_some() && last != Some(current) {
+                send_viewport_report(control, current)?;
+            }
+            last = Some(current);
+        }
+        std::thread::sleep(VIEWPORT_POLL_INTERVAL);
+    }
+}
+
+/// Spawn a thread that waits for the bridge client to connect to the
+/// resize pipe and then reports each change to the size of the
+/// console to it, until the client goes away.  This is best effort;
+/// failures are logged.
+fn report_console_resize(mut resize: PipeHandle, proc: Process, nonce: Option<String>) {
+    std::thread::spawn(move || {
+        match wait_for_client(&mut resize, &proc, nonce.as_deref(), None) {
+            Ok(true) => {}
+            _ => return,
+        }
+        let conout = match PipeHandle::open_pipe_with_access(
+            "CONOUT$",
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+        ) {
+            Ok(conout) => conout,
+            Err(err) => {
+                log::warn!("unable to follow console resize: {}", err);
+                return;
+            }
+        };
+        let _ = send_viewport_changes(&mut resize, || console_viewport(&conout));
+    });
+}
+
+/// Send a report of the viewport size over the control pipe
+fn send_viewport_report<T: BridgeTransport>(
+    control: &mut T,
+    (width, height): (usize, usize),
+) -> IoResult<()> {
+    control.write_all(format_viewport_report(width, height).as_bytes())
+}
+
+/// Pass each of the viewport reports received over the control pipe
+/// to `resize`, until the client closes it.  Lines that aren't valid
+/// reports are ignored.
+fn read_viewport_reports<T: BridgeTransport, F: FnMut(usize, usize)>(control: T, mut resize: F) {
+    for line in BufReader::new(control).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if let Some((width, height)) = parse_viewport_report(&line) {
+            resize(width, height);
+        }
+    }
+}
+
+/// How line endings in the output of the program are translated when
+/// stdout or stderr are being bridged over pipes, rather than via the
+/// pty; see `BridgeServer::set_newline_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NewlineMode {
+    /// The output is passed through unchanged
+    #[default]
+    Passthrough,
+    /// Bare `\n` line endings are translated to `\r\n`
+    ForceCrlf,
+    /// `\r\n` line endings are translated to `\n`
+    ForceLf,
+}
+
+/// Applies a NewlineMode to a stream of output that arrives in chunks.
+/// Line endings may be split across chunks, so we remember whether
+/// the previous chunk ended with `\r`.
+struct NewlineTranslator {
+    mode: NewlineMode,
+    last_was_cr: bool,
+}
+
+impl NewlineTranslator {
+    fn new(mode: NewlineMode) -> Self {
+        Self {
+            mode,
+            last_was_cr: false,
+        }
+    }
+
+    /// Translate `data`, appending the result to `out`
+    fn translate(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        match self.mode {
+            NewlineMode::Passthrough => out.extend_from_slice(data),
+            NewlineMode::ForceCrlf => {
+                for &b in data {
+                    if b == b'\n' && !self.last_was_cr {
+                        out.push(b'\r');
+                    }
+                    out.push(b);
+                    self.last_was_cr = b == b'\r';
+                }
+            }
+            NewlineMode::ForceLf => {
+                for &b in data {
+                    // A `\r` is held back until we know whether
+                    // it is the start of a `\r\n` line ending
+                    if self.last_was_cr && b != b'\n' {
+                        out.push(b'\r');
+                    }
+                    self.last_was_cr = b == b'\r';
+                    if !self.last_was_cr {
+                        out.push(b);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Called at the end of the stream to emit anything held back
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.mode == NewlineMode::ForceLf && self.last_was_cr {
+            out.push(b'\r');
+        }
+        self.last_was_cr = false;
+    }
+}
+
+/// Where `AnsiStripper` is within an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    /// Plain text
+    Ground,
+    /// Following an ESC
+    Escape,
+    /// Following the intermediate bytes of an escape sequence, such as
+    /// the `(` of `ESC ( B`
+    EscapeIntermediate,
+    /// In the parameters of a CSI sequence
+    Csi,
+    /// In the body of an OSC, DCS, SOS, PM or APC string, which ends
+    /// with ST; an OSC may also end with BEL
+    String { osc: bool },
+    /// Following an ESC in the body of a string, which may be the
+    /// start of its ST
+    StringEscape,
+}
+
+/// Removes escape sequences from a stream of output that arrives in
+/// chunks; see `BridgeServer::set_strip_ansi`.  The state is carried
+/// between chunks, so a sequence that is split across reads is still
+/// removed in its entirety.  Only 7-bit sequences are recognized, as
+/// the 8-bit C1 controls collide with UTF-8.
+struct AnsiStripper {
+    state: AnsiState,
+}
+
+impl AnsiStripper {
+    const ESC: u8 = 0x1b;
+    const BEL: u8 = 0x07;
+
+    fn new() -> Self {
+        Self {
+            state: AnsiState::Ground,
+        }
+    }
+
+    /// Strip `data`, appending the remaining text to `out`
+    fn strip(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &b in data {
+            self.state = match self.state {
+                AnsiState::Ground if b == Self::ESC => AnsiState::Escape,
+                AnsiState::Ground => {
+                    out.push(b);
+                    AnsiState::Ground
+                }
+                AnsiState::Escape | AnsiState::StringEscape => Self::escape(b),
+                AnsiState::EscapeIntermediate => match b {
+                    0x20..=0x2f => AnsiState::EscapeIntermediate,
+                    _ => AnsiState::Ground,
+                },
+                AnsiState::Csi => match b {
+                    Self::ESC => AnsiState::Escape,
+                    0x40..=0x7e => AnsiState::Ground,
+                    _ => AnsiState::Csi,
+                },
+                AnsiState::String { osc } => match b {
+                    Self::BEL if osc => AnsiState::Ground,
+                    Self::ESC => AnsiState::StringEscape,
+                    _ => AnsiState::String { osc },
+                },
+            };
+        }
+    }
+
+    /// The state that follows `b` after an ESC.  Within a string, an
+    /// ESC that isn't part of the ST ends the string and begins a new
+    /// sequence, so the same rules apply there.
+    fn escape(b: u8) -> AnsiState {
+        match b {
+            b'[' => AnsiState::Csi,
+            b']' => AnsiState::String { osc: true },
+            b'P' | b'X' | b'^' | b'_' => AnsiState::String { osc: false },
+            Self::ESC => AnsiState::Escape,
+            0x20..=0x2f => AnsiState::EscapeIntermediate,
+            // A two byte sequence such as `ESC 7` or the ST, `ESC \`,
+            // or something that isn't a valid sequence and is discarded
+            // along with the ESC
+            _ => AnsiState::Ground,
+        }
+    }
+}
+
+/// The number of bytes forwarded on each stream; see `ServeOutcome`.
+/// The copy threads may outlive `serve` if the output doesn't drain,
+/// so these are shared with them rather than collected when they exit.
+#[derive(Default)]
+struct StreamCounts {
+    stdin: Arc<AtomicU64>,
+    stdout: Arc<AtomicU64>,
+    stderr: Arc<AtomicU64>,
+    conout: Arc<AtomicU64>,
+}
+
+fn load_count(count: &AtomicU64) -> u64 {
+    count.load(Ordering::SeqCst)
+}
+
+/// Passes writes through to `dest`, adding the number of bytes
+/// written to `count`
+struct CountingWriter<W> {
+    dest: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let len = self.dest.write(buf)?;
+        self.count.fetch_add(len as u64, Ordering::SeqCst);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.dest.flush()
+    }
+}
+
+/// Accumulates the output of a stream for `BridgeServer::capture_output`.
+/// Clones share the same buffer, so that the copy held by the server can
+/// be emptied once the sink given to the copy loop is done with it.
+#[derive(Clone, Default)]
+struct CaptureBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl CaptureBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for CaptureBuffer {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Decide whether the console can be bridged, given the outcome of
+/// opening `CONIN$` and `CONOUT$`.  Bridging requires both, so if just
+/// one of them opened, neither is used, and `None` is returned to fall
+/// back to bridging over pipes.
+/// If stdout is a console, failing to open `CONOUT$` means that
+/// something is wrong with it, rather than that there is no console,
+/// so that is reported as an error instead.
+fn pair_console<T>(
+    conin: IoResult<T>,
+    conout: IoResult<T>,
+    stdout_is_pty: bool,
+) -> IoResult<Option<(T, T)>> {
+    match (conin, conout) {
+        (Ok(conin), Ok(conout)) => Ok(Some((conin, conout))),
+        (_, Err(err)) if stdout_is_pty => Err(IoError::new(
+            err.kind(),
+            format!(
+                "stdout is a console, but CONOUT$ could not be opened: {}",
+                err
+            ),
+        )),
+        (conin, conout) => {
+            for (name, res) in [("CONIN$", conin.err()), ("CONOUT$", conout.err())] {
+                if let Some(err) = res {
+                    log::warn!(
+                        "unable to open {} ({}); bridging the console over pipes instead",
+                        name,
+                        err
+                    );
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Records when the bridged program last produced output; see
+/// `BridgeServer::set_idle_timeout`
+struct OutputActivity {
+    last: Mutex<Instant>,
+}
+
+impl OutputActivity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+/// The exit code given to the bridge process when it is terminated by
+/// the idle timeout
+const IDLE_TIMEOUT_EXIT_CODE: DWORD = ERROR_TIMEOUT;
+
+/// Wait for `proc` to exit, terminating it if `activity` shows that it
+/// has produced no output for `timeout` in the meantime.
+/// Returns true if it was terminated.
+fn watch_for_idle(proc: &Process, activity: &OutputActivity, timeout: Duration) -> IoResult<bool> {
+    loop {
+        let idle = activity.idle_for();
+        if idle >= timeout {
+            // It may have exited of its own accord just now
+            if proc.wait_for(Some(0))? == WAIT_OBJECT_0 {
+                return Ok(false);
+            }
+            proc.terminate(IDLE_TIMEOUT_EXIT_CODE)?;
+            return Ok(true);
+        }
+        // Wait until the timeout would expire if nothing else
+        // happens, rounding up so that we don't spin
+        let remaining = (timeout - idle).as_millis() + 1;
+        let remaining = remaining.min(INFINITE as u128 - 1) as DWORD;
+        if proc.wait_for(Some(remaining))? == WAIT_OBJECT_0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// Retains the most recent output of the bridged program, up to a
+/// fixed number of bytes; see `BridgeServer::set_output_tail_capture`.
+struct OutputTail {
+    capacity: usize,
+    data: Mutex<VecDeque<u8>>,
+}
+
+impl OutputTail {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            data: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Append `bytes`, discarding the oldest data to make room
+    fn push(&self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let mut data = self.data.lock().unwrap();
+        let excess = (data.len() + bytes.len()).saturating_sub(self.capacity);
+        data.drain(0..excess);
+        data.extend(bytes);
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Passes writes through to `dest`, recording what was written in
+/// the output tail, if one is being captured, and noting the activity
+/// for the idle timeout, if one is set
+struct TailTee<W> {
+    dest: W,
+    tail: Option<Arc<OutputTail>>,
+    activity: Option<Arc<OutputActivity>>,
+}
+
+impl<W: Write> Write for TailTee<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let len = self.dest.write(buf)?;
+        if let Some(tail) = &self.tail {
+            tail.push(&buf[0..len]);
+        }
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.dest.flush()
+    }
+}
+
+/// Recognizes a Ctrl-Z typed at the start of a line, which is how
+/// EOF is signalled at the console; see `BridgeServer::set_ctrl_z_eof`.
+#[derive(Default)]
+struct CtrlZEof {
+    mid_line: bool,
+}
+
+impl CtrlZEof {
+    const CTRL_Z: u8 = 0x1a;
+
+    /// If `data` contains an EOF, returns the length of the input that
+    /// precedes it; the Ctrl-Z and anything after it are discarded.
+    fn find(&mut self, data: &[u8]) -> Option<usize> {
+        for (idx, &b) in data.iter().enumerate() {
+            if b == Self::CTRL_Z && !self.mid_line {
+                return Some(idx);
+            }
+            self.mid_line = b != b'\n';
+        }
+        None
+    }
+}
+
+/// Copy input from `src` to `dest` until `src` reaches EOF.
+/// Unlike `std::io::copy`, this reads at most `chunk_size` bytes at
+/// a time and forwards whatever each read returns straight away, so
+/// that an interactive program sees each keystroke as it happens.
+/// When `ctrl_z_eof` is true, a Ctrl-Z at the start of a line is also
+/// treated as EOF.
+fn forward_input<R: Read, W: Write>(
+    src: &mut R,
+    dest: &mut W,
+    chunk_size: usize,
+    ctrl_z_eof: bool,
+) -> IoResult<()> {
+    let mut eof = if ctrl_z_eof {
+        Some(CtrlZEof::default())
+    } else {
+        None
+    };
+    let mut buf = vec![0u8; chunk_size];
+    loop {
+        let len = match src.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) if is_end_of_pipe(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let data = &buf[0..len];
+        let (data, at_eof) = match eof.as_mut().and_then(|eof| eof.find(data)) {
+            Some(end) => (&data[0..end], true),
+            None => (data, false),
+        };
+        match dest.write_all(data) {
+            // The program has exited, or closed its stdin, so
+            // nothing more is needed
+            Err(err) if is_end_of_pipe(&err) => return Ok(()),
+            Err(err) => return Err(err),
+            Ok(()) if at_eof => return Ok(()),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Copy the output of the bridged program from `src` to `dest` until
+/// the program closes its end of the pipe.
+/// If the consumer of `dest` goes away first (for example, `head` in
+/// `eledo cmd | head -1`), we stop copying and close `src`, so that
+/// the next write made by the program fails and it can exit, rather
+/// than running to completion with nobody reading its output.
+/// Line endings are translated according to `newline_mode`, after
+/// escape sequences have been removed if `strip_ansi` is true.
+fn copy_output<R: BridgeTransport, W: Write>(
+    mut src: R,
+    dest: &mut W,
+    newline_mode: NewlineMode,
+    strip_ansi: bool,
+    chunk_size: usize,
+) -> IoResult<()> {
+    let mut translator = NewlineTranslator::new(newline_mode);
+    let mut stripper = if strip_ansi {
+        Some(AnsiStripper::new())
+    } else {
+        None
+    };
+    let mut buf = vec![0u8; chunk_size];
+    let mut stripped = vec![];
+    let mut translated = vec![];
+    loop {
+        let len = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if is_end_of_pipe(&err) => break,
+            Err(err) => return Err(err),
+        };
+        let data = match stripper.as_mut() {
+            Some(stripper) => {
+                stripped.clear();
+                stripper.strip(&buf[0..len], &mut stripped);
+                &stripped[..]
+            }
+            None => &buf[0..len],
+        };
+        translated.clear();
+        translator.translate(data, &mut translated);
+        if let Err(err) = dest.write_all(&translated) {
+            drop(src);
+            return Err(err);
+        }
+    }
+    translated.clear();
+    translator.finish(&mut translated);
+    dest.write_all(&translated)?;
+    dest.flush()
+}
+
+impl BridgeServer {
+    pub fn new() -> Self {
+        let stdin_is_pty = stream_is_pty(&std::io::stdin());
+        let stdout_is_pty = stream_is_pty(&std::io::stdout());
+        let stderr_is_pty = stream_is_pty(&std::io::stderr());
+
+        Self {
+            stdin_is_pty,
+            stdout_is_pty,
+            stderr_is_pty,
+            bridge_stdin: true,
+            conin: None,
+            conout: None,
+            conin_pipe: None,
+            conout_pipe: None,
+            input_mode: None,
+            output_mode: None,
+            preserve_console_modes: false,
+            geometry: None,
+            soft_reset_on_drop: true,
+            restore_main_screen_on_drop: true,
+            forward_focus_events: false,
+            bracketed_paste: false,
+            legacy_console_translation: None,
+            legacy_attributes: None,
+            mouse_input: false,
+            alt_screen: Arc::new(AtomicBool::new(false)),
+            follow_child_resize: false,
+            control_pipe: None,
+            follow_console_resize: true,
+            resize_pipe: None,
+            forward_ctrl_events: true,
+            ctrl_pipe: None,
+            bridge_console: true,
+            path_search: true,
+            low_integrity: false,
+            code_page: None,
+            merge_stderr: false,
+            ctrl_z_eof: false,
+            strip_ansi: false,
+            idle_timeout: None,
+            idle_timed_out: false,
+            output_drain_timeout: None,
+            startup_timeout: None,
+            startup_deadline: None,
+            counts: StreamCounts::default(),
+            provided: ProvidedPipes::default(),
+            newline_mode: NewlineMode::default(),
+            scripted_input: None,
+            nonce: Some(generate_nonce()),
+            copy_chunk_size: DEFAULT_COPY_CHUNK_SIZE,
+            output_coalesce: None,
+            output_tail: None,
+            stdout_sink: None,
+            stderr_sink: None,
+            captured: None,
+            on_osc: None,
+            connected_event: None,
+            require_trusted_bridge: false,
+            #[cfg(feature = "tokio")]
+            kill_on_drop: false,
+            stderr: None,
+            stdout: None,
+            stdin: None,
+        }
+    }
+
+    /// Create a bridge server that uses the server ends of the pipes
+    /// provided by the caller, rather than creating its own, so that
+    /// the bridge can be layered on top of an existing transport.
+    /// `start` uses a provided pipe for each stream that needs to be
+    /// bridged, emitting its path in the arguments for the client,
+    /// and creates pipes for any other streams that need them.
+    /// Provided pipes for streams that don't need to be bridged, for
+    /// example because they are attached to the console, are unused.
+    ///
+    /// Since the console is bridged using the conin and conout pipes
+    /// together, either both or neither must be provided.
+    /// The pipes must have been created for overlapped I/O if
+    /// `serve_async` is to be used.
+    pub fn with_pipes(
+        stdin: Option<NamedPipeServer>,
+        stdout: Option<NamedPipeServer>,
+        stderr: Option<NamedPipeServer>,
+        conin: Option<NamedPipeServer>,
+        conout: Option<NamedPipeServer>,
+    ) -> IoResult<Self> {
+        if conin.is_some() != conout.is_some() {
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "the conin and conout pipes must be provided together",
+            ));
+        }
+        let mut server = Self::new();
+        server.provided = ProvidedPipes {
+            stdin,
+            stdout,
+            stderr,
+            conin,
+            conout,
+        };
+        Ok(server)
+    }
+
+    /// Control whether a soft reset (DECSTR) is sent to the console
+    /// when the bridge server is dropped.  The default is true.
+    /// The child may have changed terminal state, such as DEC private
+    /// modes (cursor visibility, mouse reporting and so on) or text
+    /// attributes, and exited without restoring them; the soft reset
+    /// returns the terminal to a sane state for the user's shell.
+    /// Embedders that manage the terminal state themselves may prefer
+    /// to disable it, in which case only the console mode is restored.
+    pub fn set_soft_reset_on_drop(&mut self, soft_reset: bool) {
+        self.soft_reset_on_drop = soft_reset;
+    }
+
+    /// Control whether mouse events are forwarded from the console to
+    /// the program, so that clicking in a TUI works.  The default is
+    /// false.  Enabling this also turns off quick edit mode for the
+    /// duration, because while it is on the console consumes mouse
+    /// events itself to select text, rather than passing them along.
+    /// The original console mode, including quick edit, is restored
+    /// when the bridge server is dropped.
+    /// This must be called before `start`.
+    pub fn set_mouse_input(&mut self, mouse_input: bool) {
+        self.mouse_input = mouse_input;
+    }
+
+    /// Control whether the terminal is asked to report focus changes,
+    /// which it does by sending `CSI I` when it gains focus and `CSI O`
+    /// when it loses it, so that a TUI running in the pty can react to
+    /// them.  The default is false.  When enabled, `start` sends
+    /// `CSI ? 1004 h` to the console and the bridge server sends
+    /// `CSI ? 1004 l` when it is dropped.
+    /// The reports arrive as console input, which is forwarded to the
+    /// pty unchanged.  That relies on `ENABLE_VIRTUAL_TERMINAL_INPUT`,
+    /// which the bridge always sets, so that the console passes the
+    /// sequences through as text rather than discarding them, and on
+    /// `ENABLE_LINE_INPUT` and `ENABLE_ECHO_INPUT` being cleared, so
+    /// that they are neither held back until Enter nor echoed.
+    /// This must be called before `start`, and only applies when the
+    /// console is bridged via the pty.
+    pub fn set_forward_focus_events(&mut self, forward: bool) {
+        self.forward_focus_events = forward;
+    }
+
+    /// Control whether the terminal is asked to bracket pasted text
+    /// with `CSI 200 ~` and `CSI 201 ~`, so that a TUI running in the
+    /// pty can tell a paste from typing.  The default is false.
+    /// This works in the same way as `set_forward_focus_events`, using
+    /// `CSI ? 2004 h` and `CSI ? 2004 l`, and the same console input
+    /// modes apply.
+    /// This must be called before `start`.
+    pub fn set_bracketed_paste(&mut self, bracketed_paste: bool) {
+        self.bracketed_paste = bracketed_paste;
+    }
+
+    /// Control whether the bridge executable must be trusted before it
+    /// is launched.  The bridge is looked for alongside the module that
+    /// contains this crate and alongside the executable before the path
+    /// is searched, but any directory in the path may be writable by
+    /// someone else, who could plant an `eledo-pty-bridge.exe` there to
+    /// have it run with the privileges of the program.  When this is
+    /// enabled, a bridge found in the path is only launched if its
+    /// Authenticode signature verifies and was made with the same
+    /// certificate as that of the current executable, which must
+    /// therefore be signed too; otherwise `start_for_command` and
+    /// `start_for_shell_command` fail with an error of kind
+    /// `PermissionDenied`.  The default is false.
+    pub fn set_require_trusted_bridge(&mut self, require: bool) {
+        self.require_trusted_bridge = require;
+    }
+
+    /// Returns an event that is signalled once the bridge client has
+    /// connected all of the streams, which it does just before spawning
+    /// the program.  It is a manual reset event, so it stays signalled,
+    /// and is never signalled if the client doesn't connect.
+    /// This allows a host application with a wait loop of its own,
+    /// based on WaitForMultipleObjects, to wait for this alongside the
+    /// handle of the bridge process (see `Process::as_handle`), rather
+    /// than blocking in `serve`, which then runs on another thread.
+    /// The event is owned by the server, and must not be closed by the
+    /// caller.  It is closed when the server is dropped, which `serve`
+    /// and its variants do when they return, once the bridge process
+    /// has exited; so stop waiting on the event once the process handle
+    /// is signalled, or wait on a duplicate of it made via
+    /// DuplicateHandle instead.
+    pub fn connected_event(&mut self) -> IoResult<HANDLE> {
+        if self.connected_event.is_none() {
+            self.connected_event.replace(ConnectedEvent::new()?);
+        }
+        Ok(self.connected_event.as_ref().unwrap().0)
+    }
+
+    /// Control whether the VT sequences in the output of the program
+    /// are translated into calls to the console API, for legacy console
+    /// hosts that can't interpret them because they don't support
+    /// `ENABLE_VIRTUAL_TERMINAL_PROCESSING`; otherwise they appear as
+    /// garbage such as `←[31m`.  Only colors, cursor movement and
+    /// erasing are translated, and other sequences are discarded, so
+    /// full screen programs won't render properly, but the output of
+    /// most command line programs will.
+    /// By default, this is decided by `start`: the translation is used
+    /// if the console doesn't accept `ENABLE_VIRTUAL_TERMINAL_PROCESSING`.
+    /// Pass true to always translate, or false to never translate, in
+    /// which case `start` fails on such a console.
+    /// When translating, the console input is bridged without
+    /// `ENABLE_VIRTUAL_TERMINAL_INPUT` if the console rejects it, so
+    /// text is passed along but keys such as the arrows are not.
+    /// This must be called before `start`.
+    pub fn set_legacy_console_translation(&mut self, translate: bool) {
+        self.legacy_console_translation = Some(translate);
+    }
+
+    /// Set up the mode of the console output for bridging, deciding
+    /// whether it needs the output of the program to be translated;
+    /// see `set_legacy_console_translation`
+    fn apply_output_mode(&mut self, conout: &mut PipeHandle) -> IoResult<SavedMode> {
+        const LEGACY_MODE: DWORD = ENABLE_PROCESSED_OUTPUT | ENABLE_WRAP_AT_EOL_OUTPUT;
+        const VT_MODE: DWORD =
+            LEGACY_MODE | ENABLE_VIRTUAL_TERMINAL_PROCESSING | DISABLE_NEWLINE_AUTO_RETURN;
+
+        if self.legacy_console_translation != Some(true) {
+            match self.apply_console_mode(conout, VT_MODE, 0) {
+                // Some hosts accept the mode but don't keep the VT bit
+                Ok(saved)
+                    if get_console_mode(conout)? & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0 =>
+                {
+                    return Ok(saved)
+                }
+                Ok(saved) if self.legacy_console_translation == Some(false) => return Ok(saved),
+                Err(err) if self.legacy_console_translation == Some(false) => return Err(err),
+                Ok(saved) => self.restore_console_mode(conout, saved)?,
+                Err(err) => log::warn!(
+                    "the console doesn't support VT sequences ({}); translating them instead",
+                    err
+                ),
+            }
+        }
+        self.legacy_attributes = Some(conout.screen_info()?.attributes);
+        // Disable the VT bits if another process left them set
+        self.apply_console_mode(conout, LEGACY_MODE, VT_MODE & !LEGACY_MODE)
+    }
+
+    /// The sequence that `start` writes to the console, once its mode
+    /// has been set up, to enable the reports that were requested
+    fn setup_sequence(&self) -> String {
+        let mut seq = String::new();
+        if self.forward_focus_events {
+            seq.push_str("\x1b[?1004h");
+        }
+        if self.bracketed_paste {
+            seq.push_str("\x1b[?2004h");
+        }
+        seq
+    }
+
+    /// Control whether the existing console modes are preserved.
+    /// By default, `start` replaces the modes of the console input and
+    /// output with those required for bridging, and restores the
+    /// original modes when the server is dropped.
+    /// When preserving, only the bits that are required are changed;
+    /// if they are already in place, the mode is left untouched.
+    /// On drop, only the bits that were changed are restored.
+    /// The default is false.  This must be set before calling `start`.
+    pub fn set_preserve_console_modes(&mut self, preserve: bool) {
+        self.preserve_console_modes = preserve;
+    }
+
+    /// Apply `mode` to the console, or merge in just the `required`
+    /// and `cleared` bits when preserving the console modes, returning
+    /// the mode that it had along with the one that it now has
+    fn apply_console_mode(
+        &self,
+        console: &PipeHandle,
+        mode: DWORD,
+        cleared: DWORD,
+    ) -> IoResult<SavedMode> {
+        let original = get_console_mode(console)?;
+        let applied = if self.preserve_console_modes {
+            merge_console_mode(original, mode, cleared)
+        } else {
+            mode
+        };
+        if applied != original {
+            set_console_mode(console, applied)?;
+        }
+        Ok(SavedMode { original, applied })
+    }
+
+    /// The counterpart of apply_console_mode
+    fn restore_console_mode(&self, console: &PipeHandle, saved: SavedMode) -> IoResult<()> {
+        if !self.preserve_console_modes {
+            return set_console_mode(console, saved.original);
+        }
+        let now = get_console_mode(console)?;
+        let restored = saved.undo_changes(now);
+        if restored != now {
+            set_console_mode(console, restored)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the mode to apply to the console input while bridging
+    fn conin_mode(&self) -> DWORD {
+        if self.mouse_input {
+            // ENABLE_EXTENDED_FLAGS is required for the absence of
+            // ENABLE_QUICK_EDIT_MODE to take effect
+            (ENABLE_VIRTUAL_TERMINAL_INPUT | ENABLE_MOUSE_INPUT | ENABLE_EXTENDED_FLAGS)
+                & !ENABLE_QUICK_EDIT_MODE
+        } else {
+            ENABLE_VIRTUAL_TERMINAL_INPUT
+        }
+    }
+
+    /// Control whether the console is resized to match the pty when
+    /// the program running in it changes the size of its console, for
+    /// example using SetConsoleWindowInfo.  The default is false.
+    /// This must be set before calling `start`.
+    /// The console won't be made larger than will fit on the screen.
+    pub fn set_follow_child_resize(&mut self, follow: bool) {
+        self.follow_child_resize = follow;
+    }
+
+    /// Control whether the pty is resized to match the console when
+    /// the window of the console changes size while the program is
+    /// running, so that full screen programs can redraw to fit.
+    /// The default is true.  This must be set before calling `start`.
+    /// The size of the console is checked periodically, so the pty
+    /// follows it after a short delay.
+    pub fn set_follow_console_resize(&mut self, follow: bool) {
+        self.follow_console_resize = follow;
+    }
+
+    /// Control whether CTRL-C and CTRL-BREAK are forwarded to the
+    /// program rather than terminating this process, so that the
+    /// program can handle them, with `serve` returning once it exits.
+    /// The default is true.  This must be set before calling `start`.
+    /// The signals are caught with a console control handler, which is
+    /// process wide, while the bridge is being served; if the client
+    /// is not connected to receive them, they are handled as usual.
+    pub fn set_forward_ctrl_events(&mut self, forward: bool) {
+        self.forward_ctrl_events = forward;
+    }
+
+    /// Control whether the bridge client locates the program in its
+    /// path before running it; see `Command::set_path_search`.
+    /// The default is true.  This must be set before calling `start`.
+    pub fn set_path_search(&mut self, path_search: bool) {
+        self.path_search = path_search;
+    }
+
+    /// Control whether the bridge client runs the program at low
+    /// integrity, with a SAFER restricted token derived from its own
+    /// rather than with its own token, so that an elevated bridge
+    /// can run a sandboxed program; see
+    /// `Token::as_low_integrity_safer_token`.
+    /// The default is false.  This must be set before calling `start`.
+    pub fn set_low_integrity(&mut self, low_integrity: bool) {
+        self.low_integrity = low_integrity;
+    }
+
+    /// Run the program with `code_page`, such as 65001 for UTF-8 or
+    /// 932 for Shift-JIS, as the input and output code page of its
+    /// console, for the benefit of programs that depend on it.
+    /// Without this, the program gets the UTF-8 code page that
+    /// `eledo-pty-bridge` uses, or whatever else the bridge client
+    /// leaves in place.  The bridge client sets it on the console
+    /// before spawning the program, which must not be started in a
+    /// console of its own for it to apply; the program remains free to
+    /// change it.
+    /// An error is returned if the code page is not installed.
+    /// This must be set before calling `start`.
+    pub fn set_code_page(&mut self, code_page: u32) -> IoResult<()> {
+        validate_code_page(code_page)?;
+        self.code_page = Some(code_page);
+        Ok(())
+    }
+
+    /// Control whether the console is switched back to the main screen
+    /// when the bridge server is dropped, if the child switched to the
+    /// alternate screen and didn't switch back, for example because it
+    /// was a full screen application that exited uncleanly.
+    /// The default is true.
+    pub fn set_restore_main_screen_on_drop(&mut self, restore: bool) {
+        self.restore_main_screen_on_drop = restore;
+    }
+
+    /// The sequence that is written to the console when the bridge
+    /// server is dropped, in order to put it back into a sane state
+    fn teardown_sequence(&self) -> String {
+        let mut seq = String::new();
+        if self.restore_main_screen_on_drop && self.alt_screen.load(Ordering::SeqCst) {
+            // Leave the alternate screen
+            seq.push_str("\x1b[?1049l");
+        }
+        if self.forward_focus_events {
+            seq.push_str("\x1b[?1004l");
+        }
+        if self.bracketed_paste {
+            seq.push_str("\x1b[?2004l");
+        }
+        if self.soft_reset_on_drop {
+            // Emit a soft reset
+            seq.push_str("\x1b[!p");
+        }
+        seq
+    }
+
+    /// Control how line endings are translated in the output of the
+    /// program when stdout or stderr are redirected, for example to a
+    /// file, and thus are bridged over pipes rather than via the pty.
+    /// Output to the console is not affected.
+    /// The default is `NewlineMode::Passthrough`.
+    pub fn set_newline_mode(&mut self, mode: NewlineMode) {
+        self.newline_mode = mode;
+    }
+
+    /// Remove escape sequences, such as those that set colors or move
+    /// the cursor, from the stdout and stderr of the program when they
+    /// are bridged over pipes, for the benefit of consumers that can't
+    /// render them.  Output to the console via the pty is unaffected.
+    /// The default is false.
+    pub fn set_strip_ansi(&mut self, strip_ansi: bool) {
+        self.strip_ansi = strip_ansi;
+    }
+
+    /// Terminate the bridge process if the program produces no output,
+    /// whether to the console or to stdout or stderr, for `timeout`
+    /// while it is running, as it has probably hung.  The window starts
+    /// again whenever output arrives.  When this happens,
+    /// `ServeOutcome::idle_timed_out` is set, and `serve` returns an
+    /// error of kind `TimedOut`.
+    /// Only the bridge process is terminated; spawn it with
+    /// `Command::set_kill_on_close` to take the program down with it.
+    /// The default is to wait indefinitely.  Idle timeouts are not
+    /// supported by `serve_async`.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Once the bridge process has exited, wait at most `timeout` for
+    /// the rest of the output to arrive before `serve` returns, rather
+    /// than waiting for the program to close its end of the streams.
+    /// Output that arrives later is discarded.  This is useful when
+    /// the bridge process may be killed externally, as whatever it
+    /// spawned may then keep the streams open indefinitely.
+    /// The default is to wait for all of the output, unless the idle
+    /// timeout terminated the bridge process, in which case the wait
+    /// is bounded to a couple of seconds.
+    pub fn set_output_drain_timeout(&mut self, timeout: Duration) {
+        self.output_drain_timeout = Some(timeout);
+    }
+
+    /// How long to wait for the output to drain once the bridge
+    /// process has exited, if the wait is bounded at all
+    fn drain_timeout(&self) -> Option<Duration> {
+        match self.output_drain_timeout {
+            Some(timeout) => Some(timeout),
+            None if self.idle_timed_out => Some(OUTPUT_DRAIN_TIMEOUT),
+            None => None,
+        }
+    }
+
+    /// Give up if the program hasn't started within `timeout` of
+    /// calling `start`: that is, if launching the bridge process via
+    /// `launch_bridge`, which is where the UAC prompt is shown, and the
+    /// bridge client connecting, which it does just before spawning the
+    /// program, haven't both happened by then.  This avoids hanging
+    /// indefinitely when the prompt is ignored, as on a locked session.
+    /// When this happens, `launch_bridge`, `serve` or `serve_with_outcome`
+    /// return an error of kind `TimedOut` for which `is_startup_timeout`
+    /// returns true, and `serve_with_outcome` terminates the bridge
+    /// process if it can.
+    /// This must be called before `start`.  The default is to wait for
+    /// as long as the bridge process is running.  Startup timeouts are
+    /// not supported by `serve_async`.
+    pub fn set_startup_timeout(&mut self, timeout: Duration) {
+        self.startup_timeout = Some(timeout);
+    }
+
+    /// Launch the bridge process by calling `launch`, which will
+    /// typically call `Command::shell_execute` on the command returned
+    /// by `start`.  This is only needed to apply the startup timeout to
+    /// the launch; see `set_startup_timeout`.  If one was set, `launch`
+    /// is called on a thread of its own, and if it doesn't return
+    /// before the deadline, a startup timeout error is returned without
+    /// waiting for it.  A UAC prompt that is still showing is left
+    /// behind, but once the server has been dropped, the bridge that it
+    /// would launch has nothing to connect to and exits.
+    pub fn launch_bridge<F>(&self, launch: F) -> IoResult<Process>
+    where
+        F: FnOnce() -> IoResult<Process> + Send + 'static,
+    {
+        let deadline = match self.startup_deadline {
+            Some(deadline) => deadline,
+            None => return launch(),
+        };
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(launch());
+        });
+        match rx.recv_timeout(deadline.remaining()) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(deadline.expired()),
+            Err(RecvTimeoutError::Disconnected) => Err(IoError::new(
+                std::io::ErrorKind::Other,
+                "the bridge launch thread panicked",
+            )),
+        }
+    }
+
+    /// Control whether our stdin is bridged to the program when it
+    /// is redirected, or the pty is disabled, and so would be bridged
+    /// over a pipe.  When false, the program doesn't receive our stdin,
+    /// which is useful for running a program unattended, such as with
+    /// `capture_output`, without consuming input meant for us.
+    /// Input typed at a console that is bridged to the pty still
+    /// reaches the program.
+    /// The default is true.  This must be set before calling `start`.
+    pub fn set_bridge_stdin(&mut self, bridge: bool) {
+        self.bridge_stdin = bridge;
+    }
+
+    /// When both stdout and stderr are redirected, and thus bridged
+    /// over pipes, send the stderr of the program to stdout over the
+    /// same pipe, as with `2>&1`.  Copying the streams independently
+    /// can change the relative order in which their output appears;
+    /// merging them preserves the order in which it was written.
+    /// The default is false.  This must be set before calling `start`.
+    pub fn set_merge_stderr_into_stdout(&mut self, merge: bool) {
+        self.merge_stderr = merge;
+    }
+
+    /// Write the stdout of the program to `sink`, rather than to our
+    /// own stdout.  This only applies when stdout is bridged over a
+    /// pipe, which is the case when our stdout is redirected; output
+    /// written to the console via the pty is unaffected.
+    /// Newline translation and the output tail apply as usual.
+    /// The sink is flushed once the program has closed its stdout,
+    /// and `serve` waits for that before returning, subject to
+    /// `set_output_drain_timeout`.
+    /// Sinks are not supported by `serve_async`.
+    pub fn set_stdout_sink(&mut self, sink: Box<dyn Write + Send>) {
+        self.stdout_sink = Some(sink);
+    }
+
+    /// Write the stderr of the program to `sink`, rather than to our
+    /// own stderr; see `set_stdout_sink`.  When stderr is merged into
+    /// stdout via `set_merge_stderr_into_stdout`, it is written to the
+    /// stdout sink instead.
+    pub fn set_stderr_sink(&mut self, sink: Box<dyn Write + Send>) {
+        self.stderr_sink = Some(sink);
+    }
+
+    /// Capture the stdout and stderr of the program in memory, rather
+    /// than writing them to our own, so that `serve_captured` can
+    /// return them.  This disables the pty, as `disable_pty` does, so
+    /// that the output arrives over pipes and the console is left
+    /// alone; stdin is still bridged as usual, unless disabled via
+    /// `set_bridge_stdin`.
+    /// This must be called before `start`.  It replaces the sinks set
+    /// via `set_stdout_sink` and `set_stderr_sink`, and when stderr is
+    /// merged into stdout, it is captured as part of stdout.
+    pub fn capture_output(&mut self) {
+        self.disable_pty();
+        let stdout = CaptureBuffer::default();
+        let stderr = CaptureBuffer::default();
+        self.set_stdout_sink(Box::new(stdout.clone()));
+        self.set_stderr_sink(Box::new(stderr.clone()));
+        self.captured = Some((stdout, stderr));
+    }
+
+    /// Call `callback` with the payload of each OSC sequence that the
+    /// program writes to the console: the bytes between `ESC ]` and the
+    /// terminating BEL or ST, such as `9;9;C:\Users` for a program that
+    /// reports its working directory.  This allows the host to act on
+    /// the shell integration sequences of an elevated session.
+    /// The sequences are still forwarded to the console as usual, and
+    /// are recognized even when they are split across reads.
+    /// The callback is run on the thread that forwards the console
+    /// output, so it should return promptly.
+    /// This only applies when the console is bridged via the pty.
+    pub fn on_osc(&mut self, callback: OscCallback) {
+        self.on_osc = Some(callback);
+    }
+
+    /// When stdin is a console that is nevertheless bridged over a
+    /// pipe, such as when conpty is unavailable, treat a Ctrl-Z typed
+    /// at the start of a line (followed by Enter, as usual) as the end
+    /// of the input, and close the stdin of the program at that point
+    /// instead of forwarding the Ctrl-Z.  This has no effect when stdin
+    /// is redirected from a file or pipe, whose data is forwarded
+    /// verbatim.  The default is false, so that binary data is never
+    /// truncated.
+    pub fn set_ctrl_z_eof(&mut self, ctrl_z_eof: bool) {
+        self.ctrl_z_eof = ctrl_z_eof;
+    }
+
+    /// Feed the bytes read from `script` to the pty of the bridge client,
+    /// as though they had been typed into the console.  The data must be
+    /// VT encoded input; see `PsuedoCon::write_input` for details.
+    /// When `replace_console` is true the real console input is ignored,
+    /// otherwise the script is sent in addition to it.
+    /// This only has an effect when stdin is a console, as otherwise
+    /// the input is bridged over a pipe rather than via the pty.
+    pub fn set_scripted_input<R: Read + Send + 'static>(
+        &mut self,
+        script: R,
+        replace_console: bool,
+    ) {
+        self.scripted_input.replace(ScriptedInput {
+            script: Box::new(script),
+            replace_console,
+        });
+    }
+
+    /// Retain the last `bytes` bytes of the output of the program, as
+    /// written to the console and to stdout and stderr when they are
+    /// redirected, so that it can be shown if the program fails.
+    /// The output is still forwarded as it arrives; the copy is made
+    /// available via `ServeOutcome::output_tail` once the program has
+    /// exited.  Passing 0 disables the capture, which is the default.
+    pub fn set_output_tail_capture(&mut self, bytes: usize) {
+        self.output_tail = if bytes == 0 {
+            None
+        } else {
+            Some(Arc::new(OutputTail::new(bytes)))
+        };
+    }
+
+    /// Control whether the bridge client must prove that it is the
+    /// client that we launched when it connects to each of our pipes.
+    /// A random nonce is passed to the client via its arguments, and
+    /// it must send that back before any data is exchanged; a client
+    /// that fails to do so is disconnected and `serve` returns an error.
+    /// This guards against another process connecting to a pipe in
+    /// the window before the real client does.
+    /// The default is true; it can be disabled for clients other than
+    /// `eledo-pty-bridge` that don't implement the handshake.
+    /// This must be called before `start`.
+    pub fn set_connect_handshake(&mut self, enabled: bool) {
+        self.nonce = if enabled {
+            Some(generate_nonce())
+        } else {
+            None
+        };
+    }
+
+    /// Set the maximum number of bytes that are read from a stream
+    /// and forwarded in one go.  Data is forwarded as soon as it is
+    /// read, rather than waiting for a chunk to fill, so this doesn't
+    /// delay interactive input; smaller chunks interleave the streams
+    /// more finely, while larger chunks reduce the overhead of copying
+    /// large amounts of output.  The default is 4096; values less than
+    /// 1 are treated as 1.
+    pub fn set_copy_chunk_size(&mut self, bytes: usize) {
+        self.copy_chunk_size = bytes.max(1);
+    }
+
+    /// Accumulate the output of the pty for up to `interval` before
+    /// writing it to the console in one go.  Programs that produce a
+    /// lot of output can otherwise cause visible flicker, as each small
+    /// write may be rendered separately.  The output is written once
+    /// the interval has elapsed even if more is still arriving, so
+    /// this bounds the added latency.  The default is `None`, which
+    /// writes the output as soon as it arrives.
+    pub fn set_output_coalesce(&mut self, interval: Option<Duration>) {
+        self.output_coalesce = interval;
+    }
+
+    /// Returns true if stdin was a console when the server was created
+    pub fn stdin_is_pty(&self) -> bool {
+        self.stdin_is_pty
+    }
+
+    /// Returns true if stdout was a console when the server was created
+    pub fn stdout_is_pty(&self) -> bool {
+        self.stdout_is_pty
+    }
+
+    /// Returns true if stderr was a console when the server was created
+    pub fn stderr_is_pty(&self) -> bool {
+        self.stderr_is_pty
+    }
+
+    /// Returns the geometry of the console that was computed by `start`,
+    /// or `None` if there is no console or `start` hasn't been called.
+    pub fn console_geometry(&self) -> Option<ConsoleGeometry> {
+        self.geometry
+    }
+
+    pub fn start_for_command(
+        &mut self,
+        argv: &mut Vec<OsString>,
+        target_token: &Token,
+    ) -> IoResult<Command> {
+        if argv.is_empty() {
+            return Err(IoError::new(std::io::ErrorKind::InvalidInput, NoCommand));
+        }
+        let bridge_path = locate_pty_bridge(self.require_trusted_bridge)?;
+        let mut args = self.start_args(target_token)?;
+        args.command.append(argv);
+        Self::bridge_command(bridge_path, args, target_token)
+    }
+
+    /// Like `start_for_command`, but the bridge runs `line` through
+    /// cmd, as set up by `Command::set_shell_command`
+    pub fn start_for_shell_command(
+        &mut self,
+        line: &OsStr,
+        target_token: &Token,
+    ) -> IoResult<Command> {
+        let bridge_path = locate_pty_bridge(self.require_trusted_bridge)?;
+        let mut args = self.start_args(target_token)?;
+        args.shell = true;
+        args.command.push(line.to_os_string());
+        Self::bridge_command(bridge_path, args, target_token)
+    }
+
+    /// Build the command that launches the bridge client at
+    /// `bridge_path` with `args`
+    fn bridge_command(
+        bridge_path: PathBuf,
+        args: BridgeArgs,
+        target_token: &Token,
+    ) -> IoResult<Command> {
+        let mut bridge_args = vec![bridge_path.into_os_string()];
+        bridge_args.append(&mut args.to_args());
+
+        let mut bridge_cmd = Command::with_environment_for_token(&target_token)?;
+        bridge_cmd.set_argv(bridge_args);
+        bridge_cmd.hide_window();
+
+        Ok(bridge_cmd)
+    }
+
+    /// Creates the server pipe and returns the name of the pipe
+    /// so that it can be passed to the client process
+    pub fn start(&mut self, token: &Token) -> IoResult<Vec<OsString>> {
+        Ok(self.start_args(token)?.to_args())
+    }
+
+    /// Bridge stdin, stdout and stderr over pipes, even where they are
+    /// a console, rather than bridging the console to a pty.
+    /// The console is left entirely alone: its modes are not changed,
+    /// and nothing is written to it other than the output of the
+    /// program, so no VT processing takes place.  The program sees
+    /// pipes rather than a console, as it would in a CI system.
+    /// This must be called before `start`; afterwards `stdin_is_pty`
+    /// and its counterparts return false.
+    pub fn disable_pty(&mut self) {
+        self.bridge_console_over_pipes();
+    }
+
+    /// Treat the console streams as though they had been redirected,
+    /// for when the console can't be bridged to a pty
+    fn bridge_console_over_pipes(&mut self) {
+        self.stdin_is_pty = false;
+        self.stdout_is_pty = false;
+        self.stderr_is_pty = false;
+        self.bridge_console = false;
+    }
+
+    /// The implementation of `start`, which returns the arguments
+    /// before they have been converted to a command line
+    fn start_args(&mut self, token: &Token) -> IoResult<BridgeArgs> {
+        let mut args = BridgeArgs {
+            nonce: self.nonce.clone(),
+            no_path_search: !self.path_search,
+            low_integrity: self.low_integrity,
+            code_page: self.code_page,
+            ..Default::default()
+        };
+        self.startup_deadline = self.startup_timeout.map(StartupDeadline::starting_now);
+
+        if self.bridge_console && !conpty_available() {
+            // The client can't create a pty to bridge the console to,
+            // so treat the console streams as though they had been
+            // redirected.  The program sees pipes rather than a console,
+            // but is otherwise usable.
+            log::warn!("conpty is not available; bridging the console over pipes instead");
+            self.bridge_console_over_pipes();
+        }
+
+        // Both halves of the console are needed to bridge it, so open
+        // them up front, before deciding which streams need pipes
+        let mut console = None;
+        if self.bridge_console {
+            console = pair_console(
+                PipeHandle::open_pipe("CONIN$"),
+                PipeHandle::open_pipe("CONOUT$"),
+                self.stdout_is_pty,
+            )?;
+            if console.is_none() {
+                self.bridge_console_over_pipes();
+            }
+        }
+
+        if self.forward_ctrl_events {
+            let pipe = NamedPipeServer::for_token_overlapped(token)?;
+            self.ctrl_pipe.replace(pipe.pipe);
+            args.ctrl = Some(pipe.path);
+        }
+
+        if !self.stdin_is_pty && self.bridge_stdin {
+            let pipe = provided_or_new(&mut self.provided.stdin, token)?;
+            self.stdin.replace(pipe.pipe);
+            args.stdin = Some(pipe.path);
+        }
+
+        let mut stdout_path = None;
+        if !self.stdout_is_pty {
+            let pipe = provided_or_new(&mut self.provided.stdout, token)?;
+            self.stdout.replace(pipe.pipe);
+            args.stdout = Some(pipe.path.clone());
+            stdout_path.replace(pipe.path);
+        }
+
+        if !self.stderr_is_pty {
+            match stdout_path {
+                Some(path) if self.merge_stderr => {
+                    // connect_stdio_pipes shares the stdout pipe when
+                    // both streams name the same one
+                    args.stderr = Some(path);
+                }
+                _ => {
+                    let pipe = provided_or_new(&mut self.provided.stderr, token)?;
+                    self.stderr.replace(pipe.pipe);
+                    args.stderr = Some(pipe.path);
+                }
+            }
+        }
+
+        let (conin, mut conout) = match console {
+            Some(console) => console,
+            None => return Ok(args),
+        };
+
+        // This decides whether the console is a legacy one, which the
+        // mode of the input depends on, so it has to come first
+        let saved = self.apply_output_mode(&mut conout)?;
+        self.output_mode.replace(saved);
+
+        let conin_pipe = provided_or_new(&mut self.provided.conin, token)?;
+        self.conin_pipe.replace(conin_pipe.pipe);
+
+        args.conin = Some(conin_pipe.path);
+
+        let mut cleared = CONIN_CLEARED_MODE;
+        if self.mouse_input {
+            cleared |= ENABLE_QUICK_EDIT_MODE;
+        }
+        let legacy = self.legacy_attributes.is_some();
+        let saved = apply_input_mode_with(
+            // ENABLE_PROCESSED_OUTPUT |  FIXME: CTRl-C handling?
+            self.conin_mode(),
+            legacy,
+            |mode| self.apply_console_mode(&conin, mode, cleared),
+        )?;
+        self.input_mode.replace(saved);
+        self.conin.replace(conin);
+
+        let conout_pipe = provided_or_new(&mut self.provided.conout, token)?;
+        self.conout_pipe.replace(conout_pipe.pipe);
+
+        args.conout = Some(conout_pipe.path);
+
+        let mut console_info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+        let res = unsafe { GetConsoleScreenBufferInfo(conout.as_handle(), &mut console_info) };
+
+        if res == 0 {
+            return Err(win32_error_with_context(
+                "GetConsoleScreenBufferInfo",
+                IoError::last_os_error(),
+            ));
+        }
+
+        let geometry = ConsoleGeometry::with_console_info(&console_info);
+        args.set_geometry(&geometry);
+        self.geometry.replace(geometry);
+
+        if self.follow_child_resize {
+            let pipe = NamedPipeServer::for_token_overlapped(token)?;
+            self.control_pipe.replace(pipe.pipe);
+            args.control = Some(pipe.path);
+        }
+
+        if self.follow_console_resize {
+            let pipe = NamedPipeServer::for_token_overlapped(token)?;
+            self.resize_pipe.replace(pipe.pipe);
+            args.resize = Some(pipe.path);
+        }
+
+        let setup = self.setup_sequence();
+        if !setup.is_empty() && self.legacy_attributes.is_none() {
+            write_console(&mut conout, &setup)?;
+        }
+
+        self.conout.replace(conout);
+
+        Ok(args)
+    }
+
+    /// Bridge the streams until the bridge process exits, returning
+    /// its exit code.  If the bridge client never connected, an error
+    /// is returned; use `serve_with_outcome` to distinguish that case.
+    pub fn serve(self, proc: Process) -> IoResult<DWORD> {
+        let timeout = self.idle_timeout;
+        self.serve_with_outcome(proc)?.program_exit_code(timeout)
+    }
+
+    /// Bridge the streams until the bridge process exits, returning
+    /// its exit code and whether the bridge client ever connected.
+    /// When the bridge is launched via the "runas" verb, the exit code
+    /// is that of the launcher, and is meaningless if the client never
+    /// connected.
+    pub fn serve_with_outcome(mut self, proc: Process) -> IoResult<ServeOutcome> {
+        let client_connected = match self.bridge_streams(&proc) {
+            Err(err) if is_startup_timeout(&err) => {
+                // Best effort; an elevated bridge can't be terminated
+                // by us, but it will fail to connect once we're gone
+                let _ = proc.terminate(ERROR_TIMEOUT);
+                return Err(err);
+            }
+            result => result?,
+        };
+        Ok(ServeOutcome {
+            exit_code: proc.exit_code()?,
+            client_connected,
+            idle_timed_out: self.idle_timed_out,
+            stdin_bytes: load_count(&self.counts.stdin),
+            stdout_bytes: load_count(&self.counts.stdout),
+            stderr_bytes: load_count(&self.counts.stderr),
+            conout_bytes: load_count(&self.counts.conout),
+            output_tail: self
+                .output_tail
+                .as_ref()
+                .map(|tail| tail.contents())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Bridge the streams until the bridge process exits, as `serve`
+    /// does, and return its exit code along with the output that was
+    /// captured; see `capture_output`, which must have been called
+    /// before `start`.
+    pub fn serve_captured(mut self, proc: Process) -> IoResult<CapturedOutput> {
+        let (stdout, stderr) = self.captured.take().ok_or_else(|| {
+            IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "serve_captured requires capture_output to be called before start",
+            )
+        })?;
+        let timeout = self.idle_timeout;
+        // The exit code is only that of the program, and there is only
+        // any output, if the client connected and ran it
+        let exit_code = self.serve_with_outcome(proc)?.program_exit_code(timeout)?;
+        Ok(CapturedOutput {
+            exit_code,
+            stdout: stdout.take(),
+            stderr: stderr.take(),
+        })
+    }
+
+    /// Copy the streams until the bridge process exits.
+    /// Returns false if the bridge client never connected.
+    fn bridge_streams(&mut self, proc: &Process) -> IoResult<bool> {
+        let nonce = self.nonce.clone();
+        let nonce = nonce.as_deref();
+        let deadline = self.startup_deadline;
+        let chunk_size = self.copy_chunk_size;
+        let activity = self.idle_timeout.map(|_| Arc::new(OutputActivity::new()));
+        let _ctrl = match self.ctrl_pipe.take() {
+            Some(pipe) => Some(CtrlForwarder::start(
+                pipe,
+                proc.try_clone()?,
+                self.nonce.clone(),
+            )?),
+            None => None,
+        };
+        // Once a copy thread has started, every way out of here must
+        // wait for or abandon it, as the console may be restored once
+        // we return
+        let mut outputs = OutputThreads::default();
+        if let Some(conin) = self.conin.take() {
+            let mut conin_dest = self.conin_pipe.take().unwrap();
+            if !wait_for_client(&mut conin_dest, proc, nonce, deadline)? {
+                outputs.join(Some(OUTPUT_DRAIN_TIMEOUT));
+                return Ok(false);
+            }
+            let mut replace_console = false;
+            if let Some(mut scripted) = self.scripted_input.take() {
+                replace_console = scripted.replace_console;
+                let mut script_dest = CountingWriter {
+                    dest: conin_dest.duplicate()?,
+                    count: Arc::clone(&self.counts.stdin),
+                };
+                std::thread::spawn(move || {
+                    let _ =
+                        forward_input(&mut scripted.script, &mut script_dest, chunk_size, false);
+                });
+            }
+            if !replace_console {
+                let mut conin_dest = CountingWriter {
+                    dest: conin_dest,
+                    count: Arc::clone(&self.counts.stdin),
+                };
+                std::thread::spawn(move || -> IoResult<()> {
+                    let mut buf = vec![0u16; chunk_size + 1];
+                    let mut pending = None;
+                    // This includes the reports that the terminal sends
+                    // for focus changes and bracketed pastes; see
+                    // `set_forward_focus_events`
+                    loop {
+                        let utf8 = read_console(&conin, &mut buf, &mut pending)?;
+                        conin_dest.write_all(utf8.as_bytes())?;
+                    }
+                });
+            }
+        }
+
+        // Start up the console output processing thread.
+        // See ConoutFilter for more information on why this is
+        // a bit more involved than simply copying the data.
+        if let Some(conout) = self.conout.take() {
+            let mut conout_src = self.conout_pipe.take().unwrap();
+            if !wait_for_client(&mut conout_src, proc, nonce, deadline)? {
+                outputs.join(Some(OUTPUT_DRAIN_TIMEOUT));
+                return Ok(false);
+            }
+            let mut filter = ConoutFilter::new(Arc::clone(&self.alt_screen));
+            filter.on_osc = self.on_osc.take();
+            let tail = self.output_tail.clone();
+            let activity = activity.clone();
+            let count = Arc::clone(&self.counts.conout);
+            let abandoned = Arc::clone(&outputs.abandoned);
+            let coalesce = self.output_coalesce;
+            let legacy = self.legacy_attributes.is_some();
+            outputs
+                .conout
+                .replace(std::thread::spawn(move || -> IoResult<()> {
+                    let mut console = ConsoleOutput::new(conout, legacy)?;
+                    let write = move |s: &str| {
+                        if abandoned.load(Ordering::SeqCst) {
+                            // serve has given up waiting for us and the
+                            // console mode may already have been restored
+                            return Err(IoError::new(
+                                std::io::ErrorKind::Other,
+                                "console output abandoned",
+                            ));
+                        }
+                        if let Some(tail) = &tail {
+                            tail.push(s.as_bytes());
+                        }
+                        if let Some(activity) = &activity {
+                            activity.touch();
+                        }
+                        console.write(s)?;
+                        count.fetch_add(s.len() as u64, Ordering::SeqCst);
+                        Ok(())
+                    };
+                    // WriteConsoleW is synchronous, so once this returns
+                    // everything has reached the console and it is safe
+                    // for Drop to reset it and restore its mode.
+                    let interval = match coalesce {
+                        Some(interval) => interval,
+                        None => return drain_conout(&mut conout_src, filter, chunk_size, write),
+                    };
+                    let (tx, writer) = spawn_output_coalescer(interval, write);
+                    let drained = drain_conout(&mut conout_src, filter, chunk_size, |s| {
+                        tx.send(s.to_string()).map_err(|_| {
+                            IoError::new(std::io::ErrorKind::Other, "console output has stopped")
+                        })
+                    });
+                    drop(tx);
+                    // Likewise, the writer has finished writing once it exits
+                    let written = join_output_coalescer(writer);
+                    drained.and(written)
+                }));
+        }
+
+        if let Some(mut stdin_dest) = self.stdin.take() {
+            if !wait_for_client(&mut stdin_dest, proc, nonce, deadline)? {
+                outputs.join(Some(OUTPUT_DRAIN_TIMEOUT));
+                return Ok(false);
+            }
+            let ctrl_z_eof = self.ctrl_z_eof && stream_is_pty(&std::io::stdin());
+            let mut stdin_dest = CountingWriter {
+                dest: stdin_dest,
+                count: Arc::clone(&self.counts.stdin),
+            };
+            std::thread::spawn(move || {
+                let mut stdin = std::io::stdin();
+                // Returning closes our end of the pipe, which the
+                // program sees as the end of its input
+                let _ = forward_input(&mut stdin, &mut stdin_dest, chunk_size, ctrl_z_eof);
+            });
+        }
+
+        let newline_mode = self.newline_mode;
+        let strip_ansi = self.strip_ansi;
+        if let Some(mut stdout_src) = self.stdout.take() {
+            if !wait_for_client(&mut stdout_src, proc, nonce, deadline)? {
+                outputs.join(Some(OUTPUT_DRAIN_TIMEOUT));
+                return Ok(false);
+            }
+            let mut stdout = TailTee {
+                dest: CountingWriter {
+                    dest: self
+                        .stdout_sink
+                        .take()
+                        .unwrap_or_else(|| Box::new(std::io::stdout())),
+                    count: Arc::clone(&self.counts.stdout),
+                },
+                tail: self.output_tail.clone(),
+                activity: activity.clone(),
+            };
+            outputs.stdout.replace(std::thread::spawn(move || {
+                let _ = copy_output(
+                    stdout_src,
+                    &mut stdout,
+                    newline_mode,
+                    strip_ansi,
+                    chunk_size,
+                );
+            }));
+        }
+
+        if let Some(mut stderr_src) = self.stderr.take() {
+            if !wait_for_client(&mut stderr_src, proc, nonce, deadline)? {
+                outputs.join(Some(OUTPUT_DRAIN_TIMEOUT));
+                return Ok(false);
+            }
+            let mut stderr = TailTee {
+                dest: CountingWriter {
+                    dest: self
+                        .stderr_sink
+                        .take()
+                        .unwrap_or_else(|| Box::new(std::io::stderr())),
+                    count: Arc::clone(&self.counts.stderr),
+                },
+                tail: self.output_tail.clone(),
+                activity: activity.clone(),
+            };
+            outputs.stderr.replace(std::thread::spawn(move || {
+                let _ = copy_output(
+                    stderr_src,
+                    &mut stderr,
+                    newline_mode,
+                    strip_ansi,
+                    chunk_size,
+                );
+            }));
+        }
+
+        if let Some(event) = &self.connected_event {
+            event.signal()?;
+        }
+
+        if let Some(control) = self.control_pipe.take() {
+            follow_viewport_reports(control, proc.try_clone()?, self.nonce.clone());
+        }
+
+        if let Some(resize) = self.resize_pipe.take() {
+            report_console_resize(resize, proc.try_clone()?, self.nonce.clone());
+        }
+
+        match (self.idle_timeout, &activity) {
+            (Some(timeout), Some(activity)) => {
+                // The program can't have produced output before
+                // everything was connected, so start the clock now
+                activity.touch();
+                let waiter = proc.try_clone()?;
+                let activity = Arc::clone(activity);
+                let monitor =
+                    std::thread::spawn(move || watch_for_idle(&waiter, &activity, timeout));
+                let _ = proc.wait_for(None)?;
+                self.idle_timed_out = monitor.join().unwrap_or(Ok(false))?;
+            }
+            _ => {
+                let _ = proc.wait_for(None)?;
+            }
+        }
+
+        // The client may still have output in flight after it has
+        // exited; the threads finish once they have drained it all.
+        // If the client was killed, something that it spawned may keep
+        // the pipes open indefinitely, so the wait may be bounded; see
+        // `set_output_drain_timeout`.  The console is restored when we
+        // are dropped regardless of how this goes.
+        if !outputs.join(self.drain_timeout()) {
+            log::warn!(
+                "gave up waiting for the output of the bridge client \
+                 to drain; it may have been terminated"
+            );
+        }
+
+        Ok(true)
+    }
+}
+
+/// The result of `BridgeServer::serve_with_outcome`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServeOutcome {
+    /// The exit code of the bridge process
+    pub exit_code: DWORD,
+    /// Whether the bridge client connected to the server.
+    /// If it did not, then the requested command was never run.
+    pub client_connected: bool,
+    /// Whether the bridge process was terminated because the program
+    /// produced no output for the period set via
+    /// `BridgeServer::set_idle_timeout`
+    pub idle_timed_out: bool,
+    /// The number of bytes of input forwarded to the program, whether
+    /// via its stdin or its console, including any scripted input
+    pub stdin_bytes: u64,
+    /// The number of bytes of output forwarded from the stdout of the
+    /// program, after newline translation and ANSI stripping
+    pub stdout_bytes: u64,
+    /// Likewise, for stderr.  This is 0 when stderr is merged into
+    /// stdout, as it is then counted along with stdout.
+    pub stderr_bytes: u64,
+    /// The number of bytes of UTF-8 written to the console on behalf
+    /// of the program
+    pub conout_bytes: u64,
+    /// The most recent output of the program, if that was requested
+    /// via `BridgeServer::set_output_tail_capture`; otherwise empty.
+    /// Use `decode_captured` to turn it into text.
+    pub output_tail: Vec<u8>,
+}
+
+impl ServeOutcome {
+    /// The exit code of the program, as returned by `serve`, or an error
+    /// if the program never ran or was terminated by `idle_timeout`
+    fn program_exit_code(&self, idle_timeout: Option<Duration>) -> IoResult<DWORD> {
+        if self.idle_timed_out {
+            Err(IoError::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "the program produced no output for {:?} and was terminated",
+                    idle_timeout.unwrap_or_default()
+                ),
+            ))
+        } else if self.client_connected {
+            Ok(self.exit_code)
+        } else {
+            Err(IoError::new(
+                std::io::ErrorKind::NotConnected,
+                "bridge client never connected",
+            ))
+        }
+    }
+}
+
+/// The result of `BridgeServer::serve_captured`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    /// The exit code of the bridge process
+    pub exit_code: DWORD,
+    /// Everything that the program wrote to stdout.
+    /// Use `decode_captured` to turn it into text.
+    pub stdout: Vec<u8>,
+    /// Everything that the program wrote to stderr
+    pub stderr: Vec<u8>,
+}
+
+/// Decode captured output as text, honoring a UTF-8, UTF-16LE or
+/// UTF-16BE byte order mark at the start of it, as some programs
+/// (PowerShell, for example) emit UTF-16 when redirected.
+/// Without a BOM, the output is taken to be UTF-8.  Invalid sequences
+/// are replaced, rather than causing an error.
+/// Note that a BOM is only found at the very start of the output, so
+/// a tail that has overflowed its capacity is treated as UTF-8.
+pub fn decode_captured(bytes: &[u8]) -> String {
+    let utf16 = |data: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let pairs = data.chunks_exact(2);
+        let odd_byte = !pairs.remainder().is_empty();
+        let units: Vec<u16> = pairs.map(|pair| from_bytes([pair[0], pair[1]])).collect();
+        let mut text = String::from_utf16_lossy(&units);
+        if odd_byte {
+            text.push(std::char::REPLACEMENT_CHARACTER);
+        }
+        text
+    };
+
+    if let Some(data) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        String::from_utf8_lossy(data).into_owned()
+    } else if let Some(data) = bytes.strip_prefix(b"\xff\xfe") {
+        utf16(data, u16::from_le_bytes)
+    } else if let Some(data) = bytes.strip_prefix(b"\xfe\xff") {
+        utf16(data, u16::from_be_bytes)
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Dimensions below this are unlikely to be intentional
+const MIN_SANE_DIMENSION: usize = 10;
+
+/// Compute the width and height of the viewport for the console.
+/// The console info describes the buffer dimensions.
+/// We need to do a little bit of math to obtain the viewport dimensions!
+/// Some terminals report a window that is larger than the buffer, and
+/// the window can transiently be degenerate while it is being resized,
+/// so we fall back to the buffer size when the window is empty and
+/// otherwise clamp the window to the buffer.
+fn viewport_dimensions(console_info: &CONSOLE_SCREEN_BUFFER_INFO) -> (usize, usize) {
+    fn clamp_dimension(name: &str, start: i16, end: i16, buffer: i16) -> usize {
+        let buffer = (buffer as i32).max(1);
+        let window = end as i32 - start as i32 + 1;
+        let size = if window < 1 {
+            buffer
+        } else {
+            window.min(buffer)
+        } as usize;
+        if size < MIN_SANE_DIMENSION {
+            log::warn!("console viewport {} is unusually small: {}", name, size);
+        }
+        size
+    }
+
+    let window = &console_info.srWindow;
+    let width = clamp_dimension("width", window.Left, window.Right, console_info.dwSize.X);
+    let height = clamp_dimension("height", window.Top, window.Bottom, console_info.dwSize.Y);
+    (width, height)
+}
+
+/// Wait for the bridge client to connect to `pipe`.
+/// The client may take an arbitrarily long time to start up, for
+/// example, while the user deliberates over a UAC prompt, so we keep
+/// waiting for as long as the bridge process is running.  Once it has
+/// gone away, we allow one more interval for the connection to
+/// materialize before concluding that the client never connected,
+/// in which case we return false.
+/// If `nonce` is set, the client must complete the connect handshake
+/// before any data is exchanged; see `PipeHandle::verify_nonce`.
+/// A client that connects but doesn't send the nonce in time is
+/// disconnected, and we go back to waiting for the real one.
+/// If `deadline` is set and passes first, a startup timeout error is
+/// returned; see `BridgeServer::set_startup_timeout`.
+fn wait_for_client(
+    pipe: &mut PipeHandle,
+    proc: &Process,
+    nonce: Option<&str>,
+    deadline: Option<StartupDeadline>,
+) -> IoResult<bool> {
+    loop {
+        let exited = proc.wait_for(Some(0))? == WAIT_OBJECT_0;
+        let interval = match deadline {
+            Some(deadline) => deadline.remaining().min(CLIENT_CONNECT_INTERVAL),
+            None => CLIENT_CONNECT_INTERVAL,
+        };
+        if pipe.wait_for_pipe_client_timeout(interval)? {
+            if let Some(nonce) = nonce {
+                let nonce_timeout = match deadline {
+                    Some(deadline) => deadline.remaining().min(CLIENT_NONCE_TIMEOUT),
+                    None => CLIENT_NONCE_TIMEOUT,
+                };
+                match pipe.verify_nonce(nonce, nonce_timeout) {
+                    Ok(()) => {}
+                    // verify_nonce has disconnected it already
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            return Ok(true);
+        }
+        if exited {
+            return Ok(false);
+        }
+        if let Some(deadline) = deadline {
+            if deadline.remaining() == Duration::from_millis(0) {
+                return Err(deadline.expired());
+            }
+        }
+    }
+}
+
+/// When the program must have started by; see
+/// `BridgeServer::set_startup_timeout`
+#[derive(Debug, Clone, Copy)]
+struct StartupDeadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl StartupDeadline {
+    fn starting_now(timeout: Duration) -> Self {
+        Self {
+            at: Instant::now() + timeout,
+            timeout,
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    fn expired(&self) -> IoError {
+        IoError::new(std::io::ErrorKind::TimedOut, StartupTimeout(self.timeout))
+    }
+}
+
+/// The error wrapped by the `std::io::Error` returned when the program
+/// didn't start within the period set by
+/// `BridgeServer::set_startup_timeout`, which it holds
+#[derive(Debug)]
+pub struct StartupTimeout(pub Duration);
+
+impl std::fmt::Display for StartupTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the program did not start within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for StartupTimeout {}
+
+/// Returns true if `err` indicates that the program didn't start in
+/// time; see `StartupTimeout`.
+pub fn is_startup_timeout(err: &IoError) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<StartupTimeout>())
+        .unwrap_or(false)
+}
+
+/// Returns the directory that contains the module that this crate
+/// was linked into.  That is the executable when it was linked
+/// statically, but a DLL when we have been loaded into a host process.
+fn module_dir() -> Option<PathBuf> {
+    let mut module: HMODULE = std::ptr::null_mut();
+    // Any address inside this crate identifies the module
+    let address = module_dir as *const u16;
+    let res = unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            address,
+            &mut module,
+        )
+    };
+    if res == 0 {
+        return None;
+    }
+
+    let mut buf = vec![0u16; 260];
+    loop {
+        let len = unsafe { GetModuleFileNameW(module, buf.as_mut_ptr(), buf.len() as DWORD) };
+        if len == 0 {
+            return None;
+        }
+        // The name was truncated if it filled the buffer
+        if (len as usize) < buf.len() {
+            buf.truncate(len as usize);
+            break;
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+    PathBuf::from(OsString::from_wide(&buf))
+        .parent()
+        .map(Path::to_path_buf)
+}
+
+/// Locate the bridge and verify that its bitness matches our own, and
+/// if `require_trusted` is set, that it can be trusted; see
+/// `BridgeServer::set_require_trusted_bridge`
+fn locate_pty_bridge(require_trusted: bool) -> IoResult<PathBuf> {
+    let dirs = bridge_dirs()?;
+    let bridge_path = find_pty_bridge(&dirs)?;
+    if require_trusted {
+        trust::check_bridge_trust(&bridge_path, &dirs, &trust::WinVerifyTrustCheck)?;
+    }
+    check_bridge_machine(&bridge_path)?;
+    Ok(bridge_path)
+}
+
+/// The directories in which the bridge is installed: that of the
+/// module that contains this crate, if it is different, and that of
+/// the current executable
+fn bridge_dirs() -> IoResult<Vec<PathBuf>> {
+    let exe_dir = std::env::current_exe()?
+        .parent()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "current exe has no containing dir while locating pty bridge!?",
+            )
+        })?
+        .to_path_buf();
+    let mut dirs = vec![];
+    if let Some(dir) = module_dir() {
+        if dir != exe_dir {
+            dirs.push(dir);
+        }
+    }
+    dirs.push(exe_dir);
+    Ok(dirs)
+}
+
+/// Look for the bridge in `dirs`, and then in the path
+fn find_pty_bridge(dirs: &[PathBuf]) -> IoResult<PathBuf> {
+    let bridge_name = "eledo-pty-bridge.exe";
+    for dir in dirs {
+        let bridge_path = dir.join(bridge_name);
+        if bridge_path.exists() {
+            return Ok(bridge_path);
+        }
+    }
+    pathsearch::find_executable_in_path(bridge_name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "{} not found alongside the module, the executable or in the path",
+                bridge_name
+            ),
+        )
+    })
+}
+
+/// The machine type that executables built for this process have
+#[cfg(target_arch = "x86")]
+const CURRENT_MACHINE: u16 = IMAGE_FILE_MACHINE_I386;
+#[cfg(target_arch = "x86_64")]
+const CURRENT_MACHINE: u16 = IMAGE_FILE_MACHINE_AMD64;
+#[cfg(target_arch = "aarch64")]
+const CURRENT_MACHINE: u16 = IMAGE_FILE_MACHINE_ARM64;
+
+/// A human readable name for a PE machine type
+fn machine_name(machine: u16) -> String {
+    match machine {
+        IMAGE_FILE_MACHINE_I386 => "x86 (32-bit)".to_string(),
+        IMAGE_FILE_MACHINE_AMD64 => "x64 (64-bit)".to_string(),
+        IMAGE_FILE_MACHINE_ARMNT => "ARM (32-bit)".to_string(),
+        IMAGE_FILE_MACHINE_ARM64 => "ARM64 (64-bit)".to_string(),
+        other => format!("machine type 0x{:04x}", other),
+    }
+}
+
+/// Whether a PE machine type is a 64-bit architecture
+fn is_64bit_machine(machine: u16) -> bool {
+    matches!(
+        machine,
+        IMAGE_FILE_MACHINE_AMD64 | IMAGE_FILE_MACHINE_ARM64 | IMAGE_FILE_MACHINE_IA64
+    )
+}
+
+/// Extract `IMAGE_FILE_HEADER.Machine` from the start of a PE image.
+/// `header` must extend at least as far as the file header.
+fn pe_machine(header: &[u8]) -> IoResult<u16> {
+    let invalid = || IoError::new(std::io::ErrorKind::InvalidData, "not a valid PE executable");
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = header.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+
+    // The DOS header starts with `MZ`, and e_lfanew at 0x3c gives
+    // the offset of the `PE\0\0` signature, which is followed by
+    // the file header, whose first field is the machine type
+    if header.get(0..2) != Some(b"MZ") {
+        return Err(invalid());
+    }
+    let pe_offset = header
+        .get(0x3c..0x40)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(invalid)?;
+    if header.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        return Err(invalid());
+    }
+    read_u16(pe_offset + 4).ok_or_else(invalid)
+}
 
-                    let mut error = None;
-                    let mut callback = |action: Action| -> IoResult<()> {
-                        match action {
-                            Action::OperatingSystemCommand(osc) => {
-                                match *osc {
-                                    OperatingSystemCommand::SetIconNameAndWindowTitle(_) => {
-                                        if suppress_control {
-                                            // We're now sync'd up with the new pty instance.
-                                            // We ignore this first title change request because
-                                            // it is going to be the uninteresting bridge exe
-                                            suppress_control = false;
-                                            Ok(())
-                                        } else {
-                                            write_console(&mut conout, &format!("{}", osc))
-                                        }
-                                    }
-                                    _ => write_console(&mut conout, &format!("{}", osc)),
-                                }
-                            }
-                            Action::CSI(c) => {
-                                if !suppress_control {
-                                    write_console(&mut conout, &format!("{}", c))
-                                } else {
-                                    Ok(())
-                                }
-                            }
-                            _ => {
-                                suppress_control = false;
-                                write_console(&mut conout, &format!("{}", action))
-                            }
-                        }
-                    };
+/// Verify that the bridge executable at `path` was built for the same
+/// bitness as this process.  A mismatch, which can be left behind by
+/// an incomplete install, otherwise shows up as a confusing failure
+/// to connect the bridge.
+fn check_bridge_machine(path: &Path) -> IoResult<()> {
+    let mut header = vec![];
+    std::fs::File::open(path)?
+        .take(4096)
+        .read_to_end(&mut header)?;
+    let machine = pe_machine(&header)
+        .map_err(|err| IoError::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    if is_64bit_machine(machine) != is_64bit_machine(CURRENT_MACHINE) {
+        return Err(IoError::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "bridge executable bitness mismatch (found {}, expected {})",
+                machine_name(machine),
+                machine_name(CURRENT_MACHINE)
+            ),
+        ));
+    }
+    Ok(())
+}
 
-                    parser.parse(&buf[0..len], |action| {
-                        if let Err(e) = callback(action) {
-                            error.replace(e);
-                        }
-                    });
+#[cfg(test)]
+mod test {
+    use super::transport::MemoryTransport;
+    use super::*;
+    use winapi::shared::winerror::{
+        ERROR_ACCESS_DENIED, ERROR_BROKEN_PIPE, ERROR_INVALID_PARAMETER, ERROR_NO_DATA,
+        ERROR_PIPE_NOT_CONNECTED,
+    };
 
-                    if let Some(e) = error.take() {
-                        return Err(e);
-                    }
-                }
+    #[test]
+    fn disable_pty() {
+        let token = Token::with_current_process().unwrap();
+        let mut server = BridgeServer::new();
+        server.disable_pty();
+        assert!(!server.stdin_is_pty());
+        assert!(!server.stdout_is_pty());
+        assert!(!server.stderr_is_pty());
+
+        let args = server.start_args(&token).unwrap();
+        assert!(args.stdin.is_some());
+        assert!(args.stdout.is_some());
+        assert!(args.stderr.is_some());
+        assert_eq!(args.conin, None);
+        assert_eq!(args.conout, None);
+        assert_eq!(args.resize, None);
+        assert_eq!(args.width, None);
+        assert!(server.input_mode.is_none());
+        assert!(server.output_mode.is_none());
+    }
+
+    fn console_info(
+        buffer: (i16, i16),
+        window: (i16, i16, i16, i16),
+    ) -> CONSOLE_SCREEN_BUFFER_INFO {
+        let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+        info.dwSize = COORD {
+            X: buffer.0,
+            Y: buffer.1,
+        };
+        info.srWindow = SMALL_RECT {
+            Left: window.0,
+            Top: window.1,
+            Right: window.2,
+            Bottom: window.3,
+        };
+        info
+    }
+
+    /// Yields the data in small chunks and then reports a broken
+    /// pipe, as happens when the bridge client exits
+    struct ClosingPipe {
+        chunks: Vec<&'static [u8]>,
+    }
+
+    impl Read for ClosingPipe {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            if self.chunks.is_empty() {
+                return Err(IoError::new(std::io::ErrorKind::BrokenPipe, "closed"));
+            }
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn start_without_command() {
+        let token = Token::with_current_process().unwrap();
+        let mut server = BridgeServer::new();
+        let res = server.start_for_command(&mut vec![], &token);
+        assert!(res.is_err_and(|err| crate::command::is_no_command(&err)));
+    }
+
+    #[test]
+    fn console_opened_together() {
+        let denied = || -> IoResult<()> { Err(IoError::from_raw_os_error(5)) };
+
+        assert_eq!(pair_console(Ok(1), Ok(2), true).unwrap(), Some((1, 2)));
+        assert_eq!(pair_console(Ok(1), Ok(2), false).unwrap(), Some((1, 2)));
+
+        // Half a console is no use, so neither half is used
+        assert_eq!(pair_console(denied(), Ok(()), true).unwrap(), None);
+        assert_eq!(pair_console(Ok(()), denied(), false).unwrap(), None);
+        assert_eq!(pair_console(denied(), denied(), false).unwrap(), None);
+
+        // but stdout being a console that can't be opened is a problem
+        for conin in [Ok(()), denied()] {
+            let err = pair_console(conin, denied(), true).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+            assert!(err.to_string().starts_with("stdout is a console"));
+        }
+    }
+
+    #[test]
+    fn drain_conout_keeps_final_line() {
+        let mut src = ClosingPipe {
+            chunks: vec![b"first line\r\n", b"final line ", b"without newline"],
+        };
+        let mut output = String::new();
+        let filter = ConoutFilter::new(Arc::new(AtomicBool::new(false)));
+        drain_conout(&mut src, filter, DEFAULT_COPY_CHUNK_SIZE, |s| {
+            output.push_str(s);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(output, "first line\r\nfinal line without newline");
+    }
+
+    #[test]
+    fn osc_callback() {
+        let payloads = Arc::new(Mutex::new(vec![]));
+        let mut filter = ConoutFilter::new(Arc::new(AtomicBool::new(false)));
+        let recorded = Arc::clone(&payloads);
+        filter.on_osc = Some(Box::new(move |payload| {
+            recorded.lock().unwrap().push(payload.to_vec());
+        }));
+
+        let mut output = String::new();
+        let mut write = |data: &[u8]| {
+            filter
+                .write_with(data, |s| {
+                    output.push_str(s);
+                    Ok(())
+                })
+                .unwrap()
+        };
+        // The title set by the bridge itself is suppressed, and so
+        // isn't reported either
+        write(b"\x1b]0;eledo-pty-bridge.exe\x07");
+        // Split across reads, and terminated by ST rather than BEL
+        write(b"hello\x1b]9;9;C:\\Win");
+        write(b"dows\x1b");
+        write(b"\\\x1b]0;title\x07");
+
+        assert_eq!(
+            *payloads.lock().unwrap(),
+            vec![b"9;9;C:\\Windows".to_vec(), b"0;title".to_vec()]
+        );
+        assert_eq!(output, "hello\x1b]9;9;C:\\Windows\x07\x1b]0;title\x07");
+    }
+
+    #[test]
+    fn coalesced_output() {
+        let (tx, rx) = channel();
+        let writer = std::thread::spawn(move || {
+            let mut writes = vec![];
+            coalesce_output(rx, Duration::from_millis(200), |s| {
+                writes.push(s.to_string());
+                Ok(())
             })
+            .map(|_| writes)
         });
 
-        if let Some(mut stdin_dest) = self.stdin.take() {
-            stdin_dest.wait_for_pipe_client()?;
-            std::thread::spawn(move || {
-                let mut stdin = std::io::stdin();
-                let _ = std::io::copy(&mut stdin, &mut stdin_dest);
-            });
+        // A burst of output, such as ConoutFilter produces for a line
+        // of text, with each character as a separate piece
+        let burst = "a burst of output\r\n".repeat(50);
+        for c in burst.chars() {
+            tx.send(c.to_string()).unwrap();
         }
+        drop(tx);
 
-        let stdout_thread = self.stdout.take().map(|mut stdout_src| {
-            let _ = stdout_src.wait_for_pipe_client();
-            std::thread::spawn(move || {
-                let mut stdout = std::io::stdout();
-                let _ = std::io::copy(&mut stdout_src, &mut stdout);
+        let writes = writer.join().unwrap().unwrap();
+        assert_eq!(writes.concat(), burst);
+        assert!(
+            writes.len() < 10,
+            "{} pieces took {} writes",
+            burst.len(),
+            writes.len()
+        );
+    }
+
+    #[test]
+    fn coalesced_output_is_written_on_time() {
+        let (tx, rx) = channel();
+        let (written_tx, written_rx) = channel();
+        std::thread::spawn(move || {
+            coalesce_output(rx, Duration::from_millis(10), |s| {
+                let _ = written_tx.send(s.to_string());
+                Ok(())
             })
         });
-        let stderr_thread = self.stderr.take().map(|mut stderr_src| {
-            let _ = stderr_src.wait_for_pipe_client();
-            std::thread::spawn(move || {
-                let mut stderr = std::io::stderr();
-                let _ = std::io::copy(&mut stderr_src, &mut stderr);
-            })
+
+        // The output is written after the interval, even though the
+        // sender is still open and may send more
+        tx.send("\x1b[1mbold".to_string()).unwrap();
+        let written = written_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(written, "\x1b[1mbold");
+    }
+
+    fn drain_into_server(server: &BridgeServer, chunks: Vec<&'static [u8]>) {
+        let mut src = ClosingPipe { chunks };
+        let filter = ConoutFilter::new(Arc::clone(&server.alt_screen));
+        drain_conout(&mut src, filter, DEFAULT_COPY_CHUNK_SIZE, |_| Ok(())).unwrap();
+    }
+
+    #[test]
+    fn teardown_leaves_alt_screen() {
+        let mut server = BridgeServer::new();
+        assert_eq!(server.teardown_sequence(), "\x1b[!p");
+
+        drain_into_server(&server, vec![b"hello\x1b[?1049h", b"full screen app"]);
+        assert_eq!(server.teardown_sequence(), "\x1b[?1049l\x1b[!p");
+
+        server.set_restore_main_screen_on_drop(false);
+        assert_eq!(server.teardown_sequence(), "\x1b[!p");
+        server.set_soft_reset_on_drop(false);
+        assert_eq!(server.teardown_sequence(), "");
+
+        // An app that switches back on its own doesn't need our help
+        let server = BridgeServer::new();
+        drain_into_server(&server, vec![b"hello\x1b[?1049h", b"\x1b[?1049l"]);
+        assert_eq!(server.teardown_sequence(), "\x1b[!p");
+    }
+
+    fn translate(mode: NewlineMode, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut translator = NewlineTranslator::new(mode);
+        let mut out = vec![];
+        for chunk in chunks {
+            translator.translate(chunk, &mut out);
+        }
+        translator.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn newline_passthrough() {
+        let mixed: &[&[u8]] = &[b"a\r\nb\nc\r", b"\nd\r"];
+        assert_eq!(
+            translate(NewlineMode::Passthrough, mixed),
+            b"a\r\nb\nc\r\nd\r".to_vec()
+        );
+    }
+
+    #[test]
+    fn newline_force_crlf() {
+        let mixed: &[&[u8]] = &[b"a\r\nb\nc\r", b"\nd\n", b"\ne\r"];
+        assert_eq!(
+            translate(NewlineMode::ForceCrlf, mixed),
+            b"a\r\nb\r\nc\r\nd\r\n\r\ne\r".to_vec()
+        );
+    }
+
+    #[test]
+    fn newline_force_lf() {
+        let mixed: &[&[u8]] = &[b"a\r\nb\nc\r", b"\nd\r", b"e\r\r", b"\nf\r"];
+        assert_eq!(
+            translate(NewlineMode::ForceLf, mixed),
+            b"a\nb\nc\nd\re\r\nf\r".to_vec()
+        );
+    }
+
+    #[test]
+    fn copy_output_over_transport() {
+        let (mut child, server) = MemoryTransport::pair();
+        let producer = std::thread::spawn(move || {
+            for chunk in [&b"one\ntw"[..], b"o\n", b"three"] {
+                child.write_all(chunk).unwrap();
+            }
         });
+        let mut dest = vec![];
+        copy_output(server, &mut dest, NewlineMode::ForceCrlf, false, 4).unwrap();
+        producer.join().unwrap();
+        // The end of the transport is the end of the output
+        assert_eq!(dest, b"one\r\ntwo\r\nthree");
+    }
 
-        let _ = proc.wait_for(None)?;
+    #[test]
+    fn copy_output_reports_consumer_errors() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> IoResult<usize> {
+                Err(IoError::new(std::io::ErrorKind::Other, "disk full"))
+            }
 
-        stdout_thread.map(|t| t.join());
-        stderr_thread.map(|t| t.join());
-        conout_thread.map(|t| t.join());
+            fn flush(&mut self) -> IoResult<()> {
+                Ok(())
+            }
+        }
 
-        let exit_code = proc.exit_code()?;
-        Ok(exit_code)
+        let (mut child, server) = MemoryTransport::pair();
+        child.write_all(b"output").unwrap();
+        let err = copy_output(server, &mut Full, NewlineMode::Passthrough, false, 4).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        // The transport was closed, so the program can't write any more
+        let err = child.write(b"more").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
     }
-}
 
-fn locate_pty_bridge() -> IoResult<PathBuf> {
-    let bridge_name = "eledo-pty-bridge.exe";
-    let bridge_path = std::env::current_exe()?
-        .parent()
-        .ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "current exe has no containing dir while locating pty bridge!?",
-            )
-        })?
-        .join(bridge_name);
-    if bridge_path.exists() {
-        Ok(bridge_path)
-    } else {
-        pathsearch::find_executable_in_path(bridge_name).ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "{} not found alongside executable or in the path",
-                    bridge_name
-                ),
+    #[test]
+    fn copy_output_stops_when_consumer_closes() {
+        let child = PipePair::new().unwrap();
+        let mut consumer = PipePair::new().unwrap();
+        // The consumer goes away without reading anything
+        drop(consumer.read);
+
+        // Stands in for a program that produces a lot of output
+        let mut child_stdout = child.write;
+        let producer = std::thread::spawn(move || -> bool {
+            let line = [b'x'; 1024];
+            for _ in 0..100_000 {
+                if child_stdout.write_all(&line).is_err() {
+                    return false;
+                }
+            }
+            true
+        });
+
+        assert!(copy_output(
+            child.read,
+            &mut consumer.write,
+            NewlineMode::Passthrough,
+            false,
+            DEFAULT_COPY_CHUNK_SIZE
+        )
+        .is_err());
+        // The producer should have been stopped by a write error
+        // rather than running to completion
+        assert!(!producer.join().unwrap());
+    }
+
+    #[test]
+    fn scripted_pty_input() {
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let con =
+            PsuedoCon::with_input_injection(COORD { X: 80, Y: 25 }, None, input.read, output.write)
+                .unwrap();
+        let mut conout = output.read;
+
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec![
+            "cmd.exe".into(),
+            "/c".into(),
+            "set /p line=&call echo [%line%]".into(),
+        ]);
+        let proc = cmd.spawn_with_pty(&con).unwrap();
+        // Nothing is written to the relayed input; the only input
+        // comes from write_input.  It is kept open, as closing it
+        // closes the input of the pty too.
+        let relayed = input.write;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut seen = vec![];
+            let mut buf = [0u8; 4096];
+            while let Ok(len) = conout.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                seen.extend_from_slice(&buf[0..len]);
+                if String::from_utf8_lossy(&seen).contains("[scripted]") {
+                    let _ = tx.send(());
+                    break;
+                }
+            }
+        });
+
+        con.write_input(b"scripted\r").unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        proc.wait_for(None).unwrap();
+
+        drop(relayed);
+        let started = Instant::now();
+        while con.write_input(b"late\r").is_ok() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn input_injection_is_opt_in() {
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let con = PsuedoCon::new(COORD { X: 80, Y: 25 }, None, input.read, output.write).unwrap();
+        assert!(con.write_input(b"x").is_err());
+        drop(con);
+
+        // Dropping the pty stops the relay even though its input is
+        // still open, after which the relayed input has no reader
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let con =
+            PsuedoCon::with_input_injection(COORD { X: 80, Y: 25 }, None, input.read, output.write)
+                .unwrap();
+        con.write_input(b"x").unwrap();
+        drop(con);
+        let mut relayed = input.write;
+        assert!(relayed.write_all(b"x").is_err());
+    }
+
+    #[test]
+    fn resize_while_running() {
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let client = BridgePtyClient {
+            con: PsuedoCon::with_input_injection(
+                COORD { X: 80, Y: 25 },
+                None,
+                input.read,
+                output.write,
             )
-        })
+            .unwrap(),
+        };
+        let mut conout = output.read;
+
+        // Reports the size of its console before and after a line of
+        // input, which is sent once the pty has been resized
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        let report =
+            "Write-Host ('size=' + [Console]::WindowWidth + 'x' + [Console]::WindowHeight)";
+        cmd.set_argv(vec![
+            "powershell.exe".into(),
+            "-NoProfile".into(),
+            "-NonInteractive".into(),
+            "-Command".into(),
+            format!("{}; $null = Read-Host; {}", report, report).into(),
+        ]);
+        let proc = client.spawn(cmd).unwrap();
+        // Kept open, as closing it closes the input of the pty too
+        let _relayed = input.write;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut seen = vec![];
+            let mut buf = [0u8; 4096];
+            let mut expected = ["size=80x25", "size=100x30"].iter();
+            let mut want = expected.next();
+            while let Ok(len) = conout.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                seen.extend_from_slice(&buf[0..len]);
+                if let Some(size) = want {
+                    if String::from_utf8_lossy(&seen).contains(size) {
+                        let _ = tx.send(size.to_string());
+                        want = expected.next();
+                    }
+                }
+            }
+        });
+
+        let timeout = Duration::from_secs(30);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), "size=80x25");
+        assert!(client.resize(0, 30).is_err());
+        client.resize(100, 30).unwrap();
+        client.write_input(b"\r").unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), "size=100x30");
+        proc.wait_for(None).unwrap();
+    }
+
+    #[test]
+    fn output_tail_keeps_most_recent() {
+        let tail = OutputTail::new(8);
+        tail.push(b"hello ");
+        assert_eq!(tail.contents(), b"hello ");
+        tail.push(b"world");
+        assert_eq!(tail.contents(), b"lo world");
+        tail.push(b"a chunk larger than the tail");
+        assert_eq!(tail.contents(), b"the tail");
+    }
+
+    #[test]
+    fn output_tail_tee() {
+        let tail = Arc::new(OutputTail::new(4));
+        let mut tee = TailTee {
+            dest: vec![],
+            tail: Some(Arc::clone(&tail)),
+            activity: None,
+        };
+        tee.write_all(b"line 1\nline 2\n").unwrap();
+        assert_eq!(tee.dest, b"line 1\nline 2\n");
+        assert_eq!(tail.contents(), b"e 2\n");
+    }
+
+    #[test]
+    fn connect_handshake_silent_client() {
+        let token = Token::with_current_process().unwrap();
+        let mut server = NamedPipeServer::for_token_overlapped(&token).unwrap();
+        let mut client = PipeHandle::open_pipe(&server.path).unwrap();
+        server.pipe.wait_for_pipe_client().unwrap();
+        let err = server
+            .pipe
+            .verify_nonce(&generate_nonce(), Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        // The client was disconnected, leaving the pipe free for another
+        assert!(client.write_all(b"late").is_err());
+    }
+
+    /// Connect a client that sends `client_nonce` to a server that
+    /// expects `server_nonce`, returning the result for each side
+    fn connect_handshake(server_nonce: &str, client_nonce: String) -> (IoResult<()>, IoResult<()>) {
+        let token = Token::with_current_process().unwrap();
+        let mut server = NamedPipeServer::for_token_overlapped(&token).unwrap();
+        let path = server.path.clone();
+        let client = std::thread::spawn(move || {
+            let mut pipe = PipeHandle::open_pipe(&path)?;
+            pipe.send_nonce(&client_nonce)
+        });
+        server.pipe.wait_for_pipe_client().unwrap();
+        let server_result = server.pipe.verify_nonce(server_nonce, CLIENT_NONCE_TIMEOUT);
+        (server_result, client.join().unwrap())
+    }
+
+    #[test]
+    fn connect_handshake_matching_nonce() {
+        let nonce = generate_nonce();
+        let (server, client) = connect_handshake(&nonce, nonce.clone());
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[test]
+    fn connect_handshake_mismatched_nonce() {
+        let nonce = generate_nonce();
+        let mut other = generate_nonce();
+        while other == nonce {
+            other = generate_nonce();
+        }
+        let (server, client) = connect_handshake(&nonce, other);
+        assert_eq!(
+            server.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        // The server hung up rather than echoing the nonce
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn focus_and_paste_reports() {
+        let mut server = BridgeServer::new();
+        assert_eq!(server.setup_sequence(), "");
+        server.set_forward_focus_events(true);
+        server.set_bracketed_paste(true);
+        assert_eq!(server.setup_sequence(), "\x1b[?1004h\x1b[?2004h");
+        assert_eq!(server.teardown_sequence(), "\x1b[?1004l\x1b[?2004l\x1b[!p");
+        // The console passes the reports along as text
+        assert_ne!(server.conin_mode() & ENABLE_VIRTUAL_TERMINAL_INPUT, 0);
+    }
+
+    #[test]
+    fn mouse_input_mode() {
+        let mut server = BridgeServer::new();
+        assert_eq!(server.conin_mode(), ENABLE_VIRTUAL_TERMINAL_INPUT);
+
+        server.set_mouse_input(true);
+        let mode = server.conin_mode();
+        assert_ne!(mode & ENABLE_MOUSE_INPUT, 0);
+        assert_ne!(mode & ENABLE_EXTENDED_FLAGS, 0);
+        assert_eq!(mode & ENABLE_QUICK_EDIT_MODE, 0);
+        assert_ne!(mode & ENABLE_VIRTUAL_TERMINAL_INPUT, 0);
+    }
+
+    #[test]
+    fn preserved_console_modes() {
+        let required = ENABLE_VIRTUAL_TERMINAL_INPUT;
+        let original = ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_QUICK_EDIT_MODE;
+        let applied = merge_console_mode(original, required, CONIN_CLEARED_MODE);
+        assert_eq!(
+            applied,
+            ENABLE_VIRTUAL_TERMINAL_INPUT | ENABLE_QUICK_EDIT_MODE
+        );
+
+        // Nothing changes when the required bits are already in place
+        assert_eq!(
+            merge_console_mode(applied, required, CONIN_CLEARED_MODE),
+            applied
+        );
+
+        let saved = SavedMode { original, applied };
+        assert_eq!(saved.undo_changes(applied), original);
+        // Bits that someone else changed in the meantime are kept
+        assert_eq!(
+            saved.undo_changes(applied | ENABLE_MOUSE_INPUT),
+            original | ENABLE_MOUSE_INPUT
+        );
+        assert_eq!(
+            saved.undo_changes(applied & !ENABLE_QUICK_EDIT_MODE),
+            original & !ENABLE_QUICK_EDIT_MODE
+        );
+    }
+
+    #[test]
+    fn split_surrogate_pair() {
+        let units: Vec<u16> = "a\u{1F600}b".encode_utf16().collect();
+        assert_eq!(units.len(), 4);
+        let mut pending = None;
+        assert_eq!(decode_console_input(&units[0..2], &mut pending), "a");
+        assert_eq!(pending, Some(units[1]));
+
+        // read_console puts the pending unit in front of the next read
+        let next = [pending.take().unwrap(), units[2], units[3]];
+        assert_eq!(decode_console_input(&next, &mut pending), "\u{1F600}b");
+        assert_eq!(pending, None);
+
+        // A low surrogate on its own still can't be decoded
+        assert_eq!(decode_console_input(&units[2..3], &mut pending), "\u{FFFD}");
+    }
+
+    #[test]
+    fn legacy_console_input_mode() {
+        // Stands in for a legacy console, which rejects VT input
+        let legacy_console = |attempts: &mut Vec<DWORD>, mode: DWORD| {
+            attempts.push(mode);
+            if mode & ENABLE_VIRTUAL_TERMINAL_INPUT != 0 {
+                Err(IoError::from_raw_os_error(ERROR_INVALID_PARAMETER as i32))
+            } else {
+                Ok(SavedMode {
+                    original: ENABLE_LINE_INPUT,
+                    applied: mode,
+                })
+            }
+        };
+        let mode = ENABLE_VIRTUAL_TERMINAL_INPUT | ENABLE_MOUSE_INPUT;
+
+        let mut attempts = vec![];
+        let saved =
+            apply_input_mode_with(mode, true, |mode| legacy_console(&mut attempts, mode)).unwrap();
+        assert_eq!(saved.applied, ENABLE_MOUSE_INPUT);
+        assert_eq!(attempts, vec![mode, ENABLE_MOUSE_INPUT]);
+
+        // Without a legacy console having been detected, the failure
+        // is a genuine one and is reported
+        let mut attempts = vec![];
+        let err = apply_input_mode_with(mode, false, |mode| legacy_console(&mut attempts, mode))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_INVALID_PARAMETER as i32));
+        assert_eq!(attempts, vec![mode]);
+    }
+
+    #[test]
+    fn serve_after_external_termination() {
+        let token = Token::with_current_process().unwrap();
+        let stdout = NamedPipeServer::for_token_overlapped(&token).unwrap();
+        let mut server = BridgeServer::new();
+        server.set_connect_handshake(false);
+        server.set_output_drain_timeout(Duration::from_secs(2));
+        server.stdout.replace(stdout.pipe);
+
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        // The grandchild keeps the output pipe open after the child
+        // has been killed
+        cmd.set_argv(vec![
+            "cmd.exe".into(),
+            "/c".into(),
+            "start /b ping -n 30 127.0.0.1 & ping -n 30 127.0.0.1".into(),
+        ]);
+        cmd.set_stdout(PipeHandle::open_pipe(&stdout.path).unwrap())
+            .unwrap();
+        // Tears down the grandchild when serve drops the process
+        cmd.set_kill_on_close(true);
+        let proc = cmd.spawn().unwrap();
+        drop(cmd);
+
+        let sibling = proc.try_clone().unwrap();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            sibling.terminate(7).unwrap();
+        });
+
+        let started = std::time::Instant::now();
+        let outcome = server.serve_with_outcome(proc).unwrap();
+        assert!(outcome.client_connected);
+        assert_eq!(outcome.exit_code, 7);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn forward_input_does_not_wait_for_more() {
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let (mut src, mut dest) = (input.read, output.write);
+        std::thread::spawn(move || {
+            forward_input(&mut src, &mut dest, DEFAULT_COPY_CHUNK_SIZE, false)
+        });
+
+        let mut read = output.read;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let _ = tx.send(read.read(&mut buf).map(|len| buf[0..len].to_vec()));
+        });
+
+        // A single keystroke is forwarded on its own
+        let mut write = input.write;
+        write.write_all(b"q").unwrap();
+        let forwarded = rx.recv_timeout(Duration::from_secs(2)).unwrap().unwrap();
+        assert_eq!(forwarded, b"q");
+    }
+
+    fn strip_ansi(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut stripper = AnsiStripper::new();
+        let mut out = vec![];
+        for chunk in chunks {
+            stripper.strip(chunk, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn ansi_stripper() {
+        // Colors and cursor movement
+        assert_eq!(
+            strip_ansi(&[b"\x1b[1;31mred\x1b[0m plain\r\n"]),
+            b"red plain\r\n"
+        );
+        assert_eq!(strip_ansi(&[b"a\x1b[2J\x1b[10;5Hb\x1b[?25lc"]), b"abc");
+        // Titles, whether ended by BEL or ST, and charset selection
+        assert_eq!(
+            strip_ansi(&[b"\x1b]0;title\x07x\x1b]2;t\x1b\\y\x1b(Bz"]),
+            b"xyz"
+        );
+        // Text that merely looks like part of a sequence is untouched
+        assert_eq!(
+            strip_ansi(&["[31m ]0; \u{e9}".as_bytes()]),
+            "[31m ]0; \u{e9}".as_bytes()
+        );
+
+        // Sequences split across reads are still removed
+        assert_eq!(strip_ansi(&[b"one\x1b", b"[3", b"2mtwo"]), b"onetwo");
+        assert_eq!(
+            strip_ansi(&[b"\x1b]0;ti", b"tle\x1b", b"\\three"]),
+            b"three"
+        );
+    }
+
+    /// The errors that the system may report once the other end of a
+    /// pipe has gone away
+    const PIPE_CLOSED_ERRORS: [u32; 3] =
+        [ERROR_BROKEN_PIPE, ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED];
+
+    /// Yields the data in chunks and then fails with `code`
+    struct FailingPipe {
+        chunks: Vec<&'static [u8]>,
+        code: u32,
+    }
+
+    impl Read for FailingPipe {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            if self.chunks.is_empty() {
+                return Err(IoError::from_raw_os_error(self.code as i32));
+            }
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    /// Accepts a single write and then fails with `code`, as writing
+    /// to a pipe whose reader has exited does
+    struct FailingWriter {
+        written: Vec<u8>,
+        code: u32,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            if !self.written.is_empty() {
+                return Err(IoError::from_raw_os_error(self.code as i32));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pipe_closed_errors_are_benign() {
+        for &code in PIPE_CLOSED_ERRORS.iter() {
+            let err = IoError::from_raw_os_error(code as i32);
+            assert!(is_end_of_pipe(&err), "{}", code);
+            // The context hides the code, but not its meaning
+            let wrapped = pipe_io_error("WriteFile", err);
+            assert_eq!(wrapped.kind(), std::io::ErrorKind::BrokenPipe);
+            assert!(is_end_of_pipe(&wrapped), "{}", code);
+
+            // Reads that fail this way are the end of the output...
+            let mut src = FailingPipe {
+                chunks: vec![b"hello"],
+                code,
+            };
+            let mut drained = String::new();
+            let filter = ConoutFilter::new(Arc::new(AtomicBool::new(false)));
+            drain_conout(&mut src, filter, DEFAULT_COPY_CHUNK_SIZE, |s| {
+                drained.push_str(s);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(drained, "hello");
+
+            // ...and of the input
+            let mut src = FailingPipe {
+                chunks: vec![b"one", b"two"],
+                code,
+            };
+            let mut forwarded = vec![];
+            forward_input(&mut src, &mut forwarded, DEFAULT_COPY_CHUNK_SIZE, false).unwrap();
+            assert_eq!(forwarded, b"onetwo");
+
+            // Writes that fail this way mean that the program has exited
+            let mut src = FailingPipe {
+                chunks: vec![b"one", b"two"],
+                code: ERROR_ACCESS_DENIED,
+            };
+            let mut dest = FailingWriter {
+                written: vec![],
+                code,
+            };
+            forward_input(&mut src, &mut dest, DEFAULT_COPY_CHUNK_SIZE, false).unwrap();
+            assert_eq!(dest.written, b"one");
+        }
+
+        let other = IoError::from_raw_os_error(ERROR_ACCESS_DENIED as i32);
+        assert!(!is_end_of_pipe(&other));
+        assert!(!is_end_of_pipe(&pipe_io_error("ReadFile", other)));
+    }
+
+    #[test]
+    fn decode_captured_output() {
+        assert_eq!(decode_captured(b"plain \xc3\xa9\r\n"), "plain \u{e9}\r\n");
+        assert_eq!(decode_captured(b"\xef\xbb\xbfutf8"), "utf8");
+        assert_eq!(decode_captured(b"\xff\xfeh\0i\0\xe9\0"), "hi\u{e9}");
+        assert_eq!(decode_captured(b"\xfe\xff\0h\0i\0\xe9"), "hi\u{e9}");
+        assert_eq!(decode_captured(b""), "");
+
+        // Damaged input is replaced rather than rejected
+        assert_eq!(decode_captured(b"bad \xff"), "bad \u{fffd}");
+        assert_eq!(decode_captured(b"\xff\xfeh\0i"), "h\u{fffd}");
+    }
+
+    #[test]
+    fn ctrl_z_eof() {
+        let mut eof = CtrlZEof::default();
+        assert_eq!(eof.find(b"abc\x1a"), None);
+        assert_eq!(eof.find(b"\r\n"), None);
+        assert_eq!(eof.find(b"\x1a\r\n"), Some(0));
+
+        let mut eof = CtrlZEof::default();
+        assert_eq!(eof.find(b"one\r\ntwo\r\n\x1a\r\n"), Some(10));
+
+        // Forwarding stops at the EOF, closing the destination
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let (mut src, mut dest) = (input.read, output.write);
+        let mut write = input.write;
+        write.write_all(b"line\r\n\x1a\r\n").unwrap();
+        forward_input(&mut src, &mut dest, DEFAULT_COPY_CHUNK_SIZE, true).unwrap();
+        drop(dest);
+        let mut forwarded = vec![];
+        let mut read = output.read;
+        read.read_to_end(&mut forwarded).unwrap();
+        assert_eq!(forwarded, b"line\r\n");
+    }
+
+    #[test]
+    fn viewport_reports() {
+        let report = format_viewport_report(120, 40);
+        assert_eq!(parse_viewport_report(&report), Some((120, 40)));
+        assert_eq!(parse_viewport_report("viewport 120"), None);
+        assert_eq!(parse_viewport_report("viewport 0 40"), None);
+        assert_eq!(parse_viewport_report("viewport 120 40 1"), None);
+        assert_eq!(parse_viewport_report("cursor 1 2"), None);
+    }
+
+    #[test]
+    fn viewport_reports_over_transport() {
+        let (mut client, server) = MemoryTransport::pair();
+        send_viewport_report(&mut client, (120, 40)).unwrap();
+        client.write_all(b"bogus\nviewport 0 10\n").unwrap();
+        send_viewport_report(&mut client, (80, 25)).unwrap();
+        drop(client);
+
+        let mut reports = vec![];
+        read_viewport_reports(server, |width, height| reports.push((width, height)));
+        assert_eq!(reports, vec![(120, 40), (80, 25)]);
+    }
+
+    #[test]
+    fn viewport_changes() {
+        let (mut client, server) = MemoryTransport::pair();
+        let sender = std::thread::spawn(move || {
+            let mut sizes = vec![
+                Some((80, 25)),
+                Some((80, 25)),
+                None,
+                Some((100, 30)),
+                Some((100, 30)),
+                Some((120, 40)),
+            ]
+            .into_iter();
+            // Keep changing once the sizes run out, so that the
+            // sender notices when the other end goes away
+            let mut extra = 0;
+            send_viewport_changes(&mut client, move || {
+                sizes.next().unwrap_or_else(|| {
+                    extra += 1;
+                    Some((1, extra))
+                })
+            })
+        });
+
+        // The initial size and the repeats are not reported
+        let reports: Vec<_> = BufReader::new(server)
+            .lines()
+            .take(2)
+            .map(|line| parse_viewport_report(&line.unwrap()))
+            .collect();
+        assert_eq!(reports, vec![Some((100, 30)), Some((120, 40))]);
+
+        let err = sender.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn clamp_viewport_to_screen() {
+        let largest = COORD { X: 200, Y: 60 };
+        assert_eq!(clamp_viewport(120, 40, largest), (120, 40));
+        assert_eq!(clamp_viewport(500, 100, largest), (200, 60));
+        // A degenerate screen size doesn't produce an empty window
+        assert_eq!(clamp_viewport(80, 25, COORD { X: 0, Y: 0 }), (1, 1));
+    }
+
+    #[test]
+    fn with_pipes_requires_console_pair() {
+        let token = Token::with_current_process().unwrap();
+        let pipe = || Some(NamedPipeServer::for_token_overlapped(&token).unwrap());
+
+        let err = BridgeServer::with_pipes(None, None, None, pipe(), None)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(BridgeServer::with_pipes(None, None, None, None, pipe()).is_err());
+
+        assert!(BridgeServer::with_pipes(pipe(), None, None, pipe(), pipe()).is_ok());
+        assert!(BridgeServer::with_pipes(None, pipe(), pipe(), None, None).is_ok());
+    }
+
+    #[test]
+    fn pipes_are_not_ptys() {
+        let pair = PipePair::new().unwrap();
+        assert!(!stream_is_pty(&pair.read));
+        assert!(!stream_is_pty(&pair.write));
+    }
+
+    #[test]
+    fn viewport_dimensions_normal() {
+        let info = console_info((120, 9001), (0, 100, 119, 129));
+        assert_eq!(viewport_dimensions(&info), (120, 30));
+    }
+
+    #[test]
+    fn viewport_dimensions_window_larger_than_buffer() {
+        let info = console_info((80, 25), (0, 0, 199, 59));
+        assert_eq!(viewport_dimensions(&info), (80, 25));
+    }
+
+    #[test]
+    fn viewport_dimensions_degenerate_window() {
+        // A zero-size or inverted window falls back to the buffer size
+        let info = console_info((80, 25), (10, 10, 9, 9));
+        assert_eq!(viewport_dimensions(&info), (80, 25));
+
+        let info = console_info((80, 25), (0, 5, -1, 0));
+        assert_eq!(viewport_dimensions(&info), (80, 25));
+    }
+
+    #[test]
+    fn geometry_args() {
+        let mut info = console_info((120, 9001), (0, 100, 119, 129));
+        info.dwCursorPosition = COORD { X: 4, Y: 110 };
+        let geometry = ConsoleGeometry::with_console_info(&info);
+        assert_eq!(
+            geometry,
+            ConsoleGeometry {
+                width: 120,
+                height: 30,
+                cursor_x: 4,
+                cursor_y: 10,
+                buffer_height: Some(9001),
+            }
+        );
+
+        let mut args = vec![];
+        geometry.append_args(&mut args);
+        assert_eq!(
+            args,
+            [
+                "--width",
+                "120",
+                "--height",
+                "30",
+                "--buffer-height",
+                "9001",
+                "--cursor-x",
+                "4",
+                "--cursor-y",
+                "10"
+            ]
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+        );
+
+        // There's no need to pass the buffer height when it matches
+        let info = console_info((80, 25), (0, 0, 79, 24));
+        let geometry = ConsoleGeometry::with_console_info(&info);
+        assert_eq!(geometry.buffer_height, None);
+    }
+
+    #[test]
+    fn pty_client_errors() {
+        let err = |conin: &Path, conout: &Path, width| {
+            BridgePtyClient::with_params(conin, conout, width, 25, None, None)
+                .err()
+                .unwrap()
+        };
+
+        let zero = err(Path::new("in"), Path::new("out"), 0);
+        assert_eq!(zero.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(zero.to_string(), "the pty width must not be zero");
+
+        let token = Token::with_current_process().unwrap();
+        let server = NamedPipeServer::for_token(&token).unwrap();
+        let missing = PathBuf::from(r"\\.\pipe\eledo-bridge-missing");
+
+        let conout = err(&server.path, &missing, 80);
+        assert_eq!(conout.kind(), std::io::ErrorKind::NotFound);
+        assert!(
+            conout.to_string().starts_with(&format!(
+                "failed to open conout pipe: CreateFileW: {}",
+                missing.display()
+            )),
+            "{}",
+            conout
+        );
+
+        let conin = err(&missing, &server.path, 80);
+        assert!(
+            conin.to_string().starts_with(&format!(
+                "failed to open conin pipe: CreateFileW: {}",
+                missing.display()
+            )),
+            "{}",
+            conin
+        );
+    }
+
+    #[test]
+    fn console_code_page() {
+        use winapi::um::consoleapi::{GetConsoleCP, GetConsoleOutputCP};
+
+        assert_eq!(
+            set_console_code_page(12345).unwrap_err().to_string(),
+            "code page 12345 is not installed"
+        );
+        let mut server = BridgeServer::new();
+        assert!(server.set_code_page(12345).is_err());
+        server.set_code_page(65001).unwrap();
+
+        // The code page is shared by every test that uses the console
+        let _console = lock_console();
+        let (input, output) = unsafe { (GetConsoleCP(), GetConsoleOutputCP()) };
+        if output == 0 {
+            // There is no console for the code page to be set on
+            return;
+        }
+        let code_page = if output == 437 { 850 } else { 437 };
+        set_console_code_page(code_page).unwrap();
+
+        // The child shares our console, and so its code page
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "chcp".into()]);
+        let PipePair { mut read, write } = PipePair::new().unwrap();
+        cmd.set_stdout(write).unwrap();
+        let proc = cmd.spawn();
+        drop(cmd);
+        let mut reported = String::new();
+        if let Ok(proc) = &proc {
+            let _ = read.read_to_string(&mut reported);
+            let _ = proc.wait_for(None);
+        }
+        unsafe {
+            SetConsoleCP(input);
+            SetConsoleOutputCP(output);
+        }
+
+        proc.unwrap();
+        assert!(
+            reported.trim_end().ends_with(&format!(" {}", code_page)),
+            "{}",
+            reported
+        );
+    }
+
+    #[test]
+    fn oversized_dimensions_are_clamped() {
+        assert_eq!(coord_dimension("width", 120), 120);
+        assert_eq!(coord_dimension("width", i16::MAX as usize), i16::MAX);
+        // 40000 as i16 would wrap around to -25536
+        assert_eq!(coord_dimension("width", 40000), i16::MAX);
+        assert_eq!(coord_dimension("height", usize::MAX), i16::MAX);
+        assert_eq!(pty_dimension("width", 40000).unwrap(), i16::MAX);
+
+        // Negative values reported by the console don't wrap either
+        let mut info = console_info((80, -1), (0, 0, 79, 24));
+        info.dwCursorPosition = COORD { X: -3, Y: -7 };
+        let geometry = ConsoleGeometry::with_console_info(&info);
+        assert_eq!(geometry.buffer_height, None);
+        assert_eq!(geometry.cursor_x, 0);
+        assert_eq!(geometry.cursor_y, 0);
+    }
+
+    #[test]
+    fn module_dir_of_test_binary() {
+        // The crate is linked statically into the test binary
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(module_dir().as_deref(), exe.parent());
+    }
+
+    #[test]
+    fn pe_machine_type() {
+        // A minimal DOS stub pointing at a PE header at 0x40,
+        // followed by the start of an x64 file header
+        let mut header = vec![0u8; 0x40];
+        header[0..2].copy_from_slice(b"MZ");
+        header[0x3c] = 0x40;
+        header.extend_from_slice(b"PE\0\0");
+        header.extend_from_slice(&[0x64, 0x86, 0x06, 0x00]);
+        assert_eq!(pe_machine(&header).unwrap(), IMAGE_FILE_MACHINE_AMD64);
+
+        header[0x44..0x46].copy_from_slice(&IMAGE_FILE_MACHINE_I386.to_le_bytes());
+        assert_eq!(pe_machine(&header).unwrap(), IMAGE_FILE_MACHINE_I386);
+        assert!(is_64bit_machine(IMAGE_FILE_MACHINE_AMD64));
+        assert!(!is_64bit_machine(IMAGE_FILE_MACHINE_I386));
+
+        // Truncated or mislabelled images are rejected
+        assert!(pe_machine(&header[0..0x45]).is_err());
+        header[0x40] = b'X';
+        assert!(pe_machine(&header).is_err());
+        assert!(pe_machine(b"").is_err());
+
+        // The test binary itself matches, of course
+        let exe = std::env::current_exe().unwrap();
+        check_bridge_machine(&exe).unwrap();
+    }
+
+    #[test]
+    fn viewport_dimensions_minimum() {
+        // Even with a nonsensical buffer we produce a usable size
+        let info = console_info((0, -5), (0, 0, -1, -1));
+        assert_eq!(viewport_dimensions(&info), (1, 1));
     }
 }
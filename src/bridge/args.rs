@@ -0,0 +1,359 @@
+//! The command line that `BridgeServer::start` passes to the bridge
+//! client, `eledo-pty-bridge.exe`.  Both sides use `BridgeArgs`, so
+//! that the wire format is defined in exactly one place.
+use super::ConsoleGeometry;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::PathBuf;
+
+/// The arguments of the bridge client.
+/// The paths are those of the pipes that the server has created for
+/// each of the streams that it bridges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BridgeArgs {
+    pub stdin: Option<PathBuf>,
+    pub stdout: Option<PathBuf>,
+    pub stderr: Option<PathBuf>,
+    pub conin: Option<PathBuf>,
+    pub conout: Option<PathBuf>,
+    pub control: Option<PathBuf>,
+    /// The pipe over which the server reports changes to the size of
+    /// its console; see `BridgeServer::set_follow_console_resize`
+    pub resize: Option<PathBuf>,
+    /// The pipe over which the server forwards CTRL-C and CTRL-BREAK;
+    /// see `BridgeServer::set_forward_ctrl_events`
+    pub ctrl: Option<PathBuf>,
+    /// The nonce that the client must send when connecting to each pipe
+    pub nonce: Option<String>,
+    /// Pass the program to the system verbatim; see
+    /// `Command::set_path_search`
+    pub no_path_search: bool,
+    /// The command is a single command line to be run through cmd;
+    /// see `Command::set_shell_command`
+    pub shell: bool,
+    /// Run the command at low integrity; see
+    /// `BridgeServer::set_low_integrity`
+    pub low_integrity: bool,
+    /// The input and output code page of the console of the command;
+    /// see `BridgeServer::set_code_page`
+    pub code_page: Option<u32>,
+
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub buffer_height: Option<usize>,
+    pub cursor_x: Option<usize>,
+    pub cursor_y: Option<usize>,
+
+    /// The program to run and its arguments, which follow `--`
+    pub command: Vec<OsString>,
+}
+
+fn invalid(message: String) -> IoError {
+    IoError::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+impl BridgeArgs {
+    /// Set the arguments that convey `geometry` to the client
+    pub fn set_geometry(&mut self, geometry: &ConsoleGeometry) {
+        self.width = Some(geometry.width);
+        self.height = Some(geometry.height);
+        self.buffer_height = geometry.buffer_height;
+        self.cursor_x = Some(geometry.cursor_x);
+        self.cursor_y = Some(geometry.cursor_y);
+    }
+
+    /// The arguments for the second stage of the bridge client, which
+    /// runs inside the pty created by the first; see `run_bridge_client`.
+    /// These are the same, less those that the first stage consumed.
+    pub fn pty_stage(&self) -> Self {
+        Self {
+            conin: None,
+            conout: None,
+            resize: None,
+            width: None,
+            height: None,
+            ..self.clone()
+        }
+    }
+
+    /// Produce the command line, excluding the name of the client
+    /// executable.  `--` and the command are only included when the
+    /// command is not empty.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+
+        if let Some(nonce) = &self.nonce {
+            args.push("--nonce".into());
+            args.push(nonce.into());
+        }
+        if self.no_path_search {
+            args.push("--no-path-search".into());
+        }
+        if self.shell {
+            args.push("--shell".into());
+        }
+        if self.low_integrity {
+            args.push("--low-integrity".into());
+        }
+
+        let paths = [
+            ("--stdin", &self.stdin),
+            ("--stdout", &self.stdout),
+            ("--stderr", &self.stderr),
+            ("--conin", &self.conin),
+            ("--conout", &self.conout),
+            ("--resize", &self.resize),
+            ("--ctrl", &self.ctrl),
+        ];
+        for (name, path) in paths.iter() {
+            if let Some(path) = path {
+                args.push(name.into());
+                args.push(path.into());
+            }
+        }
+
+        let numbers = [
+            ("--width", self.width),
+            ("--height", self.height),
+            ("--buffer-height", self.buffer_height),
+            ("--cursor-x", self.cursor_x),
+            ("--cursor-y", self.cursor_y),
+        ];
+        for (name, value) in numbers.iter() {
+            if let Some(value) = value {
+                args.push(name.into());
+                args.push(value.to_string().into());
+            }
+        }
+
+        if let Some(code_page) = self.code_page {
+            args.push("--codepage".into());
+            args.push(code_page.to_string().into());
+        }
+
+        if let Some(control) = &self.control {
+            args.push("--control".into());
+            args.push(control.into());
+        }
+
+        if !self.command.is_empty() {
+            args.push("--".into());
+            args.extend(self.command.iter().cloned());
+        }
+        args
+    }
+
+    /// Parse the command line produced by `to_args`, excluding the
+    /// name of the client executable.  Everything after `--`, or from
+    /// the first argument that is not an option, is the command.
+    pub fn from_args<I: IntoIterator<Item = OsString>>(args: I) -> IoResult<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let name = match arg.to_str() {
+                Some("--") => break,
+                Some(name) if name.starts_with("--") => name.to_string(),
+                _ => {
+                    parsed.command.push(arg);
+                    break;
+                }
+            };
+
+            if name == "--no-path-search" {
+                parsed.no_path_search = true;
+                continue;
+            }
+            if name == "--shell" {
+                parsed.shell = true;
+                continue;
+            }
+            if name == "--low-integrity" {
+                parsed.low_integrity = true;
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| invalid(format!("{} requires a value", name)))?;
+            let number = || {
+                value
+                    .to_str()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .ok_or_else(|| invalid(format!("invalid value for {}: {:?}", name, value)))
+            };
+
+            match name.as_str() {
+                "--stdin" => parsed.stdin = Some(value.into()),
+                "--stdout" => parsed.stdout = Some(value.into()),
+                "--stderr" => parsed.stderr = Some(value.into()),
+                "--conin" => parsed.conin = Some(value.into()),
+                "--conout" => parsed.conout = Some(value.into()),
+                "--control" => parsed.control = Some(value.into()),
+                "--resize" => parsed.resize = Some(value.into()),
+                "--ctrl" => parsed.ctrl = Some(value.into()),
+                "--nonce" => {
+                    parsed.nonce = Some(value.into_string().map_err(|value| {
+                        invalid(format!("invalid value for --nonce: {:?}", value))
+                    })?)
+                }
+                "--width" => parsed.width = Some(number()?),
+                "--height" => parsed.height = Some(number()?),
+                "--buffer-height" => parsed.buffer_height = Some(number()?),
+                "--cursor-x" => parsed.cursor_x = Some(number()?),
+                "--cursor-y" => parsed.cursor_y = Some(number()?),
+                "--codepage" => {
+                    parsed.code_page = Some(u32::try_from(number()?).map_err(|_| {
+                        invalid(format!("invalid value for --codepage: {:?}", value))
+                    })?)
+                }
+                _ => return Err(invalid(format!("unrecognized bridge argument {}", name))),
+            }
+        }
+
+        parsed.command.extend(args);
+        parsed.validate()?;
+        Ok(parsed)
+    }
+
+    /// Check that the arguments that only make sense together were
+    /// given together
+    fn validate(&self) -> IoResult<()> {
+        if self.conin.is_some() != self.conout.is_some() {
+            return Err(invalid("--conin and --conout must be used together".into()));
+        }
+        if self.resize.is_some() && self.conin.is_none() {
+            return Err(invalid("--resize requires --conin and --conout".into()));
+        }
+        if self.width.is_some() != self.height.is_some() {
+            return Err(invalid("--width and --height must be used together".into()));
+        }
+        if self.cursor_x.is_some() != self.cursor_y.is_some() {
+            return Err(invalid(
+                "--cursor-x and --cursor-y must be used together".into(),
+            ));
+        }
+        if self.shell && self.command.len() != 1 {
+            return Err(invalid(
+                "--shell requires the command to be a single command line".into(),
+            ));
+        }
+        if self.conin.is_some() && self.width.is_none() {
+            return Err(invalid(
+                "--width and --height are required when bridging the console".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut args = BridgeArgs {
+            stdin: Some(r"\\.\pipe\in".into()),
+            stderr: Some(r"\\.\pipe\err".into()),
+            conin: Some(r"\\.\pipe\conin".into()),
+            conout: Some(r"\\.\pipe\conout".into()),
+            control: Some(r"\\.\pipe\control".into()),
+            resize: Some(r"\\.\pipe\resize".into()),
+            ctrl: Some(r"\\.\pipe\ctrl".into()),
+            nonce: Some("abc123".into()),
+            no_path_search: true,
+            low_integrity: true,
+            code_page: Some(932),
+            command: os_args(&["cmd.exe", "/c", "--width"]),
+            ..Default::default()
+        };
+        args.set_geometry(&ConsoleGeometry {
+            width: 120,
+            height: 30,
+            cursor_x: 4,
+            cursor_y: 10,
+            buffer_height: Some(9001),
+        });
+        assert_eq!(BridgeArgs::from_args(args.to_args()).unwrap(), args);
+
+        // The second stage keeps everything that the pty doesn't use
+        let stage = BridgeArgs::from_args(args.pty_stage().to_args()).unwrap();
+        assert_eq!(stage.conin, None);
+        assert_eq!(stage.resize, None);
+        assert_eq!(stage.width, None);
+        assert_eq!(stage.buffer_height, Some(9001));
+        assert_eq!(stage.cursor_y, Some(10));
+        assert_eq!(stage.control, args.control);
+        assert_eq!(stage.ctrl, args.ctrl);
+        assert_eq!(stage.code_page, Some(932));
+        assert!(stage.low_integrity);
+        assert_eq!(stage.command, args.command);
+
+        let shell = BridgeArgs {
+            shell: true,
+            command: os_args(&[r#"dir "C:\Program Files" | findstr x"#]),
+            ..Default::default()
+        };
+        assert_eq!(BridgeArgs::from_args(shell.to_args()).unwrap(), shell);
+
+        let empty = BridgeArgs::default();
+        assert!(empty.to_args().is_empty());
+        assert_eq!(BridgeArgs::from_args(vec![]).unwrap(), empty);
+    }
+
+    #[test]
+    fn command_without_separator() {
+        let args = BridgeArgs::from_args(os_args(&["--stdout", "out", "prog", "--stdin"])).unwrap();
+        assert_eq!(args.stdout, Some("out".into()));
+        assert_eq!(args.stdin, None);
+        assert_eq!(args.command, os_args(&["prog", "--stdin"]));
+    }
+
+    #[test]
+    fn malformed() {
+        let err = |args: &[&str]| {
+            BridgeArgs::from_args(os_args(args))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err(&["--stdin"]), "--stdin requires a value");
+        assert_eq!(
+            err(&["--bogus", "1"]),
+            "unrecognized bridge argument --bogus"
+        );
+        assert_eq!(
+            err(&["--width", "wide", "--height", "1"]),
+            "invalid value for --width: \"wide\""
+        );
+        assert_eq!(
+            err(&["--codepage", "4294967296"]),
+            "invalid value for --codepage: \"4294967296\""
+        );
+        assert_eq!(
+            err(&["--conin", "a"]),
+            "--conin and --conout must be used together"
+        );
+        assert_eq!(
+            err(&["--conin", "a", "--conout", "b"]),
+            "--width and --height are required when bridging the console"
+        );
+        assert_eq!(
+            err(&["--shell", "--", "dir", "/b"]),
+            "--shell requires the command to be a single command line"
+        );
+        assert_eq!(
+            err(&["--resize", "a"]),
+            "--resize requires --conin and --conout"
+        );
+        assert_eq!(
+            err(&["--cursor-x", "1"]),
+            "--cursor-x and --cursor-y must be used together"
+        );
+    }
+}
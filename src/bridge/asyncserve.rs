@@ -0,0 +1,384 @@
+//! An implementation of the bridge server that runs on the tokio
+//! runtime rather than spawning a thread per stream.
+use super::{
+    follow_viewport_reports, is_end_of_pipe, join_output_coalescer, read_console,
+    report_console_resize, spawn_output_coalescer, stream_is_pty, AnsiStripper, BridgeServer,
+    ConoutFilter, ConsoleOutput, CtrlForwarder, CtrlZEof, NewlineMode, NewlineTranslator,
+    CLIENT_CONNECT_INTERVAL,
+};
+use crate::pipe::{check_nonce, PipeHandle};
+use crate::process::Process;
+use std::io::{Error as IoError, Read, Result as IoResult};
+use std::os::windows::io::IntoRawHandle;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::windows::named_pipe::NamedPipeServer as AsyncPipe;
+use tokio::task::JoinHandle;
+use winapi::shared::minwindef::DWORD;
+use winapi::um::winbase::WAIT_OBJECT_0;
+
+/// Writes a piece of console output
+type WriteFn = dyn FnMut(&str) -> IoResult<()> + Send;
+
+/// Holds the tasks that are copying data for the streams, and aborts
+/// them if the serve_async future is dropped before they complete.
+#[derive(Default)]
+struct TaskSet(Vec<JoinHandle<IoResult<()>>>);
+
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+impl TaskSet {
+    /// Wait for all of the tasks to complete, ignoring their results
+    async fn join(&mut self) {
+        for task in self.0.iter_mut() {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Terminates the child if the serve_async future is dropped
+/// before the child has exited, if that was requested.
+struct ChildGuard {
+    proc: Process,
+    kill_on_drop: bool,
+    exited: bool,
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if self.kill_on_drop && !self.exited {
+            let _ = self.proc.terminate(1);
+        }
+    }
+}
+
+/// Take ownership of a server pipe created by BridgeServer::start
+/// and wait for the client to connect to it.
+/// This follows the same approach as the blocking wait_for_client
+/// function in the parent module, including the connect handshake.
+async fn connect(pipe: PipeHandle, proc: &Process, nonce: Option<&str>) -> IoResult<AsyncPipe> {
+    let mut pipe = accept(pipe, proc).await?;
+    if let Some(nonce) = nonce {
+        let mut received = vec![0u8; nonce.len()];
+        let res = match pipe.read_exact(&mut received).await {
+            Ok(_) => check_nonce(&received, nonce),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            let _ = pipe.disconnect();
+            return Err(err);
+        }
+        pipe.write_all(nonce.as_bytes()).await?;
+    }
+    Ok(pipe)
+}
+
+async fn accept(pipe: PipeHandle, proc: &Process) -> IoResult<AsyncPipe> {
+    // The async pipe requires a handle that was opened for overlapped
+    // I/O.  BridgeServer::start always creates its pipes that way, but
+    // pipes passed to with_pipes might not have been.
+    if !pipe.is_overlapped() {
+        return Err(IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            "serve_async requires pipes that were created for overlapped I/O",
+        ));
+    }
+    let pipe = unsafe { AsyncPipe::from_raw_handle(pipe.into_raw_handle())? };
+    loop {
+        let exited = proc.wait_for(Some(0))? == WAIT_OBJECT_0;
+        match tokio::time::timeout(CLIENT_CONNECT_INTERVAL, pipe.connect()).await {
+            Ok(Ok(())) => return Ok(pipe),
+            Ok(Err(err)) => return Err(crate::win32_error_with_context("ConnectNamedPipe", err)),
+            Err(_) if exited => {
+                return Err(IoError::new(
+                    std::io::ErrorKind::NotConnected,
+                    "bridge client never connected",
+                ))
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// The async equivalent of copy_output in the parent module
+async fn copy_output<W: AsyncWrite + Unpin>(
+    src: &mut AsyncPipe,
+    dest: &mut W,
+    newline_mode: NewlineMode,
+    strip_ansi: bool,
+    chunk_size: usize,
+) -> IoResult<()> {
+    let mut translator = NewlineTranslator::new(newline_mode);
+    let mut stripper = if strip_ansi {
+        Some(AnsiStripper::new())
+    } else {
+        None
+    };
+    let mut buf = vec![0u8; chunk_size];
+    let mut stripped = vec![];
+    let mut translated = vec![];
+    loop {
+        let len = match src.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if is_end_of_pipe(&err) => break,
+            Err(err) => return Err(err),
+        };
+        let data = match stripper.as_mut() {
+            Some(stripper) => {
+                stripped.clear();
+                stripper.strip(&buf[0..len], &mut stripped);
+                &stripped[..]
+            }
+            None => &buf[0..len],
+        };
+        translated.clear();
+        translator.translate(data, &mut translated);
+        dest.write_all(&translated).await?;
+    }
+    translated.clear();
+    translator.finish(&mut translated);
+    dest.write_all(&translated).await?;
+    dest.flush().await
+}
+
+impl BridgeServer {
+    /// Control whether the child process is terminated if the
+    /// future returned by `serve_async` is dropped before the
+    /// child has exited.  The default is to leave it running.
+    pub fn set_kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.kill_on_drop = kill_on_drop;
+    }
+
+    /// An async equivalent of `serve` that copies the streams using
+    /// tasks on the tokio runtime rather than dedicated threads.
+    /// The exception is console input, which can only be consumed
+    /// via blocking calls, and so is read from a dedicated thread.
+    ///
+    /// Dropping the returned future closes the pipes to the bridge
+    /// client and restores the console modes, and will terminate
+    /// the child if `set_kill_on_drop(true)` was used.
+    pub async fn serve_async(mut self, proc: Process) -> IoResult<DWORD> {
+        if self.stdout_sink.is_some() || self.stderr_sink.is_some() {
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "serve_async does not support output sinks",
+            ));
+        }
+        if self.idle_timeout.is_some() {
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "serve_async does not support idle timeouts",
+            ));
+        }
+        let mut child = ChildGuard {
+            proc,
+            kill_on_drop: self.kill_on_drop,
+            exited: false,
+        };
+        let mut inputs = TaskSet::default();
+        let nonce = self.nonce.clone();
+        let nonce = nonce.as_deref();
+        let chunk_size = self.copy_chunk_size;
+        let mut outputs = TaskSet::default();
+        let _ctrl = match self.ctrl_pipe.take() {
+            Some(pipe) => Some(CtrlForwarder::start(
+                pipe,
+                child.proc.try_clone()?,
+                self.nonce.clone(),
+            )?),
+            None => None,
+        };
+
+        if let Some(conin) = self.conin.take() {
+            let mut conin_dest =
+                connect(self.conin_pipe.take().unwrap(), &child.proc, nonce).await?;
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
+            let mut replace_console = false;
+            if let Some(mut scripted) = self.scripted_input.take() {
+                replace_console = scripted.replace_console;
+                let tx = tx.clone();
+                std::thread::spawn(move || -> IoResult<()> {
+                    let mut buf = vec![0u8; chunk_size];
+                    loop {
+                        let len = scripted.script.read(&mut buf)?;
+                        if len == 0 || tx.blocking_send(buf[0..len].to_vec()).is_err() {
+                            return Ok(());
+                        }
+                    }
+                });
+            }
+            if !replace_console {
+                std::thread::spawn(move || -> IoResult<()> {
+                    let mut buf = vec![0u16; chunk_size + 1];
+                    let mut pending = None;
+                    loop {
+                        let utf8 = read_console(&conin, &mut buf, &mut pending)?;
+                        if tx.blocking_send(utf8.into_bytes()).is_err() {
+                            // The serve_async future has gone away
+                            return Ok(());
+                        }
+                    }
+                });
+            }
+            inputs.0.push(tokio::spawn(async move {
+                while let Some(data) = rx.recv().await {
+                    conin_dest.write_all(&data).await?;
+                }
+                Ok(())
+            }));
+        }
+
+        if let Some(conout) = self.conout.take() {
+            let mut conout_src =
+                connect(self.conout_pipe.take().unwrap(), &child.proc, nonce).await?;
+            let mut console = ConsoleOutput::new(conout, self.legacy_attributes.is_some())?;
+            let alt_screen = std::sync::Arc::clone(&self.alt_screen);
+            let on_osc = self.on_osc.take();
+            let coalesce = self.output_coalesce;
+            outputs.0.push(tokio::spawn(async move {
+                let mut filter = ConoutFilter::new(alt_screen);
+                filter.on_osc = on_osc;
+                let mut buf = vec![0u8; chunk_size];
+                // The coalescing writer blocks while it waits for more
+                // output, so it runs on a thread of its own
+                let (mut write, writer): (Box<WriteFn>, _) = match coalesce {
+                    Some(interval) => {
+                        let (tx, writer) =
+                            spawn_output_coalescer(interval, move |s| console.write(s));
+                        let send = move |s: &str| {
+                            tx.send(s.to_string()).map_err(|_| {
+                                IoError::new(
+                                    std::io::ErrorKind::Other,
+                                    "console output has stopped",
+                                )
+                            })
+                        };
+                        (Box::new(send), Some(writer))
+                    }
+                    None => (Box::new(move |s| console.write(s)), None),
+                };
+
+                loop {
+                    let len = match conout_src.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(len) => len,
+                        Err(err) if is_end_of_pipe(&err) => break,
+                        Err(err) => return Err(err),
+                    };
+                    filter.write_with(&buf[0..len], &mut write)?;
+                }
+
+                // Closes the channel to the writer, if any
+                drop(write);
+                match writer {
+                    Some(writer) => {
+                        tokio::task::spawn_blocking(move || join_output_coalescer(writer))
+                            .await
+                            .map_err(|err| IoError::new(std::io::ErrorKind::Other, err))?
+                    }
+                    None => Ok(()),
+                }
+            }));
+        }
+
+        if let Some(stdin_dest) = self.stdin.take() {
+            let mut stdin_dest = connect(stdin_dest, &child.proc, nonce).await?;
+            let mut eof = if self.ctrl_z_eof && stream_is_pty(&std::io::stdin()) {
+                Some(CtrlZEof::default())
+            } else {
+                None
+            };
+            inputs.0.push(tokio::spawn(async move {
+                // As with forward_input in the parent module, forward
+                // each read as soon as it completes
+                let mut stdin = tokio::io::stdin();
+                let mut buf = vec![0u8; chunk_size];
+                loop {
+                    let len = stdin.read(&mut buf).await?;
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    let data = &buf[0..len];
+                    let (data, at_eof) = match eof.as_mut().and_then(|eof| eof.find(data)) {
+                        Some(end) => (&data[0..end], true),
+                        None => (data, false),
+                    };
+                    match stdin_dest.write_all(data).await {
+                        Err(err) if is_end_of_pipe(&err) => return Ok(()),
+                        Err(err) => return Err(err),
+                        Ok(()) if at_eof => return Ok(()),
+                        Ok(()) => {}
+                    }
+                }
+            }));
+        }
+
+        let newline_mode = self.newline_mode;
+        let strip_ansi = self.strip_ansi;
+        if let Some(stdout_src) = self.stdout.take() {
+            let mut stdout_src = connect(stdout_src, &child.proc, nonce).await?;
+            outputs.0.push(tokio::spawn(async move {
+                copy_output(
+                    &mut stdout_src,
+                    &mut tokio::io::stdout(),
+                    newline_mode,
+                    strip_ansi,
+                    chunk_size,
+                )
+                .await
+            }));
+        }
+
+        if let Some(stderr_src) = self.stderr.take() {
+            let mut stderr_src = connect(stderr_src, &child.proc, nonce).await?;
+            outputs.0.push(tokio::spawn(async move {
+                copy_output(
+                    &mut stderr_src,
+                    &mut tokio::io::stderr(),
+                    newline_mode,
+                    strip_ansi,
+                    chunk_size,
+                )
+                .await
+            }));
+        }
+
+        if let Some(event) = &self.connected_event {
+            event.signal()?;
+        }
+
+        if let Some(control) = self.control_pipe.take() {
+            follow_viewport_reports(control, child.proc.try_clone()?, self.nonce.clone());
+        }
+
+        if let Some(resize) = self.resize_pipe.take() {
+            report_console_resize(resize, child.proc.try_clone()?, self.nonce.clone());
+        }
+
+        // There is no async way to wait for a process on the tokio
+        // runtime, so we wait on a second handle to it from the
+        // blocking thread pool.
+        let waiter = child.proc.try_clone()?;
+        tokio::task::spawn_blocking(move || waiter.wait_for(None))
+            .await
+            .map_err(|err| IoError::new(std::io::ErrorKind::Other, err))??;
+        child.exited = true;
+
+        // As with serve, the wait for the output to drain may be
+        // bounded; dropping the TaskSet aborts anything still running.
+        match self.drain_timeout() {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, outputs.join()).await;
+            }
+            None => outputs.join().await,
+        }
+
+        child.proc.exit_code()
+    }
+}
@@ -0,0 +1,247 @@
+//! Forwarding CTRL-C and CTRL-BREAK from the console of the bridge
+//! server to the program; see `BridgeServer::set_forward_ctrl_events`.
+//!
+//! The console only signals the processes that are attached to it, and
+//! the program is attached to a different console, so the server catches
+//! the signals with a console control handler and sends them over the
+//! ctrl pipe, and the client raises them again in its own console via
+//! GenerateConsoleCtrlEvent.  Both sides survive the signal, so that the
+//! server only exits once the program does.
+//! When the console is bridged, CTRL-C usually arrives as input and
+//! reaches the program through the pty instead, but CTRL-BREAK is
+//! always delivered as a signal.
+use super::{open_bridge_pipe, wait_for_client, BridgeTransport};
+use crate::pipe::PipeHandle;
+use crate::process::Process;
+use crate::win32_error_with_context;
+use lazy_static::lazy_static;
+use std::io::{BufRead, BufReader, Error as IoError, Result as IoResult};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+use winapi::um::consoleapi::SetConsoleCtrlHandler;
+use winapi::um::wincon::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT, CTRL_C_EVENT};
+
+/// The console control events that are forwarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlEvent {
+    CtrlC,
+    CtrlBreak,
+}
+
+impl CtrlEvent {
+    fn from_ctrl_type(ctrl_type: DWORD) -> Option<Self> {
+        match ctrl_type {
+            CTRL_C_EVENT => Some(Self::CtrlC),
+            CTRL_BREAK_EVENT => Some(Self::CtrlBreak),
+            _ => None,
+        }
+    }
+
+    fn ctrl_type(self) -> DWORD {
+        match self {
+            Self::CtrlC => CTRL_C_EVENT,
+            Self::CtrlBreak => CTRL_BREAK_EVENT,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::CtrlC => "ctrl-c",
+            Self::CtrlBreak => "ctrl-break",
+        }
+    }
+}
+
+/// Format a message that forwards `event` over the ctrl pipe
+fn format_ctrl_event(event: CtrlEvent) -> String {
+    format!("{}\n", event.name())
+}
+
+/// Parse a line produced by `format_ctrl_event`
+fn parse_ctrl_event(line: &str) -> Option<CtrlEvent> {
+    [CtrlEvent::CtrlC, CtrlEvent::CtrlBreak]
+        .iter()
+        .copied()
+        .find(|event| event.name() == line.trim_end())
+}
+
+lazy_static! {
+    /// Where the handler of the server sends the events that it
+    /// catches, once there is a client connected to receive them
+    static ref FORWARD: Mutex<Option<Sender<CtrlEvent>>> = Mutex::new(None);
+}
+
+/// The console control handler of the bridge server.  It only claims
+/// an event when it can be forwarded; otherwise the default handling
+/// applies and the server exits, just as it would without the handler.
+unsafe extern "system" fn forward_ctrl_handler(ctrl_type: DWORD) -> BOOL {
+    let event = match CtrlEvent::from_ctrl_type(ctrl_type) {
+        Some(event) => event,
+        None => return FALSE,
+    };
+    match FORWARD.lock().as_deref() {
+        Ok(Some(forward)) if forward.send(event).is_ok() => TRUE,
+        _ => FALSE,
+    }
+}
+
+/// The console control handler of the bridge client, which survives
+/// the events that it raises for the program so that it can wait for
+/// the program to respond to them
+unsafe extern "system" fn survive_ctrl_handler(ctrl_type: DWORD) -> BOOL {
+    if CtrlEvent::from_ctrl_type(ctrl_type).is_some() {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+fn set_ctrl_handler(handler: unsafe extern "system" fn(DWORD) -> BOOL, add: bool) -> IoResult<()> {
+    if unsafe { SetConsoleCtrlHandler(Some(handler), add as BOOL) } == 0 {
+        return Err(win32_error_with_context(
+            "SetConsoleCtrlHandler",
+            IoError::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Send each of the events received from `events` over `dest`, until
+/// the other end goes away or there are no more events
+fn send_ctrl_events<T: BridgeTransport>(dest: &mut T, events: Receiver<CtrlEvent>) -> IoResult<()> {
+    for event in events {
+        dest.write_all(format_ctrl_event(event).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Pass each of the events received over `src` to `raise`, until the
+/// server closes it.  Lines that aren't valid events are ignored.
+fn read_ctrl_events<T: BridgeTransport, F: FnMut(CtrlEvent)>(src: T, mut raise: F) {
+    for line in BufReader::new(src).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if let Some(event) = parse_ctrl_event(&line) {
+            raise(event);
+        }
+    }
+}
+
+/// Forwards the control events of the console of the bridge server
+/// to the client for as long as it is alive.  The handler is process
+/// wide, so only one bridge can forward the events at a time.
+pub struct CtrlForwarder(());
+
+impl CtrlForwarder {
+    /// Install the handler, and spawn a thread that waits for the
+    /// client to connect to `pipe` and then sends it the events.
+    /// Until the client connects, the events are handled as usual.
+    pub fn start(mut pipe: PipeHandle, proc: Process, nonce: Option<String>) -> IoResult<Self> {
+        set_ctrl_handler(forward_ctrl_handler, true)?;
+        std::thread::spawn(move || {
+            match wait_for_client(&mut pipe, &proc, nonce.as_deref(), None) {
+                Ok(true) => {}
+                _ => return,
+            }
+            let (forward, events) = channel();
+            if let Ok(mut slot) = FORWARD.lock() {
+                slot.replace(forward);
+            }
+            let _ = send_ctrl_events(&mut pipe, events);
+        });
+        Ok(Self(()))
+    }
+}
+
+impl Drop for CtrlForwarder {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = FORWARD.lock() {
+            slot.take();
+        }
+        let _ = set_ctrl_handler(forward_ctrl_handler, false);
+    }
+}
+
+/// Connect to the ctrl pipe of the bridge server at `path`, and raise
+/// each of the events that it forwards in our console, where the
+/// program receives them too.  We survive the events ourselves, so
+/// that we go on to report the exit code of the program.
+pub fn raise_ctrl_events(path: &Path, nonce: Option<&str>) -> IoResult<()> {
+    let mut pipe = open_bridge_pipe("ctrl", path)?;
+    if let Some(nonce) = nonce {
+        pipe.send_nonce(nonce)?;
+    }
+    set_ctrl_handler(survive_ctrl_handler, true)?;
+    std::thread::spawn(move || {
+        read_ctrl_events(pipe, |event| {
+            // CTRL-C can only be sent to the whole console, so both
+            // events are, which includes us and the program
+            if unsafe { GenerateConsoleCtrlEvent(event.ctrl_type(), 0) } == 0 {
+                log::warn!(
+                    "unable to raise {}: {}",
+                    event.name(),
+                    IoError::last_os_error()
+                );
+            }
+        });
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::transport::MemoryTransport;
+    use super::*;
+
+    #[test]
+    fn ctrl_event_messages() {
+        for &event in &[CtrlEvent::CtrlC, CtrlEvent::CtrlBreak] {
+            assert_eq!(parse_ctrl_event(&format_ctrl_event(event)), Some(event));
+        }
+        assert_eq!(parse_ctrl_event("ctrl-z"), None);
+        assert_eq!(parse_ctrl_event("ctrl-c ctrl-c"), None);
+    }
+
+    #[test]
+    fn ctrl_events_over_transport() {
+        let (mut server, client) = MemoryTransport::pair();
+        let (forward, events) = channel();
+        forward.send(CtrlEvent::CtrlBreak).unwrap();
+        forward.send(CtrlEvent::CtrlC).unwrap();
+        drop(forward);
+        send_ctrl_events(&mut server, events).unwrap();
+        drop(server);
+
+        let mut raised = vec![];
+        read_ctrl_events(client, |event| raised.push(event));
+        assert_eq!(raised, vec![CtrlEvent::CtrlBreak, CtrlEvent::CtrlC]);
+    }
+
+    #[test]
+    fn forward_handler() {
+        use winapi::um::wincon::CTRL_CLOSE_EVENT;
+        let handle = |ctrl_type| unsafe { forward_ctrl_handler(ctrl_type) };
+
+        // Nothing to forward to, so the default handling applies
+        assert_eq!(handle(CTRL_C_EVENT), FALSE);
+
+        let (forward, events) = channel();
+        FORWARD.lock().unwrap().replace(forward);
+        assert_eq!(handle(CTRL_C_EVENT), TRUE);
+        assert_eq!(handle(CTRL_BREAK_EVENT), TRUE);
+        assert_eq!(handle(CTRL_CLOSE_EVENT), FALSE);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![CtrlEvent::CtrlC, CtrlEvent::CtrlBreak]
+        );
+
+        // Once the receiving end is gone, the events aren't claimed
+        drop(events);
+        assert_eq!(handle(CTRL_BREAK_EVENT), FALSE);
+        FORWARD.lock().unwrap().take();
+    }
+}
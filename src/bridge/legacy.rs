@@ -0,0 +1,551 @@
+//! A minimal translation of the VT sequences that the pty produces into
+//! calls to the console API, for legacy console hosts that don't support
+//! `ENABLE_VIRTUAL_TERMINAL_PROCESSING`; see
+//! `BridgeServer::set_legacy_console_translation`.
+//! Only the common subset is interpreted: SGR colors, intensity and
+//! reverse video, cursor movement, and erasing in the line or the
+//! display.  Every other sequence is discarded, rather than being shown
+//! as garbage such as `←[?25l`.
+use super::write_console;
+use crate::pipe::PipeHandle;
+use crate::win32_error_with_context;
+use std::io::{Error as IoError, Result as IoResult};
+use winapi::shared::minwindef::{DWORD, WORD};
+use winapi::um::wincon::{
+    FillConsoleOutputAttribute, FillConsoleOutputCharacterW, GetConsoleScreenBufferInfo,
+    SetConsoleCursorPosition, SetConsoleTextAttribute, BACKGROUND_INTENSITY,
+    CONSOLE_SCREEN_BUFFER_INFO, FOREGROUND_INTENSITY,
+};
+use winapi::um::wincontypes::COORD;
+
+/// The state of the screen buffer that the translation depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenInfo {
+    pub cursor_x: i16,
+    pub cursor_y: i16,
+    /// The window onto the buffer, which is what VT coordinates are
+    /// relative to
+    pub left: i16,
+    pub top: i16,
+    pub width: i16,
+    pub height: i16,
+    pub buffer_width: i16,
+    pub attributes: WORD,
+}
+
+/// The console API calls that the translation is expressed in
+pub trait LegacyConsole {
+    fn write_text(&mut self, text: &str) -> IoResult<()>;
+    fn screen_info(&mut self) -> IoResult<ScreenInfo>;
+    fn set_text_attribute(&mut self, attributes: WORD) -> IoResult<()>;
+    fn set_cursor_position(&mut self, x: i16, y: i16) -> IoResult<()>;
+    /// Fill `len` cells from `x`, `y` with spaces in `attributes`
+    fn fill(&mut self, x: i16, y: i16, len: DWORD, attributes: WORD) -> IoResult<()>;
+}
+
+fn console_call(name: &str, res: i32) -> IoResult<()> {
+    if res == 0 {
+        Err(win32_error_with_context(name, IoError::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+impl LegacyConsole for PipeHandle {
+    fn write_text(&mut self, text: &str) -> IoResult<()> {
+        write_console(self, text)
+    }
+
+    fn screen_info(&mut self) -> IoResult<ScreenInfo> {
+        let mut info: CONSOLE_SCREEN_BUFFER_INFO = unsafe { std::mem::zeroed() };
+        console_call("GetConsoleScreenBufferInfo", unsafe {
+            GetConsoleScreenBufferInfo(self.as_handle(), &mut info)
+        })?;
+        let window = &info.srWindow;
+        Ok(ScreenInfo {
+            cursor_x: info.dwCursorPosition.X,
+            cursor_y: info.dwCursorPosition.Y,
+            left: window.Left,
+            top: window.Top,
+            width: window.Right - window.Left + 1,
+            height: window.Bottom - window.Top + 1,
+            buffer_width: info.dwSize.X,
+            attributes: info.wAttributes,
+        })
+    }
+
+    fn set_text_attribute(&mut self, attributes: WORD) -> IoResult<()> {
+        console_call("SetConsoleTextAttribute", unsafe {
+            SetConsoleTextAttribute(self.as_handle(), attributes)
+        })
+    }
+
+    fn set_cursor_position(&mut self, x: i16, y: i16) -> IoResult<()> {
+        console_call("SetConsoleCursorPosition", unsafe {
+            SetConsoleCursorPosition(self.as_handle(), COORD { X: x, Y: y })
+        })
+    }
+
+    fn fill(&mut self, x: i16, y: i16, len: DWORD, attributes: WORD) -> IoResult<()> {
+        let start = COORD { X: x, Y: y };
+        let mut written = 0;
+        console_call("FillConsoleOutputCharacterW", unsafe {
+            FillConsoleOutputCharacterW(self.as_handle(), ' ' as u16, len, start, &mut written)
+        })?;
+        console_call("FillConsoleOutputAttribute", unsafe {
+            FillConsoleOutputAttribute(self.as_handle(), attributes, len, start, &mut written)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    /// In a CSI sequence, collecting its parameters
+    Csi,
+    /// In the body of an OSC, DCS, SOS, PM or APC string
+    String {
+        osc: bool,
+    },
+    StringEscape,
+}
+
+/// The foreground bits of a console attribute, including intensity
+const FOREGROUND_MASK: WORD = 0x0f;
+/// Likewise, for the background
+const BACKGROUND_MASK: WORD = 0xf0;
+
+/// Convert an ANSI color number, in which red is the lowest bit, to
+/// the bits of a console foreground color, in which blue is
+fn console_color(ansi: u16) -> WORD {
+    ((ansi & 1) << 2) | (ansi & 2) | ((ansi & 4) >> 2)
+}
+
+/// Interprets the output of the pty, making the equivalent console API
+/// calls on `console`.  The state is carried between writes, so that a
+/// sequence split across reads of the pty is still recognized.
+pub struct LegacyTranslator<C> {
+    console: C,
+    state: State,
+    /// The parameter and intermediate bytes of the current CSI sequence
+    params: String,
+    /// Text that hasn't been written yet; runs of text are accumulated
+    /// so that they are written in as few calls as possible
+    text: String,
+    /// The attributes in effect when translation started, which SGR 0
+    /// and the default colors restore
+    default_attributes: WORD,
+    /// The attributes requested by SGR, before reverse video
+    attributes: WORD,
+    reverse: bool,
+    /// The attributes last applied to the console
+    applied: WORD,
+}
+
+impl<C: LegacyConsole> LegacyTranslator<C> {
+    pub fn new(mut console: C) -> IoResult<Self> {
+        let attributes = console.screen_info()?.attributes & (FOREGROUND_MASK | BACKGROUND_MASK);
+        Ok(Self {
+            console,
+            state: State::Ground,
+            params: String::new(),
+            text: String::new(),
+            default_attributes: attributes,
+            attributes,
+            reverse: false,
+            applied: attributes,
+        })
+    }
+
+    /// Translate `s`, writing its text to the console
+    pub fn write(&mut self, s: &str) -> IoResult<()> {
+        const ESC: char = '\x1b';
+        const BEL: char = '\x07';
+        for c in s.chars() {
+            self.state = match self.state {
+                State::Ground if c == ESC => State::Escape,
+                State::Ground => {
+                    self.text.push(c);
+                    State::Ground
+                }
+                State::Escape | State::StringEscape => self.escape(c),
+                State::EscapeIntermediate => match c {
+                    '\x20'..='\x2f' => State::EscapeIntermediate,
+                    _ => State::Ground,
+                },
+                State::Csi => match c {
+                    ESC => State::Escape,
+                    '\x40'..='\x7e' => {
+                        self.dispatch(c)?;
+                        State::Ground
+                    }
+                    _ => {
+                        self.params.push(c);
+                        State::Csi
+                    }
+                },
+                State::String { osc } => match c {
+                    BEL if osc => State::Ground,
+                    ESC => State::StringEscape,
+                    _ => State::String { osc },
+                },
+            };
+        }
+        self.flush_text()
+    }
+
+    fn escape(&mut self, c: char) -> State {
+        match c {
+            '[' => {
+                self.params.clear();
+                State::Csi
+            }
+            ']' => State::String { osc: true },
+            'P' | 'X' | '^' | '_' => State::String { osc: false },
+            '\x1b' => State::Escape,
+            '\x20'..='\x2f' => State::EscapeIntermediate,
+            _ => State::Ground,
+        }
+    }
+
+    fn flush_text(&mut self) -> IoResult<()> {
+        if self.text.is_empty() {
+            return Ok(());
+        }
+        let text = std::mem::take(&mut self.text);
+        self.console.write_text(&text)
+    }
+
+    /// Perform the CSI sequence ending in `last`.  Sequences with a
+    /// private marker, such as `CSI ? 25 l`, or with intermediates are
+    /// not in the supported subset and are ignored.
+    fn dispatch(&mut self, last: char) -> IoResult<()> {
+        if !self.params.chars().all(|c| c.is_ascii_digit() || c == ';') {
+            return Ok(());
+        }
+        let params: Vec<Option<u16>> = self.params.split(';').map(|p| p.parse().ok()).collect();
+        // A missing or zero count means one
+        let count = |p: Option<u16>| p.unwrap_or(1).max(1).min(i16::MAX as u16) as i16;
+        let first = count(params[0]);
+        match last {
+            'm' => {
+                self.flush_text()?;
+                self.sgr(&params)
+            }
+            'A' | 'B' | 'C' | 'D' | 'E' | 'F' | 'G' | 'H' | 'f' | 'd' => {
+                self.flush_text()?;
+                let info = self.console.screen_info()?;
+                let (x, y) = (info.cursor_x, info.cursor_y);
+                let column = |n: i16| info.left.saturating_add(n - 1);
+                let row = |n: i16| info.top.saturating_add(n - 1);
+                let (x, y) = match last {
+                    'A' => (x, y.saturating_sub(first)),
+                    'B' => (x, y.saturating_add(first)),
+                    'C' => (x.saturating_add(first), y),
+                    'D' => (x.saturating_sub(first), y),
+                    'E' => (info.left, y.saturating_add(first)),
+                    'F' => (info.left, y.saturating_sub(first)),
+                    'G' => (column(first), y),
+                    'd' => (x, row(first)),
+                    _ => (column(count(params.get(1).copied().flatten())), row(first)),
+                };
+                let right = info.left + info.width - 1;
+                let bottom = info.top + info.height - 1;
+                self.console
+                    .set_cursor_position(x.max(info.left).min(right), y.max(info.top).min(bottom))
+            }
+            'J' | 'K' => {
+                self.flush_text()?;
+                let info = self.console.screen_info()?;
+                let (x, y) = (info.cursor_x as DWORD, info.cursor_y as DWORD);
+                let line = info.buffer_width as DWORD;
+                let top = info.top as DWORD;
+                let bottom = top + info.height as DWORD - 1;
+                let (start_x, start_y, len) = match (last, params[0].unwrap_or(0)) {
+                    ('K', 0) => (x, y, line.saturating_sub(x)),
+                    ('K', 1) => (0, y, x + 1),
+                    ('K', 2) => (0, y, line),
+                    ('J', 0) => (
+                        x,
+                        y,
+                        line.saturating_sub(x) + bottom.saturating_sub(y) * line,
+                    ),
+                    ('J', 1) => (0, top, y.saturating_sub(top) * line + x + 1),
+                    ('J', 2) => (0, top, info.height as DWORD * line),
+                    // Erasing the scrollback is not supported
+                    _ => return Ok(()),
+                };
+                let attributes = self.effective_attributes();
+                self.console
+                    .fill(start_x as i16, start_y as i16, len, attributes)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn sgr(&mut self, params: &[Option<u16>]) -> IoResult<()> {
+        let mut params = params.iter().map(|p| p.unwrap_or(0));
+        while let Some(p) = params.next() {
+            match p {
+                0 => {
+                    self.attributes = self.default_attributes;
+                    self.reverse = false;
+                }
+                1 => self.attributes |= FOREGROUND_INTENSITY,
+                22 => self.attributes &= !FOREGROUND_INTENSITY,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                30..=37 => self.set_foreground(console_color(p - 30), false),
+                90..=97 => self.set_foreground(console_color(p - 90), true),
+                39 => {
+                    self.attributes = (self.attributes & !FOREGROUND_MASK)
+                        | (self.default_attributes & FOREGROUND_MASK)
+                }
+                40..=47 => self.set_background(console_color(p - 40), false),
+                100..=107 => self.set_background(console_color(p - 100), true),
+                49 => {
+                    self.attributes = (self.attributes & !BACKGROUND_MASK)
+                        | (self.default_attributes & BACKGROUND_MASK)
+                }
+                38 | 48 => {
+                    // Only the 16 colors of the 256 color palette that
+                    // the console has are supported; the rest of the
+                    // palette and RGB colors are skipped
+                    match params.next() {
+                        Some(5) => {
+                            if let Some(n @ 0..=15) = params.next() {
+                                let color = console_color(n & 7);
+                                if p == 38 {
+                                    self.set_foreground(color, n > 7)
+                                } else {
+                                    self.set_background(color, n > 7)
+                                }
+                            }
+                        }
+                        Some(2) => {
+                            params.nth(2);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let attributes = self.effective_attributes();
+        if attributes != self.applied {
+            self.console.set_text_attribute(attributes)?;
+            self.applied = attributes;
+        }
+        Ok(())
+    }
+
+    /// Set the foreground color, keeping the intensity unless `bright`
+    /// requires it for the color
+    fn set_foreground(&mut self, color: WORD, bright: bool) {
+        let intensity = if bright {
+            FOREGROUND_INTENSITY
+        } else {
+            self.attributes & FOREGROUND_INTENSITY
+        };
+        self.attributes = (self.attributes & !FOREGROUND_MASK) | color | intensity;
+    }
+
+    fn set_background(&mut self, color: WORD, bright: bool) {
+        let intensity = if bright { BACKGROUND_INTENSITY } else { 0 };
+        self.attributes = (self.attributes & !BACKGROUND_MASK) | (color << 4) | intensity;
+    }
+
+    /// The attributes to apply, which have the colors swapped for
+    /// reverse video
+    fn effective_attributes(&self) -> WORD {
+        if self.reverse {
+            ((self.attributes & FOREGROUND_MASK) << 4) | ((self.attributes & BACKGROUND_MASK) >> 4)
+        } else {
+            self.attributes
+        }
+    }
+}
+
+/// Where the console output of the program is written: straight to the
+/// console when it interprets VT sequences itself, or via a translator
+/// when it doesn't
+pub enum ConsoleOutput {
+    Vt(PipeHandle),
+    Legacy(LegacyTranslator<PipeHandle>),
+}
+
+impl ConsoleOutput {
+    pub fn new(conout: PipeHandle, legacy: bool) -> IoResult<Self> {
+        if legacy {
+            Ok(Self::Legacy(LegacyTranslator::new(conout)?))
+        } else {
+            Ok(Self::Vt(conout))
+        }
+    }
+
+    pub fn write(&mut self, s: &str) -> IoResult<()> {
+        match self {
+            Self::Vt(conout) => write_console(conout, s),
+            Self::Legacy(translator) => translator.write(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Call {
+        Text(String),
+        Attribute(WORD),
+        Cursor(i16, i16),
+        Fill(i16, i16, DWORD, WORD),
+    }
+
+    /// Records the calls made to it.  The window is 80x25 and starts on
+    /// row 10 of the buffer.  Writing text doesn't move the cursor.
+    struct Recorder {
+        calls: Vec<Call>,
+        cursor: (i16, i16),
+    }
+
+    impl LegacyConsole for &mut Recorder {
+        fn write_text(&mut self, text: &str) -> IoResult<()> {
+            self.calls.push(Call::Text(text.to_string()));
+            Ok(())
+        }
+
+        fn screen_info(&mut self) -> IoResult<ScreenInfo> {
+            Ok(ScreenInfo {
+                cursor_x: self.cursor.0,
+                cursor_y: self.cursor.1,
+                left: 0,
+                top: 10,
+                width: 80,
+                height: 25,
+                buffer_width: 80,
+                attributes: 0x07,
+            })
+        }
+
+        fn set_text_attribute(&mut self, attributes: WORD) -> IoResult<()> {
+            self.calls.push(Call::Attribute(attributes));
+            Ok(())
+        }
+
+        fn set_cursor_position(&mut self, x: i16, y: i16) -> IoResult<()> {
+            self.cursor = (x, y);
+            self.calls.push(Call::Cursor(x, y));
+            Ok(())
+        }
+
+        fn fill(&mut self, x: i16, y: i16, len: DWORD, attributes: WORD) -> IoResult<()> {
+            self.calls.push(Call::Fill(x, y, len, attributes));
+            Ok(())
+        }
+    }
+
+    fn translate(chunks: &[&str]) -> Vec<Call> {
+        let mut recorder = Recorder {
+            calls: vec![],
+            cursor: (5, 12),
+        };
+        let mut translator = LegacyTranslator::new(&mut recorder).unwrap();
+        for chunk in chunks {
+            translator.write(chunk).unwrap();
+        }
+        drop(translator);
+        recorder.calls
+    }
+
+    fn text(s: &str) -> Call {
+        Call::Text(s.to_string())
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(
+            translate(&["a\x1b[31mred\x1b[1;44mboth\x1b[0mplain"]),
+            vec![
+                text("a"),
+                Call::Attribute(0x04),
+                text("red"),
+                Call::Attribute(0x1c),
+                text("both"),
+                Call::Attribute(0x07),
+                text("plain"),
+            ]
+        );
+        assert_eq!(
+            translate(&["\x1b[92;100mx\x1b[39my\x1b[7mz\x1b[27;49m"]),
+            vec![
+                Call::Attribute(0x8a),
+                text("x"),
+                Call::Attribute(0x87),
+                text("y"),
+                Call::Attribute(0x78),
+                text("z"),
+                Call::Attribute(0x07),
+            ]
+        );
+        // The 16 palette colors are supported; RGB is skipped
+        assert_eq!(
+            translate(&["\x1b[38;5;9m\x1b[38;2;1;2;3;44m"]),
+            vec![Call::Attribute(0x0c), Call::Attribute(0x1c)]
+        );
+        // Nothing to do when the attributes don't change
+        assert_eq!(translate(&["\x1b[mx\x1b[0;39m"]), vec![text("x")]);
+        // A sequence that is split between writes
+        assert_eq!(
+            translate(&["\x1b[3", "2mx"]),
+            vec![Call::Attribute(0x02), text("x")]
+        );
+    }
+
+    #[test]
+    fn cursor() {
+        assert_eq!(
+            translate(&["\x1b[2;3H\x1b[A\x1b[5A\x1b[10C\x1b[G\x1b[99;999H\x1b[3D\x1b[2F\x1b[4d"]),
+            vec![
+                Call::Cursor(2, 11),
+                Call::Cursor(2, 10),
+                // Movement is confined to the window
+                Call::Cursor(2, 10),
+                Call::Cursor(12, 10),
+                Call::Cursor(0, 10),
+                Call::Cursor(79, 34),
+                Call::Cursor(76, 34),
+                Call::Cursor(0, 32),
+                Call::Cursor(0, 13),
+            ]
+        );
+    }
+
+    #[test]
+    fn erase() {
+        assert_eq!(
+            translate(&["\x1b[K\x1b[1K\x1b[2K\x1b[J\x1b[1J\x1b[41m\x1b[2J\x1b[3J"]),
+            vec![
+                Call::Fill(5, 12, 75, 0x07),
+                Call::Fill(0, 12, 6, 0x07),
+                Call::Fill(0, 12, 80, 0x07),
+                Call::Fill(5, 12, 75 + 22 * 80, 0x07),
+                Call::Fill(0, 10, 2 * 80 + 6, 0x07),
+                Call::Attribute(0x47),
+                Call::Fill(0, 10, 25 * 80, 0x47),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_sequences_are_dropped() {
+        assert_eq!(
+            translate(&["x\x1b[?25l\x1b]0;title\x07y\x1b(B\x1b[!pz\x1b7"]),
+            vec![text("xyz")]
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Tests that wire a `BridgeServer` to a bridge client running in the
+//! test process, exercising the full bridge flow without elevation or
+//! a separate `eledo-pty-bridge.exe`.
+use super::*;
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::handleapi::DuplicateHandle;
+use winapi::um::processthreadsapi::{GetCurrentProcess, GetProcessId};
+use winapi::um::winnt::DUPLICATE_SAME_ACCESS;
+
+/// Start the server, parsing its arguments just as the bridge would
+fn start(server: &mut BridgeServer, token: &Token) -> BridgeArgs {
+    BridgeArgs::from_args(server.start(token).unwrap()).unwrap()
+}
+
+/// Create a server that bridges either just the pty or just stdout and
+/// stderr, regardless of what the test process is attached to.
+/// Neither path touches the stdin of the test runner.
+///
+/// The child is only spawned once the client has connected, and there
+/// is no bridge process for the server to wait on in the meantime, so
+/// the connect handshake (which is covered by its own tests) can't be
+/// completed here and is disabled.
+fn loopback_server(pty: bool) -> BridgeServer {
+    let mut server = BridgeServer::new();
+    server.set_bridge_stdin(false);
+    server.stdout_is_pty = pty;
+    server.stderr_is_pty = pty;
+    server.bridge_console = pty;
+    server.set_connect_handshake(false);
+    server.set_output_tail_capture(4096);
+    if pty {
+        server.set_scripted_input(std::io::empty(), true);
+    }
+    server
+}
+
+fn echo_hello(token: &Token) -> Command {
+    let mut cmd = Command::with_environment_for_token(token).unwrap();
+    cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "echo hello".into()]);
+    cmd
+}
+
+#[test]
+fn piped_round_trip() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    let args = start(&mut server, &token);
+    assert!(args.conin.is_none());
+
+    let mut cmd = echo_hello(&token);
+    connect_stdio_pipes(
+        &mut cmd,
+        None,
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        None,
+    )
+    .unwrap();
+    let proc = cmd.spawn().unwrap();
+    // Release our copies of the client ends, so that the server sees
+    // the end of the output once the child exits
+    drop(cmd);
+
+    let outcome = server.serve_with_outcome(proc).unwrap();
+    assert!(outcome.client_connected);
+    assert_eq!(outcome.exit_code, 0);
+    assert_eq!(outcome.output_tail, b"hello\r\n");
+    assert_eq!(outcome.stdout_bytes, 7);
+    assert_eq!(outcome.stderr_bytes, 0);
+    assert_eq!(outcome.conout_bytes, 0);
+}
+
+#[test]
+fn captured_output() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.capture_output();
+    let args = start(&mut server, &token);
+    assert!(args.conin.is_none());
+    // Disabling the pty doesn't bring back the stdin of the test runner
+    assert!(args.stdin.is_none());
+
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "(echo out)&(echo err)1>&2".into(),
+    ]);
+    connect_stdio_pipes(
+        &mut cmd,
+        None,
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        None,
+    )
+    .unwrap();
+    let proc = cmd.spawn().unwrap();
+    drop(cmd);
+
+    let output = server.serve_captured(proc).unwrap();
+    assert_eq!(output.exit_code, 0);
+    assert_eq!(output.stdout, b"out\r\n");
+    assert_eq!(output.stderr, b"err\r\n");
+}
+
+#[test]
+fn serve_captured_client_never_connects() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.capture_output();
+    start(&mut server, &token);
+
+    // Exits without ever connecting to the output pipes
+    let proc = echo_hello(&token).spawn().unwrap();
+    let err = server.serve_captured(proc).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn serve_captured_requires_capture() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    start(&mut server, &token);
+    let proc = echo_hello(&token).spawn().unwrap();
+    let err = server.serve_captured(proc).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// Make a handle to the same event that outlives the server
+fn duplicate_event(event: HANDLE) -> Process {
+    let mut dup = std::ptr::null_mut();
+    let res = unsafe {
+        DuplicateHandle(
+            GetCurrentProcess(),
+            event,
+            GetCurrentProcess(),
+            &mut dup,
+            0,
+            0,
+            DUPLICATE_SAME_ACCESS,
+        )
+    };
+    assert_ne!(res, 0);
+    // Process is simply an owner of a waitable handle
+    Process::with_handle(dup)
+}
+
+#[test]
+fn connected_event() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    let event = duplicate_event(server.connected_event().unwrap());
+    assert_eq!(
+        server.connected_event().unwrap(),
+        server.connected_event().unwrap()
+    );
+    let args = start(&mut server, &token);
+    assert_eq!(event.wait_for(Some(0)).unwrap(), WAIT_TIMEOUT);
+
+    let mut cmd = echo_hello(&token);
+    connect_stdio_pipes(
+        &mut cmd,
+        None,
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        None,
+    )
+    .unwrap();
+    let proc = cmd.spawn().unwrap();
+    drop(cmd);
+    let serve = std::thread::spawn(move || server.serve_with_outcome(proc));
+
+    assert_eq!(event.wait_for(Some(10_000)).unwrap(), WAIT_OBJECT_0);
+    assert!(serve.join().unwrap().unwrap().client_connected);
+
+    // It isn't signalled when the client never connects
+    let mut server = loopback_server(false);
+    let event = duplicate_event(server.connected_event().unwrap());
+    start(&mut server, &token);
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 0".into()]);
+    let outcome = server.serve_with_outcome(cmd.spawn().unwrap()).unwrap();
+    assert!(!outcome.client_connected);
+    assert_eq!(event.wait_for(Some(0)).unwrap(), WAIT_TIMEOUT);
+}
+
+/// A sink that its creator can inspect after the server is done with it
+#[derive(Clone, Default)]
+struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_sinks() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    let stdout = SharedSink::default();
+    let stderr = SharedSink::default();
+    server.set_stdout_sink(Box::new(stdout.clone()));
+    server.set_stderr_sink(Box::new(stderr.clone()));
+    let args = start(&mut server, &token);
+
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "echo out&1>&2 echo err".into(),
+    ]);
+    connect_stdio_pipes(
+        &mut cmd,
+        None,
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        None,
+    )
+    .unwrap();
+    let proc = cmd.spawn().unwrap();
+    drop(cmd);
+
+    let outcome = server.serve_with_outcome(proc).unwrap();
+    assert!(outcome.client_connected);
+    assert_eq!(*stdout.0.lock().unwrap(), b"out\r\n");
+    assert_eq!(*stderr.0.lock().unwrap(), b"err\r\n");
+    assert_eq!(outcome.stdout_bytes, 5);
+    assert_eq!(outcome.stderr_bytes, 5);
+}
+
+#[test]
+fn idle_timeout_terminates_silent_program() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.set_idle_timeout(Duration::from_millis(500));
+    let args = start(&mut server, &token);
+
+    // Says hello, then sits silently for several seconds
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "echo hello&ping -n 10 127.0.0.1 >nul".into(),
+    ]);
+    connect_stdio_pipes(
+        &mut cmd,
+        None,
+        args.stdout.as_deref(),
+        args.stderr.as_deref(),
+        None,
+    )
+    .unwrap();
+    let proc = cmd.spawn().unwrap();
+    drop(cmd);
+
+    let outcome = server.serve_with_outcome(proc).unwrap();
+    assert!(outcome.idle_timed_out);
+    assert_eq!(outcome.exit_code, IDLE_TIMEOUT_EXIT_CODE);
+    assert_eq!(outcome.output_tail, b"hello\r\n");
+}
+
+/// A program that is running, but is not a bridge client and so
+/// never connects
+fn never_connects(token: &Token) -> Process {
+    let mut cmd = Command::with_environment_for_token(token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "ping -n 30 127.0.0.1 >nul".into(),
+    ]);
+    cmd.spawn().unwrap()
+}
+
+#[test]
+fn startup_timeout_client_never_connects() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.set_startup_timeout(Duration::from_millis(500));
+    start(&mut server, &token);
+
+    let proc = never_connects(&token);
+    let waiter = proc.try_clone().unwrap();
+    let started = Instant::now();
+    let err = server.serve_with_outcome(proc).err().unwrap();
+    assert!(is_startup_timeout(&err));
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The bridge process is torn down along with the server
+    assert_eq!(waiter.wait_for(Some(5000)).unwrap(), WAIT_OBJECT_0);
+    assert_eq!(waiter.exit_code().unwrap(), ERROR_TIMEOUT);
+}
+
+#[test]
+fn startup_timeout_bounds_launch() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.set_startup_timeout(Duration::from_millis(500));
+    start(&mut server, &token);
+
+    // Stands in for a UAC prompt that nobody answers
+    let started = Instant::now();
+    let err = server
+        .launch_bridge(|| {
+            std::thread::sleep(Duration::from_secs(30));
+            Err(IoError::new(std::io::ErrorKind::Other, "unreachable"))
+        })
+        .err()
+        .unwrap();
+    assert!(is_startup_timeout(&err));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // A launch that completes in time is returned as is
+    let launched = never_connects(&token);
+    let pid = unsafe { GetProcessId(launched.as_handle()) };
+    let proc = server.launch_bridge(move || Ok(launched)).unwrap();
+    assert_eq!(unsafe { GetProcessId(proc.as_handle()) }, pid);
+    proc.terminate(0).unwrap();
+}
+
+#[test]
+fn failed_launch_restores_console_modes() {
+    let (conin, conout) = match (
+        PipeHandle::open_pipe("CONIN$"),
+        PipeHandle::open_pipe("CONOUT$"),
+    ) {
+        (Ok(conin), Ok(conout)) => (conin, conout),
+        // There is no console to change the modes of
+        _ => return,
+    };
+    let _console = lock_console();
+    let modes = || {
+        (
+            get_console_mode(&conin).unwrap(),
+            get_console_mode(&conout).unwrap(),
+        )
+    };
+    let before = modes();
+
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(true);
+    start(&mut server, &token);
+    assert!(server.console_geometry().is_some());
+    let err = server
+        .launch_bridge(|| Err(IoError::new(std::io::ErrorKind::Other, "launch failed")))
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "launch failed");
+
+    // This is what eledo relies on before exiting
+    drop(server);
+    assert_eq!(modes(), before);
+}
+
+#[test]
+fn idle_timeout_bounds_output_drain() {
+    let (conin, conout) = match (
+        PipeHandle::open_pipe("CONIN$"),
+        PipeHandle::open_pipe("CONOUT$"),
+    ) {
+        (Ok(conin), Ok(conout)) => (conin, conout),
+        // The pty path bridges the console, so there must be one
+        _ => return,
+    };
+    let _console = lock_console();
+    let modes = || {
+        (
+            get_console_mode(&conin).unwrap(),
+            get_console_mode(&conout).unwrap(),
+        )
+    };
+    let before = modes();
+
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(true);
+    server.set_idle_timeout(Duration::from_millis(500));
+    let args = start(&mut server, &token);
+    let geometry = server.console_geometry().unwrap();
+    let client = BridgePtyClient::with_params(
+        args.conin.as_ref().unwrap(),
+        args.conout.as_ref().unwrap(),
+        geometry.width,
+        geometry.height,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "ping -n 30 127.0.0.1 >nul".into(),
+    ]);
+    let proc = client.spawn(cmd).unwrap();
+
+    // The pty outlives the terminated program and holds the console
+    // output open, so only the bound on the drain lets serve return
+    let started = Instant::now();
+    let outcome = server.serve_with_outcome(proc).unwrap();
+    assert!(outcome.idle_timed_out);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(modes(), before);
+    drop(client);
+}
+
+#[test]
+fn pty_round_trip() {
+    if PipeHandle::open_pipe("CONOUT$").is_err() {
+        // The pty path bridges the console, so there must be one
+        return;
+    }
+    let _console = lock_console();
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(true);
+    let args = start(&mut server, &token);
+    let geometry = server.console_geometry().unwrap();
+
+    let client = BridgePtyClient::with_params(
+        args.conin.as_ref().unwrap(),
+        args.conout.as_ref().unwrap(),
+        geometry.width,
+        geometry.height,
+        None,
+        None,
+    )
+    .unwrap();
+    let proc = client.spawn(echo_hello(&token)).unwrap();
+    let server_proc = proc.try_clone().unwrap();
+    let serve = std::thread::spawn(move || server.serve_with_outcome(server_proc));
+
+    assert_eq!(client.run_keep_alive(proc).unwrap(), 0);
+    // Closing the pty ends the console output
+    drop(client);
+
+    let outcome = serve.join().unwrap().unwrap();
+    assert!(outcome.client_connected);
+    assert!(String::from_utf8_lossy(&outcome.output_tail).contains("hello"));
+}
+
+#[test]
+fn merged_stderr_keeps_order() {
+    let token = Token::with_current_process().unwrap();
+    let mut server = loopback_server(false);
+    server.set_merge_stderr_into_stdout(true);
+    let args = start(&mut server, &token);
+    let stdout = args.stdout.clone();
+    assert!(stdout.is_some());
+    assert_eq!(args.stderr, stdout);
+
+    let mut cmd = Command::with_environment_for_token(&token).unwrap();
+    cmd.set_argv(vec![
+        "cmd.exe".into(),
+        "/c".into(),
+        "echo out1&1>&2 echo err1&echo out2&1>&2 echo err2".into(),
+    ]);
+    connect_stdio_pipes(&mut cmd, None, stdout.as_deref(), stdout.as_deref(), None).unwrap();
+    let proc = cmd.spawn().unwrap();
+    drop(cmd);
+
+    let outcome = server.serve_with_outcome(proc).unwrap();
+    assert!(outcome.client_connected);
+    assert_eq!(outcome.output_tail, b"out1\r\nerr1\r\nout2\r\nerr2\r\n");
+}
@@ -0,0 +1,147 @@
+//! The byte streams over which the bridge server and client exchange
+//! the output of the program and the control protocol.  In production
+//! these are always named pipes, but the copy loops only depend on
+//! `BridgeTransport`, so that the tests can exercise them over the
+//! in-memory `MemoryTransport` rather than real pipes.
+use crate::pipe::PipeHandle;
+use std::io::{Read, Write};
+
+/// A connected stream between the bridge server and client.
+/// Once the other end has gone away, reads report the end of the
+/// stream, either as `Ok(0)` or as a `BrokenPipe` error after any
+/// remaining data has been read, and writes fail with `BrokenPipe`.
+/// Dropping the transport closes it.
+pub trait BridgeTransport: Read + Write + Send {}
+
+impl BridgeTransport for PipeHandle {}
+
+#[cfg(test)]
+pub use memory::MemoryTransport;
+
+#[cfg(test)]
+mod memory {
+    use super::BridgeTransport;
+    use std::collections::VecDeque;
+    use std::io::{Error as IoError, Read, Result as IoResult, Write};
+    use std::sync::{Arc, Condvar, Mutex};
+
+    /// The data flowing in one direction between the two ends
+    #[derive(Default)]
+    struct Channel {
+        state: Mutex<ChannelState>,
+        ready: Condvar,
+    }
+
+    #[derive(Default)]
+    struct ChannelState {
+        data: VecDeque<u8>,
+        closed: bool,
+    }
+
+    impl Channel {
+        fn close(&self) {
+            self.state.lock().unwrap().closed = true;
+            self.ready.notify_all();
+        }
+    }
+
+    fn closed_error() -> IoError {
+        IoError::new(
+            std::io::ErrorKind::BrokenPipe,
+            "the other end of the transport has been closed",
+        )
+    }
+
+    /// One end of an in-memory transport, which behaves as the ends
+    /// of a named pipe do: reads block until there is data, and once
+    /// the other end has been dropped they return what remains and
+    /// then fail with `BrokenPipe`, as do writes.
+    pub struct MemoryTransport {
+        incoming: Arc<Channel>,
+        outgoing: Arc<Channel>,
+    }
+
+    impl MemoryTransport {
+        /// Create both ends of a transport
+        pub fn pair() -> (Self, Self) {
+            let a = Arc::new(Channel::default());
+            let b = Arc::new(Channel::default());
+            (
+                Self {
+                    incoming: Arc::clone(&a),
+                    outgoing: Arc::clone(&b),
+                },
+                Self {
+                    incoming: b,
+                    outgoing: a,
+                },
+            )
+        }
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let mut state = self.incoming.state.lock().unwrap();
+            while state.data.is_empty() && !state.closed {
+                state = self.incoming.ready.wait(state).unwrap();
+            }
+            if state.data.is_empty() {
+                return Err(closed_error());
+            }
+            let len = buf.len().min(state.data.len());
+            for (dest, src) in buf.iter_mut().zip(state.data.drain(0..len)) {
+                *dest = src;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            let mut state = self.outgoing.state.lock().unwrap();
+            if state.closed {
+                return Err(closed_error());
+            }
+            state.data.extend(buf);
+            self.outgoing.ready.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryTransport {
+        fn drop(&mut self) {
+            self.incoming.close();
+            self.outgoing.close();
+        }
+    }
+
+    impl BridgeTransport for MemoryTransport {}
+
+    mod test {
+        use super::*;
+
+        #[test]
+        fn closing() {
+            let (mut a, mut b) = MemoryTransport::pair();
+            a.write_all(b"hello").unwrap();
+            b.write_all(b"there").unwrap();
+            drop(a);
+
+            // What was written before the close can still be read
+            let mut buf = [0u8; 3];
+            assert_eq!(b.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf, b"hel");
+            assert_eq!(b.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf[0..2], b"lo");
+
+            let err = b.read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+            let err = b.write(b"more").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+    }
+}
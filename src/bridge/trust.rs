@@ -0,0 +1,331 @@
+//! Deciding whether the bridge executable that was located can be
+//! trusted; see `BridgeServer::set_require_trusted_bridge`.
+//!
+//! The bridge runs with the privileges that the program is given, so
+//! whoever can plant an `eledo-pty-bridge.exe` where we look for it can
+//! run code at those privileges, or at least within the session of the
+//! user, and see everything that passes through the bridge.  The
+//! directory of the executable (or of the module that contains this
+//! crate) is where the bridge is installed, and is as trustworthy as the
+//! executable itself, but any directory in the path may be writable by
+//! someone else.  A bridge found elsewhere is therefore only trusted if
+//! it has a valid Authenticode signature from the same certificate as
+//! the executable.  A valid signature alone would only prove that the
+//! bridge was signed by someone, which anybody can arrange.
+use crate::{os_str_to_null_terminated_vec, win32_error_with_context};
+use std::ffi::OsString;
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::{Component, Path, PathBuf};
+use std::ptr::null_mut;
+use winapi::shared::guiddef::GUID;
+use winapi::um::softpub::WINTRUST_ACTION_GENERIC_VERIFY_V2;
+use winapi::um::wincrypt::{
+    CertCloseStore, CertFreeCertificateContext, CertGetSubjectCertificateFromStore, CryptMsgClose,
+    CryptMsgGetParam, CryptQueryObject, CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+    CERT_QUERY_FORMAT_FLAG_BINARY, CERT_QUERY_OBJECT_FILE, CMSG_SIGNER_CERT_INFO_PARAM, HCERTSTORE,
+    HCRYPTMSG, PKCS_7_ASN_ENCODING, X509_ASN_ENCODING,
+};
+use winapi::um::wintrust::{
+    WinVerifyTrust, WINTRUST_DATA, WINTRUST_FILE_INFO, WTD_CHOICE_FILE, WTD_REVOKE_NONE,
+    WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE,
+};
+
+/// Verifies the signature of an executable.  This is a trait so that
+/// the tests can decide the outcome, as there is no executable that is
+/// reliably signed on every machine.
+pub trait SignatureCheck {
+    fn verify_signature(&self, path: &Path) -> IoResult<()>;
+}
+
+/// Verifies the embedded Authenticode signature of an executable via
+/// WinVerifyTrust, without any UI or revocation checks, and that it was
+/// made with the same certificate as that of the current executable.
+/// When the current executable isn't signed, nothing verifies.
+pub struct WinVerifyTrustCheck;
+
+impl SignatureCheck for WinVerifyTrustCheck {
+    fn verify_signature(&self, path: &Path) -> IoResult<()> {
+        verify_trust(path)?;
+        let exe = std::env::current_exe()?;
+        let expected = signer_certificate(&exe).map_err(|err| {
+            IoError::new(
+                err.kind(),
+                format!("{} has no signer to match: {}", exe.display(), err),
+            )
+        })?;
+        if signer_certificate(path)? == expected {
+            Ok(())
+        } else {
+            Err(IoError::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("it was not signed by the signer of {}", exe.display()),
+            ))
+        }
+    }
+}
+
+/// The message and certificate store of an embedded signature, which
+/// are closed when this is dropped
+struct EmbeddedSignature {
+    store: HCERTSTORE,
+    msg: HCRYPTMSG,
+}
+
+impl Drop for EmbeddedSignature {
+    fn drop(&mut self) {
+        unsafe {
+            CryptMsgClose(self.msg);
+            CertCloseStore(self.store, 0);
+        }
+    }
+}
+
+/// Returns the encoded certificate of the signer of the embedded
+/// Authenticode signature of `path`.  The signature isn't verified.
+fn signer_certificate(path: &Path) -> IoResult<Vec<u8>> {
+    let wide = os_str_to_null_terminated_vec(path.as_os_str());
+    let mut signature = EmbeddedSignature {
+        store: null_mut(),
+        msg: null_mut(),
+    };
+    let res = unsafe {
+        CryptQueryObject(
+            CERT_QUERY_OBJECT_FILE,
+            wide.as_ptr() as *const _,
+            CERT_QUERY_CONTENT_FLAG_PKCS7_SIGNED_EMBED,
+            CERT_QUERY_FORMAT_FLAG_BINARY,
+            0,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            &mut signature.store,
+            &mut signature.msg,
+            null_mut(),
+        )
+    };
+    if res == 0 {
+        return Err(win32_error_with_context(
+            "CryptQueryObject",
+            IoError::last_os_error(),
+        ));
+    }
+
+    // The CERT_INFO that identifies the signer, which holds pointers
+    // and so is read into a buffer that is suitably aligned for them
+    let mut size = 0;
+    let get_info = |buf: *mut u64, size: &mut u32| unsafe {
+        CryptMsgGetParam(
+            signature.msg,
+            CMSG_SIGNER_CERT_INFO_PARAM,
+            0,
+            buf as *mut _,
+            size,
+        )
+    };
+    if get_info(null_mut(), &mut size) == 0 {
+        return Err(win32_error_with_context(
+            "CryptMsgGetParam",
+            IoError::last_os_error(),
+        ));
+    }
+    let mut info = vec![0u64; (size as usize).div_ceil(8)];
+    if get_info(info.as_mut_ptr(), &mut size) == 0 {
+        return Err(win32_error_with_context(
+            "CryptMsgGetParam",
+            IoError::last_os_error(),
+        ));
+    }
+
+    let cert = unsafe {
+        CertGetSubjectCertificateFromStore(
+            signature.store,
+            X509_ASN_ENCODING | PKCS_7_ASN_ENCODING,
+            info.as_mut_ptr() as *mut _,
+        )
+    };
+    if cert.is_null() {
+        return Err(win32_error_with_context(
+            "CertGetSubjectCertificateFromStore",
+            IoError::last_os_error(),
+        ));
+    }
+    let encoded = unsafe {
+        std::slice::from_raw_parts((*cert).pbCertEncoded, (*cert).cbCertEncoded as usize).to_vec()
+    };
+    unsafe {
+        CertFreeCertificateContext(cert);
+    }
+    Ok(encoded)
+}
+
+/// Verify the embedded Authenticode signature of `path`, whoever made it
+fn verify_trust(path: &Path) -> IoResult<()> {
+    let wide = os_str_to_null_terminated_vec(path.as_os_str());
+    let mut file = WINTRUST_FILE_INFO {
+        cbStruct: std::mem::size_of::<WINTRUST_FILE_INFO>() as _,
+        pcwszFilePath: wide.as_ptr(),
+        hFile: null_mut(),
+        pgKnownSubject: std::ptr::null(),
+    };
+    let mut data: WINTRUST_DATA = unsafe { std::mem::zeroed() };
+    data.cbStruct = std::mem::size_of::<WINTRUST_DATA>() as _;
+    data.dwUIChoice = WTD_UI_NONE;
+    data.fdwRevocationChecks = WTD_REVOKE_NONE;
+    data.dwUnionChoice = WTD_CHOICE_FILE;
+    unsafe {
+        *data.u.pFile_mut() = &mut file;
+    }
+    data.dwStateAction = WTD_STATEACTION_VERIFY;
+
+    let mut action: GUID = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+    let status = unsafe { WinVerifyTrust(null_mut(), &mut action, &mut data as *mut _ as *mut _) };
+    // Release the state that the verification allocated
+    data.dwStateAction = WTD_STATEACTION_CLOSE;
+    unsafe {
+        WinVerifyTrust(null_mut(), &mut action, &mut data as *mut _ as *mut _);
+    }
+
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(win32_error_with_context(
+            "WinVerifyTrust",
+            IoError::from_raw_os_error(status),
+        ))
+    }
+}
+
+/// The components of `path` for comparison, ignoring case as the file
+/// system does and ignoring `.` components.  `..` is deliberately kept
+/// rather than resolved, so a path that leaves a directory that way is
+/// never taken to be within it.
+fn comparable(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase().into())
+        .collect()
+}
+
+/// Returns true if `path` is directly within one of `dirs`
+pub fn is_in_trusted_dir(path: &Path, dirs: &[PathBuf]) -> bool {
+    if !path.is_absolute() {
+        return false;
+    }
+    let parent = match path.parent() {
+        Some(parent) => comparable(parent),
+        None => return false,
+    };
+    dirs.iter()
+        .any(|dir| dir.is_absolute() && comparable(dir) == parent)
+}
+
+/// Check that the bridge at `path` can be trusted: that it is in one
+/// of the trusted `dirs`, or else that its signature verifies
+pub fn check_bridge_trust(
+    path: &Path,
+    dirs: &[PathBuf],
+    signature: &dyn SignatureCheck,
+) -> IoResult<()> {
+    if is_in_trusted_dir(path, dirs) {
+        return Ok(());
+    }
+    signature.verify_signature(path).map_err(|err| {
+        IoError::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "refusing to launch the bridge {}: it is not alongside the executable, \
+                 and its signature could not be verified: {}",
+                path.display(),
+                err
+            ),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A signature check that always has the same outcome
+    struct Signed(bool);
+
+    impl SignatureCheck for Signed {
+        fn verify_signature(&self, _path: &Path) -> IoResult<()> {
+            if self.0 {
+                Ok(())
+            } else {
+                Err(IoError::new(std::io::ErrorKind::Other, "not signed"))
+            }
+        }
+    }
+
+    #[test]
+    fn trusted_dirs() {
+        let dirs = vec![
+            PathBuf::from(r"C:\Program Files\EleDo"),
+            PathBuf::from(r"D:\tools\"),
+        ];
+        let trusted = |path: &str| is_in_trusted_dir(Path::new(path), &dirs);
+        assert!(trusted(r"C:\Program Files\EleDo\eledo-pty-bridge.exe"));
+        assert!(trusted(r"c:\program files\eledo\.\eledo-pty-bridge.exe"));
+        assert!(trusted(r"D:\tools\eledo-pty-bridge.exe"));
+
+        assert!(!trusted(r"C:\Program Files\EleDo\bin\eledo-pty-bridge.exe"));
+        assert!(!trusted(r"C:\Program Files\eledo-pty-bridge.exe"));
+        assert!(!trusted(
+            r"C:\Program Files\EleDo\..\EleDo\eledo-pty-bridge.exe"
+        ));
+        assert!(!trusted(
+            r"C:\Users\me\AppData\Local\Temp\eledo-pty-bridge.exe"
+        ));
+        assert!(!trusted(r"eledo-pty-bridge.exe"));
+        assert!(!trusted(r"EleDo\eledo-pty-bridge.exe"));
+
+        // A relative trusted dir matches nothing
+        let relative = vec![PathBuf::from("EleDo")];
+        assert!(!is_in_trusted_dir(
+            Path::new(r"C:\EleDo\eledo-pty-bridge.exe"),
+            &relative
+        ));
+    }
+
+    #[test]
+    fn bridge_trust() {
+        let dirs = vec![PathBuf::from(r"C:\EleDo")];
+        let sibling = Path::new(r"C:\EleDo\eledo-pty-bridge.exe");
+        let planted = Path::new(r"C:\Users\me\bin\eledo-pty-bridge.exe");
+
+        assert!(check_bridge_trust(sibling, &dirs, &Signed(false)).is_ok());
+        assert!(check_bridge_trust(planted, &dirs, &Signed(true)).is_ok());
+
+        let err = check_bridge_trust(planted, &dirs, &Signed(false)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            err.to_string(),
+            "refusing to launch the bridge C:\\Users\\me\\bin\\eledo-pty-bridge.exe: \
+             it is not alongside the executable, and its signature could not be \
+             verified: not signed"
+        );
+    }
+
+    #[test]
+    fn unsigned_executable() {
+        // The test executable is never signed
+        let exe = std::env::current_exe().unwrap();
+        assert!(WinVerifyTrustCheck.verify_signature(&exe).is_err());
+        assert!(signer_certificate(&exe).is_err());
+    }
+
+    #[test]
+    fn signer_must_match_executable() {
+        // Signed by Microsoft, which the test executable is not, so
+        // this fails even though the signature itself is valid
+        let notepad = Path::new(r"C:\Windows\System32\notepad.exe");
+        if signer_certificate(notepad).is_err() {
+            // Catalog signed rather than embedded on this system
+            return;
+        }
+        verify_trust(notepad).unwrap();
+        assert!(WinVerifyTrustCheck.verify_signature(notepad).is_err());
+    }
+}
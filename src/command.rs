@@ -1,16 +1,25 @@
+use crate::job::Job;
 use crate::pipe::*;
 use crate::process::Process;
 use crate::procthreadattr::ProcThreadAttributeList;
 use crate::psuedocon::PsuedoCon;
+use crate::token::parse_mandatory_label;
 use crate::{os_str_to_null_terminated_vec, win32_error_with_context, Token};
 use std::ffi::{OsStr, OsString};
-use std::io::{Error as IoError, Result as IoResult};
-use std::os::windows::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::io::{Error as IoError, Result as IoResult, Write};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::io::AsRawHandle;
+use std::path::{Component, Path, PathBuf};
 use std::ptr::null_mut;
+use std::time::Duration;
 use winapi::shared::minwindef::{BOOL, DWORD, LPVOID};
+use winapi::shared::winerror::{
+    ERROR_BUSY, ERROR_CANCELLED, ERROR_DDE_FAIL, ERROR_NOT_ENOUGH_MEMORY, ERROR_OUTOFMEMORY,
+    ERROR_SHARING_VIOLATION, ERROR_TIMEOUT, RPC_S_CALL_FAILED, RPC_S_SERVER_TOO_BUSY,
+    RPC_S_SERVER_UNAVAILABLE,
+};
 use winapi::um::combaseapi::CoInitializeEx;
-use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::handleapi::{CloseHandle, SetHandleInformation, INVALID_HANDLE_VALUE};
 use winapi::um::objbase::{COINIT_APARTMENTTHREADED, COINIT_DISABLE_OLE1DDE};
 use winapi::um::processthreadsapi::{
     CreateProcessAsUserW, CreateProcessW, PROCESS_INFORMATION, STARTUPINFOW,
@@ -18,9 +27,10 @@ use winapi::um::processthreadsapi::{
 use winapi::um::shellapi::{ShellExecuteExW, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW};
 use winapi::um::userenv::{CreateEnvironmentBlock, DestroyEnvironmentBlock};
 use winapi::um::winbase::{
-    CREATE_DEFAULT_ERROR_MODE, CREATE_NEW_CONSOLE, CREATE_NEW_PROCESS_GROUP,
-    CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, STARTF_USESHOWWINDOW,
-    STARTF_USESTDHANDLES, STARTUPINFOEXW,
+    CreateProcessWithLogonW, CREATE_DEFAULT_ERROR_MODE, CREATE_NEW_CONSOLE,
+    CREATE_NEW_PROCESS_GROUP, CREATE_NO_WINDOW, CREATE_SUSPENDED, CREATE_UNICODE_ENVIRONMENT,
+    DETACHED_PROCESS, EXTENDED_STARTUPINFO_PRESENT, HANDLE_FLAG_INHERIT, LOGON_WITH_PROFILE,
+    STARTF_USESHOWWINDOW, STARTF_USESTDHANDLES, STARTUPINFOEXW,
 };
 use winapi::um::winnt::{HANDLE, LPCWSTR, LPWSTR};
 use winapi::um::winuser::{SW_HIDE, SW_SHOWNORMAL};
@@ -98,6 +108,86 @@ impl EnvironmentBlock {
     }
 }
 
+/// Environment variables that are retained by `Command::set_env_allowlist`
+/// even when they are not named, because too many things break without them
+const REQUIRED_ENV_VARS: &[&str] = &["SystemRoot"];
+
+/// Split an environment block into its `key=value` entries
+fn parse_env_block(block: &[u16]) -> Vec<OsString> {
+    block
+        .split(|&c| c == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(OsString::from_wide)
+        .collect()
+}
+
+/// Returns the key portion of a `key=value` entry.
+/// Note that the key may itself start with `=`, as is the case
+/// for the variables that track the current directory per drive.
+fn env_entry_key(entry: &OsStr) -> OsString {
+    let wide: Vec<u16> = entry.encode_wide().collect();
+    let len = wide
+        .iter()
+        .skip(1)
+        .position(|&c| c == '=' as u16)
+        .map(|pos| pos + 1)
+        .unwrap_or(wide.len());
+    OsString::from_wide(&wide[0..len])
+}
+
+/// Split a `key=value` entry into its key and value
+fn split_env_entry(entry: &OsStr) -> (OsString, OsString) {
+    let key = env_entry_key(entry);
+    let wide: Vec<u16> = entry.encode_wide().collect();
+    let value = wide
+        .get(key.encode_wide().count() + 1..)
+        .map(OsString::from_wide)
+        .unwrap_or_default();
+    (key, value)
+}
+
+fn env_key_eq(a: &OsStr, b: &OsStr) -> bool {
+    a.to_string_lossy().to_uppercase() == b.to_string_lossy().to_uppercase()
+}
+
+/// Merge two lists of `key=value` entries, with the entries from
+/// `overlay` replacing those from `base` that have the same key.
+/// Keys are compared case insensitively, and the case of the key is
+/// taken from the entry that wins.
+fn merge_env_entries(base: Vec<OsString>, overlay: Vec<OsString>) -> Vec<OsString> {
+    let mut merged: Vec<OsString> = vec![];
+    for entry in base.into_iter().chain(overlay) {
+        let key = env_entry_key(&entry);
+        match merged
+            .iter_mut()
+            .find(|existing| env_key_eq(&env_entry_key(existing), &key))
+        {
+            Some(existing) => *existing = entry,
+            None => merged.push(entry),
+        }
+    }
+    merged
+}
+
+/// Build an environment block from `key=value` entries.
+/// The system requires that the block be sorted case insensitively by
+/// key, and terminated by an additional null.
+fn build_env_block(mut entries: Vec<OsString>) -> Vec<u16> {
+    entries.sort_by_key(|entry| env_entry_key(entry).to_string_lossy().to_uppercase());
+
+    let mut block = vec![];
+    for entry in entries {
+        block.extend(entry.encode_wide());
+        block.push(0);
+    }
+    if block.is_empty() {
+        // An empty block still needs its double-null terminator
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
 /// Helper for ensuring that handles from a spawned
 /// process are closed
 struct ProcInfo(PROCESS_INFORMATION);
@@ -119,13 +209,15 @@ impl ProcInfo {
         Self(unsafe { std::mem::zeroed() })
     }
 
-    /// Take ownership of the process handle
+    /// Take ownership of the process and thread handles
     pub fn process(&mut self) -> Option<Process> {
         if self.0.hProcess.is_null() {
             None
         } else {
-            let proc = Process::with_handle(self.0.hProcess);
+            let mut proc = Process::with_handle(self.0.hProcess);
+            proc.set_thread(self.0.hThread);
             self.0.hProcess = null_mut();
+            self.0.hThread = null_mut();
             Some(proc)
         }
     }
@@ -133,29 +225,125 @@ impl ProcInfo {
 
 pub struct Command {
     args: Vec<OsString>,
+    program: Option<OsString>,
+    argv0: Option<OsString>,
     env: Vec<u16>,
     cwd: PathBuf,
     hide_window: bool,
     stdin: Option<PipeHandle>,
     stdout: Option<PipeHandle>,
     stderr: Option<PipeHandle>,
+    inherit_handles: Vec<InheritHandle>,
+    creation_flags: DWORD,
+    response_file_threshold: Option<usize>,
+    kill_on_close: bool,
+    path_search: bool,
+    run_login_profile: bool,
+    mandatory_label: Option<String>,
+    shell_command: bool,
+    detach_console: bool,
+    no_window: bool,
+    before_spawn: Option<Box<StartupInfoHook>>,
+    before_shell_execute: Option<Box<ShellExecuteHook>>,
 }
 
+/// Adjusts the STARTUPINFOW passed to `CreateProcess*`
+type StartupInfoHook = dyn FnMut(&mut STARTUPINFOW) + Send;
+/// Adjusts the SHELLEXECUTEINFOW passed to `ShellExecuteExW`
+type ShellExecuteHook = dyn FnMut(&mut SHELLEXECUTEINFOW) + Send;
+
+/// A handle that has been passed to `Command::inherit_handle`.
+/// The caller retains ownership of it, so it is not closed here.
+struct InheritHandle(HANDLE);
+
+/// The handle is only passed along to the child, so moving it between
+/// threads along with the Command is fine.
+unsafe impl Send for InheritHandle {}
+
 impl Command {
     pub fn with_environment_for_token(token: &Token) -> IoResult<Self> {
         let env = EnvironmentBlock::with_token(token)?.as_vec();
         let cwd = std::env::current_dir()?;
         Ok(Self {
             args: vec![],
+            program: None,
+            argv0: None,
             env,
             cwd,
             stdin: None,
             stdout: None,
             stderr: None,
             hide_window: false,
+            inherit_handles: vec![],
+            creation_flags: 0,
+            response_file_threshold: None,
+            kill_on_close: false,
+            path_search: true,
+            run_login_profile: false,
+            mandatory_label: None,
+            shell_command: false,
+            detach_console: false,
+            no_window: false,
+            before_spawn: None,
+            before_shell_execute: None,
         })
     }
 
+    /// Like `with_environment_for_token`, but rather than replacing
+    /// the environment of the current process, the environment derived
+    /// from the token is merged on top of it.  This allows variables
+    /// set in the current process to reach the program, while those
+    /// from the profile of the token take precedence when both define
+    /// the same variable (compared case insensitively).
+    pub fn with_merged_environment_for_token(token: &Token) -> IoResult<Self> {
+        let mut cmd = Self::with_environment_for_token(token)?;
+        let current = std::env::vars_os()
+            .map(|(key, value)| {
+                let mut entry = key;
+                entry.push("=");
+                entry.push(value);
+                entry
+            })
+            .collect();
+        cmd.env = build_env_block(merge_env_entries(current, parse_env_block(&cmd.env)));
+        Ok(cmd)
+    }
+
+    /// Reduce the environment to just the variables named by `keys`,
+    /// along with a minimal set of variables that are required for
+    /// processes to function correctly (such as `SystemRoot`).
+    /// Names are matched case insensitively, as is the convention
+    /// on Windows.
+    pub fn set_env_allowlist(&mut self, keys: &[&OsStr]) {
+        let entries = parse_env_block(&self.env)
+            .into_iter()
+            .filter(|entry| {
+                let key = env_entry_key(entry);
+                keys.iter()
+                    .copied()
+                    .chain(REQUIRED_ENV_VARS.iter().map(OsStr::new))
+                    .any(|allowed| env_key_eq(&key, allowed))
+            })
+            .collect();
+        self.env = build_env_block(entries);
+    }
+
+    /// Remove all variables from the environment
+    pub fn clear_env(&mut self) {
+        self.env = build_env_block(vec![]);
+    }
+
+    /// Returns the environment that will be passed to the program, as
+    /// key and value pairs in the order that they appear in the block.
+    /// This is intended for diagnosing why a variable did or did not
+    /// reach the program.
+    pub fn environment(&self) -> Vec<(OsString, OsString)> {
+        parse_env_block(&self.env)
+            .iter()
+            .map(|entry| split_env_entry(entry))
+            .collect()
+    }
+
     pub fn set_command_from_current_process(&mut self) -> IoResult<()> {
         self.args = std::env::args_os().collect();
         Ok(())
@@ -165,35 +353,389 @@ impl Command {
         self.hide_window = true;
     }
 
+    /// Replace the additional process creation flags, such as
+    /// `CREATE_NEW_PROCESS_GROUP` or `CREATE_NO_WINDOW`, that are
+    /// passed when spawning the command.  The default is to pass none,
+    /// which leaves just the flags that Command itself requires, such
+    /// as `CREATE_UNICODE_ENVIRONMENT`.  These flags don't apply to
+    /// `shell_execute`, and `spawn_with_token` always adds
+    /// `CREATE_NEW_CONSOLE` and `CREATE_NEW_PROCESS_GROUP`.
+    /// An error is returned if the flags are contradictory, in which
+    /// case the flags are left unchanged.
+    pub fn set_creation_flags(&mut self, flags: DWORD) -> IoResult<()> {
+        validate_creation_flags(flags)?;
+        self.creation_flags = flags;
+        Ok(())
+    }
+
+    /// Add to the process creation flags; see `set_creation_flags`
+    pub fn add_creation_flags(&mut self, flags: DWORD) -> IoResult<()> {
+        self.set_creation_flags(self.creation_flags | flags)
+    }
+
     pub fn set_argv(&mut self, argv: Vec<OsString>) {
         self.args = argv;
+        self.shell_command = false;
+    }
+
+    /// Run `line` through the command interpreter, as `cmd /s /c "line"`,
+    /// so that pipelines, redirections and builtins such as `dir` can be
+    /// used.  The interpreter is the one named by `COMSPEC` in the
+    /// environment of the command, falling back to `cmd.exe`.
+    /// `line` reaches cmd exactly as given, with no quoting applied, and
+    /// so has cmd's parsing semantics: `&`, `|`, `<`, `>` and `^` are
+    /// special unless quoted, and `%VAR%` references are expanded.
+    /// This replaces any arguments set by `set_argv`; response files are
+    /// not used, as cmd doesn't understand them.
+    pub fn set_shell_command<S: Into<OsString>>(&mut self, line: S) {
+        let comspec = parse_env_block(&self.env)
+            .iter()
+            .map(|entry| split_env_entry(entry))
+            .find(|(key, _)| env_key_eq(key, OsStr::new("COMSPEC")))
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| "cmd.exe".into());
+        self.args = vec![comspec, "/s".into(), "/c".into(), line.into()];
+        self.shell_command = true;
+    }
+
+    /// Launch `program`, rather than the program named by the first
+    /// argument.  Unless `set_argv0` is also used, the child sees
+    /// `program` as its first argument, in place of the first of those
+    /// passed to `set_argv`, which is otherwise ignored.
+    /// When path search is disabled, `program` must be the path of
+    /// the executable, as the system doesn't search for it.
+    pub fn set_program<S: Into<OsString>>(&mut self, program: S) {
+        self.program = Some(program.into());
+    }
+
+    /// Present `name` to the child as its first argument, which some
+    /// programs, such as multi-call binaries, use to decide what to do.
+    /// The program that is launched is still the one named by
+    /// `set_program`, or by the first argument passed to `set_argv`.
+    /// This has no effect with `shell_execute`, which has no way to
+    /// pass on the first argument.
+    pub fn set_argv0<S: Into<OsString>>(&mut self, name: S) {
+        self.argv0 = Some(name.into());
+    }
+
+    /// The program to launch.
+    /// The spawn methods check that there is one before calling this.
+    fn program(&self) -> &OsStr {
+        self.program.as_deref().unwrap_or_else(|| &self.args[0])
+    }
+
+    /// The first argument that the child sees
+    fn argv0(&self) -> &OsStr {
+        self.argv0
+            .as_deref()
+            .or(self.program.as_deref())
+            .unwrap_or_else(|| &self.args[0])
+    }
+
+    /// Opt in to passing the arguments via a response file if the
+    /// command line would otherwise be longer than `threshold` UTF-16
+    /// code units; the command line is limited to 32767 units, and
+    /// less than that in the case of `shell_execute`.
+    /// When this happens, the arguments following the program name
+    /// are written to a temporary UTF-16 file and the program is
+    /// invoked with the single argument `@path\to\file`.
+    ///
+    /// Only some programs (such as compilers and linkers) understand
+    /// response files, so only use this with programs that are known
+    /// to support them in this form.  The file is created in the temp
+    /// directory of the current user, so it may not be readable by a
+    /// program running as a different user.
+    /// The file is removed when the `Process` returned from the spawn
+    /// method is dropped, so keep that around until the program has
+    /// exited.
+    pub fn use_response_file_over(&mut self, threshold: usize) {
+        self.response_file_threshold = Some(threshold);
+    }
+
+    /// When enabled, the spawned process doesn't share the console of
+    /// this process, and so can't read from or write to it.  It is
+    /// created with `DETACHED_PROCESS`, so has no console at all, unless
+    /// `CREATE_NEW_CONSOLE` or `CREATE_NO_WINDOW` was passed to
+    /// `set_creation_flags`, in which case it gets a console of its own.
+    /// The stdio handles of this process are not passed on either, so
+    /// only those set via `set_stdin`, `set_stdout` and `set_stderr`
+    /// are available to the child, and nothing is inherited unless a
+    /// stdio handle is set or `inherit_handle` is used.
+    /// This has no effect on `spawn_with_pty`, whose child already has
+    /// the pseudo console as its console, nor on `shell_execute`.
+    pub fn set_detach_console(&mut self, detach_console: bool) {
+        self.detach_console = detach_console;
+    }
+
+    /// When enabled, the spawned process is created with
+    /// `CREATE_NO_WINDOW`, so that a console program gets a console
+    /// that has no window, rather than flashing one up when this
+    /// process has no console to share, such as in a service.
+    /// Use `set_stdin`, `set_stdout` and `set_stderr` to capture its
+    /// output, as nothing written to that console can be seen.
+    /// This has no effect on `shell_execute`, including the `runas`
+    /// verb, which must show the UAC prompt, nor on `spawn_with_pty`,
+    /// and it is overridden by `spawn_with_token`, which always gives
+    /// the program a console of its own.  Nor does it apply if
+    /// `CREATE_NEW_CONSOLE` or `DETACHED_PROCESS` was passed to
+    /// `set_creation_flags`, as those can't be combined with it.
+    pub fn set_no_window(&mut self, no_window: bool) {
+        self.no_window = no_window;
+    }
+
+    /// When enabled, the spawned process is placed into a job object
+    /// that terminates it, along with any processes that it spawns in
+    /// turn, when the job is closed.  The job is held by the `Process`
+    /// returned from the spawn method, so the whole tree is torn down
+    /// when that is dropped, or when we exit or are killed.
+    /// The process is created suspended and only resumed once it has
+    /// been assigned to the job, so that it cannot spawn anything
+    /// outside of the job.
+    /// This applies to the direct spawn methods; it has no effect when
+    /// launching via the `runas` verb.
+    pub fn set_kill_on_close(&mut self, kill_on_close: bool) {
+        self.kill_on_close = kill_on_close;
+    }
+
+    /// Control whether the program named by the first argument is
+    /// located in the path of the current process, via `resolve_program`,
+    /// before it is launched.  The default is true.
+    /// When disabled, the program is passed through verbatim and is
+    /// located by the system at spawn time instead, following the
+    /// rules of `CreateProcessW` or `ShellExecuteExW` as appropriate.
+    pub fn set_path_search(&mut self, path_search: bool) {
+        self.path_search = path_search;
+    }
+
+    /// When enabled, and the program is a shell that is known to be
+    /// able to skip its startup scripts, any arguments telling it to
+    /// do so are dropped, so that the session is set up just as in a
+    /// freshly opened terminal.  The shells that are recognized are:
+    /// * `cmd.exe`, which skips its `AutoRun` commands when given `/d`
+    /// * `powershell.exe` and `pwsh.exe`, which skip the profile scripts
+    ///   when given `-NoProfile` (or an abbreviation such as `-nop`)
+    ///
+    /// Only the options preceding the command to run (`/c` or `/k` for
+    /// cmd, `-Command`, `-File` or `-EncodedCommand` for PowerShell)
+    /// are considered.  Other programs are launched unchanged.
+    /// The default is false, which passes the arguments through
+    /// verbatim.
+    pub fn set_run_login_profile(&mut self, run_login_profile: bool) {
+        self.run_login_profile = run_login_profile;
+    }
+
+    /// The arguments following the first, adjusted for
+    /// `set_run_login_profile`
+    fn trailing_args(&self) -> Vec<&OsStr> {
+        let args = self.args.iter().skip(1).map(OsString::as_os_str);
+        if !self.run_login_profile {
+            return args.collect();
+        }
+        match LoginShell::for_program(self.program()) {
+            Some(shell) => shell.strip_profile_suppression(args),
+            None => args.collect(),
+        }
+    }
+
+    /// Run the program with the mandatory integrity label `label`,
+    /// rather than that of the token it is spawned with; see
+    /// `Token::with_mandatory_label` for the forms that it can take.
+    /// An error of kind `InvalidInput` is returned if `label` is not a
+    /// mandatory label sid.
+    /// The label is applied to a copy of the token passed to
+    /// `spawn_as_user` or `spawn_with_token`.  The other spawn methods
+    /// don't take a token to apply it to, so they fail while a label
+    /// is set.
+    pub fn set_mandatory_label(&mut self, label: &str) -> IoResult<()> {
+        parse_mandatory_label(label)?;
+        self.mandatory_label = Some(label.to_string());
+        Ok(())
+    }
+
+    /// Returns `token` with the label from `set_mandatory_label`
+    /// applied, if one was set
+    fn labelled_token(&self, token: &Token) -> IoResult<Option<Token>> {
+        match &self.mandatory_label {
+            Some(label) => token.with_mandatory_label(label).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Fail if `set_mandatory_label` was used, as `method` has no token
+    /// to apply it to
+    fn check_no_mandatory_label(&self, method: &str) -> IoResult<()> {
+        match &self.mandatory_label {
+            Some(label) => Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "mandatory label {} can't be applied by {}; \
+                     use spawn_as_user or spawn_with_token",
+                    label, method
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Set a function that is called with the STARTUPINFOW just before
+    /// the process is created by any of the spawn methods, allowing
+    /// fields that Command has no setter for (such as `lpDesktop`,
+    /// `lpTitle` or the window position) to be adjusted.
+    /// This is an advanced escape hatch: the hook runs after Command has
+    /// filled in the structure, so it can clobber the stdio handles,
+    /// flags and size that Command relies upon; changing `cb` or
+    /// `hStd*` is not supported.  Any pointers it stores must remain
+    /// valid until the spawn method returns.
+    /// The hook is called for each spawn, so may be called more than once.
+    /// It is not called when launching via `shell_execute`, including
+    /// the `runas` verb; use `before_shell_execute` for that.
+    pub fn before_spawn(&mut self, hook: Box<StartupInfoHook>) {
+        self.before_spawn = Some(hook);
     }
 
-    fn executable_and_command_line(&self, skip: usize) -> IoResult<(Vec<u16>, Vec<u16>)> {
-        let exe_path = PathBuf::from(self.args[0].clone());
-        let exe_path = if !exe_path.has_root() {
-            pathsearch::find_executable_in_path(&exe_path).ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("{:?} not found in the path", exe_path),
-                )
-            })?
+    /// Set a function that is called with the SHELLEXECUTEINFOW just
+    /// before `ShellExecuteExW` is invoked by `shell_execute`, allowing
+    /// it to be adjusted, for example to set `hwnd` so that the UAC
+    /// prompt is parented to a window.
+    /// As with `before_spawn`, this is an advanced escape hatch; clearing
+    /// `SEE_MASK_NOCLOSEPROCESS` from `fMask` means that no process
+    /// handle is returned, and the launch will be reported as failing.
+    /// The hook is called for each attempt made by
+    /// `shell_execute_with_retry`.
+    pub fn before_shell_execute(&mut self, hook: Box<ShellExecuteHook>) {
+        self.before_shell_execute = Some(hook);
+    }
+
+    /// Run the `before_spawn` hook, if any
+    fn run_before_spawn(&mut self, si: &mut STARTUPINFOW) {
+        if let Some(hook) = self.before_spawn.as_mut() {
+            hook(si);
+        }
+    }
+
+    /// Returns the application name to pass to the CreateProcess
+    /// family of functions.  When path search is disabled, this is
+    /// NULL, which makes them take the program from the command line
+    /// and search for it themselves, unless the first argument of the
+    /// command line differs from the program.
+    fn application_name(&self, exe: &mut Vec<u16>) -> LPWSTR {
+        if self.path_search || self.argv0() != self.program() {
+            exe.as_mut_ptr()
+        } else {
+            null_mut()
+        }
+    }
+
+    /// Returns the creation flags to pass when spawning the process
+    fn spawn_flags(&self) -> DWORD {
+        let mut flags = self.creation_flags;
+        if self.kill_on_close {
+            flags |= CREATE_SUSPENDED;
+        }
+        if self.no_window && flags & (CREATE_NEW_CONSOLE | DETACHED_PROCESS) == 0 {
+            flags |= CREATE_NO_WINDOW;
+        }
+        if self.detach_console && flags & (CREATE_NEW_CONSOLE | CREATE_NO_WINDOW) == 0 {
+            flags |= DETACHED_PROCESS;
+        }
+        flags
+    }
+
+    /// Whether to let the child inherit the inheritable handles of
+    /// this process; see `handles_to_inherit` for which ones it gets
+    fn inherits_handles(&self) -> bool {
+        !self.detach_console || !self.handles_to_inherit().is_empty()
+    }
+
+    /// Take ownership of the newly spawned process from `pi`,
+    /// assigning it to a job if `set_kill_on_close` was used, and
+    /// resuming it unless it was requested to start suspended.
+    fn spawned(&self, mut pi: ProcInfo, response_file: Option<ResponseFile>) -> IoResult<Process> {
+        let mut proc = pi.process().unwrap();
+        proc.set_response_file(response_file);
+        proc.set_group_leader(self.creation_flags & CREATE_NEW_PROCESS_GROUP != 0);
+        if self.kill_on_close {
+            let job = Job::new_kill_on_close().and_then(|job| {
+                job.assign(proc.as_handle())?;
+                Ok(job)
+            });
+            let job = match job {
+                Ok(job) => job,
+                Err(err) => {
+                    // Don't leave it suspended forever
+                    let _ = proc.terminate(1);
+                    return Err(err);
+                }
+            };
+            proc.set_job(job);
+            if self.creation_flags & CREATE_SUSPENDED == 0 {
+                proc.resume()?;
+            }
+        }
+        Ok(proc)
+    }
+
+    fn executable_and_command_line(
+        &self,
+        skip: usize,
+    ) -> IoResult<(Vec<u16>, Vec<u16>, Option<ResponseFile>)> {
+        if self.program.is_none() && self.args.is_empty() {
+            return Err(IoError::new(std::io::ErrorKind::InvalidInput, NoCommand));
+        }
+        let exe_path = if self.path_search {
+            resolve_program(self.program())?
         } else {
-            exe_path
+            PathBuf::from(self.program())
         };
 
         let executable = os_str_to_null_terminated_vec(&exe_path.as_os_str());
 
+        let args = self.trailing_args();
+        let argv = std::iter::once(self.argv0()).chain(args.iter().copied());
+        let last = args.len();
         let mut cmdline = Vec::<u16>::new();
-        for arg in self.args.iter().skip(skip) {
+        for (i, arg) in argv.enumerate().skip(skip) {
             if !cmdline.is_empty() {
                 cmdline.push(' ' as u16);
             }
-            append_quoted(&arg, &mut cmdline);
+            if self.shell_command && i == last {
+                // cmd /s strips exactly these quotes and takes the
+                // rest literally
+                cmdline.push('"' as u16);
+                cmdline.extend(arg.encode_wide());
+                cmdline.push('"' as u16);
+            } else {
+                append_quoted(&arg, &mut cmdline);
+            }
         }
         cmdline.push(0);
 
-        Ok((executable, cmdline))
+        let threshold = match self.response_file_threshold {
+            Some(threshold)
+                if cmdline.len() > threshold && !args.is_empty() && !self.shell_command =>
+            {
+                threshold
+            }
+            _ => return Ok((executable, cmdline, None)),
+        };
+
+        let response_file = ResponseFile::with_args(&args)?;
+        let mut cmdline = Vec::<u16>::new();
+        if skip == 0 {
+            append_quoted(self.argv0(), &mut cmdline);
+            cmdline.push(' ' as u16);
+        }
+        append_quoted(&response_file.argument(), &mut cmdline);
+        cmdline.push(0);
+        if cmdline.len() > threshold {
+            log::warn!(
+                "command line is still {} units long with a response file",
+                cmdline.len()
+            );
+        }
+
+        Ok((executable, cmdline, Some(response_file)))
     }
 
     pub fn set_stdin(&mut self, p: PipeHandle) -> IoResult<()> {
@@ -214,6 +756,94 @@ impl Command {
         Ok(())
     }
 
+    /// Arrange for `handle` to be inherited by the child, in addition
+    /// to any stdio handles.  The handle is marked as inheritable, but
+    /// remains owned by the caller.
+    ///
+    /// Once any handle has been added, or any of the stdio handles
+    /// has been set, the child inherits only those handles, rather
+    /// than every inheritable handle in this process.  This is arranged
+    /// using the `PROC_THREAD_ATTRIBUTE_HANDLE_LIST` attribute, and so
+    /// the `spawn`, `spawn_as_user` and `spawn_with_pty` methods
+    /// create the process with `EXTENDED_STARTUPINFO_PRESENT`.
+    /// None of `spawn_with_token`, `spawn_with_logon` or `shell_execute`
+    /// (and thus the "runas" route to elevation) can pass a handle list,
+    /// so the handles are not inherited when those are used.
+    ///
+    /// The handle must remain open until the child has been spawned;
+    /// otherwise an unrelated handle that reuses its value may be
+    /// passed on to the child.
+    pub fn inherit_handle<H: AsRawHandle>(&mut self, handle: &H) -> IoResult<()> {
+        let handle = handle.as_raw_handle() as HANDLE;
+        let res = unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT) };
+        if res == 0 {
+            return Err(win32_error_with_context(
+                "SetHandleInformation HANDLE_FLAG_INHERIT",
+                IoError::last_os_error(),
+            ));
+        }
+        self.inherit_handles.push(InheritHandle(handle));
+        Ok(())
+    }
+
+    /// Build the extended startup info, along with the attribute list
+    /// it refers to, which must be kept alive until the process has
+    /// been created.  The attribute list is `None` if no attributes
+    /// are required, in which case the plain `StartupInfo` should be used.
+    fn make_startup_info_ex(
+        &self,
+        psuedocon: Option<&PsuedoCon>,
+    ) -> IoResult<(STARTUPINFOEXW, Option<ProcThreadAttributeList>)> {
+        let mut si = STARTUPINFOEXW {
+            StartupInfo: self.make_startup_info(),
+            lpAttributeList: null_mut(),
+        };
+
+        let handles = self.handles_to_inherit();
+        let num_attributes = psuedocon.is_some() as DWORD + (!handles.is_empty()) as DWORD;
+        if num_attributes == 0 {
+            return Ok((si, None));
+        }
+
+        let mut attrs = ProcThreadAttributeList::with_capacity(num_attributes)?;
+        if let Some(psuedocon) = psuedocon {
+            attrs.set_pty(psuedocon.as_handle())?;
+        }
+        if !handles.is_empty() {
+            attrs.set_handle_list(handles)?;
+        }
+
+        si.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+        si.lpAttributeList = attrs.as_mut_ptr();
+        Ok((si, Some(attrs)))
+    }
+
+    /// The handles that the child should inherit: the stdio handles,
+    /// along with any passed to `inherit_handle`.
+    /// When this is not empty, the child is restricted to inheriting
+    /// exactly these handles.  Otherwise any other inheritable handles
+    /// in this process, such as those that other threads are in the
+    /// middle of passing to their own children, or the ends of pipes
+    /// that belong to the bridge server, would also be inherited and
+    /// could keep those pipes open in the child.
+    /// When it is empty, we leave things as they are so that the child
+    /// can inherit our own stdio handles in the usual way.
+    fn handles_to_inherit(&self) -> Vec<HANDLE> {
+        // The list must not contain duplicates
+        let mut handles: Vec<HANDLE> = vec![];
+        let stdio = [&self.stdin, &self.stdout, &self.stderr];
+        for handle in stdio
+            .iter()
+            .filter_map(|pipe| pipe.as_ref().map(|p| p.as_handle()))
+            .chain(self.inherit_handles.iter().map(|h| h.0))
+        {
+            if !handles.contains(&handle) {
+                handles.push(handle);
+            }
+        }
+        handles
+    }
+
     fn make_startup_info(&self) -> STARTUPINFOW {
         let mut si: STARTUPINFOW = unsafe { std::mem::zeroed() };
         si.cb = std::mem::size_of::<STARTUPINFOW>() as u32;
@@ -224,7 +854,11 @@ impl Command {
             si.wShowWindow = SW_HIDE as _;
         }
 
-        if self.stdin.is_some() || self.stdout.is_some() || self.stderr.is_some() {
+        if self.detach_console
+            || self.stdin.is_some()
+            || self.stdout.is_some()
+            || self.stderr.is_some()
+        {
             si.dwFlags |= STARTF_USESTDHANDLES;
             si.hStdInput = INVALID_HANDLE_VALUE;
             si.hStdOutput = INVALID_HANDLE_VALUE;
@@ -244,14 +878,66 @@ impl Command {
         si
     }
 
+    /// Launch the command via ShellExecuteExW using the specified verb.
+    /// If the verb is "runas" and the user declines the UAC prompt,
+    /// an error wrapping `ElevationDeclined` is returned; use
+    /// `is_elevation_declined` to test for that case.
+    /// If the launch doesn't yield a handle to a new process, there is
+    /// no exit code to report, and an error wrapping `NoProcessHandle`
+    /// is returned rather than a process that can't be waited for.
     pub fn shell_execute(&mut self, verb: &str) -> IoResult<Process> {
+        self.shell_execute_with_retry(verb, 1, Duration::from_millis(0))
+    }
+
+    /// Like `shell_execute`, but makes up to `attempts` attempts in the
+    /// face of transient system errors, sleeping between attempts for
+    /// `backoff`, doubling each time.
+    /// The user declining the UAC prompt is never retried, as that would
+    /// simply prompt them again.
+    pub fn shell_execute_with_retry(
+        &mut self,
+        verb: &str,
+        attempts: usize,
+        backoff: Duration,
+    ) -> IoResult<Process> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            let err = match self.shell_execute_once(verb) {
+                Ok(proc) => return Ok(proc),
+                Err(err) => err,
+            };
+            match classify_shell_execute_error(&err) {
+                ShellExecuteFailure::Declined => {
+                    return Err(IoError::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        ElevationDeclined,
+                    ))
+                }
+                ShellExecuteFailure::Transient if attempt < attempts => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                _ if err.raw_os_error().is_some() => {
+                    return Err(win32_error_with_context("ShellExecuteExW", err))
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Make a single attempt at ShellExecuteExW, returning the
+    /// underlying OS error on failure so that it can be classified
+    fn shell_execute_once(&mut self, verb: &str) -> IoResult<Process> {
+        self.check_no_mandatory_label("shell_execute")?;
         unsafe {
             CoInitializeEx(
                 null_mut(),
                 COINIT_APARTMENTTHREADED | COINIT_DISABLE_OLE1DDE,
             )
         };
-        let (exe, params) = self.executable_and_command_line(1)?;
+        let (exe, params, response_file) = self.executable_and_command_line(1)?;
         let cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
         let verb = os_str_to_null_terminated_vec(OsStr::new(verb));
 
@@ -277,32 +963,45 @@ impl Command {
             hProcess: null_mut(),
         };
 
+        if let Some(hook) = self.before_shell_execute.as_mut() {
+            hook(&mut info);
+        }
         let res = unsafe { ShellExecuteExW(&mut info) };
 
         if res == 0 {
-            Err(win32_error_with_context(
-                "ShellExecuteExW",
-                IoError::last_os_error(),
-            ))
+            Err(IoError::last_os_error())
+        } else if info.hProcess.is_null() {
+            // This happens when the request was handed off to an
+            // existing process, such as when the verb is handled
+            // via DDE; there is nothing for us to wait on.
+            Err(IoError::new(std::io::ErrorKind::Other, NoProcessHandle))
         } else {
-            Ok(Process::with_handle(info.hProcess))
+            let mut proc = Process::with_handle(info.hProcess);
+            proc.set_response_file(response_file);
+            Ok(proc)
         }
     }
 
     pub fn spawn_with_pty(&mut self, psuedocon: &PsuedoCon) -> IoResult<Process> {
-        let mut si = STARTUPINFOEXW {
-            StartupInfo: self.make_startup_info(),
-            lpAttributeList: null_mut(),
-        };
-        si.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+        // The pseudo console is not ours, so there is nothing to
+        // detach from, and the child needs its handles; it must also
+        // attach to the pseudo console rather than a console of its own
+        let detach_console = std::mem::replace(&mut self.detach_console, false);
+        let no_window = std::mem::replace(&mut self.no_window, false);
+        let res = self.spawn_in_pty(psuedocon);
+        self.detach_console = detach_console;
+        self.no_window = no_window;
+        res
+    }
 
-        let mut attrs = ProcThreadAttributeList::with_capacity(1)?;
-        attrs.set_pty(psuedocon.con)?;
-        si.lpAttributeList = attrs.as_mut_ptr();
+    fn spawn_in_pty(&mut self, psuedocon: &PsuedoCon) -> IoResult<Process> {
+        self.check_no_mandatory_label("spawn_with_pty")?;
+        let (mut si, _attrs) = self.make_startup_info_ex(Some(psuedocon))?;
 
         let mut pi = ProcInfo::new();
-        let (mut exe, mut command_line) = self.executable_and_command_line(0)?;
+        let (mut exe, mut command_line, response_file) = self.executable_and_command_line(0)?;
         let mut cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
+        self.run_before_spawn(&mut si.StartupInfo);
 
         let proc_attributes = null_mut();
         let thread_attributes = null_mut();
@@ -310,12 +1009,12 @@ impl Command {
 
         let res = unsafe {
             CreateProcessW(
-                exe.as_mut_ptr(),
+                self.application_name(&mut exe),
                 command_line.as_mut_ptr(),
                 proc_attributes,
                 thread_attributes,
                 inherit_handles as _,
-                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT | self.spawn_flags(),
                 self.env.as_mut_ptr() as *mut _,
                 cwd.as_mut_ptr(),
                 &mut si.StartupInfo,
@@ -328,31 +1027,38 @@ impl Command {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(pi.process().unwrap())
+            self.spawned(pi, response_file)
         }
     }
 
     pub fn spawn(&mut self) -> IoResult<Process> {
-        let mut si = self.make_startup_info();
+        self.check_no_mandatory_label("spawn")?;
+        let (mut si, attrs) = self.make_startup_info_ex(None)?;
+        let extended = if attrs.is_some() {
+            EXTENDED_STARTUPINFO_PRESENT
+        } else {
+            0
+        };
         let mut pi = ProcInfo::new();
-        let (mut exe, mut command_line) = self.executable_and_command_line(0)?;
+        let (mut exe, mut command_line, response_file) = self.executable_and_command_line(0)?;
         let mut cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
+        self.run_before_spawn(&mut si.StartupInfo);
 
         let proc_attributes = null_mut();
         let thread_attributes = null_mut();
-        let inherit_handles = true;
+        let inherit_handles = self.inherits_handles();
 
         let res = unsafe {
             CreateProcessW(
-                exe.as_mut_ptr(),
+                self.application_name(&mut exe),
                 command_line.as_mut_ptr(),
                 proc_attributes,
                 thread_attributes,
                 inherit_handles as _,
-                CREATE_UNICODE_ENVIRONMENT,
+                CREATE_UNICODE_ENVIRONMENT | extended | self.spawn_flags(),
                 self.env.as_mut_ptr() as *mut _,
                 cwd.as_mut_ptr(),
-                &mut si,
+                &mut si.StartupInfo,
                 &mut pi.0,
             )
         };
@@ -362,33 +1068,55 @@ impl Command {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(pi.process().unwrap())
+            self.spawned(pi, response_file)
         }
     }
 
+    /// Like `spawn`, but the primary thread of the process is created
+    /// suspended, so that nothing runs in the process until
+    /// `Process::resume` is called.  This allows for attaching a debugger
+    /// or applying limits to the process before it starts.
+    /// Only processes that are spawned directly can be resumed; there
+    /// is no equivalent for launching via the `runas` verb.
+    pub fn spawn_suspended(&mut self) -> IoResult<Process> {
+        let flags = self.creation_flags;
+        self.creation_flags |= CREATE_SUSPENDED;
+        let res = self.spawn();
+        self.creation_flags = flags;
+        res
+    }
+
     pub fn spawn_as_user(&mut self, token: &Token) -> IoResult<Process> {
-        let mut si = self.make_startup_info();
+        let labelled = self.labelled_token(token)?;
+        let token = labelled.as_ref().unwrap_or(token);
+        let (mut si, attrs) = self.make_startup_info_ex(None)?;
+        let extended = if attrs.is_some() {
+            EXTENDED_STARTUPINFO_PRESENT
+        } else {
+            0
+        };
         let mut pi = ProcInfo::new();
 
-        let (mut exe, mut command_line) = self.executable_and_command_line(0)?;
+        let (mut exe, mut command_line, response_file) = self.executable_and_command_line(0)?;
         let mut cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
+        self.run_before_spawn(&mut si.StartupInfo);
 
         let proc_attributes = null_mut();
         let thread_attributes = null_mut();
-        let inherit_handles = true;
+        let inherit_handles = self.inherits_handles();
 
         let res = unsafe {
             CreateProcessAsUserW(
                 token.token,
-                exe.as_mut_ptr(),
+                self.application_name(&mut exe),
                 command_line.as_mut_ptr(),
                 proc_attributes,
                 thread_attributes,
                 inherit_handles as _,
-                CREATE_UNICODE_ENVIRONMENT,
+                CREATE_UNICODE_ENVIRONMENT | extended | self.spawn_flags(),
                 self.env.as_mut_ptr() as *mut _,
                 cwd.as_mut_ptr(),
-                &mut si,
+                &mut si.StartupInfo,
                 &mut pi.0,
             )
         };
@@ -398,16 +1126,19 @@ impl Command {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(pi.process().unwrap())
+            self.spawned(pi, response_file)
         }
     }
 
     pub fn spawn_with_token(&mut self, token: &Token) -> IoResult<Process> {
+        let labelled = self.labelled_token(token)?;
+        let token = labelled.as_ref().unwrap_or(token);
         let mut si = self.make_startup_info();
 
         let mut pi = ProcInfo::new();
-        let (mut exe, mut command_line) = self.executable_and_command_line(0)?;
+        let (mut exe, mut command_line, response_file) = self.executable_and_command_line(0)?;
         let mut cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
+        self.run_before_spawn(&mut si);
 
         let logon_flags = 0;
 
@@ -415,8 +1146,9 @@ impl Command {
             CreateProcessWithTokenW(
                 token.token,
                 logon_flags,
-                exe.as_mut_ptr(),
+                self.application_name(&mut exe),
                 command_line.as_mut_ptr(),
+                self.spawn_flags()|
                 CREATE_UNICODE_ENVIRONMENT|
                 // Note that these flags are unconditionally or'd
                 // in by CreateProcessWithTokenW: they're included
@@ -436,9 +1168,456 @@ impl Command {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(pi.process().unwrap())
+            self.spawned(pi, response_file)
+        }
+    }
+
+    /// Spawn the command as the account identified by `username` and
+    /// `domain`, logging on with `password` via `CreateProcessWithLogonW`
+    /// and loading the profile of that account.  `domain` may be `None`
+    /// when `username` is in UPN (`user@domain`) form, and `"."` names
+    /// the local machine.
+    ///
+    /// This is intended for unattended, non-interactive scenarios such
+    /// as running a build step as a service account.  It does not
+    /// bypass UAC: the process receives whatever token the logon policy
+    /// grants to that account, which for an administrator that is
+    /// subject to UAC is the filtered, non-elevated token.
+    ///
+    /// The copies of the credentials made here are zeroed once the
+    /// process has been created; the caller is responsible for the
+    /// strings that it passes in.
+    /// As with `spawn_with_token`, the environment of the command is
+    /// passed along as is, so build it for the target account rather
+    /// than relying on that of the current process, and the handle
+    /// list from `inherit_handle` is not supported.
+    pub fn spawn_with_logon(
+        &mut self,
+        username: &str,
+        domain: Option<&str>,
+        password: &str,
+    ) -> IoResult<Process> {
+        self.check_no_mandatory_label("spawn_with_logon")?;
+        let credentials = LogonCredentials::new(username, domain, password);
+        let mut si = self.make_startup_info();
+
+        let mut pi = ProcInfo::new();
+        let (mut exe, mut command_line, response_file) = self.executable_and_command_line(0)?;
+        let mut cwd = os_str_to_null_terminated_vec(self.cwd.as_os_str());
+        self.run_before_spawn(&mut si);
+
+        let res = unsafe {
+            CreateProcessWithLogonW(
+                credentials.username.as_ptr(),
+                credentials.domain_ptr(),
+                credentials.password.as_ptr(),
+                LOGON_WITH_PROFILE,
+                self.application_name(&mut exe),
+                command_line.as_mut_ptr(),
+                self.spawn_flags() | CREATE_UNICODE_ENVIRONMENT,
+                self.env.as_mut_ptr() as *mut _,
+                cwd.as_mut_ptr(),
+                &mut si,
+                &mut pi.0,
+            )
+        };
+        // Capture the error before dropping the credentials, which
+        // could otherwise clobber it
+        let err = IoError::last_os_error();
+        drop(credentials);
+        if res != 1 {
+            Err(win32_error_with_context("CreateProcessWithLogonW", err))
+        } else {
+            self.spawned(pi, response_file)
+        }
+    }
+}
+
+/// A null terminated wide copy of one of the credentials passed to
+/// `Command::spawn_with_logon`.  The copy is overwritten with zeroes
+/// when this is dropped, so that the password doesn't linger in
+/// memory that has been returned to the allocator.
+struct WideSecret(Vec<u16>);
+
+impl WideSecret {
+    fn new(s: &str) -> Self {
+        let mut wide = Vec::with_capacity(s.len() + 1);
+        wide.extend(s.encode_utf16());
+        wide.push(0);
+        Self(wide)
+    }
+
+    fn as_ptr(&self) -> LPCWSTR {
+        self.0.as_ptr()
+    }
+
+    fn clear(&mut self) {
+        for c in self.0.iter_mut() {
+            // A volatile write can't be optimized away, even though
+            // the memory is about to be freed
+            unsafe { std::ptr::write_volatile(c, 0) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl Drop for WideSecret {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// The credentials for `CreateProcessWithLogonW`, marshalled into the
+/// form that it expects.
+struct LogonCredentials {
+    username: WideSecret,
+    domain: Option<WideSecret>,
+    password: WideSecret,
+}
+
+impl LogonCredentials {
+    fn new(username: &str, domain: Option<&str>, password: &str) -> Self {
+        Self {
+            username: WideSecret::new(username),
+            domain: domain.map(WideSecret::new),
+            password: WideSecret::new(password),
+        }
+    }
+
+    /// The domain is passed as NULL when not specified, which is
+    /// required when the username is in UPN (`user@domain`) form.
+    fn domain_ptr(&self) -> LPCWSTR {
+        self.domain
+            .as_ref()
+            .map(WideSecret::as_ptr)
+            .unwrap_or(std::ptr::null())
+    }
+}
+
+/// The PowerShell options that take a value, which may follow them as
+/// a separate argument.  PowerShell accepts prefixes of them too, and
+/// some short aliases, which `strip_profile_suppression` also checks.
+const POWERSHELL_VALUE_OPTIONS: &[&str] = &[
+    "executionpolicy",
+    "windowstyle",
+    "inputformat",
+    "outputformat",
+    "version",
+    "configurationname",
+    "configurationfile",
+    "psconsolefile",
+    "workingdirectory",
+    "settingsfile",
+    "custompipename",
+    "encodedarguments",
+];
+
+/// The shells that `Command::set_run_login_profile` knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoginShell {
+    Cmd,
+    PowerShell,
+}
+
+impl LoginShell {
+    fn for_program(program: &OsStr) -> Option<Self> {
+        let stem = std::path::Path::new(program)
+            .file_stem()?
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        match stem.as_str() {
+            "cmd" => Some(Self::Cmd),
+            "powershell" | "pwsh" => Some(Self::PowerShell),
+            _ => None,
+        }
+    }
+
+    /// Drop the options that tell the shell to skip its startup
+    /// scripts from `args`, which follow the program name; everything
+    /// from the command to run onwards is left alone
+    fn strip_profile_suppression<'a, I: Iterator<Item = &'a OsStr>>(
+        self,
+        args: I,
+    ) -> Vec<&'a OsStr> {
+        let mut result = vec![];
+        let mut in_options = true;
+        let mut takes_value = false;
+        for arg in args {
+            if in_options {
+                let option = arg.to_string_lossy().to_ascii_lowercase();
+                let name = match option.strip_prefix('/').or(option.strip_prefix('-')) {
+                    Some(name) => name,
+                    None => {
+                        // A bare word is either the value of the
+                        // previous option or, failing that, the command
+                        // or script to run, which ends the options
+                        in_options = takes_value;
+                        takes_value = false;
+                        result.push(arg);
+                        continue;
+                    }
+                };
+                takes_value = false;
+                match self {
+                    Self::Cmd => match name {
+                        "d" => continue,
+                        "c" | "k" | "r" => in_options = false,
+                        _ => {}
+                    },
+                    Self::PowerShell => {
+                        if name.len() >= 3 && "noprofile".starts_with(name) {
+                            continue;
+                        }
+                        let ends_options = ["command", "file", "encodedcommand"]
+                            .iter()
+                            .any(|c| !name.is_empty() && c.starts_with(name))
+                            || name == "ec";
+                        if ends_options {
+                            in_options = false;
+                        } else {
+                            takes_value = POWERSHELL_VALUE_OPTIONS
+                                .iter()
+                                .any(|o| !name.is_empty() && o.starts_with(name))
+                                || ["ep", "ex", "if", "of", "wd", "ea"].contains(&name);
+                        }
+                    }
+                }
+            }
+            result.push(arg);
+        }
+        result
+    }
+}
+
+/// Locate `program` the way that the shells do.  A bare name, such as
+/// `cmd`, is searched for in the path, while a name that includes a
+/// directory, such as `tools\foo` or `C:\tools\foo.exe`, is checked
+/// directly, relative to the current directory, rather than searched
+/// for.  In both cases the extensions in `PATHEXT` are tried if the
+/// name doesn't match a file as given.  Forward slashes are accepted
+/// in place of backslashes, and are converted to them.
+pub fn resolve_program(program: &OsStr) -> IoResult<PathBuf> {
+    let path = PathBuf::from(normalize_separators(program));
+    if path.components().count() == 1 && !path.has_root() {
+        return pathsearch::find_executable_in_path(&path).ok_or_else(|| {
+            IoError::new(
+                std::io::ErrorKind::NotFound,
+                format!("{:?} not found in the path", path),
+            )
+        });
+    }
+
+    let path: PathBuf = std::env::current_dir()?
+        .join(path)
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    with_path_extensions(&path)
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            IoError::new(
+                std::io::ErrorKind::NotFound,
+                format!("{:?} not found", path),
+            )
+        })
+}
+
+/// Replace the forward slashes in `path` with backslashes
+fn normalize_separators(path: &OsStr) -> OsString {
+    let wide: Vec<u16> = path
+        .encode_wide()
+        .map(|c| if c == b'/' as u16 { b'\\' as u16 } else { c })
+        .collect();
+    OsString::from_wide(&wide)
+}
+
+/// `path` itself, followed by `path` with each of the extensions in
+/// `PATHEXT` appended, in the same way as `pathsearch`
+fn with_path_extensions(path: &Path) -> Vec<PathBuf> {
+    let path_ext = std::env::var_os("PATHEXT").unwrap_or_else(|| ".EXE".into());
+    let mut candidates = vec![path.to_path_buf()];
+    for ext in std::env::split_paths(&path_ext) {
+        let mut extended = path.as_os_str().to_os_string();
+        extended.push(ext);
+        candidates.push(extended.into());
+    }
+    candidates
+}
+
+/// A temporary file that holds the arguments for a program, as
+/// arranged by `Command::use_response_file_over`.  The file is removed
+/// when this is dropped.
+pub(crate) struct ResponseFile {
+    path: PathBuf,
+}
+
+impl Drop for ResponseFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl ResponseFile {
+    fn with_args(args: &[&OsStr]) -> IoResult<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "eledo-args-{:x}-{:x}.rsp",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+
+        // A byte order mark lets the program know that this is UTF-16
+        let mut data = vec![0xfeff_u16];
+        for arg in args {
+            append_quoted(arg, &mut data);
+            data.extend(OsStr::new("\r\n").encode_wide());
+        }
+        let bytes: Vec<u8> = data.iter().flat_map(|c| c.to_le_bytes().to_vec()).collect();
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let response_file = Self { path };
+        file.write_all(&bytes)?;
+        Ok(response_file)
+    }
+
+    /// The argument that refers the program to this file
+    fn argument(&self) -> OsString {
+        let mut arg = OsString::from("@");
+        arg.push(&self.path);
+        arg
+    }
+}
+
+/// Reject combinations of process creation flags that CreateProcess
+/// either refuses or silently ignores
+fn validate_creation_flags(flags: DWORD) -> IoResult<()> {
+    let conflicts = [
+        (
+            DETACHED_PROCESS,
+            CREATE_NEW_CONSOLE,
+            "DETACHED_PROCESS",
+            "CREATE_NEW_CONSOLE",
+        ),
+        (
+            CREATE_NO_WINDOW,
+            CREATE_NEW_CONSOLE,
+            "CREATE_NO_WINDOW",
+            "CREATE_NEW_CONSOLE",
+        ),
+        (
+            CREATE_NO_WINDOW,
+            DETACHED_PROCESS,
+            "CREATE_NO_WINDOW",
+            "DETACHED_PROCESS",
+        ),
+    ];
+    for (a, b, a_name, b_name) in conflicts.iter() {
+        if flags & a != 0 && flags & b != 0 {
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} cannot be combined with {}", a_name, b_name),
+            ));
         }
     }
+    Ok(())
+}
+
+/// The error wrapped by the `std::io::Error` returned from
+/// `Command::shell_execute` when the user declines the UAC prompt
+#[derive(Debug)]
+pub struct ElevationDeclined;
+
+impl std::fmt::Display for ElevationDeclined {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the request to elevate was declined")
+    }
+}
+
+impl std::error::Error for ElevationDeclined {}
+
+/// Returns true if `err` indicates that the user declined the UAC prompt
+pub fn is_elevation_declined(err: &IoError) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<ElevationDeclined>())
+        .unwrap_or(false)
+}
+
+/// The error wrapped by the `std::io::Error` returned from
+/// `Command::shell_execute` when the launch succeeded but did not
+/// provide a handle to the launched process, so it can't be waited
+/// for and its exit code is unavailable
+#[derive(Debug)]
+pub struct NoProcessHandle;
+
+impl std::fmt::Display for NoProcessHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "the elevation launcher returned no process handle, \
+             so the exit code of the program is unavailable"
+        )
+    }
+}
+
+impl std::error::Error for NoProcessHandle {}
+
+/// Returns true if `err` indicates that the launched process
+/// handle was unavailable; see `NoProcessHandle`.
+pub fn is_no_process_handle(err: &IoError) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<NoProcessHandle>())
+        .unwrap_or(false)
+}
+
+/// The error wrapped by the `std::io::Error` returned from the spawn
+/// methods of a `Command` that has no program to run, because neither
+/// `set_argv` nor `set_program` provided one
+#[derive(Debug)]
+pub struct NoCommand;
+
+impl std::fmt::Display for NoCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no program was given to run")
+    }
+}
+
+impl std::error::Error for NoCommand {}
+
+/// Returns true if `err` indicates that there was no program to run;
+/// see `NoCommand`.
+pub fn is_no_command(err: &IoError) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<NoCommand>())
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShellExecuteFailure {
+    /// The user declined the UAC prompt
+    Declined,
+    /// A system error that may succeed if we try again
+    Transient,
+    /// Everything else
+    Permanent,
+}
+
+fn classify_shell_execute_error(err: &IoError) -> ShellExecuteFailure {
+    match err.raw_os_error().map(|code| code as DWORD) {
+        Some(ERROR_CANCELLED) => ShellExecuteFailure::Declined,
+        Some(ERROR_BUSY)
+        | Some(ERROR_DDE_FAIL)
+        | Some(ERROR_NOT_ENOUGH_MEMORY)
+        | Some(ERROR_OUTOFMEMORY)
+        | Some(ERROR_SHARING_VIOLATION)
+        | Some(ERROR_TIMEOUT)
+        | Some(RPC_S_CALL_FAILED)
+        | Some(RPC_S_SERVER_TOO_BUSY)
+        | Some(RPC_S_SERVER_UNAVAILABLE) => ShellExecuteFailure::Transient,
+        _ => ShellExecuteFailure::Permanent,
+    }
 }
 
 // Borrowed from https://github.com/wez/wezterm/blob/65707aba56f940f8c370f0465f0f3f2a6303a9cc/pty/src/cmdbuilder.rs#L313
@@ -488,3 +1667,657 @@ fn append_quoted(arg: &OsStr, cmdline: &mut Vec<u16>) {
     }
     cmdline.push('"' as u16);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, WAIT_TIMEOUT};
+    use winapi::um::minwinbase::STILL_ACTIVE;
+
+    fn classify(code: DWORD) -> ShellExecuteFailure {
+        classify_shell_execute_error(&IoError::from_raw_os_error(code as i32))
+    }
+
+    #[test]
+    fn pty_attribute() {
+        let input = PipePair::new().unwrap();
+        let output = PipePair::new().unwrap();
+        let size = winapi::um::wincontypes::COORD { X: 80, Y: 25 };
+        let con = PsuedoCon::new(size, None, input.read, output.write).unwrap();
+        let token = Token::with_current_process().unwrap();
+        let cmd = Command::with_environment_for_token(&token).unwrap();
+
+        // The child is only attached to the pty via the attribute list
+        let (si, attrs) = cmd.make_startup_info_ex(Some(&con)).unwrap();
+        let mut attrs = attrs.unwrap();
+        assert_eq!(si.lpAttributeList, attrs.as_mut_ptr());
+        assert_eq!(
+            si.StartupInfo.cb as usize,
+            std::mem::size_of::<STARTUPINFOEXW>()
+        );
+
+        let (si, attrs) = cmd.make_startup_info_ex(None).unwrap();
+        assert!(attrs.is_none());
+        assert!(si.lpAttributeList.is_null());
+    }
+
+    #[test]
+    fn login_profile_options() {
+        let strip = |program: &str, args: &[&str]| {
+            let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+            match LoginShell::for_program(OsStr::new(program)) {
+                Some(shell) => shell
+                    .strip_profile_suppression(args.into_iter())
+                    .into_iter()
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>(),
+                None => args
+                    .into_iter()
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .collect(),
+            }
+        };
+
+        assert_eq!(strip("cmd.exe", &["/d", "/k"]), vec!["/k"]);
+        assert_eq!(
+            strip(
+                r"C:\Windows\System32\CMD.EXE",
+                &["/Q", "/D", "/c", "echo", "/d"]
+            ),
+            vec!["/Q", "/c", "echo", "/d"]
+        );
+        assert_eq!(
+            strip(
+                "pwsh",
+                &[
+                    "-nop",
+                    "-ExecutionPolicy",
+                    "Bypass",
+                    "-NoProfile",
+                    "-NoExit"
+                ]
+            ),
+            vec!["-ExecutionPolicy", "Bypass", "-NoExit"]
+        );
+        assert_eq!(
+            strip(
+                "powershell.exe",
+                &["-NoProfile", "-c", "echo", "-NoProfile"]
+            ),
+            vec!["-c", "echo", "-NoProfile"]
+        );
+        // The arguments of a script are its own
+        assert_eq!(
+            strip("pwsh", &["-nop", "script.ps1", "-NoProfile"]),
+            vec!["script.ps1", "-NoProfile"]
+        );
+        assert_eq!(
+            strip("pwsh", &["-ep", "Bypass", "-nop", "script.ps1", "-nop"]),
+            vec!["-ep", "Bypass", "script.ps1", "-nop"]
+        );
+        // No is too short to be taken for -NoProfile
+        assert_eq!(strip("pwsh", &["-no"]), vec!["-no"]);
+        assert_eq!(strip("bash.exe", &["/d"]), vec!["/d"]);
+    }
+
+    #[test]
+    fn logon_credentials_marshalling() {
+        let wide = |s: &str| s.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+
+        let creds = LogonCredentials::new("builder", Some("."), "hunter2");
+        assert_eq!(creds.username.0, wide("builder"));
+        assert_eq!(creds.domain.as_ref().unwrap().0, wide("."));
+        assert_eq!(creds.password.0, wide("hunter2"));
+        assert_eq!(creds.domain_ptr(), creds.domain.as_ref().unwrap().as_ptr());
+
+        let creds = LogonCredentials::new("builder@example.com", None, "p\u{e4}ss");
+        assert!(creds.domain_ptr().is_null());
+        assert_eq!(creds.password.0, wide("p\u{e4}ss"));
+
+        let mut secret = WideSecret::new("hunter2");
+        secret.clear();
+        assert_eq!(secret.0.len(), 8);
+        assert!(secret.0.iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn shell_execute_error_classification() {
+        assert_eq!(classify(ERROR_CANCELLED), ShellExecuteFailure::Declined);
+        assert_eq!(classify(ERROR_BUSY), ShellExecuteFailure::Transient);
+        assert_eq!(classify(ERROR_TIMEOUT), ShellExecuteFailure::Transient);
+        assert_eq!(
+            classify(ERROR_FILE_NOT_FOUND),
+            ShellExecuteFailure::Permanent
+        );
+        assert_eq!(
+            classify(ERROR_ACCESS_DENIED),
+            ShellExecuteFailure::Permanent
+        );
+
+        // Errors that didn't come from the OS are never retried
+        let err = IoError::new(std::io::ErrorKind::Other, "not found in the path");
+        assert_eq!(
+            classify_shell_execute_error(&err),
+            ShellExecuteFailure::Permanent
+        );
+    }
+
+    #[test]
+    fn spawn_with_inherited_handle() {
+        let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 3".into()]);
+        cmd.inherit_handle(&file).unwrap();
+        // Passing the same handle twice must not produce a duplicate
+        // entry in the handle list
+        cmd.inherit_handle(&file).unwrap();
+
+        let proc = cmd.spawn().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 3);
+    }
+
+    #[test]
+    fn creation_flags() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_creation_flags(CREATE_NEW_PROCESS_GROUP).unwrap();
+        cmd.add_creation_flags(CREATE_NO_WINDOW).unwrap();
+        assert_eq!(
+            cmd.creation_flags,
+            CREATE_NEW_PROCESS_GROUP | CREATE_NO_WINDOW
+        );
+
+        assert!(cmd.add_creation_flags(CREATE_NEW_CONSOLE).is_err());
+        assert_eq!(
+            cmd.creation_flags,
+            CREATE_NEW_PROCESS_GROUP | CREATE_NO_WINDOW
+        );
+
+        assert!(validate_creation_flags(DETACHED_PROCESS | CREATE_NEW_CONSOLE).is_err());
+        assert!(validate_creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP).is_ok());
+    }
+
+    #[test]
+    fn no_window() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        assert_eq!(cmd.spawn_flags() & CREATE_NO_WINDOW, 0);
+
+        cmd.set_no_window(true);
+        assert_eq!(cmd.spawn_flags(), CREATE_NO_WINDOW);
+        // The child then has a console of its own, so isn't detached
+        cmd.set_detach_console(true);
+        assert_eq!(cmd.spawn_flags(), CREATE_NO_WINDOW);
+        cmd.set_detach_console(false);
+
+        // It can't be combined with an explicit choice of console
+        cmd.set_creation_flags(CREATE_NEW_CONSOLE).unwrap();
+        assert_eq!(cmd.spawn_flags(), CREATE_NEW_CONSOLE);
+
+        cmd.set_creation_flags(0).unwrap();
+        cmd.set_stdout(PipeHandle::open_pipe("NUL").unwrap())
+            .unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 4".into()]);
+        let proc = cmd.spawn().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 4);
+    }
+
+    #[test]
+    fn spawn_suspended() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 3".into()]);
+        let proc = cmd.spawn_suspended().unwrap();
+        assert_eq!(cmd.creation_flags, 0);
+
+        // The process exists, but it can't exit until it is resumed
+        assert_eq!(proc.exit_code().unwrap(), STILL_ACTIVE);
+        assert_eq!(proc.wait_for(Some(500)).unwrap(), WAIT_TIMEOUT);
+
+        proc.resume().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 3);
+
+        // A clone doesn't have the thread handle
+        assert!(proc.try_clone().unwrap().resume().is_err());
+    }
+
+    #[test]
+    fn shell_command_line() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_shell_command(r#"echo "a  b"|findstr "a"&echo c"#);
+
+        let output = PipePair::new().unwrap();
+        cmd.set_stdout(output.write).unwrap();
+        let proc = cmd.spawn().unwrap();
+        drop(cmd);
+        let mut echoed = String::new();
+        let mut read = output.read;
+        std::io::Read::read_to_string(&mut read, &mut echoed).unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(echoed, "\"a  b\"\r\nc\r\n");
+    }
+
+    #[test]
+    fn detached_console() {
+        let token = Token::with_current_process().unwrap();
+        let console_check = |detach: bool| {
+            let mut cmd = Command::with_environment_for_token(&token).unwrap();
+            cmd.set_shell_command("(type nul>CONOUT$) 2>nul && echo shared || echo detached");
+            cmd.set_detach_console(detach);
+            let output = PipePair::new().unwrap();
+            cmd.set_stdout(output.write).unwrap();
+            let proc = cmd.spawn().unwrap();
+            drop(cmd);
+            let mut echoed = String::new();
+            let mut read = output.read;
+            std::io::Read::read_to_string(&mut read, &mut echoed).unwrap();
+            proc.wait_for(None).unwrap();
+            echoed
+        };
+
+        assert_eq!(console_check(true), "detached\r\n");
+        if PipeHandle::open_pipe("CONOUT$").is_ok() {
+            // Without it, the child shares the console of the test runner
+            assert_eq!(console_check(false), "shared\r\n");
+        }
+    }
+
+    #[test]
+    fn argv0_override() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        // cmd.exe echoes its own command line, so we can see the
+        // first argument that it was given
+        cmd.set_argv(vec![
+            "ignored".into(),
+            "/c".into(),
+            "echo".into(),
+            "%CMDCMDLINE%".into(),
+        ]);
+        cmd.set_program(resolve_program(OsStr::new("cmd.exe")).unwrap());
+        cmd.set_argv0("multicall");
+        assert_eq!(cmd.argv0(), OsStr::new("multicall"));
+
+        let output = PipePair::new().unwrap();
+        cmd.set_stdout(output.write).unwrap();
+        let proc = cmd.spawn().unwrap();
+        drop(cmd);
+        let mut echoed = String::new();
+        let mut read = output.read;
+        std::io::Read::read_to_string(&mut read, &mut echoed).unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(echoed, "multicall /c echo %CMDCMDLINE%\r\n");
+
+        // The program stands in for argv[0] when that isn't overridden
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["ignored".into()]);
+        cmd.set_program("prog.exe");
+        assert_eq!(cmd.argv0(), OsStr::new("prog.exe"));
+    }
+
+    #[test]
+    fn before_spawn_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 4".into()]);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&calls);
+        cmd.before_spawn(Box::new(move |si| {
+            // Command has already filled in the structure
+            assert_eq!(si.cb as usize, std::mem::size_of::<STARTUPINFOW>());
+            si.dwFlags |= STARTF_USESHOWWINDOW;
+            si.wShowWindow = SW_HIDE as _;
+            seen.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        for _ in 0..2 {
+            let proc = cmd.spawn().unwrap();
+            proc.wait_for(None).unwrap();
+            assert_eq!(proc.exit_code().unwrap(), 4);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn resolve_program_with_directory() {
+        let cmd_exe = resolve_program(OsStr::new("cmd")).unwrap();
+        let lowercase = |path: PathBuf| path.to_string_lossy().to_lowercase();
+
+        // An absolute path is used directly, with forward slashes and
+        // without its extension
+        let forward = cmd_exe
+            .with_extension("")
+            .to_string_lossy()
+            .replace('\\', "/");
+        let resolved = resolve_program(OsStr::new(&forward)).unwrap();
+        assert_eq!(lowercase(resolved), lowercase(cmd_exe));
+
+        // A relative path is taken relative to the current directory,
+        // which is the root of the crate while testing, not searched for
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            resolve_program(OsStr::new("./src/lib.rs")).unwrap(),
+            cwd.join(r"src\lib.rs")
+        );
+        assert_eq!(
+            resolve_program(OsStr::new("src/lib.rs")).unwrap(),
+            cwd.join(r"src\lib.rs")
+        );
+        let err = resolve_program(OsStr::new("./cmd.exe")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            format!("{:?} not found", cwd.join("cmd.exe"))
+        );
+    }
+
+    #[test]
+    fn path_search() {
+        let cmd_exe = resolve_program(OsStr::new("cmd.exe")).unwrap();
+        assert!(cmd_exe.has_root());
+        assert_eq!(resolve_program(cmd_exe.as_os_str()).unwrap(), cmd_exe);
+        let err = resolve_program(OsStr::new("no-such-program-for-eledo.exe")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        // Without path search, CreateProcessW locates the program
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_path_search(false);
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 4".into()]);
+        let proc = cmd.spawn().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 4);
+
+        // and reports a program that it can't find when spawning
+        cmd.set_argv(vec!["no-such-program-for-eledo.exe".into()]);
+        let err = cmd.spawn().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn kill_on_close() {
+        let output = PipePair::new().unwrap();
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        // The child exits straight away, leaving a long running
+        // grandchild that holds the write end of the output pipe
+        cmd.set_argv(vec![
+            "cmd.exe".into(),
+            "/c".into(),
+            "start /b ping -n 60 127.0.0.1".into(),
+        ]);
+        cmd.set_stdout(output.write).unwrap();
+        cmd.set_kill_on_close(true);
+        let proc = cmd.spawn().unwrap();
+        proc.wait_for(None).unwrap();
+        drop(cmd);
+
+        // Reading reaches the end of the pipe once the grandchild is gone
+        let mut read = output.read;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 256];
+            while let Ok(len) = std::io::Read::read(&mut read, &mut buf) {
+                if len == 0 {
+                    break;
+                }
+            }
+            let _ = tx.send(());
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_err());
+
+        // Closing the job terminates the grandchild
+        drop(proc);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn response_file() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        let mut argv: Vec<OsString> = vec!["C:\\link.exe".into()];
+        argv.extend((0..100).map(|i| OsString::from(format!("object file {}.obj", i))));
+        cmd.set_argv(argv);
+
+        // Below the threshold, the arguments are passed directly
+        cmd.use_response_file_over(32767);
+        let (_, cmdline, response_file) = cmd.executable_and_command_line(0).unwrap();
+        assert!(response_file.is_none());
+        assert!(cmdline.len() > 100);
+
+        cmd.use_response_file_over(100);
+        let (_, cmdline, response_file) = cmd.executable_and_command_line(0).unwrap();
+        let response_file = response_file.unwrap();
+        let mut expect: Vec<u16> = OsStr::new("C:\\link.exe ").encode_wide().collect();
+        append_quoted(&response_file.argument(), &mut expect);
+        expect.push(0);
+        assert_eq!(cmdline, expect);
+
+        let data = std::fs::read(&response_file.path).unwrap();
+        assert_eq!(&data[0..2], &[0xff, 0xfe]);
+        let text: Vec<u16> = data[2..]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        let text = String::from_utf16(&text).unwrap();
+        assert!(text.starts_with("\"object file 0.obj\"\r\n"));
+        assert!(text.ends_with("\"object file 99.obj\"\r\n"));
+
+        let path = response_file.path.clone();
+        drop(response_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn child_only_inherits_its_stdio() {
+        let stdin = PipePair::new().unwrap();
+        // Stands in for the server end of some other pipe, which has
+        // been made inheritable by someone else
+        let leaked = PipePair::new().unwrap();
+        leaked.write.make_inheritable().unwrap();
+
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        // Waits for a line on stdin, which keeps the child alive
+        // until we close our end of its stdin
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "set /p line=".into()]);
+        cmd.set_stdin(stdin.read).unwrap();
+        let proc = cmd.spawn().unwrap();
+
+        // If the child had inherited the write end of the leaked pipe,
+        // then reading from it would not reach EOF until the child exited
+        let PipePair { mut read, write } = leaked;
+        drop(write);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            let _ = tx.send(std::io::Read::read(&mut read, &mut buf).map(|_| ()));
+        });
+        let result = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(result.is_err());
+
+        drop(stdin.write);
+        proc.wait_for(None).unwrap();
+    }
+
+    fn env_block(entries: &[&str]) -> Vec<u16> {
+        build_env_block(entries.iter().map(OsString::from).collect())
+    }
+
+    #[test]
+    fn merge_env_case_insensitively() {
+        let entries = |list: &[&str]| list.iter().map(OsString::from).collect::<Vec<_>>();
+        let merged = merge_env_entries(
+            entries(&["Path=C:\\parent", "TOOL_HOME=C:\\tool"]),
+            entries(&["PATH=C:\\profile", "USERNAME=someone"]),
+        );
+        assert_eq!(
+            merged,
+            entries(&["PATH=C:\\profile", "TOOL_HOME=C:\\tool", "USERNAME=someone"])
+        );
+    }
+
+    #[test]
+    fn environment_matches_block() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        let entries = |env: &[(OsString, OsString)]| {
+            env.iter()
+                .map(|(key, value)| {
+                    let mut entry = key.clone();
+                    entry.push("=");
+                    entry.push(value);
+                    entry
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let env = cmd.environment();
+        assert!(env
+            .iter()
+            .any(|(key, _)| env_key_eq(key, OsStr::new("SystemRoot"))));
+        assert_eq!(entries(&env), parse_env_block(&cmd.env));
+
+        cmd.set_env_allowlist(&[OsStr::new("windir")]);
+        let env = cmd.environment();
+        assert!(env.iter().all(|(key, _)| {
+            env_key_eq(key, OsStr::new("SystemRoot")) || env_key_eq(key, OsStr::new("windir"))
+        }));
+        assert_eq!(build_env_block(entries(&env)), cmd.env);
+
+        cmd.clear_env();
+        assert!(cmd.environment().is_empty());
+
+        // Keys may start with an `=`, and values may contain one
+        assert_eq!(
+            split_env_entry(OsStr::new("=C:=C:\\a=b")),
+            (OsString::from("=C:"), OsString::from("C:\\a=b"))
+        );
+        assert_eq!(
+            split_env_entry(OsStr::new("EMPTY=")),
+            (OsString::from("EMPTY"), OsString::new())
+        );
+    }
+
+    #[test]
+    fn merged_environment_reaches_child() {
+        std::env::set_var("DEELEVATE_MERGED_ENV_TEST", "42");
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_merged_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec![
+            "cmd.exe".into(),
+            "/c".into(),
+            "exit %DEELEVATE_MERGED_ENV_TEST%".into(),
+        ]);
+        let proc = cmd.spawn().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 42);
+    }
+
+    #[test]
+    fn env_block_sorted_and_terminated() {
+        let block = env_block(&["b=2", "A=1", "=C:=C:\\"]);
+        let expect: Vec<u16> = OsStr::new("=C:=C:\\\0A=1\0b=2\0\0").encode_wide().collect();
+        assert_eq!(block, expect);
+
+        assert_eq!(env_block(&[]), vec![0, 0]);
+    }
+
+    #[test]
+    fn env_allowlist() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.env = env_block(&["Path=C:\\bin", "SECRET=hunter2", "SYSTEMROOT=C:\\Windows"]);
+        cmd.set_env_allowlist(&[OsStr::new("PATH")]);
+
+        let entries = parse_env_block(&cmd.env);
+        assert_eq!(
+            entries,
+            vec![
+                OsString::from("Path=C:\\bin"),
+                OsString::from("SYSTEMROOT=C:\\Windows")
+            ]
+        );
+        assert!(!entries
+            .iter()
+            .any(|entry| env_key_eq(&env_entry_key(entry), OsStr::new("SECRET"))));
+        assert_eq!(&cmd.env[cmd.env.len() - 2..], &[0, 0]);
+
+        cmd.clear_env();
+        assert_eq!(cmd.env, vec![0, 0]);
+    }
+
+    #[test]
+    fn env_allowlist_reaches_child() {
+        std::env::set_var("DEELEVATE_ALLOWED_ENV_TEST", "kept");
+        std::env::set_var("DEELEVATE_DENIED_ENV_TEST", "leaked");
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_merged_environment_for_token(&token).unwrap();
+        cmd.set_env_allowlist(&[OsStr::new("deelevate_allowed_env_test")]);
+        // cmd leaves a reference to a variable that isn't set as it is
+        cmd.set_shell_command(
+            "echo [%DEELEVATE_ALLOWED_ENV_TEST%][%DEELEVATE_DENIED_ENV_TEST%][%PATH%][%SystemRoot%]",
+        );
+
+        let output = PipePair::new().unwrap();
+        cmd.set_stdout(output.write).unwrap();
+        let proc = cmd.spawn().unwrap();
+        drop(cmd);
+        let mut echoed = String::new();
+        let mut read = output.read;
+        std::io::Read::read_to_string(&mut read, &mut echoed).unwrap();
+        proc.wait_for(None).unwrap();
+        let system_root = std::env::var("SystemRoot").unwrap();
+        assert_eq!(
+            echoed,
+            format!(
+                "[kept][%DEELEVATE_DENIED_ENV_TEST%][%PATH%][{}]\r\n",
+                system_root
+            )
+        );
+    }
+
+    #[test]
+    fn elevation_declined() {
+        let err = IoError::new(std::io::ErrorKind::PermissionDenied, ElevationDeclined);
+        assert!(is_elevation_declined(&err));
+        assert!(!is_elevation_declined(&IoError::from_raw_os_error(
+            ERROR_CANCELLED as i32
+        )));
+    }
+
+    #[test]
+    fn missing_program() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec![]);
+        let err = cmd.spawn().err().unwrap();
+        assert!(is_no_command(&err), "{}", err);
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(is_no_command(&cmd.shell_execute("open").err().unwrap()));
+
+        cmd.set_argv(vec!["eledo-no-such-program".into()]);
+        let err = cmd.spawn().err().unwrap();
+        assert!(!is_no_command(&err));
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn no_process_handle() {
+        let err = IoError::new(std::io::ErrorKind::Other, NoProcessHandle);
+        assert!(is_no_process_handle(&err));
+        assert!(!is_elevation_declined(&err));
+        assert_eq!(
+            classify_shell_execute_error(&err),
+            ShellExecuteFailure::Permanent
+        );
+    }
+}
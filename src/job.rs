@@ -0,0 +1,75 @@
+//! Working with job objects
+use crate::win32_error_with_context;
+use std::io::{Error as IoError, Result as IoResult};
+use std::ptr::null_mut;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject};
+use winapi::um::winnt::{
+    JobObjectExtendedLimitInformation, HANDLE, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+};
+
+/// An owning wrapper around a job object handle.
+/// The job is configured so that the processes assigned to it, along
+/// with any processes that they spawn, are terminated when the last
+/// handle to it is closed.
+pub(crate) struct Job {
+    handle: HANDLE,
+}
+
+/// The compiler thinks it isn't send because HANDLE is a pointer
+/// type.  We happen to know that moving the handle between threads
+/// is totally fine, hence this impl.
+unsafe impl Send for Job {}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+impl Job {
+    /// Create an anonymous job that kills its processes when closed
+    pub fn new_kill_on_close() -> IoResult<Self> {
+        let handle = unsafe { CreateJobObjectW(null_mut(), null_mut()) };
+        if handle.is_null() {
+            return Err(win32_error_with_context(
+                "CreateJobObjectW",
+                IoError::last_os_error(),
+            ));
+        }
+        let job = Self { handle };
+
+        let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+        limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        let res = unsafe {
+            SetInformationJobObject(
+                job.handle,
+                JobObjectExtendedLimitInformation,
+                &mut limits as *mut _ as *mut _,
+                std::mem::size_of_val(&limits) as u32,
+            )
+        };
+        if res == 0 {
+            return Err(win32_error_with_context(
+                "SetInformationJobObject JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE",
+                IoError::last_os_error(),
+            ));
+        }
+        Ok(job)
+    }
+
+    /// Assign the process identified by `proc` to the job
+    pub fn assign(&self, proc: HANDLE) -> IoResult<()> {
+        if unsafe { AssignProcessToJobObject(self.handle, proc) } == 0 {
+            Err(win32_error_with_context(
+                "AssignProcessToJobObject",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
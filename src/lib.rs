@@ -1,9 +1,13 @@
 use std::ffi::OsStr;
 use std::io::Error as IoError;
 use std::os::windows::ffi::OsStrExt;
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_CANCELLED, ERROR_ELEVATION_REQUIRED, ERROR_PIPE_BUSY,
+};
 
 mod bridge;
 mod command;
+mod job;
 mod pipe;
 mod process;
 mod procthreadattr;
@@ -12,18 +16,75 @@ mod sid;
 mod spawn;
 mod token;
 
-pub use bridge::{BridgePtyClient, BridgeServer};
-pub use command::Command;
+pub use bridge::{
+    connect_stdio_pipes, decode_captured, is_startup_timeout, run_bridge_client, stream_is_pty,
+    BridgeArgs, BridgePtyClient, BridgeServer, BridgeTransport, CapturedOutput, ConsoleGeometry,
+    NewlineMode, OscCallback, ServeOutcome, StartupTimeout,
+};
+pub use command::{
+    is_elevation_declined, is_no_command, is_no_process_handle, resolve_program, Command,
+    ElevationDeclined, NoCommand, NoProcessHandle,
+};
 #[doc(hidden)]
-pub use pipe::PipeHandle;
-pub use spawn::{spawn_with_elevated_privileges, spawn_with_normal_privileges};
+pub use pipe::{NamedPipeServer, PipeHandle};
+pub use process::{Process, WaitOutcome, SHUTDOWN_EXIT_CODE};
+pub use psuedocon::{conpty_available, PsuedoCon, HPCON};
+pub use spawn::{
+    elevate_self, run_elevated_with_timeout, spawn_with_elevated_privileges,
+    spawn_with_normal_privileges,
+};
 pub use token::PrivilegeLevel;
 pub use token::Token;
 
 fn win32_error_with_context(context: &str, err: IoError) -> IoError {
-    IoError::new(err.kind(), format!("{}: {}", context, err))
+    match err.raw_os_error().and_then(win32_error_guidance) {
+        Some(guidance) => IoError::new(err.kind(), format!("{}: {}: {}", context, err, guidance)),
+        None => IoError::new(err.kind(), format!("{}: {}", context, err)),
+    }
+}
+
+/// Returns some guidance for the error codes whose system provided
+/// message is terse or misleading in the context of this crate.
+fn win32_error_guidance(code: i32) -> Option<&'static str> {
+    match code as u32 {
+        ERROR_ELEVATION_REQUIRED => {
+            Some("the program requires elevation; use the \"runas\" verb to trigger a UAC prompt")
+        }
+        ERROR_CANCELLED => Some("the UAC elevation prompt was declined by the user"),
+        ERROR_ACCESS_DENIED => Some(
+            "the token lacks the required access; check whether the process needs to be elevated",
+        ),
+        ERROR_PIPE_BUSY => {
+            Some("the pipe is already connected to another client; was the bridge launched twice?")
+        }
+        _ => None,
+    }
 }
 
 fn os_str_to_null_terminated_vec(s: &OsStr) -> Vec<u16> {
     s.encode_wide().chain(std::iter::once(0)).collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_guidance() {
+        assert_eq!(
+            win32_error_guidance(ERROR_CANCELLED as i32),
+            Some("the UAC elevation prompt was declined by the user")
+        );
+        assert!(win32_error_guidance(ERROR_PIPE_BUSY as i32).is_some());
+        assert_eq!(win32_error_guidance(0), None);
+
+        let err = win32_error_with_context(
+            "ShellExecuteExW",
+            IoError::from_raw_os_error(ERROR_CANCELLED as i32),
+        );
+        let message = err.to_string();
+        assert!(message.starts_with("ShellExecuteExW: "));
+        assert!(message.contains(&format!("(os error {})", ERROR_CANCELLED)));
+        assert!(message.ends_with("declined by the user"));
+    }
+}
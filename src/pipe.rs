@@ -1,25 +1,48 @@
 use crate::{os_str_to_null_terminated_vec, win32_error_with_context, Token};
-use std::io::{Error as IoError, Result as IoResult};
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::os::windows::prelude::*;
 use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::sync::atomic::AtomicUsize;
-use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+use std::time::{Duration, Instant};
+use winapi::shared::minwindef::{BOOL, DWORD};
+use winapi::shared::winerror::{
+    ERROR_BROKEN_PIPE, ERROR_FILE_NOT_FOUND, ERROR_IO_PENDING, ERROR_MORE_DATA, ERROR_NO_DATA,
+    ERROR_OPERATION_ABORTED, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, ERROR_PIPE_NOT_CONNECTED,
+    WAIT_TIMEOUT,
+};
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::fileapi::{CreateFileW, FlushFileBuffers, ReadFile, WriteFile, OPEN_EXISTING};
 use winapi::um::handleapi::{
     CloseHandle, DuplicateHandle, SetHandleInformation, INVALID_HANDLE_VALUE,
 };
-use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
-use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, CreatePipe};
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+use winapi::um::minwinbase::{OVERLAPPED, SECURITY_ATTRIBUTES};
+use winapi::um::namedpipeapi::{
+    ConnectNamedPipe, CreateNamedPipeW, CreatePipe, DisconnectNamedPipe, PeekNamedPipe,
+    SetNamedPipeHandleState, WaitNamedPipeW,
+};
 use winapi::um::processthreadsapi::GetCurrentProcess;
 use winapi::um::processthreadsapi::GetCurrentProcessId;
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
 use winapi::um::winbase::*;
 use winapi::um::winnt::{DUPLICATE_SAME_ACCESS, GENERIC_READ, GENERIC_WRITE, HANDLE};
 
+/// How long `open_pipe_waiting` sleeps between attempts to open a
+/// pipe that doesn't exist yet
+const PIPE_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The size of the input and output buffers of the named pipes that
+/// we create
+const PIPE_BUFFER_SIZE: DWORD = 4096;
+
 /// A little container type for holding a pipe file handle
 #[derive(Debug)]
-pub struct PipeHandle(HANDLE);
+pub struct PipeHandle {
+    handle: HANDLE,
+    /// Whether the handle was opened for overlapped I/O
+    overlapped: bool,
+}
 /// The compiler thinks it isn't send because HANDLE is a pointer
 /// type.  We happen to know that moving the handle between threads
 /// is totally fine, hence this impl.
@@ -27,7 +50,7 @@ unsafe impl Send for PipeHandle {}
 
 impl PipeHandle {
     pub fn make_inheritable(&self) -> IoResult<()> {
-        let res = unsafe { SetHandleInformation(self.0, HANDLE_FLAG_INHERIT, 1) };
+        let res = unsafe { SetHandleInformation(self.handle, HANDLE_FLAG_INHERIT, 1) };
         if res != 1 {
             Err(win32_error_with_context(
                 "SetHandleInformation HANDLE_FLAG_INHERIT",
@@ -38,19 +61,58 @@ impl PipeHandle {
         }
     }
 
+    /// Returns true if the handle was opened for overlapped I/O
+    pub fn is_overlapped(&self) -> bool {
+        self.overlapped
+    }
+
     pub fn as_handle(&self) -> HANDLE {
-        self.0
+        self.handle
     }
 
     pub fn create_named_pipe_byte_mode_for_token<P: AsRef<Path>>(
         name: P,
         token: &Token,
+    ) -> IoResult<Self> {
+        Self::create_named_pipe_for_token(name, token, 0, PIPE_TYPE_BYTE | PIPE_READMODE_BYTE)
+    }
+
+    /// Create a message mode named pipe, in which each write is read
+    /// back as a discrete message; see
+    /// `NamedPipeServer::for_token_message_mode`.
+    pub fn create_named_pipe_message_mode_for_token<P: AsRef<Path>>(
+        name: P,
+        token: &Token,
+    ) -> IoResult<Self> {
+        Self::create_named_pipe_for_token(name, token, 0, PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE)
+    }
+
+    /// Create a byte mode named pipe that is opened for overlapped
+    /// I/O.  This allows for waiting for a client with a timeout,
+    /// and also makes the pipe suitable for use with async runtimes.
+    pub fn create_overlapped_named_pipe_byte_mode_for_token<P: AsRef<Path>>(
+        name: P,
+        token: &Token,
+    ) -> IoResult<Self> {
+        Self::create_named_pipe_for_token(
+            name,
+            token,
+            FILE_FLAG_OVERLAPPED,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE,
+        )
+    }
+
+    fn create_named_pipe_for_token<P: AsRef<Path>>(
+        name: P,
+        token: &Token,
+        open_flags: DWORD,
+        pipe_mode: DWORD,
     ) -> IoResult<Self> {
         let descriptor = token.create_security_descriptor()?;
 
         let path = os_str_to_null_terminated_vec(name.as_ref().as_os_str());
         let max_instances = 1;
-        let buf_size = 4096;
+        let buf_size = PIPE_BUFFER_SIZE;
         let default_timeout_ms = 100;
         let mut security_attr = SECURITY_ATTRIBUTES {
             nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as _,
@@ -61,8 +123,8 @@ impl PipeHandle {
         let handle = unsafe {
             CreateNamedPipeW(
                 path.as_ptr(),
-                PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
-                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE | open_flags,
+                pipe_mode | PIPE_REJECT_REMOTE_CLIENTS,
                 max_instances,
                 buf_size,
                 buf_size,
@@ -71,7 +133,10 @@ impl PipeHandle {
             )
         };
         if handle != INVALID_HANDLE_VALUE {
-            Ok(Self(handle))
+            Ok(Self {
+                handle,
+                overlapped: open_flags & FILE_FLAG_OVERLAPPED != 0,
+            })
         } else {
             Err(win32_error_with_context(
                 "CreateNamedPipeW",
@@ -82,7 +147,69 @@ impl PipeHandle {
 
     /// Wait for a short period for a client to connect to
     /// this pipe instance.
-    pub fn wait_for_pipe_client(&self) -> IoResult<()> {
+    pub fn wait_for_pipe_client(&mut self) -> IoResult<()> {
+        if self.wait_for_pipe_client_timeout(std::time::Duration::from_millis(2500))? {
+            Ok(())
+        } else {
+            Err(IoError::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out waiting for pipe client to connect",
+            ))
+        }
+    }
+
+    /// Wait up to `timeout` for a client to connect to this pipe
+    /// instance.  Returns `Ok(false)` if that time elapsed without
+    /// a client connecting.
+    pub fn wait_for_pipe_client_timeout(&mut self, timeout: std::time::Duration) -> IoResult<bool> {
+        if !self.overlapped {
+            return self.wait_for_pipe_client_blocking(timeout);
+        }
+
+        let mut overlapped = Overlapped::new()?;
+        let res = unsafe { ConnectNamedPipe(self.handle, overlapped.as_mut_ptr()) };
+        if res == 0 {
+            match unsafe { GetLastError() } {
+                // The client connected before we got here
+                ERROR_PIPE_CONNECTED => return Ok(true),
+                ERROR_IO_PENDING => {}
+                _ => {
+                    return Err(win32_error_with_context(
+                        "ConnectNamedPipe",
+                        IoError::last_os_error(),
+                    ))
+                }
+            }
+        }
+
+        let timeout_ms = timeout.as_millis().min(INFINITE as u128 - 1) as DWORD;
+        match unsafe { WaitForSingleObject(overlapped.event, timeout_ms) } {
+            WAIT_OBJECT_0 => {
+                overlapped.result(self.handle, false, "ConnectNamedPipe")?;
+                Ok(true)
+            }
+            WAIT_TIMEOUT => {
+                unsafe { CancelIoEx(self.handle, overlapped.as_mut_ptr()) };
+                // Wait for the cancellation to complete; the client may
+                // have connected in the meantime, in which case the
+                // operation completes successfully.
+                match overlapped.result(self.handle, true, "ConnectNamedPipe") {
+                    Ok(_) => Ok(true),
+                    Err(err) if err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) => {
+                        Ok(false)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            _ => Err(win32_error_with_context(
+                "WaitForSingleObject(ConnectNamedPipe)",
+                IoError::last_os_error(),
+            )),
+        }
+    }
+
+    /// The fallback for pipes that were not opened for overlapped I/O
+    fn wait_for_pipe_client_blocking(&self, timeout: std::time::Duration) -> IoResult<bool> {
         // One does not simply do non-blocking pipe work.
         // We spawn a thread that will cancel all IO on this pipe if
         // we don't send it a message within the timeout.
@@ -93,16 +220,13 @@ impl PipeHandle {
         // pointer to another thread
         struct HandleHolder(HANDLE);
         unsafe impl Send for HandleHolder {}
-        let handle = HandleHolder(self.0);
+        let handle = HandleHolder(self.handle);
 
-        // This thread will cancel all IO on self.0 if not signalled
+        // This thread will cancel all IO on self.handle if not signalled
         // in time to stop it.
         std::thread::spawn(move || {
-            if rx
-                .recv_timeout(std::time::Duration::from_millis(2500))
-                .is_err()
-            {
-                unsafe { winapi::um::ioapiset::CancelIoEx(handle.0, null_mut()) };
+            if rx.recv_timeout(timeout).is_err() {
+                unsafe { CancelIoEx(handle.0, null_mut()) };
             }
         });
 
@@ -110,15 +234,17 @@ impl PipeHandle {
         // ensure that we send a message to the timeout thread before
         // we unwind.
         let res = (move || {
-            let res = unsafe { ConnectNamedPipe(self.0, null_mut()) };
+            let res = unsafe { ConnectNamedPipe(self.handle, null_mut()) };
             let err = unsafe { GetLastError() };
-            if res == 0 && err != ERROR_PIPE_CONNECTED {
+            if res == 0 && err == ERROR_OPERATION_ABORTED {
+                Ok(false)
+            } else if res == 0 && err != ERROR_PIPE_CONNECTED {
                 Err(win32_error_with_context(
                     "ConnectNamedPipe",
                     IoError::last_os_error(),
                 ))
             } else {
-                Ok(())
+                Ok(true)
             }
         })();
         let _ = tx.send(());
@@ -126,6 +252,145 @@ impl PipeHandle {
         res
     }
 
+    /// The client side of the connect handshake: send `nonce` to the
+    /// server and confirm that the server echoes it back, proving that
+    /// it is the server that launched us.
+    pub fn send_nonce(&mut self, nonce: &str) -> IoResult<()> {
+        self.write_all(nonce.as_bytes())?;
+        let mut echo = vec![0u8; nonce.len()];
+        self.read_exact(&mut echo)?;
+        check_nonce(&echo, nonce)
+    }
+
+    /// The server side of the connect handshake: read the nonce sent
+    /// by the client that just connected and, if it matches `nonce`,
+    /// echo it back.  Otherwise the client is disconnected and an
+    /// error is returned, as it isn't the client that we launched.
+    /// A client that doesn't send the nonce within `timeout` is
+    /// disconnected too, with a `TimedOut` error, so that it can't
+    /// hold on to the pipe; this requires an overlapped handle.
+    pub fn verify_nonce(&mut self, nonce: &str, timeout: Duration) -> IoResult<()> {
+        let mut received = vec![0u8; nonce.len()];
+        let res = self
+            .read_exact_timeout(&mut received, timeout)
+            .and_then(|complete| {
+                if complete {
+                    Ok(())
+                } else {
+                    Err(IoError::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out waiting for pipe client to send the nonce",
+                    ))
+                }
+            })
+            .and_then(|_| check_nonce(&received, nonce));
+        if let Err(err) = res {
+            unsafe {
+                DisconnectNamedPipe(self.handle);
+            }
+            return Err(err);
+        }
+        self.write_all(nonce.as_bytes())
+    }
+
+    /// Read exactly enough bytes to fill `buf`, giving up once `timeout`
+    /// has elapsed, in which case `Ok(false)` is returned and some of
+    /// the bytes may have been consumed.  A handle that wasn't opened
+    /// for overlapped I/O can't be read with a timeout, so this blocks
+    /// like `read_exact` for those.
+    pub fn read_exact_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> IoResult<bool> {
+        if !self.overlapped {
+            self.read_exact(buf)?;
+            return Ok(true);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let rest = &mut buf[filled..];
+            let mut overlapped = Overlapped::new()?;
+            let res = unsafe {
+                ReadFile(
+                    self.handle,
+                    rest.as_mut_ptr() as *mut _,
+                    rest.len() as _,
+                    null_mut(),
+                    overlapped.as_mut_ptr(),
+                )
+            };
+            if res == 0 {
+                let err = IoError::last_os_error();
+                if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                    return Err(pipe_io_error("ReadFile", err));
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let timeout_ms = remaining.as_millis().min(INFINITE as u128 - 1) as DWORD;
+            let timed_out = match unsafe { WaitForSingleObject(overlapped.event, timeout_ms) } {
+                WAIT_OBJECT_0 => false,
+                WAIT_TIMEOUT => {
+                    unsafe { CancelIoEx(self.handle, overlapped.as_mut_ptr()) };
+                    true
+                }
+                _ => {
+                    return Err(win32_error_with_context(
+                        "WaitForSingleObject(ReadFile)",
+                        IoError::last_os_error(),
+                    ))
+                }
+            };
+            // Wait for the cancellation to complete; the read may have
+            // completed in the meantime, in which case we keep the data
+            // and the next wait times out straight away.
+            match overlapped.raw_result(self.handle, true) {
+                Ok(0) => {
+                    return Err(IoError::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(num_read) => filled += num_read,
+                Err(err)
+                    if timed_out && err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) =>
+                {
+                    return Ok(false)
+                }
+                Err(err) => return Err(pipe_io_error("ReadFile", err)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of bytes that are waiting to be read from
+    /// the pipe, without blocking or consuming them.
+    /// If the other end has been closed, this is 0, the same as when
+    /// there is nothing to read yet; the next read will report the
+    /// end of the pipe.
+    pub fn peek(&self) -> IoResult<usize> {
+        let mut available: DWORD = 0;
+        let ok = unsafe {
+            PeekNamedPipe(
+                self.handle,
+                null_mut(),
+                0,
+                null_mut(),
+                &mut available,
+                null_mut(),
+            )
+        };
+        if ok == 0 {
+            let err = IoError::last_os_error();
+            if err.raw_os_error().is_some_and(is_pipe_closed_error) {
+                Ok(0)
+            } else {
+                Err(win32_error_with_context("PeekNamedPipe", err))
+            }
+        } else {
+            Ok(available as usize)
+        }
+    }
+
     pub fn duplicate(&self) -> IoResult<Self> {
         let proc = unsafe { GetCurrentProcess() };
         let mut duped = INVALID_HANDLE_VALUE;
@@ -134,7 +399,7 @@ impl PipeHandle {
         let res = unsafe {
             DuplicateHandle(
                 proc,
-                self.0,
+                self.handle,
                 proc,
                 &mut duped,
                 access,
@@ -148,13 +413,140 @@ impl PipeHandle {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(Self(duped))
+            Ok(Self {
+                handle: duped,
+                overlapped: self.overlapped,
+            })
         }
     }
 
+    /// Open an existing pipe, or one of the console pseudo-files,
+    /// for reading and writing without sharing.
     pub fn open_pipe<P: AsRef<Path>>(name: P) -> IoResult<Self> {
+        Self::open_pipe_with_access(name, GENERIC_READ | GENERIC_WRITE, 0)
+    }
+
+    /// Open an existing pipe with the specified `access` (a combination
+    /// of GENERIC_READ and GENERIC_WRITE) and `share_mode` (a combination
+    /// of FILE_SHARE_READ and FILE_SHARE_WRITE) passed through to CreateFileW.
+    ///
+    /// For the console pseudo-files:
+    /// * `CONIN$` requires GENERIC_READ to read input and GENERIC_WRITE
+    ///   to change its mode; use FILE_SHARE_READ to allow others to
+    ///   open it too.
+    /// * `CONOUT$` requires GENERIC_WRITE to write, and GENERIC_READ
+    ///   to query it with eg: GetConsoleScreenBufferInfo or GetConsoleMode;
+    ///   use FILE_SHARE_WRITE to allow others to open it too.
+    /// * Changing the mode of either requires both GENERIC_READ and
+    ///   GENERIC_WRITE.
+    pub fn open_pipe_with_access<P: AsRef<Path>>(
+        name: P,
+        access: DWORD,
+        share_mode: DWORD,
+    ) -> IoResult<Self> {
         let path = os_str_to_null_terminated_vec(name.as_ref().as_os_str());
-        let share_mode = 0;
+        Self::create_file(&path, access, share_mode).map_err(|err| {
+            win32_error_with_context(&format!("CreateFileW: {}", name.as_ref().display()), err)
+        })
+    }
+
+    /// Open the client end of a message mode pipe, such as one created
+    /// by `NamedPipeServer::for_token_message_mode`, switching it to
+    /// message read mode so that `read_message` returns whole messages.
+    pub fn open_pipe_message_mode<P: AsRef<Path>>(name: P) -> IoResult<Self> {
+        let pipe = Self::open_pipe(name)?;
+        let mut mode = PIPE_READMODE_MESSAGE;
+        let res =
+            unsafe { SetNamedPipeHandleState(pipe.handle, &mut mode, null_mut(), null_mut()) };
+        if res == 0 {
+            Err(win32_error_with_context(
+                "SetNamedPipeHandleState PIPE_READMODE_MESSAGE",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(pipe)
+        }
+    }
+
+    /// Read the next message from a pipe in message read mode.
+    /// A `read` with a buffer that is smaller than the message fails
+    /// with `ERROR_MORE_DATA`, leaving the rest of the message to be
+    /// read by the next call, and losing track of how much was read;
+    /// this instead keeps reading until the message is complete.
+    /// It requires a handle that was not opened for overlapped I/O.
+    pub fn read_message(&mut self) -> IoResult<Vec<u8>> {
+        if self.overlapped {
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "read_message doesn't support overlapped pipes",
+            ));
+        }
+        let mut message = vec![];
+        loop {
+            let start = message.len();
+            message.resize(start + PIPE_BUFFER_SIZE as usize, 0);
+            let mut num_read = 0;
+            let ok = unsafe {
+                ReadFile(
+                    self.handle,
+                    message[start..].as_mut_ptr() as *mut _,
+                    PIPE_BUFFER_SIZE,
+                    &mut num_read,
+                    null_mut(),
+                )
+            };
+            message.truncate(start + num_read as usize);
+            if ok != 0 {
+                return Ok(message);
+            }
+            let err = IoError::last_os_error();
+            if err.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+                return Err(pipe_io_error("ReadFile", err));
+            }
+        }
+    }
+
+    /// Like `open_pipe`, but waits up to `timeout` for the pipe to
+    /// become available.  The server may not have created the pipe
+    /// yet, or its only instance may still be connected to another
+    /// client; both cases are retried until `timeout` elapses, after
+    /// which the error from the final attempt is returned.
+    pub fn open_pipe_waiting<P: AsRef<Path>>(name: P, timeout: Duration) -> IoResult<Self> {
+        let path = os_str_to_null_terminated_vec(name.as_ref().as_os_str());
+        let deadline = Instant::now() + timeout;
+        loop {
+            let err = match Self::create_file(&path, GENERIC_READ | GENERIC_WRITE, 0) {
+                Ok(pipe) => return Ok(pipe),
+                Err(err) => err,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match err.raw_os_error().map(|code| code as u32) {
+                Some(ERROR_PIPE_BUSY) if !remaining.is_zero() => {
+                    // This returns when an instance is free or the time is
+                    // up; either way, the next attempt decides the outcome.
+                    // A timeout of 0 would mean the server's default and
+                    // INFINITE would wait forever, so avoid both.
+                    let timeout_ms = remaining.as_millis().clamp(1, (INFINITE - 1) as u128);
+                    unsafe {
+                        WaitNamedPipeW(path.as_ptr(), timeout_ms as DWORD);
+                    }
+                }
+                Some(ERROR_FILE_NOT_FOUND) if !remaining.is_zero() => {
+                    std::thread::sleep(remaining.min(PIPE_RETRY_INTERVAL));
+                }
+                _ => {
+                    return Err(win32_error_with_context(
+                        &format!("CreateFileW: {}", name.as_ref().display()),
+                        err,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Call CreateFileW on the null terminated `path`, returning the
+    /// unadorned OS error on failure so that callers can inspect it
+    fn create_file(path: &[u16], access: DWORD, share_mode: DWORD) -> IoResult<Self> {
         let mut security_attr = SECURITY_ATTRIBUTES {
             nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as _,
             lpSecurityDescriptor: null_mut(),
@@ -166,7 +558,7 @@ impl PipeHandle {
         let handle = unsafe {
             CreateFileW(
                 path.as_ptr(),
-                GENERIC_READ | GENERIC_WRITE,
+                access,
                 share_mode,
                 &mut security_attr,
                 OPEN_EXISTING,
@@ -175,13 +567,12 @@ impl PipeHandle {
             )
         };
         if handle != INVALID_HANDLE_VALUE {
-            Ok(Self(handle))
+            Ok(Self {
+                handle,
+                overlapped: false,
+            })
         } else {
-            let err = IoError::last_os_error();
-            Err(win32_error_with_context(
-                &format!("CreateFileW: {}", name.as_ref().display()),
-                err,
-            ))
+            Err(IoError::last_os_error())
         }
     }
 }
@@ -192,20 +583,41 @@ impl AsRawHandle for PipeHandle {
     }
 }
 
+impl IntoRawHandle for PipeHandle {
+    fn into_raw_handle(self) -> RawHandle {
+        let handle = self.as_handle() as RawHandle;
+        std::mem::forget(self);
+        handle
+    }
+}
+
 impl Drop for PipeHandle {
     fn drop(&mut self) {
         unsafe {
-            CloseHandle(self.0);
+            CloseHandle(self.handle);
         }
     }
 }
 
 impl std::io::Read for PipeHandle {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.overlapped {
+            let handle = self.handle;
+            return overlapped_io("ReadFile", handle, |overlapped| unsafe {
+                ReadFile(
+                    handle,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as _,
+                    null_mut(),
+                    overlapped,
+                )
+            });
+        }
+
         let mut num_read = 0;
         let ok = unsafe {
             ReadFile(
-                self.0,
+                self.handle,
                 buf.as_mut_ptr() as *mut _,
                 buf.len() as _,
                 &mut num_read,
@@ -214,7 +626,7 @@ impl std::io::Read for PipeHandle {
         };
         if ok == 0 {
             let err = IoError::last_os_error();
-            Err(win32_error_with_context("ReadFile", err))
+            Err(pipe_io_error("ReadFile", err))
         /*
         if err.kind() == std::io::ErrorKind::BrokenPipe {
             Ok(0)
@@ -230,10 +642,23 @@ impl std::io::Read for PipeHandle {
 
 impl std::io::Write for PipeHandle {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.overlapped {
+            let handle = self.handle;
+            return overlapped_io("WriteFile", handle, |overlapped| unsafe {
+                WriteFile(
+                    handle,
+                    buf.as_ptr() as *const _,
+                    buf.len() as u32,
+                    null_mut(),
+                    overlapped,
+                )
+            });
+        }
+
         let mut num_wrote = 0;
         let ok = unsafe {
             WriteFile(
-                self.0,
+                self.handle,
                 buf.as_ptr() as *const _,
                 buf.len() as u32,
                 &mut num_wrote,
@@ -241,17 +666,14 @@ impl std::io::Write for PipeHandle {
             )
         };
         if ok == 0 {
-            Err(win32_error_with_context(
-                "WriteFile",
-                IoError::last_os_error(),
-            ))
+            Err(pipe_io_error("WriteFile", IoError::last_os_error()))
         } else {
             Ok(num_wrote as usize)
         }
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        if unsafe { FlushFileBuffers(self.0) } != 1 {
+        if unsafe { FlushFileBuffers(self.handle) } != 1 {
             Err(win32_error_with_context(
                 "FlushFileBuffers",
                 IoError::last_os_error(),
@@ -262,23 +684,179 @@ impl std::io::Write for PipeHandle {
     }
 }
 
+/// An OVERLAPPED struct along with the event that is
+/// signalled when the associated operation completes
+struct Overlapped {
+    overlapped: OVERLAPPED,
+    event: HANDLE,
+}
+
+impl Drop for Overlapped {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.event);
+        }
+    }
+}
+
+impl Overlapped {
+    fn new() -> IoResult<Self> {
+        let manual_reset = true;
+        let initial_state = false;
+        let event = unsafe {
+            CreateEventW(
+                null_mut(),
+                manual_reset as _,
+                initial_state as _,
+                null_mut(),
+            )
+        };
+        if event.is_null() {
+            return Err(win32_error_with_context(
+                "CreateEventW",
+                IoError::last_os_error(),
+            ));
+        }
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        overlapped.hEvent = event;
+        Ok(Self { overlapped, event })
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut OVERLAPPED {
+        &mut self.overlapped
+    }
+
+    /// Retrieve the number of bytes transferred by the operation,
+    /// optionally waiting for it to complete
+    fn result(&mut self, handle: HANDLE, wait: bool, context: &str) -> IoResult<usize> {
+        self.raw_result(handle, wait)
+            .map_err(|err| win32_error_with_context(context, err))
+    }
+
+    /// Like `result`, but the error is the one reported by the system
+    fn raw_result(&mut self, handle: HANDLE, wait: bool) -> IoResult<usize> {
+        let mut num_bytes = 0;
+        let res =
+            unsafe { GetOverlappedResult(handle, &mut self.overlapped, &mut num_bytes, wait as _) };
+        if res == 0 {
+            Err(IoError::last_os_error())
+        } else {
+            Ok(num_bytes as usize)
+        }
+    }
+}
+
+/// Issue an I/O operation against a handle that was opened for
+/// overlapped I/O, and block until it completes.
+fn overlapped_io<F: FnOnce(*mut OVERLAPPED) -> BOOL>(
+    context: &str,
+    handle: HANDLE,
+    func: F,
+) -> IoResult<usize> {
+    let mut overlapped = Overlapped::new()?;
+    if func(overlapped.as_mut_ptr()) == 0 {
+        let err = IoError::last_os_error();
+        if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+            return Err(pipe_io_error(context, err));
+        }
+    }
+    overlapped
+        .raw_result(handle, true)
+        .map_err(|err| pipe_io_error(context, err))
+}
+
+/// Returns true if `code` reports that the other end of a pipe has
+/// gone away.  Depending on exactly when that happens relative to the
+/// read or write, this can be any of these errors.
+pub(crate) fn is_pipe_closed_error(code: i32) -> bool {
+    matches!(
+        code as u32,
+        ERROR_BROKEN_PIPE | ERROR_NO_DATA | ERROR_PIPE_NOT_CONNECTED
+    )
+}
+
+/// Add context to an error from reading or writing a pipe, as with
+/// `win32_error_with_context`.  The context hides the original error
+/// code, so the end of the pipe is always reported as `BrokenPipe`,
+/// whichever of the errors accepted by `is_pipe_closed_error` the
+/// system used for it.
+pub(crate) fn pipe_io_error(context: &str, err: IoError) -> IoError {
+    let closed = err.raw_os_error().is_some_and(is_pipe_closed_error);
+    let err = win32_error_with_context(context, err);
+    if closed {
+        IoError::new(std::io::ErrorKind::BrokenPipe, err.to_string())
+    } else {
+        err
+    }
+}
+
 pub struct NamedPipeServer {
     pub pipe: PipeHandle,
     pub path: PathBuf,
 }
 
 impl NamedPipeServer {
+    #[allow(unused)]
     pub fn for_token(token: &Token) -> IoResult<Self> {
+        let path = Self::unique_path();
+        let pipe = PipeHandle::create_named_pipe_byte_mode_for_token(&path, token)?;
+        Ok(Self { pipe, path })
+    }
+
+    /// Like `for_token`, but the pipe is opened for overlapped I/O
+    pub fn for_token_overlapped(token: &Token) -> IoResult<Self> {
+        let path = Self::unique_path();
+        let pipe = PipeHandle::create_overlapped_named_pipe_byte_mode_for_token(&path, token)?;
+        Ok(Self { pipe, path })
+    }
+
+    /// Like `for_token`, but the pipe is a message mode pipe, so that
+    /// each write is read by the other end as a discrete message,
+    /// with no need to frame them.  The client must use
+    /// `PipeHandle::open_pipe_message_mode` to read whole messages.
+    ///
+    /// Each message must be written with a single call to `write`;
+    /// `write_all` only does that when the first write consumes
+    /// everything, which is the case for a blocking pipe.  A message
+    /// that is larger than the 4096 byte pipe buffer is still delivered
+    /// intact, but its write doesn't complete until the other end has
+    /// read it.  Use `PipeHandle::read_message` to read the messages:
+    /// `read` fails with `ERROR_MORE_DATA` if its buffer is smaller
+    /// than the message.
+    pub fn for_token_message_mode(token: &Token) -> IoResult<Self> {
+        let path = Self::unique_path();
+        let pipe = PipeHandle::create_named_pipe_message_mode_for_token(&path, token)?;
+        Ok(Self { pipe, path })
+    }
+
+    fn unique_path() -> PathBuf {
         static ID: AtomicUsize = AtomicUsize::new(1);
-        let path: PathBuf = format!(
+        format!(
             "\\\\.\\pipe\\eledo-bridge-{:x}-{:x}-{:x}",
             unsafe { GetCurrentProcessId() },
             ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
             rand::random::<u32>()
         )
-        .into();
-        let pipe = PipeHandle::create_named_pipe_byte_mode_for_token(&path, token)?;
-        Ok(Self { pipe, path })
+        .into()
+    }
+}
+
+/// Generate a random nonce for the connect handshake; see
+/// `PipeHandle::send_nonce` and `PipeHandle::verify_nonce`.
+pub fn generate_nonce() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Compare a nonce received during the connect handshake with the
+/// one that we expected
+pub(crate) fn check_nonce(received: &[u8], nonce: &str) -> IoResult<()> {
+    if received == nonce.as_bytes() {
+        Ok(())
+    } else {
+        Err(IoError::new(
+            std::io::ErrorKind::PermissionDenied,
+            "pipe peer sent the wrong nonce during the connect handshake",
+        ))
     }
 }
 
@@ -306,8 +884,114 @@ impl PipePair {
             ));
         }
         Ok(Self {
-            read: PipeHandle(read),
-            write: PipeHandle(write),
+            read: PipeHandle {
+                handle: read,
+                overlapped: false,
+            },
+            write: PipeHandle {
+                handle: write,
+                overlapped: false,
+            },
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peek() {
+        let mut pair = PipePair::new().unwrap();
+        assert_eq!(pair.read.peek().unwrap(), 0);
+
+        pair.write.write_all(b"hello").unwrap();
+        assert_eq!(pair.read.peek().unwrap(), 5);
+        // Peeking doesn't consume anything
+        assert_eq!(pair.read.peek().unwrap(), 5);
+
+        let mut buf = [0u8; 5];
+        pair.read.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(pair.read.peek().unwrap(), 0);
+
+        // A closed pipe has nothing to read, rather than being an error
+        let PipePair { read, write } = pair;
+        drop(write);
+        assert_eq!(read.peek().unwrap(), 0);
+    }
+
+    #[test]
+    fn open_waits_for_busy_pipe() {
+        let token = Token::with_current_process().unwrap();
+        let NamedPipeServer { mut pipe, path } = NamedPipeServer::for_token(&token).unwrap();
+        let first = PipeHandle::open_pipe(&path).unwrap();
+        pipe.wait_for_pipe_client().unwrap();
+
+        // The only instance is taken, so a second client must wait
+        let busy = PipeHandle::open_pipe(&path).unwrap_err();
+        assert!(busy.to_string().contains("already connected"), "{}", busy);
+
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(first);
+            unsafe {
+                DisconnectNamedPipe(pipe.as_handle());
+            }
+            // The instance only becomes available to the waiting
+            // client once it is listening again
+            pipe.wait_for_pipe_client().unwrap();
+            pipe
+        });
+        let second = PipeHandle::open_pipe_waiting(&path, Duration::from_secs(10));
+        let _pipe = server.join().unwrap();
+        second.unwrap();
+    }
+
+    #[test]
+    fn message_mode() {
+        let token = Token::with_current_process().unwrap();
+        let NamedPipeServer { mut pipe, path } =
+            NamedPipeServer::for_token_message_mode(&token).unwrap();
+        let mut client = PipeHandle::open_pipe_message_mode(&path).unwrap();
+        pipe.wait_for_pipe_client().unwrap();
+
+        let large = vec![b'x'; PIPE_BUFFER_SIZE as usize * 3 + 1];
+        let writer = {
+            let large = large.clone();
+            std::thread::spawn(move || {
+                for message in [&b"one"[..], b"two three", &large] {
+                    assert_eq!(client.write(message).unwrap(), message.len());
+                }
+                client
+            })
+        };
+        assert_eq!(pipe.read_message().unwrap(), b"one");
+        assert_eq!(pipe.read_message().unwrap(), b"two three");
+        assert_eq!(pipe.read_message().unwrap(), large);
+        let mut client = writer.join().unwrap();
+
+        // Messages flow the other way too, and a short read leaves the
+        // rest for the next
+        pipe.write_all(b"reply").unwrap();
+        let mut buf = [0u8; 2];
+        assert!(client.read(&mut buf).is_err());
+        assert_eq!(&buf, b"re");
+        assert_eq!(client.read_message().unwrap(), b"ply");
+    }
+
+    #[test]
+    fn open_waiting_gives_up() {
+        let path = NamedPipeServer::unique_path();
+        let start = Instant::now();
+        let err = PipeHandle::open_pipe_waiting(&path, Duration::from_millis(200)).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("CreateFileW: {}", path.display())),
+            "{}",
+            err
+        );
+    }
+}
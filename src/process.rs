@@ -1,15 +1,60 @@
 //! Working with process handles
+use crate::command::ResponseFile;
+use crate::job::Job;
 use crate::win32_error_with_context;
 use std::io::{Error as IoError, Result as IoResult};
-use winapi::shared::minwindef::DWORD;
-use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
-use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
-use winapi::um::synchapi::WaitForSingleObject;
-use winapi::um::winbase::{INFINITE, WAIT_FAILED};
-use winapi::um::winnt::HANDLE;
+use std::ptr::null_mut;
+use std::time::Duration;
+use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, TRUE};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{ERROR_INVALID_PARAMETER, ERROR_PROCESS_ABORTED, WAIT_TIMEOUT};
+use winapi::um::handleapi::{CloseHandle, DuplicateHandle, INVALID_HANDLE_VALUE};
+use winapi::um::processthreadsapi::{
+    GetCurrentProcess, GetExitCodeProcess, GetProcessId, OpenProcess, ResumeThread, SuspendThread,
+    TerminateProcess,
+};
+use winapi::um::synchapi::WaitForMultipleObjects;
+use winapi::um::winbase::{INFINITE, WAIT_FAILED, WAIT_OBJECT_0};
+use winapi::um::wincon::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+use winapi::um::winnt::{
+    DUPLICATE_SAME_ACCESS, HANDLE, PROCESS_QUERY_LIMITED_INFORMATION, SYNCHRONIZE,
+};
+use winapi::um::winuser::{EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE};
+
+/// The exit code given to a process that `Process::shutdown` had to
+/// terminate because it didn't stop within the grace period.  It is
+/// ERROR_PROCESS_ABORTED rather than a small number so that it can be
+/// told apart from the exit codes that programs commonly use.
+pub const SHUTDOWN_EXIT_CODE: DWORD = ERROR_PROCESS_ABORTED;
+
+/// The result of `Process::wait_for_or_event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The process exited with the contained exit code
+    Exited(DWORD),
+    /// The timeout elapsed first
+    TimedOut,
+    /// The cancel event was signalled first
+    Cancelled,
+}
 
 /// An owning wrapper around handles that represent processes
-pub struct Process(HANDLE);
+pub struct Process {
+    handle: HANDLE,
+    /// The handle to the primary thread of the process, if we spawned
+    /// it directly; null otherwise
+    thread: HANDLE,
+    /// The response file that holds the arguments of the process, if
+    /// any; it is removed when the process handle is dropped
+    response_file: Option<ResponseFile>,
+    /// The job that the process was assigned to, if any; when this
+    /// is closed, the process and its descendants are terminated
+    job: Option<Job>,
+    /// Whether we created the process with `CREATE_NEW_PROCESS_GROUP`,
+    /// making it the leader of a process group that CTRL_BREAK_EVENT
+    /// can be addressed to
+    group_leader: bool,
+}
 /// The compiler thinks it isn't send because HANDLE is a pointer
 /// type.  We happen to know that moving the handle between threads
 /// is totally fine, hence this impl.
@@ -18,7 +63,10 @@ unsafe impl Send for Process {}
 impl Drop for Process {
     fn drop(&mut self) {
         unsafe {
-            CloseHandle(self.0);
+            CloseHandle(self.handle);
+            if !self.thread.is_null() {
+                CloseHandle(self.thread);
+            }
         }
     }
 }
@@ -31,34 +79,175 @@ impl Process {
         pid: DWORD,
     ) -> IoResult<Self> {
         let proc = unsafe { OpenProcess(desired_access, inherit_handles as _, pid) };
-        if proc == INVALID_HANDLE_VALUE {
-            Err(win32_error_with_context(
-                "OpenProcess",
-                IoError::last_os_error(),
-            ))
+        if proc.is_null() {
+            let err = IoError::last_os_error();
+            if err.raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32) {
+                // This is how OpenProcess reports an unknown pid
+                return Err(IoError::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("OpenProcess: there is no process with pid {}", pid),
+                ));
+            }
+            Err(win32_error_with_context("OpenProcess", err))
         } else {
-            Ok(Self(proc))
+            Ok(Self::with_handle(proc))
         }
     }
 
-    /// Returns the underlying raw handle value
+    /// Open an existing process, such as one that was launched without
+    /// waiting for it, so that it can be waited on and its exit code
+    /// retrieved.  Only the access needed for that is requested, so
+    /// this works for elevated processes too, but `terminate` won't.
+    /// An error of kind `NotFound` is returned if the process no
+    /// longer exists.
+    pub fn from_pid(pid: DWORD) -> IoResult<Self> {
+        Self::with_process_id(SYNCHRONIZE | PROCESS_QUERY_LIMITED_INFORMATION, false, pid)
+    }
+
+    /// Returns the underlying raw handle value, which is signalled
+    /// when the process exits, so it can be waited on along with other
+    /// objects, such as `BridgeServer::connected_event`.
+    /// The handle remains owned by this Process instance and is closed
+    /// when it is dropped, so the caller must not close it.
     pub fn as_handle(&self) -> HANDLE {
-        self.0
+        self.handle
     }
 
     /// Takes ownership of the provided handle and will close
     /// it when this Process instance is dropped!
     pub fn with_handle(proc: HANDLE) -> Self {
-        Self(proc)
+        Self {
+            handle: proc,
+            thread: null_mut(),
+            response_file: None,
+            job: None,
+            group_leader: false,
+        }
+    }
+
+    /// Take ownership of the job that the process was assigned to,
+    /// so that it lives as long as we do
+    pub(crate) fn set_job(&mut self, job: Job) {
+        self.job.replace(job);
+    }
+
+    /// Take ownership of the handle to the primary thread of the
+    /// process, which is needed by `resume`
+    pub(crate) fn set_thread(&mut self, thread: HANDLE) {
+        self.thread = thread;
+    }
+
+    /// Record that we created the process with `CREATE_NEW_PROCESS_GROUP`
+    pub(crate) fn set_group_leader(&mut self, group_leader: bool) {
+        self.group_leader = group_leader;
+    }
+
+    /// Returns the raw handle to the primary thread of the process,
+    /// which remains owned by this Process instance and is closed
+    /// when it is dropped.
+    /// This is only available for processes that were spawned directly;
+    /// launching via the `runas` verb doesn't provide a handle to the
+    /// primary thread, and neither do `try_clone`, `with_handle` or
+    /// `from_pid`, so None is returned for those.
+    pub fn main_thread_handle(&self) -> Option<HANDLE> {
+        if self.thread.is_null() {
+            None
+        } else {
+            Some(self.thread)
+        }
+    }
+
+    /// Returns the primary thread, or an error naming what couldn't
+    /// be done without it
+    fn main_thread_for(&self, action: &str) -> IoResult<HANDLE> {
+        self.main_thread_handle().ok_or_else(|| {
+            IoError::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "the primary thread of this process is not available to {}",
+                    action
+                ),
+            )
+        })
+    }
+
+    /// Resume the primary thread of a process that was started via
+    /// `Command::spawn_suspended`, or suspended by `suspend`.
+    /// The thread only runs once it has been resumed as many times as
+    /// it was suspended.
+    /// This requires `main_thread_handle`, so isn't possible for
+    /// processes that were launched via the `runas` verb.
+    pub fn resume(&self) -> IoResult<()> {
+        let thread = self.main_thread_for("resume")?;
+        if unsafe { ResumeThread(thread) } == DWORD::MAX {
+            Err(win32_error_with_context(
+                "ResumeThread",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Suspend the primary thread of the process, until it is resumed
+    /// by `resume`.  Other threads of the process keep running.
+    /// This requires `main_thread_handle`, so isn't possible for
+    /// processes that were launched via the `runas` verb.
+    pub fn suspend(&self) -> IoResult<()> {
+        let thread = self.main_thread_for("suspend")?;
+        if unsafe { SuspendThread(thread) } == DWORD::MAX {
+            Err(win32_error_with_context(
+                "SuspendThread",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take ownership of the response file that was created for the
+    /// arguments of this process, so that it lives as long as we do
+    pub(crate) fn set_response_file(&mut self, response_file: Option<ResponseFile>) {
+        self.response_file = response_file;
     }
 
     /// Wait for the specified duration (in milliseconds!) to pass.
     /// Use None to wait forever.
     pub fn wait_for(&self, duration: Option<DWORD>) -> IoResult<DWORD> {
-        let res = unsafe { WaitForSingleObject(self.0, duration.unwrap_or(INFINITE)) };
+        self.wait(duration.unwrap_or(INFINITE), null_mut())
+    }
+
+    /// Wait for the process to exit, for `timeout` to pass, or for
+    /// the `cancel` event to be signalled, whichever happens first.
+    /// Use None to wait forever.  `cancel` may be null, in which case
+    /// only the process and the timeout are waited for.
+    /// This allows a blocking wait to be cancelled from another thread.
+    pub fn wait_for_or_event(
+        &self,
+        timeout: Option<Duration>,
+        cancel: HANDLE,
+    ) -> IoResult<WaitOutcome> {
+        let timeout = timeout
+            .map(|t| t.as_millis().min((INFINITE - 1) as u128) as DWORD)
+            .unwrap_or(INFINITE);
+        match self.wait(timeout, cancel)? {
+            WAIT_OBJECT_0 => Ok(WaitOutcome::Exited(self.exit_code()?)),
+            WAIT_TIMEOUT => Ok(WaitOutcome::TimedOut),
+            _ => Ok(WaitOutcome::Cancelled),
+        }
+    }
+
+    /// Wait on the process and the optional cancel event, returning
+    /// the raw result of WaitForMultipleObjects
+    fn wait(&self, timeout: DWORD, cancel: HANDLE) -> IoResult<DWORD> {
+        let handles = [self.handle, cancel];
+        let count = if cancel.is_null() { 1 } else { 2 };
+        let wait_all = false;
+        let res =
+            unsafe { WaitForMultipleObjects(count, handles.as_ptr(), wait_all as _, timeout) };
         if res == WAIT_FAILED {
             Err(win32_error_with_context(
-                "WaitForSingleObject(process)",
+                "WaitForMultipleObjects(process)",
                 IoError::last_os_error(),
             ))
         } else {
@@ -66,10 +255,101 @@ impl Process {
         }
     }
 
+    /// Create a second owning handle that refers to the same process
+    pub fn try_clone(&self) -> IoResult<Self> {
+        let proc = unsafe { GetCurrentProcess() };
+        let mut duped = INVALID_HANDLE_VALUE;
+        let inheritable = false;
+        let access = 0;
+        let res = unsafe {
+            DuplicateHandle(
+                proc,
+                self.handle,
+                proc,
+                &mut duped,
+                access,
+                inheritable as _,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+        if res == 0 {
+            Err(win32_error_with_context(
+                "DuplicateHandle(process)",
+                IoError::last_os_error(),
+            ))
+        } else {
+            let mut clone = Self::with_handle(duped);
+            clone.group_leader = self.group_leader;
+            Ok(clone)
+        }
+    }
+
+    /// Forcibly terminate the process, causing it to exit with
+    /// the specified exit code
+    pub fn terminate(&self, exit_code: DWORD) -> IoResult<()> {
+        if unsafe { TerminateProcess(self.handle, exit_code) } != 0 {
+            Ok(())
+        } else {
+            Err(win32_error_with_context(
+                "TerminateProcess",
+                IoError::last_os_error(),
+            ))
+        }
+    }
+
+    /// Ask the process to stop, and give it up to `grace` to do so
+    /// before terminating it with `SHUTDOWN_EXIT_CODE`, returning its
+    /// exit code either way.  This gives the process a chance to clean
+    /// up after itself, unlike `terminate`.
+    ///
+    /// The process is asked to stop in two ways, as there is no single
+    /// mechanism that every kind of program responds to:
+    /// * CTRL_BREAK_EVENT is sent to its process group, but only if
+    ///   we spawned it with `CREATE_NEW_PROCESS_GROUP` (see
+    ///   `Command::set_creation_flags`), making it the leader of a
+    ///   group of its own.  Otherwise there is no way to address the
+    ///   signal to it alone: it would reach every process on our
+    ///   console, including us.  It also has to share our console.
+    /// * WM_CLOSE is posted to each of its top level windows, which is
+    ///   how GUI programs are asked to close.
+    ///
+    /// Terminating the process requires the handle to have
+    /// `PROCESS_TERMINATE` access, which `Process::from_pid` doesn't
+    /// request.
+    pub fn shutdown(&self, grace: Duration) -> IoResult<DWORD> {
+        if self.wait_for(Some(0))? == WAIT_OBJECT_0 {
+            return self.exit_code();
+        }
+
+        let pid = unsafe { GetProcessId(self.handle) };
+        if pid != 0 {
+            // These are best effort; the process may not have a console
+            // or any windows, and the grace period covers both cases
+            unsafe {
+                if self.group_leader {
+                    GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid);
+                }
+                EnumWindows(Some(post_close_to_process), pid as LPARAM);
+            }
+        }
+
+        if let WaitOutcome::Exited(exit_code) = self.wait_for_or_event(Some(grace), null_mut())? {
+            return Ok(exit_code);
+        }
+        if let Err(err) = self.terminate(SHUTDOWN_EXIT_CODE) {
+            // It may have exited of its own accord just now
+            if self.wait_for(Some(0))? != WAIT_OBJECT_0 {
+                return Err(err);
+            }
+        }
+        self.wait_for(None)?;
+        self.exit_code()
+    }
+
     /// Retrieves the exit code from the process
     pub fn exit_code(&self) -> IoResult<DWORD> {
         let mut exit_code = 0;
-        if unsafe { GetExitCodeProcess(self.0, &mut exit_code) } != 0 {
+        if unsafe { GetExitCodeProcess(self.handle, &mut exit_code) } != 0 {
             Ok(exit_code)
         } else {
             Err(win32_error_with_context(
@@ -79,3 +359,106 @@ impl Process {
         }
     }
 }
+
+/// The EnumWindows callback for `Process::shutdown`, which asks each
+/// window that belongs to the process whose pid is `pid` to close
+unsafe extern "system" fn post_close_to_process(hwnd: HWND, pid: LPARAM) -> BOOL {
+    let mut window_pid = 0;
+    GetWindowThreadProcessId(hwnd, &mut window_pid);
+    if window_pid as LPARAM == pid {
+        PostMessageW(hwnd, WM_CLOSE, 0, 0);
+    }
+    // Keep enumerating, as the process may have several windows
+    TRUE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Command, Token};
+    use winapi::um::processthreadsapi::{GetCurrentProcessId, GetThreadId};
+    use winapi::um::winbase::CREATE_NEW_PROCESS_GROUP;
+
+    #[test]
+    fn from_pid() {
+        let current = Process::from_pid(unsafe { GetCurrentProcessId() }).unwrap();
+        assert_eq!(current.wait_for(Some(0)).unwrap(), WAIT_TIMEOUT);
+
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 3".into()]);
+        let proc = cmd.spawn().unwrap();
+        let pid = unsafe { GetProcessId(proc.as_handle()) };
+
+        let attached = Process::from_pid(pid).unwrap();
+        assert_eq!(attached.wait_for(None).unwrap(), WAIT_OBJECT_0);
+        assert_eq!(attached.exit_code().unwrap(), 3);
+
+        // Real pids are multiples of 4, so this can never refer to a
+        // process, unlike the pid of one that has exited, which may
+        // still be open elsewhere or be reused straight away
+        let err = Process::from_pid(3).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn main_thread() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 3".into()]);
+        let proc = cmd.spawn_suspended().unwrap();
+        let thread = proc.main_thread_handle().unwrap();
+        assert_ne!(unsafe { GetThreadId(thread) }, 0);
+
+        // Suspending again means that it takes two resumes to run it
+        proc.suspend().unwrap();
+        proc.resume().unwrap();
+        assert_eq!(proc.wait_for(Some(200)).unwrap(), WAIT_TIMEOUT);
+        proc.resume().unwrap();
+        proc.wait_for(None).unwrap();
+        assert_eq!(proc.exit_code().unwrap(), 3);
+
+        // Only the process that we spawned has the thread
+        let clone = proc.try_clone().unwrap();
+        assert!(clone.main_thread_handle().is_none());
+        assert_eq!(
+            clone.suspend().unwrap_err().to_string(),
+            "the primary thread of this process is not available to suspend"
+        );
+        let current = Process::from_pid(unsafe { GetCurrentProcessId() }).unwrap();
+        assert!(current.main_thread_handle().is_none());
+    }
+
+    #[test]
+    fn shutdown() {
+        let token = Token::with_current_process().unwrap();
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec!["cmd.exe".into(), "/c".into(), "exit 3".into()]);
+        let proc = cmd.spawn().unwrap();
+        // It shares our process group, so CTRL_BREAK_EVENT can't be
+        // sent to it without also reaching us
+        assert!(!proc.group_leader);
+        proc.wait_for(None).unwrap();
+        // There is nothing to do for a process that has already exited
+        assert_eq!(proc.shutdown(Duration::from_millis(100)).unwrap(), 3);
+
+        // Sleeps for much longer than we are prepared to wait
+        let mut cmd = Command::with_environment_for_token(&token).unwrap();
+        cmd.set_argv(vec![
+            "cmd.exe".into(),
+            "/c".into(),
+            "ping -n 30 127.0.0.1 >nul".into(),
+        ]);
+        cmd.set_creation_flags(CREATE_NEW_PROCESS_GROUP).unwrap();
+        cmd.set_kill_on_close(true);
+        let proc = cmd.spawn().unwrap();
+        assert!(proc.group_leader);
+        assert!(proc.try_clone().unwrap().group_leader);
+        let started = std::time::Instant::now();
+        // Whether it heeds CTRL_BREAK_EVENT depends on whether we have
+        // a console to share with it, so only check that it stopped
+        proc.shutdown(Duration::from_millis(500)).unwrap();
+        assert_eq!(proc.wait_for(Some(0)).unwrap(), WAIT_OBJECT_0);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
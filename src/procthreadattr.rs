@@ -5,11 +5,16 @@ use std::mem;
 use std::ptr;
 use winapi::shared::minwindef::DWORD;
 use winapi::um::processthreadsapi::*;
+use winapi::um::winnt::HANDLE;
 
 const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x00020016;
+const PROC_THREAD_ATTRIBUTE_HANDLE_LIST: usize = 0x00020002;
 
 pub struct ProcThreadAttributeList {
     data: Vec<u8>,
+    /// The attribute list references this storage rather than
+    /// copying it, so we need to keep it alive alongside the list
+    handles: Vec<HANDLE>,
 }
 
 impl ProcThreadAttributeList {
@@ -39,7 +44,10 @@ impl ProcThreadAttributeList {
                 IoError::last_os_error(),
             ))
         } else {
-            Ok(Self { data })
+            Ok(Self {
+                data,
+                handles: vec![],
+            })
         }
     }
 
@@ -68,6 +76,31 @@ impl ProcThreadAttributeList {
             Ok(())
         }
     }
+
+    /// Restrict the handles inherited by the child to `handles`,
+    /// each of which must already be marked as inheritable.
+    pub fn set_handle_list(&mut self, handles: Vec<HANDLE>) -> IoResult<()> {
+        self.handles = handles;
+        let res = unsafe {
+            UpdateProcThreadAttribute(
+                self.as_mut_ptr(),
+                0,
+                PROC_THREAD_ATTRIBUTE_HANDLE_LIST,
+                self.handles.as_mut_ptr() as *mut _,
+                mem::size_of::<HANDLE>() * self.handles.len(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            Err(win32_error_with_context(
+                "UpdateProcThreadAttribute failed",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl Drop for ProcThreadAttributeList {
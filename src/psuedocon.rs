@@ -1,12 +1,17 @@
-use crate::pipe::PipeHandle;
+use crate::pipe::{PipeHandle, PipePair};
 use lazy_static::lazy_static;
+use shared_library::dynamic_library::DynamicLibrary;
 use shared_library::shared_library;
-use std::io::{Error as IoError, Result as IoResult};
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::os::windows::io::AsRawHandle;
 use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use winapi::shared::minwindef::DWORD;
 use winapi::shared::winerror::{HRESULT, S_OK};
 use winapi::um::handleapi::*;
+use winapi::um::ioapiset::CancelIoEx;
 use winapi::um::wincon::COORD;
 use winapi::um::winnt::HANDLE;
 
@@ -24,29 +29,156 @@ shared_library!(ConPtyFuncs,
     pub fn ClosePseudoConsole(hpc: HPCON),
 );
 
-fn load_conpty() -> ConPtyFuncs {
+fn load_conpty() -> Option<ConPtyFuncs> {
     // If the kernel doesn't export these functions then their system is
-    // too old and we cannot run.
-    let kernel = ConPtyFuncs::open(Path::new("kernel32.dll")).expect(
-        "this system does not support conpty.  Windows 10 October 2018 or newer is required",
-    );
+    // too old for us to create a pty.
+    let kernel = match ConPtyFuncs::open(Path::new("kernel32.dll")) {
+        Ok(kernel) => kernel,
+        Err(err) => {
+            log::warn!("conpty is not available: {:?}", err);
+            return None;
+        }
+    };
 
     // We prefer to use a sideloaded conpty.dll and openconsole.exe host deployed
     // alongside the application.  We check for this after checking for kernel
     // support so that we don't try to proceed and do something crazy.
-    if let Ok(sideloaded) = ConPtyFuncs::open(Path::new("conpty.dll")) {
-        sideloaded
-    } else {
-        kernel
-    }
+    Some(ConPtyFuncs::open(Path::new("conpty.dll")).unwrap_or(kernel))
+}
+
+type ClearPseudoConsoleFn = unsafe extern "system" fn(hpc: HPCON) -> HRESULT;
+
+/// Holds the optional ConptyClearPseudoConsole function, along with
+/// the library that it came from so that it remains loaded.
+struct ConPtyClear {
+    _lib: DynamicLibrary,
+    func: ClearPseudoConsoleFn,
+}
+
+unsafe impl Send for ConPtyClear {}
+unsafe impl Sync for ConPtyClear {}
+
+fn load_conpty_clear() -> Option<ConPtyClear> {
+    // Only the sideloaded conpty.dll has this function; there is
+    // no equivalent in the kernel implementation.
+    let lib = DynamicLibrary::open(Some(Path::new("conpty.dll"))).ok()?;
+    let func: *mut u8 = unsafe { lib.symbol("ConptyClearPseudoConsole") }.ok()?;
+    let func = unsafe { std::mem::transmute::<*mut u8, ClearPseudoConsoleFn>(func) };
+    Some(ConPtyClear { _lib: lib, func })
 }
 
 lazy_static! {
-    static ref CONPTY: ConPtyFuncs = load_conpty();
+    static ref CONPTY: Option<ConPtyFuncs> = load_conpty();
+    static ref CONPTY_CLEAR: Option<ConPtyClear> = load_conpty_clear();
+}
+
+/// Returns true if this system supports the pseudo console API, which
+/// requires Windows 10 October 2018 or newer.  When it doesn't,
+/// `PsuedoCon::new` fails and `BridgeServer` falls back to bridging
+/// the streams over pipes.
+pub fn conpty_available() -> bool {
+    CONPTY.is_some()
+}
+
+fn conpty() -> IoResult<&'static ConPtyFuncs> {
+    CONPTY.as_ref().ok_or_else(|| {
+        IoError::new(
+            std::io::ErrorKind::Other,
+            "this system does not support conpty.  Windows 10 October 2018 or newer is required",
+        )
+    })
+}
+
+/// Copy data from `src` to the input of the pty until `src` is closed,
+/// and then close the input of the pty too, so that it sees the end of
+/// the input just as it would reading from `src` directly.
+/// Each chunk is written while holding the lock so that it cannot be
+/// interleaved with data sent via `PsuedoCon::write_input`.
+/// `src` is handed back when the thread finishes, so that it remains
+/// open for as long as `InputRelay::stop` may need to cancel a read.
+fn relay_input(mut src: PipeHandle, dest: Arc<Mutex<Option<PipeHandle>>>) -> InputRelay {
+    let handle = src.as_handle();
+    let writer = Arc::clone(&dest);
+    let thread = std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = match src.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            let written = match writer.lock().unwrap().as_mut() {
+                Some(dest) => dest.write_all(&buf[0..len]),
+                None => break,
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+        writer.lock().unwrap().take();
+        src
+    });
+    InputRelay {
+        src: handle,
+        thread,
+        dest,
+    }
+}
+
+/// The thread that relays the input of a pty that was created via
+/// `PsuedoCon::with_input_injection`
+struct InputRelay {
+    /// The handle that the thread reads from, which it owns
+    src: HANDLE,
+    thread: JoinHandle<PipeHandle>,
+    /// The write end of the input of the pty, until the relayed
+    /// input is closed
+    dest: Arc<Mutex<Option<PipeHandle>>>,
+}
+
+impl InputRelay {
+    /// Close the input of the pty and wait for the thread to finish,
+    /// cancelling the read that it is blocked in, if any.  The read
+    /// is cancelled repeatedly in case the thread was between reads.
+    fn stop(self) {
+        self.dest.lock().unwrap().take();
+        while !self.thread.is_finished() {
+            unsafe { CancelIoEx(self.src, null_mut()) };
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let _ = self.thread.join();
+    }
+}
+
+fn create_psuedo_console(size: COORD, input: &PipeHandle, output: &PipeHandle) -> IoResult<HPCON> {
+    let conpty = conpty()?;
+    let mut con: HPCON = INVALID_HANDLE_VALUE;
+    let result = unsafe {
+        (conpty.CreatePseudoConsole)(
+            size,
+            input.as_raw_handle() as _,
+            output.as_raw_handle() as _,
+            0,
+            &mut con,
+        )
+    };
+    if result != S_OK {
+        Err(IoError::new(
+            std::io::ErrorKind::Other,
+            format!("failed to create psuedo console: HRESULT {}", result),
+        ))
+    } else {
+        Ok(con)
+    }
 }
 
 pub struct PsuedoCon {
-    pub(crate) con: HPCON,
+    con: HPCON,
+    buffer_height: Option<i16>,
+    /// Only present when input injection is enabled
+    relay: Option<InputRelay>,
+    /// The output of the pty, retained so that `reset` can clear the
+    /// terminal when the pty itself can't be cleared
+    output: Mutex<PipeHandle>,
 }
 
 unsafe impl Send for PsuedoCon {}
@@ -54,34 +186,140 @@ unsafe impl Sync for PsuedoCon {}
 
 impl Drop for PsuedoCon {
     fn drop(&mut self) {
-        unsafe { (CONPTY.ClosePseudoConsole)(self.con) };
+        // We were created successfully, so conpty is available
+        if let Some(conpty) = CONPTY.as_ref() {
+            unsafe { (conpty.ClosePseudoConsole)(self.con) };
+        }
+        if let Some(relay) = self.relay.take() {
+            relay.stop();
+        }
     }
 }
 
 impl PsuedoCon {
-    pub fn new(size: COORD, input: PipeHandle, output: PipeHandle) -> IoResult<Self> {
-        let mut con: HPCON = INVALID_HANDLE_VALUE;
-        let result = unsafe {
-            (CONPTY.CreatePseudoConsole)(
-                size,
-                input.as_raw_handle() as _,
-                output.as_raw_handle() as _,
-                0,
-                &mut con,
-            )
-        };
-        if result != S_OK {
-            Err(IoError::new(
+    /// Returns true if pseudo consoles can be created on this system;
+    /// see `conpty_available`.
+    pub fn is_supported() -> bool {
+        conpty_available()
+    }
+
+    /// Create a pseudo console with the specified viewport size.
+    /// The pseudo console API only has a single size, so `buffer_height`
+    /// is recorded here in order that it can be applied to the screen
+    /// buffer from inside the console by the process that is spawned
+    /// into it.  If it is `None` then the buffer matches the viewport.
+    /// The pty reads directly from `input`; see `with_input_injection`
+    /// for a pty that also accepts input from `write_input`.
+    pub fn new(
+        size: COORD,
+        buffer_height: Option<i16>,
+        input: PipeHandle,
+        output: PipeHandle,
+    ) -> IoResult<Self> {
+        let con = create_psuedo_console(size, &input, &output)?;
+        Ok(Self {
+            con,
+            buffer_height,
+            relay: None,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Create a pseudo console as for `new`, except that the pty doesn't
+    /// read directly from `input`.  Instead, the data is relayed from it
+    /// by a background thread so that `write_input` can inject additional
+    /// input alongside it.  Once `input` is closed, so is the input of
+    /// the pty, after which `write_input` fails.
+    pub fn with_input_injection(
+        size: COORD,
+        buffer_height: Option<i16>,
+        input: PipeHandle,
+        output: PipeHandle,
+    ) -> IoResult<Self> {
+        let pty_input = PipePair::new()?;
+        let con = create_psuedo_console(size, &pty_input.read, &output)?;
+        let writer = Arc::new(Mutex::new(Some(pty_input.write)));
+        Ok(Self {
+            con,
+            buffer_height,
+            relay: Some(relay_input(input, writer)),
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Write `bytes` to the input side of the pseudo console, as though
+    /// they had been typed.  The pty expects VT encoded input: printable
+    /// text is sent as UTF-8, Enter is `\r` rather than `\n`, and
+    /// special keys use their VT sequences, for example `\x1b[A` for
+    /// the up arrow and `\x03` for Ctrl-C.  The pty translates these
+    /// into the key events that the child reads from its console.
+    /// This requires a pty created via `with_input_injection`.
+    pub fn write_input(&self, bytes: &[u8]) -> IoResult<()> {
+        let relay = self.relay.as_ref().ok_or_else(|| {
+            IoError::new(
                 std::io::ErrorKind::Other,
-                format!("failed to create psuedo console: HRESULT {}", result),
-            ))
-        } else {
-            Ok(Self { con })
+                "input injection is not enabled for this psuedo console",
+            )
+        })?;
+        let mut input = relay.dest.lock().unwrap();
+        let input = input.as_mut().ok_or_else(|| {
+            IoError::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the input of the psuedo console has been closed",
+            )
+        })?;
+        input.write_all(bytes)?;
+        input.flush()
+    }
+
+    /// Returns the underlying pseudo console handle, for use with
+    /// other pseudo console APIs.  Ownership is retained by this
+    /// `PsuedoCon`, which closes the handle when it is dropped, so the
+    /// handle must not be closed or used after that point.  Operations
+    /// that change the state of the pseudo console, such as resizing
+    /// it, may confuse the bridge; prefer the methods provided here.
+    pub fn as_handle(&self) -> HPCON {
+        self.con
+    }
+
+    /// Returns the desired screen buffer height, if one was specified
+    pub fn buffer_height(&self) -> Option<i16> {
+        self.buffer_height
+    }
+
+    /// Clear the screen and scrollback of the pseudo console, so that
+    /// a subsequent process doesn't see state left over from a previous
+    /// one.  The conpty.dll that can be deployed alongside the application
+    /// can clear the buffer of the pty itself.  The pseudo console built
+    /// into Windows can't, so instead the VT sequences that clear the
+    /// screen and scrollback and home the cursor are written to the
+    /// output of the pty, which clears the terminal that renders it.
+    /// An error is returned only if neither of these works.
+    pub fn reset(&self) -> IoResult<()> {
+        if let Some(clear) = CONPTY_CLEAR.as_ref() {
+            let result = unsafe { (clear.func)(self.con) };
+            if result == S_OK {
+                return Ok(());
+            }
+            log::debug!(
+                "ConptyClearPseudoConsole failed: HRESULT {}; clearing the terminal instead",
+                result
+            );
         }
+        let mut output = self.output.lock().unwrap();
+        output
+            .write_all(b"\x1b[2J\x1b[3J\x1b[H")
+            .and_then(|_| output.flush())
+            .map_err(|err| {
+                IoError::new(
+                    err.kind(),
+                    format!("failed to reset psuedo console: {}", err),
+                )
+            })
     }
 
     pub fn resize(&self, size: COORD) -> IoResult<()> {
-        let result = unsafe { (CONPTY.ResizePseudoConsole)(self.con, size) };
+        let result = unsafe { (conpty()?.ResizePseudoConsole)(self.con, size) };
         if result != S_OK {
             Err(IoError::new(
                 std::io::ErrorKind::Other,
@@ -1,10 +1,17 @@
+use crate::win32_error_with_context;
+use std::ffi::OsStr;
 use std::io::{Error as IoError, Result as IoResult};
 use winapi::shared::minwindef::DWORD;
+use winapi::shared::sddl::ConvertStringSidToSidW;
 use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::um::securitybaseapi::{CreateWellKnownSid, GetLengthSid, IsWellKnownSid};
+use winapi::um::securitybaseapi::{
+    CreateWellKnownSid, GetLengthSid, GetSidIdentifierAuthority, GetSidSubAuthorityCount,
+    IsWellKnownSid,
+};
+use winapi::um::winbase::LocalFree;
 use winapi::um::winnt::SID;
-use winapi::um::winnt::WELL_KNOWN_SID_TYPE;
+use winapi::um::winnt::{PSID, SECURITY_MANDATORY_LABEL_AUTHORITY, WELL_KNOWN_SID_TYPE};
 
 /// A little helper trait to make it easier to operate on SIDs
 /// that reside in various storage
@@ -79,10 +86,58 @@ impl AsSid for &WellKnownSid {
     }
 }
 
+/// Stores the data for a sid parsed from its string form
+pub struct StringSid {
+    data: Vec<u8>,
+}
+
+impl StringSid {
+    /// Parse a sid in the `S-1-...` form, or one of the two letter
+    /// aliases (eg: `LW` for the low integrity label) understood by
+    /// ConvertStringSidToSidW
+    pub fn with_string(sid: &str) -> IoResult<Self> {
+        let wide = crate::os_str_to_null_terminated_vec(OsStr::new(sid));
+        let mut psid: PSID = std::ptr::null_mut();
+        if unsafe { ConvertStringSidToSidW(wide.as_ptr(), &mut psid) } == 0 {
+            return Err(win32_error_with_context(
+                &format!("ConvertStringSidToSidW {:?}", sid),
+                IoError::last_os_error(),
+            ));
+        }
+
+        // Take a copy so that we don't have to manage the LocalAlloc'd
+        // storage that the system returned
+        let len = get_length_sid(psid as *const SID) as usize;
+        let data = unsafe { std::slice::from_raw_parts(psid as *const u8, len) }.to_vec();
+        unsafe {
+            LocalFree(psid);
+        }
+        Ok(Self { data })
+    }
+
+    /// Returns true if this is a mandatory integrity label, such as
+    /// `S-1-16-8192` for the medium integrity level
+    pub fn is_mandatory_label(&self) -> bool {
+        let psid = self.as_sid() as PSID;
+        unsafe {
+            (*GetSidIdentifierAuthority(psid)).Value == SECURITY_MANDATORY_LABEL_AUTHORITY
+                && *GetSidSubAuthorityCount(psid) == 1
+        }
+    }
+}
+
+impl AsSid for &StringSid {
+    fn as_sid(self) -> *const SID {
+        self.data.as_ptr() as *const SID
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use winapi::um::winnt::{WinBuiltinAdministratorsSid, WinBuiltinUsersSid};
+    use winapi::um::winnt::{
+        WinBuiltinAdministratorsSid, WinBuiltinUsersSid, WinLowLabelSid, WinMediumLabelSid,
+    };
 
     #[test]
     fn sid_well_known() {
@@ -90,4 +145,21 @@ mod tests {
         assert!(is_well_known(&sid, WinBuiltinAdministratorsSid));
         assert!(!is_well_known(&sid, WinBuiltinUsersSid));
     }
+
+    #[test]
+    fn sid_from_string() {
+        let sid = StringSid::with_string("S-1-16-8192").unwrap();
+        assert!(is_well_known(&sid, WinMediumLabelSid));
+        assert!(sid.is_mandatory_label());
+
+        let sid = StringSid::with_string("LW").unwrap();
+        assert!(is_well_known(&sid, WinLowLabelSid));
+        assert!(sid.is_mandatory_label());
+
+        let sid = StringSid::with_string("S-1-5-32-544").unwrap();
+        assert!(is_well_known(&sid, WinBuiltinAdministratorsSid));
+        assert!(!sid.is_mandatory_label());
+
+        assert!(StringSid::with_string("not a sid").is_err());
+    }
 }
@@ -2,7 +2,10 @@ use crate::bridge::BridgeServer;
 use crate::command::*;
 use crate::{PrivilegeLevel, Token};
 use std::convert::TryInto;
+use std::ffi::OsString;
 use std::io::Result as IoResult;
+use std::time::Duration;
+use winapi::shared::minwindef::DWORD;
 
 /// Spawn a copy of the current process using the provided token.
 /// The existing streams are passed through to the child.
@@ -80,6 +83,73 @@ pub fn spawn_with_elevated_privileges() -> IoResult<()> {
     std::process::exit(server.serve(proc)? as _);
 }
 
+/// If the current process is already elevated, this function returns
+/// `Ok` and the intent is that the host program continue with its
+/// normal operation.
+///
+/// Otherwise, assuming no errors were detected, this function will
+/// not return to the caller.  Instead the current executable is
+/// launched again via the `runas` verb, with `extra_args` as its
+/// arguments, which shows the UAC prompt.  Unlike
+/// `spawn_with_elevated_privileges`, the stdio streams are not
+/// bridged: the new instance runs in a console of its own.
+/// *This* process waits for it and then exits with its exit code.
+/// If the user declines the prompt, an error for which
+/// `is_elevation_declined` returns true is returned.
+pub fn elevate_self<I, S>(extra_args: I) -> IoResult<()>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<OsString>,
+{
+    let token = Token::with_current_process()?;
+    match token.privilege_level()? {
+        PrivilegeLevel::NotPrivileged => {}
+        PrivilegeLevel::HighIntegrityAdmin | PrivilegeLevel::Elevated => return Ok(()),
+    }
+
+    let mut argv: Vec<OsString> = vec![std::env::current_exe()?.into()];
+    argv.extend(extra_args.into_iter().map(Into::into));
+
+    let mut cmd = Command::with_environment_for_token(&token)?;
+    cmd.set_argv(argv);
+    let proc = cmd.shell_execute("runas")?;
+
+    proc.wait_for(None)?;
+
+    let exit_code = proc.exit_code()?;
+    std::process::exit(exit_code as _);
+}
+
+/// Run `argv` with elevated privileges, bridged to the current
+/// terminal session, and return its exit code.
+/// If the current process is already privileged, the program is run
+/// directly.  Otherwise the bridge is launched via the `runas` verb,
+/// which shows the UAC prompt, and if the prompt hasn't been accepted
+/// and the program started within `timeout`, everything is torn down
+/// and an error for which `is_startup_timeout` returns true is
+/// returned; see `BridgeServer::set_startup_timeout`.
+/// Unlike `spawn_with_elevated_privileges`, this returns to the caller.
+pub fn run_elevated_with_timeout(argv: Vec<OsString>, timeout: Duration) -> IoResult<DWORD> {
+    let token = Token::with_current_process()?;
+    let target_token = match token.privilege_level()? {
+        PrivilegeLevel::NotPrivileged => token.as_medium_integrity_safer_token()?,
+        PrivilegeLevel::HighIntegrityAdmin | PrivilegeLevel::Elevated => {
+            let mut cmd = Command::with_environment_for_token(&token)?;
+            cmd.set_argv(argv);
+            let proc = cmd.spawn()?;
+            proc.wait_for(None)?;
+            return proc.exit_code();
+        }
+    };
+
+    let mut server = BridgeServer::new();
+    server.set_startup_timeout(timeout);
+    let mut argv = argv;
+    let mut bridge_cmd = server.start_for_command(&mut argv, &target_token)?;
+    let proc = server.launch_bridge(move || bridge_cmd.shell_execute("runas"))?;
+    server.serve(proc)
+}
+
 /// This function is for use by C/C++ code that wants to test whether the
 /// current session is elevated.  The return value is 0 for a non-privileged
 /// process and non-zero for a privileged process.
@@ -87,7 +157,7 @@ pub fn spawn_with_elevated_privileges() -> IoResult<()> {
 /// terminate.
 #[no_mangle]
 pub extern "C" fn deelevate_is_privileged_process() -> i32 {
-    match Token::with_current_process().and_then(|token| token.privilege_level()) {
+    match PrivilegeLevel::current() {
         Ok(PrivilegeLevel::Elevated) | Ok(PrivilegeLevel::HighIntegrityAdmin) => 1,
         Ok(PrivilegeLevel::NotPrivileged) => 0,
         Err(e) => {
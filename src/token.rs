@@ -1,10 +1,15 @@
 use crate::process::Process;
-use crate::sid::{get_length_sid, is_well_known, AsSid, WellKnownSid};
+use crate::sid::{get_length_sid, is_well_known, AsSid, StringSid, WellKnownSid};
 use crate::win32_error_with_context;
+use std::ffi::OsString;
 use std::io::{Error as IoError, Result as IoResult};
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
 use std::ptr::null_mut;
 use winapi::shared::minwindef::{BOOL, DWORD};
-use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS};
+use winapi::shared::winerror::{
+    ERROR_INSUFFICIENT_BUFFER, ERROR_NO_SUCH_LOGON_SESSION, ERROR_SUCCESS,
+};
 use winapi::um::accctrl::{
     EXPLICIT_ACCESSW, NO_INHERITANCE, NO_MULTIPLE_TRUSTEE, SET_ACCESS, TRUSTEE_IS_SID,
     TRUSTEE_IS_USER, TRUSTEE_W,
@@ -18,15 +23,19 @@ use winapi::um::securitybaseapi::{
     CheckTokenMembership, DuplicateTokenEx, GetTokenInformation, ImpersonateLoggedOnUser,
     InitializeSecurityDescriptor, SetSecurityDescriptorDacl, SetTokenInformation,
 };
-use winapi::um::winbase::{LocalAlloc, LocalFree};
+use winapi::um::userenv::GetUserProfileDirectoryW;
+use winapi::um::winbase::{LocalAlloc, LocalFree, LookupAccountSidW, LookupPrivilegeNameW};
 use winapi::um::winnt::{
-    SecurityImpersonation, TokenElevationType, TokenElevationTypeFull, TokenImpersonation,
-    TokenIntegrityLevel, TokenPrimary, TokenUser, WinBuiltinAdministratorsSid, WinHighLabelSid,
-    WinMediumLabelSid, GENERIC_READ, GENERIC_WRITE, HANDLE, PACL, PROCESS_QUERY_INFORMATION,
-    PSECURITY_DESCRIPTOR, SECURITY_DESCRIPTOR_MIN_LENGTH, SECURITY_DESCRIPTOR_REVISION,
-    SE_GROUP_INTEGRITY, SID, SID_AND_ATTRIBUTES, TOKEN_ADJUST_DEFAULT, TOKEN_ADJUST_SESSIONID,
+    SecurityImpersonation, TokenElevationType, TokenElevationTypeDefault, TokenElevationTypeFull,
+    TokenImpersonation, TokenIntegrityLevel, TokenLinkedToken, TokenPrimary, TokenPrivileges,
+    TokenType, TokenUser, WinBuiltinAdministratorsSid, WinHighLabelSid, WinLowLabelSid,
+    WinMediumLabelSid, GENERIC_READ, GENERIC_WRITE, HANDLE, LUID, LUID_AND_ATTRIBUTES, PACL,
+    PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION, PSECURITY_DESCRIPTOR,
+    SECURITY_DESCRIPTOR_MIN_LENGTH, SECURITY_DESCRIPTOR_REVISION, SE_GROUP_INTEGRITY,
+    SE_PRIVILEGE_ENABLED, SID, SID_AND_ATTRIBUTES, TOKEN_ADJUST_DEFAULT, TOKEN_ADJUST_SESSIONID,
     TOKEN_ASSIGN_PRIMARY, TOKEN_DUPLICATE, TOKEN_ELEVATION_TYPE, TOKEN_IMPERSONATE,
-    TOKEN_MANDATORY_LABEL, TOKEN_QUERY, TOKEN_TYPE,
+    TOKEN_LINKED_TOKEN, TOKEN_MANDATORY_LABEL, TOKEN_PRIVILEGES, TOKEN_QUERY, TOKEN_TYPE,
+    WELL_KNOWN_SID_TYPE,
 };
 use winapi::um::winsafer::{
     SaferCloseLevel, SaferComputeTokenFromLevel, SaferCreateLevel, SAFER_LEVELID_NORMALUSER,
@@ -34,6 +43,20 @@ use winapi::um::winsafer::{
 };
 use winapi::um::winuser::{GetShellWindow, GetWindowThreadProcessId};
 
+/// Parse `label` as a sid, checking that it is a mandatory label;
+/// see `Token::with_mandatory_label`
+pub(crate) fn parse_mandatory_label(label: &str) -> IoResult<StringSid> {
+    let sid = StringSid::with_string(label)
+        .map_err(|err| IoError::new(std::io::ErrorKind::InvalidInput, err))?;
+    if !sid.is_mandatory_label() {
+        return Err(IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a mandatory integrity label sid", label),
+        ));
+    }
+    Ok(sid)
+}
+
 /// Indicates the effective level of privileges held by the token
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PrivilegeLevel {
@@ -51,6 +74,15 @@ pub enum PrivilegeLevel {
     HighIntegrityAdmin,
 }
 
+impl PrivilegeLevel {
+    /// Returns the privilege level of the current process.
+    /// This is a shortcut for obtaining the token of the current
+    /// process and querying its privilege level.
+    pub fn current() -> IoResult<Self> {
+        Token::with_current_process()?.privilege_level()
+    }
+}
+
 /// A helper that wraps a TOKEN_MANDATORY_LABEL struct.
 /// That struct holds a SID and some attribute flags.
 /// Its use in this module is to query the integrity level
@@ -82,6 +114,35 @@ impl TokenIntegrityLevel {
     pub fn is_high(&self) -> bool {
         is_well_known(self.sid(), WinHighLabelSid)
     }
+
+    /// Return true if this is a low integrity level label
+    pub fn is_low(&self) -> bool {
+        is_well_known(self.sid(), WinLowLabelSid)
+    }
+}
+
+/// Resolve a privilege LUID to its programmatic name
+fn lookup_privilege_name(luid: &LUID) -> IoResult<String> {
+    let mut luid = *luid;
+    let mut buf = vec![0u16; 64];
+    loop {
+        let mut len = buf.len() as DWORD;
+        let res =
+            unsafe { LookupPrivilegeNameW(null_mut(), &mut luid, buf.as_mut_ptr(), &mut len) };
+        if res != 0 {
+            // On success, len is the length excluding the terminator
+            return Ok(String::from_utf16_lossy(&buf[0..len as usize]));
+        }
+        // On failure, len is the required size including the terminator
+        let err = unsafe { GetLastError() };
+        if err != ERROR_INSUFFICIENT_BUFFER || len as usize <= buf.len() {
+            return Err(win32_error_with_context(
+                "LookupPrivilegeNameW",
+                IoError::last_os_error(),
+            ));
+        }
+        buf.resize(len as usize, 0);
+    }
 }
 
 /// `Token` represents a set of credentials and privileges.  A process
@@ -134,6 +195,28 @@ impl Token {
         }
     }
 
+    /// Obtain the token of the process identified by `pid`, so that it
+    /// can be inspected, for example via `privilege_level`.
+    /// Only limited access to the process is requested, which is enough
+    /// to open its token; however, opening the token of a process that
+    /// runs as a different user, or that is elevated when we are not,
+    /// usually fails with an access denied error.
+    pub fn with_process_id(pid: DWORD) -> IoResult<Self> {
+        let proc = Process::with_process_id(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?;
+        let mut token: HANDLE = INVALID_HANDLE_VALUE;
+        let res = unsafe {
+            OpenProcessToken(proc.as_handle(), TOKEN_QUERY | TOKEN_DUPLICATE, &mut token)
+        };
+        if res != 1 {
+            Err(win32_error_with_context(
+                &format!("OpenProcessToken(pid {})", pid),
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(Self { token })
+        }
+    }
+
     pub(crate) fn create_security_descriptor(&self) -> IoResult<SecurityDescriptor> {
         let user = self.user()?;
 
@@ -221,6 +304,99 @@ impl Token {
     /// HighIntegrityAdmin privilege level and want to proceed
     /// with a normal privilege token.
     pub fn as_medium_integrity_safer_token(&self) -> IoResult<Self> {
+        let token = self.as_normal_user_safer_token()?;
+        token.set_integrity(WinMediumLabelSid, "Medium")?;
+        Ok(token)
+    }
+
+    /// Build a low integrity level normal user access token
+    /// from the current token.
+    /// This is suitable for running a program in a sandbox where
+    /// it cannot write to most of the filesystem or registry, nor
+    /// interact with processes running at medium integrity or above.
+    pub fn as_low_integrity_safer_token(&self) -> IoResult<Self> {
+        let token = self.as_normal_user_safer_token()?;
+        token.set_integrity(WinLowLabelSid, "Low")?;
+        Ok(token)
+    }
+
+    /// Build a copy of this token that carries the mandatory integrity
+    /// label `label`, which is either in the `S-1-16-...` form, or one
+    /// of the aliases such as `ME` that are understood by
+    /// ConvertStringSidToSidW.  An error of kind `InvalidInput` is
+    /// returned if `label` is not a mandatory label sid.
+    /// Lowering the integrity level is always permitted, but raising
+    /// it requires `SeRelabelPrivilege`.
+    pub fn with_mandatory_label(&self, label: &str) -> IoResult<Self> {
+        let sid = parse_mandatory_label(label)?;
+        let token = self.duplicate_as_primary_token()?;
+        token.set_integrity_sid(&sid, label)?;
+        Ok(token)
+    }
+
+    /// Returns true if the token has a low integrity level label
+    pub fn is_low_integrity(&self) -> IoResult<bool> {
+        Ok(self.integrity_level()?.is_low())
+    }
+
+    /// Returns the privileges held by the token, as pairs of the
+    /// privilege name (eg: `SeShutdownPrivilege`) and whether that
+    /// privilege is currently enabled.
+    pub fn privileges(&self) -> IoResult<Vec<(String, bool)>> {
+        let mut size: DWORD = 0;
+        let err;
+
+        unsafe {
+            GetTokenInformation(self.token, TokenPrivileges, null_mut(), 0, &mut size);
+            err = GetLastError();
+        };
+
+        // The call should have failed and told us we need more space.
+        // Even a token with no privileges needs room for the count.
+        if err != ERROR_INSUFFICIENT_BUFFER {
+            return Err(win32_error_with_context(
+                "GetTokenInformation TokenPrivileges unexpected failure",
+                IoError::last_os_error(),
+            ));
+        }
+
+        // Allocate and zero out the storage
+        let mut data = vec![0u8; size as usize];
+
+        unsafe {
+            if GetTokenInformation(
+                self.token,
+                TokenPrivileges,
+                data.as_mut_ptr() as *mut _,
+                size,
+                &mut size,
+            ) == 0
+            {
+                return Err(win32_error_with_context(
+                    "GetTokenInformation TokenPrivileges",
+                    IoError::last_os_error(),
+                ));
+            }
+        };
+
+        let privs: &[LUID_AND_ATTRIBUTES] = unsafe {
+            let header = &*(data.as_ptr() as *const TOKEN_PRIVILEGES);
+            std::slice::from_raw_parts(header.Privileges.as_ptr(), header.PrivilegeCount as usize)
+        };
+
+        privs
+            .iter()
+            .map(|p| {
+                let name = lookup_privilege_name(&p.Luid)?;
+                Ok((name, p.Attributes & SE_PRIVILEGE_ENABLED != 0))
+            })
+            .collect()
+    }
+
+    /// Use the SAFER APIs to compute a normal user token from the
+    /// current token; this removes administrative group membership
+    /// and privileges but leaves the integrity level unchanged.
+    fn as_normal_user_safer_token(&self) -> IoResult<Self> {
         let mut level: SAFER_LEVEL_HANDLE = null_mut();
         let res = unsafe {
             SaferCreateLevel(
@@ -256,17 +432,19 @@ impl Token {
             ));
         }
 
-        let token = Self { token };
-        token.set_medium_integrity()?;
-        Ok(token)
+        Ok(Self { token })
+    }
+
+    fn set_integrity(&self, label: WELL_KNOWN_SID_TYPE, label_name: &str) -> IoResult<()> {
+        let sid = WellKnownSid::with_well_known(label)?;
+        self.set_integrity_sid(&sid, label_name)
     }
 
-    fn set_medium_integrity(&self) -> IoResult<()> {
-        let medium = WellKnownSid::with_well_known(WinMediumLabelSid)?;
+    fn set_integrity_sid<S: AsSid + Copy>(&self, sid: S, label_name: &str) -> IoResult<()> {
         let mut tml = TOKEN_MANDATORY_LABEL {
             Label: SID_AND_ATTRIBUTES {
                 Attributes: SE_GROUP_INTEGRITY,
-                Sid: medium.as_sid() as *mut _,
+                Sid: sid.as_sid() as *mut _,
             },
         };
 
@@ -275,12 +453,12 @@ impl Token {
                 self.token,
                 TokenIntegrityLevel,
                 &mut tml as *mut TOKEN_MANDATORY_LABEL as *mut _,
-                std::mem::size_of_val(&tml) as u32 + get_length_sid(&medium),
+                std::mem::size_of_val(&tml) as u32 + get_length_sid(sid),
             )
         };
         if res != 1 {
             Err(win32_error_with_context(
-                "SetTokenInformation(TokenIntegrityLevel Medium)",
+                &format!("SetTokenInformation(TokenIntegrityLevel {})", label_name),
                 IoError::last_os_error(),
             ))
         } else {
@@ -292,14 +470,45 @@ impl Token {
     /// for use in impersonation related APIs, which includes the
     /// check_membership method.
     fn duplicate_as_impersonation_token(&self) -> IoResult<Self> {
-        self.duplicate(TokenImpersonation)
+        self.duplicate_as(TokenImpersonation)
     }
 
     fn duplicate_as_primary_token(&self) -> IoResult<Self> {
-        self.duplicate(TokenPrimary)
+        self.duplicate_as(TokenPrimary)
+    }
+
+    /// Create an independent copy of this token, of the same type
+    /// (primary or impersonation) as this one.  The copy has its own
+    /// handle, so it can be used to derive other tokens, or be dropped,
+    /// without affecting this one.
+    pub fn duplicate(&self) -> IoResult<Self> {
+        self.duplicate_as(self.token_type()?)
+    }
+
+    /// Returns whether this is a primary or an impersonation token
+    fn token_type(&self) -> IoResult<TOKEN_TYPE> {
+        let mut token_type: TOKEN_TYPE = 0;
+        let mut size: DWORD = 0;
+        let res = unsafe {
+            GetTokenInformation(
+                self.token,
+                TokenType,
+                &mut token_type as *mut TOKEN_TYPE as *mut _,
+                std::mem::size_of_val(&token_type) as u32,
+                &mut size,
+            )
+        };
+        if res != 1 {
+            Err(win32_error_with_context(
+                "GetTokenInformation TokenType",
+                IoError::last_os_error(),
+            ))
+        } else {
+            Ok(token_type)
+        }
     }
 
-    fn duplicate(&self, token_type: TOKEN_TYPE) -> IoResult<Self> {
+    fn duplicate_as(&self, token_type: TOKEN_TYPE) -> IoResult<Self> {
         let mut dup: HANDLE = INVALID_HANDLE_VALUE;
         let res = unsafe {
             DuplicateTokenEx(
@@ -394,6 +603,90 @@ impl Token {
         Ok(TokenIntegrityLevel { data })
     }
 
+    /// Returns the name of the user that the token belongs to, in
+    /// `DOMAIN\user` form
+    pub fn user_name(&self) -> IoResult<String> {
+        let user = self.user()?;
+        let mut name = vec![0u16; 64];
+        let mut domain = vec![0u16; 64];
+        loop {
+            let mut name_len = name.len() as DWORD;
+            let mut domain_len = domain.len() as DWORD;
+            let mut sid_use = 0;
+            let res = unsafe {
+                LookupAccountSidW(
+                    null_mut(),
+                    user.sid() as *mut _,
+                    name.as_mut_ptr(),
+                    &mut name_len,
+                    domain.as_mut_ptr(),
+                    &mut domain_len,
+                    &mut sid_use,
+                )
+            };
+            if res != 0 {
+                // On success, the lengths exclude the terminator
+                return Ok(format!(
+                    "{}\\{}",
+                    String::from_utf16_lossy(&domain[0..domain_len as usize]),
+                    String::from_utf16_lossy(&name[0..name_len as usize])
+                ));
+            }
+            if unsafe { GetLastError() } != ERROR_INSUFFICIENT_BUFFER {
+                return Err(win32_error_with_context(
+                    "LookupAccountSidW",
+                    IoError::last_os_error(),
+                ));
+            }
+            // On failure, the lengths are those that are required
+            name.resize((name_len as usize).max(name.len()), 0);
+            domain.resize((domain_len as usize).max(domain.len()), 0);
+        }
+    }
+
+    /// Returns the profile directory of the user that the token
+    /// belongs to, such as `C:\Users\name`.  This can differ from the
+    /// `USERPROFILE` of the current process, such as when elevating
+    /// with the credentials of a different administrator.
+    /// An error is returned if the user has no profile, for example
+    /// because they have never logged on to this machine.
+    pub fn profile_directory(&self) -> IoResult<PathBuf> {
+        let mut size: DWORD = 0;
+        let err;
+
+        unsafe {
+            GetUserProfileDirectoryW(self.token, null_mut(), &mut size);
+            err = GetLastError();
+        };
+
+        // The call should have failed and told us we need more space
+        if err != ERROR_INSUFFICIENT_BUFFER {
+            return Err(win32_error_with_context(
+                "GetUserProfileDirectoryW unexpected failure",
+                IoError::last_os_error(),
+            ));
+        }
+
+        let mut path = vec![0u16; size as usize];
+        let res = unsafe { GetUserProfileDirectoryW(self.token, path.as_mut_ptr(), &mut size) };
+        if res == 0 {
+            return Err(win32_error_with_context(
+                "GetUserProfileDirectoryW",
+                IoError::last_os_error(),
+            ));
+        }
+
+        // The size includes the terminator
+        let len = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+        if len == 0 {
+            return Err(IoError::new(
+                std::io::ErrorKind::NotFound,
+                "the user has no profile directory",
+            ));
+        }
+        Ok(OsString::from_wide(&path[..len]).into())
+    }
+
     /// Retrieve the integrity level label of the process.
     fn integrity_level(&self) -> IoResult<TokenIntegrityLevel> {
         let mut size: DWORD = 0;
@@ -480,6 +773,51 @@ impl Token {
         }
     }
 
+    /// Obtain the linked token for a UAC split-token admin.
+    /// When the current token is the limited half of the pair, this
+    /// returns the full elevated counterpart (and vice versa).
+    /// Returns `None` for a standard user that has no linked token.
+    ///
+    /// Note that holding the linked token is not the same thing as
+    /// being able to use it: spawning a process with it still requires
+    /// the appropriate privileges, and this does not bypass UAC consent
+    /// in situations where consent is mandated by policy.
+    pub fn linked_token(&self) -> IoResult<Option<Self>> {
+        if self.elevation_type()? == TokenElevationTypeDefault {
+            return Ok(None);
+        }
+
+        let mut linked = TOKEN_LINKED_TOKEN {
+            LinkedToken: INVALID_HANDLE_VALUE,
+        };
+        let mut size: DWORD = 0;
+        let res = unsafe {
+            GetTokenInformation(
+                self.token,
+                TokenLinkedToken,
+                &mut linked as *mut TOKEN_LINKED_TOKEN as *mut _,
+                std::mem::size_of_val(&linked) as u32,
+                &mut size,
+            )
+        };
+        if res != 1 {
+            let err = unsafe { GetLastError() };
+            if err == ERROR_NO_SUCH_LOGON_SESSION {
+                // There is no linked token for this logon session
+                Ok(None)
+            } else {
+                Err(win32_error_with_context(
+                    "GetTokenInformation TokenLinkedToken",
+                    IoError::last_os_error(),
+                ))
+            }
+        } else {
+            Ok(Some(Self {
+                token: linked.LinkedToken,
+            }))
+        }
+    }
+
     /// Impersonate applies the token to the current thread only.
     /// This isn't a supported API: it is present to furnish an
     /// example that shows that it doesn't behave how you might
@@ -518,6 +856,127 @@ mod test {
         assert_eq!(level, PrivilegeLevel::NotPrivileged);
     }
 
+    #[test]
+    fn token_for_process_id() {
+        let own = Token::with_current_process().unwrap();
+        let pid = unsafe { winapi::um::processthreadsapi::GetCurrentProcessId() };
+        let token = Token::with_process_id(pid).unwrap();
+        assert_eq!(
+            token.privilege_level().unwrap(),
+            own.privilege_level().unwrap()
+        );
+
+        let user = token.user_name().unwrap();
+        assert!(user.contains('\\'), "{}", user);
+        assert_eq!(user, own.user_name().unwrap());
+    }
+
+    #[test]
+    fn profile_directory() {
+        let token = Token::with_current_process().unwrap();
+        let profile = token.profile_directory().unwrap();
+        assert!(profile.is_dir(), "{}", profile.display());
+        if let Some(expected) = std::env::var_os("USERPROFILE") {
+            assert!(
+                profile.as_os_str().eq_ignore_ascii_case(&expected),
+                "{} vs {:?}",
+                profile.display(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn current_privilege_level() {
+        let token = Token::with_current_process().unwrap();
+        assert_eq!(
+            PrivilegeLevel::current().unwrap(),
+            token.privilege_level().unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicate_token() {
+        let token = Token::with_current_process().unwrap();
+        let level = token.privilege_level().unwrap();
+
+        // Derive a medium token from a duplicate and then discard
+        // the duplicate; the original should be unaffected.
+        {
+            let dup = token.duplicate().unwrap();
+            assert_eq!(dup.privilege_level().unwrap(), level);
+            let medium = dup.as_medium_integrity_safer_token().unwrap();
+            assert_eq!(
+                medium.privilege_level().unwrap(),
+                PrivilegeLevel::NotPrivileged
+            );
+        }
+
+        assert_eq!(token.privilege_level().unwrap(), level);
+        crate::Command::with_environment_for_token(&token).unwrap();
+    }
+
+    #[test]
+    fn low_integrity_token() {
+        let token = Token::with_current_process().unwrap();
+        assert!(!token.is_low_integrity().unwrap());
+        let low = token.as_low_integrity_safer_token().unwrap();
+        assert!(low.is_low_integrity().unwrap());
+        assert_eq!(
+            low.privilege_level().unwrap(),
+            PrivilegeLevel::NotPrivileged
+        );
+    }
+
+    #[test]
+    fn mandatory_label_token() {
+        let token = Token::with_current_process().unwrap();
+        let low = token.with_mandatory_label("S-1-16-4096").unwrap();
+        assert!(low.is_low_integrity().unwrap());
+        assert!(!token.is_low_integrity().unwrap());
+
+        let kind = |label| {
+            token
+                .with_mandatory_label(label)
+                .err()
+                .map(|err| err.kind())
+        };
+        assert_eq!(kind("S-1-5-32-544"), Some(std::io::ErrorKind::InvalidInput));
+        assert_eq!(kind("bogus"), Some(std::io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn get_privileges() {
+        let token = Token::with_current_process().unwrap();
+        let privs = token.privileges().unwrap();
+        for (name, enabled) in &privs {
+            eprintln!("{} enabled={}", name, enabled);
+            assert!(name.starts_with("Se"), "{}", name);
+        }
+
+        // The safer token strips privileges but never adds any
+        let medium = token.as_medium_integrity_safer_token().unwrap();
+        let medium_privs = medium.privileges().unwrap();
+        assert!(medium_privs.len() <= privs.len());
+        for (name, _) in &medium_privs {
+            assert!(privs.iter().any(|(n, _)| n == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn get_linked_token() {
+        // Whether there is a linked token depends on how the test
+        // is being run, but the query itself should not fail.
+        let token = Token::with_current_process().unwrap();
+        match token.linked_token().unwrap() {
+            Some(linked) => eprintln!(
+                "linked token priv level is {:?}",
+                linked.privilege_level().unwrap()
+            ),
+            None => eprintln!("there is no linked token"),
+        }
+    }
+
     #[test]
     fn get_shell_token() {
         // We should either successfully obtain the shell token (if we're